@@ -24,7 +24,15 @@ impl Database {
 pub struct IO {}
 
 impl limbo_core::IO for IO {
-    fn open_file(&self, _path: &str) -> Result<Rc<dyn limbo_core::File>> {
+    fn open_file(
+        &self,
+        _path: &str,
+        _flags: limbo_core::OpenFlags,
+    ) -> Result<Rc<dyn limbo_core::File>> {
+        todo!();
+    }
+
+    fn remove_file(&self, _path: &str) -> Result<()> {
         todo!();
     }
 
@@ -48,4 +56,12 @@ impl limbo_core::PageIO for PageIO {
     ) -> Result<()> {
         todo!()
     }
+
+    fn sync(&self) -> Result<()> {
+        todo!()
+    }
+
+    fn truncate(&self, _len: usize) -> Result<()> {
+        todo!()
+    }
 }
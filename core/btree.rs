@@ -1,16 +1,27 @@
-use crate::pager::Pager;
-use crate::sqlite3_ondisk::{BTreeCell, TableInteriorCell, TableLeafCell};
-use crate::types::{Cursor, CursorResult, OwnedRecord};
+use crate::io::IO;
+use crate::pager::{Page, Pager};
+use crate::sqlite3_ondisk::{
+    write_record, BTreeCell, BTreePage, BTreePageHeader, IndexInteriorCell, IndexLeafCell,
+    OverflowPage, PageContent, PageType, TableInteriorCell, TableLeafCell, DATABASE_HEADER_SIZE,
+};
+use crate::types::{Cursor, CursorResult, OwnedRecord, OwnedValue, SeekOp};
 
 use anyhow::Result;
 
 use std::cell::{Ref, RefCell};
+use std::cmp::Ordering;
 use std::rc::Rc;
 
 pub struct MemPage {
     parent: Option<Rc<MemPage>>,
     page_idx: usize,
+    // The next cell to visit. When visiting the page backwards, this is
+    // instead the number of entries that remain to be visited, where the
+    // children of an interior page count as entries of their own.
     cell_idx: RefCell<usize>,
+    // Set on an index interior page once the left subtree of the current
+    // cell has been visited, so that the cell's own key is next.
+    left_visited: RefCell<bool>,
 }
 
 impl MemPage {
@@ -19,25 +30,94 @@ impl MemPage {
             parent,
             page_idx,
             cell_idx: RefCell::new(cell_idx),
+            left_visited: RefCell::new(false),
         }
     }
 
+    /// A page to visit backwards, starting from its last entry. The number
+    /// of entries is only known once the page is read.
+    pub fn new_at_end(parent: Option<Rc<MemPage>>, page_idx: usize) -> Self {
+        Self::new(parent, page_idx, usize::MAX)
+    }
+
     pub fn cell_idx(&self) -> usize {
         *self.cell_idx.borrow()
     }
 
+    pub fn is_at_end(&self) -> bool {
+        self.cell_idx() == usize::MAX
+    }
+
+    pub fn set_cell_idx(&self, cell_idx: usize) {
+        self.cell_idx.replace(cell_idx);
+    }
+
+    pub fn retreat(&self) {
+        let mut cell_idx = self.cell_idx.borrow_mut();
+        *cell_idx -= 1;
+    }
+
     pub fn advance(&self) {
         let mut cell_idx = self.cell_idx.borrow_mut();
         *cell_idx += 1;
+        self.left_visited.replace(false);
+    }
+
+    pub fn left_visited(&self) -> bool {
+        *self.left_visited.borrow()
+    }
+
+    pub fn set_left_visited(&self) {
+        self.left_visited.replace(true);
     }
 }
 
+/// An interior page on the path from the root to a leaf, and the index of the
+/// child pointer that was followed. An index equal to the number of cells
+/// stands for the right-most pointer.
+struct PathEntry {
+    page_idx: usize,
+    page: Rc<Page>,
+    child_idx: usize,
+}
+
+/// The first overflow page of a payload, the part of the payload that has
+/// been read so far and the next overflow page to read.
+type OverflowState = (u32, Vec<u8>, Option<u32>);
+
+/// Progress of a search for a key in an index b-tree, kept across I/O so that
+/// a retry resumes the search instead of reading every page and overflow
+/// page on the way down again.
+struct IndexSeek {
+    key: OwnedRecord,
+    op: SeekOp,
+    parent: Option<Rc<MemPage>>,
+    page_idx: usize,
+    // The cells of the page that remain to be searched.
+    lo: usize,
+    hi: Option<usize>,
+    // Set once the pages to iterate through have been set up, while the
+    // entry the search found is read.
+    positioned: bool,
+}
+
+/// A cursor over a table b-tree, which it visits in rowid order, or an index
+/// b-tree, which it visits in key order. Which one it is follows from the type
+/// of the root page.
 pub struct BTreeCursor {
     pager: Rc<Pager>,
     root_page: usize,
     page: RefCell<Option<Rc<MemPage>>>,
     rowid: RefCell<Option<u64>>,
     record: RefCell<Option<OwnedRecord>>,
+    // Set by NullRow: the cursor reads as a row of NULLs until it moves.
+    null_flag: bool,
+    // Kept across I/O while reading an overflow chain.
+    overflow: RefCell<Option<OverflowState>>,
+    seek: RefCell<Option<IndexSeek>>,
+    // Rowid of an inserted or deleted row that the cursor has yet to be moved
+    // past, kept across I/O.
+    written_key: Option<i64>,
 }
 
 impl BTreeCursor {
@@ -48,6 +128,10 @@ impl BTreeCursor {
             page: RefCell::new(None),
             rowid: RefCell::new(None),
             record: RefCell::new(None),
+            null_flag: false,
+            overflow: RefCell::new(None),
+            seek: RefCell::new(None),
+            written_key: None,
         }
     }
 
@@ -64,7 +148,7 @@ impl BTreeCursor {
                 return Ok(CursorResult::IO);
             }
             let page = page.contents.read().unwrap();
-            let page = page.as_ref().unwrap();
+            let page = page.as_ref().unwrap().as_btree();
             if mem_page.cell_idx() >= page.cells.len() {
                 let parent = mem_page.parent.clone();
                 match page.header.right_most_pointer {
@@ -96,22 +180,1236 @@ impl BTreeCursor {
                     self.page.replace(Some(Rc::new(mem_page)));
                     continue;
                 }
-                BTreeCell::TableLeafCell(TableLeafCell { _rowid, _payload }) => {
+                BTreeCell::TableLeafCell(cell) => {
+                    let record = match self.read_cell_record(
+                        &cell._payload,
+                        cell.payload_size,
+                        cell.first_overflow_page,
+                    )? {
+                        CursorResult::Ok(record) => record,
+                        CursorResult::IO => return Ok(CursorResult::IO),
+                    };
                     mem_page.advance();
-                    let record = crate::sqlite3_ondisk::read_record(_payload)?;
-                    return Ok(CursorResult::Ok((Some(*_rowid), Some(record))));
+                    return Ok(CursorResult::Ok((Some(cell._rowid), Some(record))));
+                }
+                BTreeCell::IndexInteriorCell(cell) => {
+                    // The keys in the left subtree come before the cell's own.
+                    if !mem_page.left_visited() {
+                        mem_page.set_left_visited();
+                        let mem_page =
+                            MemPage::new(Some(mem_page.clone()), cell.left_child_page as usize, 0);
+                        self.page.replace(Some(Rc::new(mem_page)));
+                        continue;
+                    }
+                    let record = match self.read_cell_record(
+                        &cell.payload,
+                        cell.payload_size,
+                        cell.first_overflow_page,
+                    )? {
+                        CursorResult::Ok(record) => record,
+                        CursorResult::IO => return Ok(CursorResult::IO),
+                    };
+                    mem_page.advance();
+                    return Ok(CursorResult::Ok((index_key_rowid(&record), Some(record))));
+                }
+                BTreeCell::IndexLeafCell(cell) => {
+                    let record = match self.read_cell_record(
+                        &cell.payload,
+                        cell.payload_size,
+                        cell.first_overflow_page,
+                    )? {
+                        CursorResult::Ok(record) => record,
+                        CursorResult::IO => return Ok(CursorResult::IO),
+                    };
+                    mem_page.advance();
+                    return Ok(CursorResult::Ok((index_key_rowid(&record), Some(record))));
+                }
+            }
+        }
+    }
+}
+
+impl BTreeCursor {
+    /// Like `get_next_record`, but moving backwards through the b-tree, for a
+    /// cursor that was moved to its end by `last`.
+    fn get_prev_record(&mut self) -> Result<CursorResult<(Option<u64>, Option<OwnedRecord>)>> {
+        loop {
+            let mem_page = {
+                let mem_page = self.page.borrow();
+                let mem_page = mem_page.as_ref().unwrap();
+                mem_page.clone()
+            };
+            let page = self.pager.read_page(mem_page.page_idx)?;
+            if page.is_locked() {
+                return Ok(CursorResult::IO);
+            }
+            let page = page.contents.read().unwrap();
+            let page = page.as_ref().unwrap().as_btree();
+            if mem_page.is_at_end() {
+                let num_entries = match page.header.page_type {
+                    PageType::TableInterior => page.cells.len() + 1,
+                    PageType::IndexInterior => 2 * page.cells.len() + 1,
+                    PageType::TableLeaf | PageType::IndexLeaf => page.cells.len(),
+                };
+                mem_page.set_cell_idx(num_entries);
+            }
+            let Some(entry_idx) = mem_page.cell_idx().checked_sub(1) else {
+                match mem_page.parent.clone() {
+                    Some(parent) => {
+                        self.page.replace(Some(parent));
+                        continue;
+                    }
+                    None => return Ok(CursorResult::Ok((None, None))),
+                }
+            };
+            // The entries of a table interior page are the children of its
+            // cells followed by the right-most child. Those of an index
+            // interior page alternate between children and the cells' own
+            // keys.
+            let child = match page.header.page_type {
+                PageType::TableInterior => Some(match page.cells.get(entry_idx) {
+                    Some(BTreeCell::TableInteriorCell(cell)) => cell._left_child_page,
+                    _ => page.header.right_most_pointer.unwrap(),
+                }),
+                PageType::IndexInterior if entry_idx % 2 == 0 => {
+                    Some(match page.cells.get(entry_idx / 2) {
+                        Some(BTreeCell::IndexInteriorCell(cell)) => cell.left_child_page,
+                        _ => page.header.right_most_pointer.unwrap(),
+                    })
                 }
+                _ => None,
+            };
+            if let Some(child) = child {
+                mem_page.retreat();
+                let mem_page = MemPage::new_at_end(Some(mem_page.clone()), child as usize);
+                self.page.replace(Some(Rc::new(mem_page)));
+                continue;
             }
+            let cell_idx = if page.header.page_type == PageType::IndexInterior {
+                entry_idx / 2
+            } else {
+                entry_idx
+            };
+            let (record, rowid) = match &page.cells[cell_idx] {
+                BTreeCell::TableLeafCell(cell) => (
+                    self.read_cell_record(
+                        &cell._payload,
+                        cell.payload_size,
+                        cell.first_overflow_page,
+                    )?,
+                    Some(cell._rowid),
+                ),
+                BTreeCell::IndexInteriorCell(cell) => (
+                    self.read_cell_record(
+                        &cell.payload,
+                        cell.payload_size,
+                        cell.first_overflow_page,
+                    )?,
+                    None,
+                ),
+                BTreeCell::IndexLeafCell(cell) => (
+                    self.read_cell_record(
+                        &cell.payload,
+                        cell.payload_size,
+                        cell.first_overflow_page,
+                    )?,
+                    None,
+                ),
+                BTreeCell::TableInteriorCell(_) => unreachable!("expected a key cell"),
+            };
+            let record = match record {
+                CursorResult::Ok(record) => record,
+                CursorResult::IO => return Ok(CursorResult::IO),
+            };
+            mem_page.retreat();
+            let rowid = rowid.or_else(|| index_key_rowid(&record));
+            return Ok(CursorResult::Ok((rowid, Some(record))));
         }
     }
 }
 
+/// The rowid of the row an index key points to, which is the key's last
+/// column.
+fn index_key_rowid(record: &OwnedRecord) -> Option<u64> {
+    match record.values.last() {
+        Some(OwnedValue::Integer(rowid)) => Some(*rowid as u64),
+        _ => None,
+    }
+}
+
+impl BTreeCursor {
+    /// Decode the record stored in a cell, given the local part of its
+    /// payload and where the rest of it continues.
+    fn read_cell_record(
+        &self,
+        local_payload: &[u8],
+        payload_size: u64,
+        first_overflow_page: Option<u32>,
+    ) -> Result<CursorResult<OwnedRecord>> {
+        let Some(first_overflow_page) = first_overflow_page else {
+            return Ok(CursorResult::Ok(crate::sqlite3_ondisk::read_record(
+                local_payload,
+            )?));
+        };
+        match self.read_overflow_payload(local_payload, payload_size, first_overflow_page)? {
+            CursorResult::Ok(payload) => Ok(CursorResult::Ok(crate::sqlite3_ondisk::read_record(
+                &payload,
+            )?)),
+            CursorResult::IO => Ok(CursorResult::IO),
+        }
+    }
+
+    /// Reassemble the payload of a cell that spills into overflow pages.
+    /// Returns IO while an overflow page is being read; the pages read so far
+    /// are remembered, so the caller can simply retry.
+    fn read_overflow_payload(
+        &self,
+        local_payload: &[u8],
+        payload_size: u64,
+        first_overflow_page: u32,
+    ) -> Result<CursorResult<Vec<u8>>> {
+        let payload_size = payload_size as usize;
+        let mut overflow = self.overflow.borrow_mut();
+        // A retry may come back to a different cell than the one whose
+        // payload was being read, e.g. while searching a page.
+        if !matches!(*overflow, Some((page, _, _)) if page == first_overflow_page) {
+            let mut payload = Vec::with_capacity(payload_size);
+            payload.extend_from_slice(local_payload);
+            overflow.replace((first_overflow_page, payload, Some(first_overflow_page)));
+        }
+        let (_, payload, next_page) = overflow.as_mut().unwrap();
+        while payload.len() < payload_size {
+            let Some(page_idx) = *next_page else {
+                // Drop the partial payload so that a retry starts over.
+                overflow.take();
+                anyhow::bail!("overflow chain is shorter than its payload");
+            };
+            let page = self.pager.read_overflow_page(page_idx as usize)?;
+            if page.is_locked() {
+                return Ok(CursorResult::IO);
+            }
+            let contents = page.contents.read().unwrap();
+            let contents = contents.as_ref().unwrap().as_overflow();
+            let remaining = payload_size - payload.len();
+            let n = remaining.min(contents.data.len());
+            payload.extend_from_slice(&contents.data[..n]);
+            *next_page = contents.next_page;
+        }
+        Ok(CursorResult::Ok(overflow.take().unwrap().1))
+    }
+
+    /// Walk from the root to the leaf page that `key` belongs in. Returns the
+    /// interior pages that were passed through, and the leaf.
+    #[allow(clippy::type_complexity)]
+    fn find_leaf(&self, key: i64) -> Result<CursorResult<(Vec<PathEntry>, usize, Rc<Page>)>> {
+        let mut path = Vec::new();
+        let mut page_idx = self.root_page;
+        loop {
+            let page = self.pager.read_page(page_idx)?;
+            if page.is_locked() {
+                return Ok(CursorResult::IO);
+            }
+            let child = {
+                let contents = page.contents.read().unwrap();
+                let contents = contents.as_ref().unwrap().as_btree();
+                match contents.header.page_type {
+                    PageType::TableLeaf => None,
+                    PageType::TableInterior => {
+                        // Keys in a left child are less than or equal to the cell's key.
+                        let child_idx = contents
+                            .cells
+                            .partition_point(|cell| (cell.rowid() as i64) < key);
+                        let child = match contents.cells.get(child_idx) {
+                            Some(BTreeCell::TableInteriorCell(cell)) => cell._left_child_page,
+                            _ => contents.header.right_most_pointer.unwrap(),
+                        };
+                        Some((child_idx, child as usize))
+                    }
+                    _ => anyhow::bail!("page {} is not a table b-tree page", page_idx),
+                }
+            };
+            match child {
+                Some((child_idx, child)) => {
+                    path.push(PathEntry {
+                        page_idx,
+                        page,
+                        child_idx,
+                    });
+                    page_idx = child;
+                }
+                None => return Ok(CursorResult::Ok((path, page_idx, page))),
+            }
+        }
+    }
+
+    /// Free all pages of the b-tree, including its root and overflow pages.
+    pub fn destroy(&self) -> Result<()> {
+        let mut pages = vec![self.root_page];
+        while let Some(page_idx) = pages.pop() {
+            let page = self.read_page_sync(page_idx)?;
+            let overflow_pages = {
+                let contents = page.contents.read().unwrap();
+                let contents = contents.as_ref().unwrap().as_btree();
+                pages.extend(
+                    contents
+                        .cells
+                        .iter()
+                        .filter_map(|cell| match cell {
+                            BTreeCell::TableInteriorCell(cell) => Some(cell._left_child_page),
+                            BTreeCell::IndexInteriorCell(cell) => Some(cell.left_child_page),
+                            _ => None,
+                        })
+                        .chain(contents.header.right_most_pointer)
+                        .map(|page| page as usize),
+                );
+                contents
+                    .cells
+                    .iter()
+                    .map(|cell| cell.first_overflow_page())
+                    .collect::<Vec<_>>()
+            };
+            for first_overflow_page in overflow_pages {
+                self.free_overflow_chain(first_overflow_page)?;
+            }
+            self.pager.free_page(page_idx)?;
+        }
+        Ok(())
+    }
+
+    /// Set up the pages to iterate through so that the next record the cursor
+    /// reads is cell `cell_idx` of the table leaf `page_idx`, which was reached
+    /// through `path`.
+    fn move_to(&self, path: &[PathEntry], page_idx: usize, cell_idx: usize) {
+        let mut parent = None;
+        for entry in path {
+            let contents = entry.page.contents.read().unwrap();
+            let contents = contents.as_ref().unwrap().as_btree();
+            // A table interior cell is passed before its child is visited, and
+            // there is nothing left to visit after the right-most child.
+            if entry.child_idx < contents.cells.len() {
+                let mem_page = MemPage::new(parent, entry.page_idx, entry.child_idx + 1);
+                parent = Some(Rc::new(mem_page));
+            }
+        }
+        let mem_page = MemPage::new(parent, page_idx, cell_idx);
+        self.page.replace(Some(Rc::new(mem_page)));
+    }
+
+    /// Descend from the root of an index b-tree to the first entry that is
+    /// past `key` as given by `op`, and set up the pages to iterate through so
+    /// that it is the next record the cursor reads.
+    fn move_to_index_key(&self, key: &OwnedRecord, op: SeekOp) -> Result<CursorResult<()>> {
+        let mut seek = self.seek.borrow_mut();
+        if !matches!(&*seek, Some(seek) if seek.key == *key && seek.op == op) {
+            seek.replace(IndexSeek {
+                key: key.clone(),
+                op,
+                parent: None,
+                page_idx: self.root_page,
+                lo: 0,
+                hi: None,
+                positioned: false,
+            });
+        }
+        let seek = seek.as_mut().unwrap();
+        if seek.positioned {
+            return Ok(CursorResult::Ok(()));
+        }
+        loop {
+            let page = self.pager.read_page(seek.page_idx)?;
+            if page.is_locked() {
+                return Ok(CursorResult::IO);
+            }
+            let contents = page.contents.read().unwrap();
+            let contents = contents.as_ref().unwrap().as_btree();
+            // Binary search for the first cell past the key.
+            let mut hi = *seek.hi.get_or_insert(contents.cells.len());
+            while seek.lo < hi {
+                let mid = (seek.lo + hi) / 2;
+                let record = match &contents.cells[mid] {
+                    BTreeCell::IndexInteriorCell(cell) => self.read_cell_record(
+                        &cell.payload,
+                        cell.payload_size,
+                        cell.first_overflow_page,
+                    )?,
+                    BTreeCell::IndexLeafCell(cell) => self.read_cell_record(
+                        &cell.payload,
+                        cell.payload_size,
+                        cell.first_overflow_page,
+                    )?,
+                    _ => anyhow::bail!("page {} is not an index b-tree page", seek.page_idx),
+                };
+                let record = match record {
+                    CursorResult::Ok(record) => record,
+                    CursorResult::IO => return Ok(CursorResult::IO),
+                };
+                let past = match op {
+                    SeekOp::GE => record.compare_prefix(key) != Ordering::Less,
+                    SeekOp::GT => record.compare_prefix(key) == Ordering::Greater,
+                };
+                if past {
+                    hi = mid;
+                    seek.hi = Some(hi);
+                } else {
+                    seek.lo = mid + 1;
+                }
+            }
+            let child = match contents.cells.get(seek.lo) {
+                // The entry is in the cell's left subtree or, failing that, is
+                // the cell's own key.
+                Some(BTreeCell::IndexInteriorCell(cell)) => {
+                    let mem_page = MemPage::new(seek.parent.take(), seek.page_idx, seek.lo);
+                    mem_page.set_left_visited();
+                    seek.parent = Some(Rc::new(mem_page));
+                    Some(cell.left_child_page)
+                }
+                Some(_) => None,
+                None => contents.header.right_most_pointer,
+            };
+            match child {
+                Some(child) => {
+                    seek.page_idx = child as usize;
+                    seek.lo = 0;
+                    seek.hi = None;
+                }
+                None => break,
+            }
+        }
+        let mem_page = MemPage::new(seek.parent.take(), seek.page_idx, seek.lo);
+        self.page.replace(Some(Rc::new(mem_page)));
+        seek.positioned = true;
+        Ok(CursorResult::Ok(()))
+    }
+
+    fn insert_cell(&self, key: i64, mut payload: Vec<u8>) -> Result<CursorResult<()>> {
+        let (path, page_idx, page) = match self.find_leaf(key)? {
+            CursorResult::Ok(leaf) => leaf,
+            CursorResult::IO => return Ok(CursorResult::IO),
+        };
+        let payload_size = payload.len();
+        let first_overflow_page = self.spill_payload(&mut payload, PageType::TableLeaf)?;
+        let (appended, replaced) = {
+            let mut contents = page.contents.write().unwrap();
+            let contents = contents.as_mut().unwrap().as_btree_mut();
+            let cell = BTreeCell::TableLeafCell(TableLeafCell {
+                _rowid: key as u64,
+                _payload: payload,
+                payload_size: payload_size as u64,
+                first_overflow_page,
+            });
+            // A row that is replaced is rewritten in place; the page is only
+            // split if the new row does not fit.
+            match contents
+                .cells
+                .binary_search_by_key(&key, |cell| cell.rowid() as i64)
+            {
+                Ok(idx) => (
+                    false,
+                    Some(std::mem::replace(&mut contents.cells[idx], cell)),
+                ),
+                Err(idx) => {
+                    contents.cells.insert(idx, cell);
+                    (idx == contents.cells.len() - 1, None)
+                }
+            }
+        };
+        if let Some(replaced) = replaced {
+            self.free_overflow_chain(replaced.first_overflow_page())?;
+        }
+        self.balance(path, page_idx, page, appended)?;
+        Ok(CursorResult::Ok(()))
+    }
+
+    /// Store the part of a payload that does not fit in its cell on a page of
+    /// the given type in an overflow chain, leaving the part that does in
+    /// `payload`. Returns the first page of the chain, if any.
+    fn spill_payload(&self, payload: &mut Vec<u8>, page_type: PageType) -> Result<Option<u32>> {
+        let local_size = self.pager.payload_local_size(payload.len(), page_type);
+        let first_overflow_page = if local_size < payload.len() {
+            Some(self.write_overflow_chain(&payload[local_size..])?)
+        } else {
+            None
+        };
+        payload.truncate(local_size);
+        Ok(first_overflow_page)
+    }
+
+    /// Store the part of a payload that does not fit in its cell in a chain of
+    /// new overflow pages. Returns the first page of the chain.
+    fn write_overflow_chain(&self, data: &[u8]) -> Result<u32> {
+        let chunk_size = self.pager.usable_size() - 4;
+        let pages = data
+            .chunks(chunk_size)
+            .map(|_| self.pager.allocate_overflow_page())
+            .collect::<Result<Vec<_>>>()?;
+        for (i, chunk) in data.chunks(chunk_size).enumerate() {
+            let (page_idx, page) = &pages[i];
+            page.contents
+                .write()
+                .unwrap()
+                .replace(PageContent::Overflow(OverflowPage {
+                    next_page: pages.get(i + 1).map(|(next, _)| *next as u32),
+                    data: chunk.to_vec(),
+                }));
+            self.pager.add_dirty(*page_idx, page);
+        }
+        Ok(pages[0].0 as u32)
+    }
+
+    /// Return the pages of an overflow chain, if any, to the freelist.
+    fn free_overflow_chain(&self, first_overflow_page: Option<u32>) -> Result<()> {
+        let mut next_page = first_overflow_page;
+        while let Some(page_idx) = next_page {
+            let page = self.pager.read_overflow_page(page_idx as usize)?;
+            while page.is_locked() {
+                self.pager.io.run_once()?;
+            }
+            next_page = page
+                .contents
+                .read()
+                .unwrap()
+                .as_ref()
+                .unwrap()
+                .as_overflow()
+                .next_page;
+            self.pager.free_page(page_idx as usize)?;
+        }
+        Ok(())
+    }
+
+    /// Read a page and wait for it. Changes to a b-tree are not resumed after
+    /// I/O part way through, as the pages are written as soon as they change.
+    fn read_page_sync(&self, page_idx: usize) -> Result<Rc<Page>> {
+        let page = self.pager.read_page(page_idx)?;
+        while page.is_locked() {
+            self.pager.io.run_once()?;
+        }
+        Ok(page)
+    }
+
+    /// Decode the key stored in an index cell, waiting for any overflow pages.
+    fn read_key_sync(&self, cell: &BTreeCell) -> Result<OwnedRecord> {
+        loop {
+            let record = match cell {
+                BTreeCell::IndexInteriorCell(cell) => self.read_cell_record(
+                    &cell.payload,
+                    cell.payload_size,
+                    cell.first_overflow_page,
+                )?,
+                BTreeCell::IndexLeafCell(cell) => self.read_cell_record(
+                    &cell.payload,
+                    cell.payload_size,
+                    cell.first_overflow_page,
+                )?,
+                _ => unreachable!("expected an index cell"),
+            };
+            match record {
+                CursorResult::Ok(record) => return Ok(record),
+                CursorResult::IO => self.pager.io.run_once()?,
+            }
+        }
+    }
+
+    /// Remove the row with rowid `key` from the table, along with its
+    /// overflow pages.
+    fn delete_cell(&self, key: i64) -> Result<CursorResult<()>> {
+        let (path, page_idx, page) = match self.find_leaf(key)? {
+            CursorResult::Ok(leaf) => leaf,
+            CursorResult::IO => return Ok(CursorResult::IO),
+        };
+        // Only the cell is dropped here; when the page is written back the
+        // bytes it occupied become a freeblock (or fragmented bytes if fewer
+        // than four) rather than the remaining cells being moved up.
+        let cell = {
+            let mut contents = page.contents.write().unwrap();
+            let contents = contents.as_mut().unwrap().as_btree_mut();
+            match contents
+                .cells
+                .binary_search_by_key(&key, |cell| cell.rowid() as i64)
+            {
+                Ok(idx) => contents.cells.remove(idx),
+                Err(_) => anyhow::bail!("no row with rowid {} to delete", key),
+            }
+        };
+        self.free_overflow_chain(cell.first_overflow_page())?;
+        self.balance_after_delete(path, page_idx, page, None)?;
+        Ok(CursorResult::Ok(()))
+    }
+
+    /// Set up the pages to iterate through so that the next record the cursor
+    /// reads is the first row of the table past `key`.
+    fn move_past(&self, key: i64) -> Result<CursorResult<()>> {
+        let (path, page_idx, page) = match self.find_leaf(key)? {
+            CursorResult::Ok(leaf) => leaf,
+            CursorResult::IO => return Ok(CursorResult::IO),
+        };
+        let cell_idx = {
+            let contents = page.contents.read().unwrap();
+            let contents = contents.as_ref().unwrap().as_btree();
+            contents
+                .cells
+                .partition_point(|cell| (cell.rowid() as i64) <= key)
+        };
+        self.move_to(&path, page_idx, cell_idx);
+        Ok(CursorResult::Ok(()))
+    }
+
+    /// Walk from the root of an index b-tree to `key`, or to the leaf that it
+    /// belongs in if it is not in the index. Returns the interior pages that
+    /// were passed through, the page the walk ended on, and the position of
+    /// the key on that page or where it would be inserted.
+    #[allow(clippy::type_complexity)]
+    fn find_index_key(
+        &self,
+        key: &OwnedRecord,
+    ) -> Result<(Vec<PathEntry>, usize, Rc<Page>, Result<usize, usize>)> {
+        let mut path = Vec::new();
+        let mut page_idx = self.root_page;
+        loop {
+            let page = self.read_page_sync(page_idx)?;
+            let (pos, child) = {
+                let contents = page.contents.read().unwrap();
+                let contents = contents.as_ref().unwrap().as_btree();
+                if !matches!(
+                    contents.header.page_type,
+                    PageType::IndexInterior | PageType::IndexLeaf
+                ) {
+                    anyhow::bail!("page {} is not an index b-tree page", page_idx);
+                }
+                let mut lo = 0;
+                let mut hi = contents.cells.len();
+                let mut pos = None;
+                while lo < hi {
+                    let mid = (lo + hi) / 2;
+                    match self
+                        .read_key_sync(&contents.cells[mid])?
+                        .compare_prefix(key)
+                    {
+                        Ordering::Less => lo = mid + 1,
+                        Ordering::Greater => hi = mid,
+                        Ordering::Equal => {
+                            pos = Some(mid);
+                            break;
+                        }
+                    }
+                }
+                let pos = pos.ok_or(lo);
+                let child = match pos {
+                    Err(child_idx) if contents.header.page_type == PageType::IndexInterior => {
+                        Some((child_idx, child_page(contents, child_idx)))
+                    }
+                    _ => None,
+                };
+                (pos, child)
+            };
+            match child {
+                Some((child_idx, child)) => {
+                    path.push(PathEntry {
+                        page_idx,
+                        page,
+                        child_idx,
+                    });
+                    page_idx = child;
+                }
+                None => return Ok((path, page_idx, page, pos)),
+            }
+        }
+    }
+
+    /// Add `key` to an index b-tree, unless it is there already.
+    fn insert_index_key(&self, key: &OwnedRecord) -> Result<()> {
+        let (path, page_idx, page, pos) = self.find_index_key(key)?;
+        // Keys end with the rowid, so an equal key is the same entry.
+        let Err(cell_idx) = pos else {
+            return Ok(());
+        };
+        let mut payload = Vec::new();
+        write_record(key, &mut payload);
+        let payload_size = payload.len();
+        let first_overflow_page = self.spill_payload(&mut payload, PageType::IndexLeaf)?;
+        let appended = {
+            let mut contents = page.contents.write().unwrap();
+            let contents = contents.as_mut().unwrap().as_btree_mut();
+            contents.cells.insert(
+                cell_idx,
+                BTreeCell::IndexLeafCell(IndexLeafCell {
+                    payload,
+                    payload_size: payload_size as u64,
+                    first_overflow_page,
+                }),
+            );
+            cell_idx == contents.cells.len() - 1
+        };
+        self.balance(path, page_idx, page, appended)?;
+        Ok(())
+    }
+
+    /// Remove `key` from an index b-tree. A key on an interior page is
+    /// replaced by the key before it, which is the last key of a leaf, so
+    /// that it is always a leaf that loses a cell. Returns whether the key
+    /// was found.
+    fn delete_index_key(&self, key: &OwnedRecord) -> Result<bool> {
+        let (mut path, page_idx, page, pos) = self.find_index_key(key)?;
+        let Ok(cell_idx) = pos else {
+            return Ok(false);
+        };
+        let removed = {
+            let mut contents = page.contents.write().unwrap();
+            let contents = contents.as_mut().unwrap().as_btree_mut();
+            match contents.header.page_type {
+                PageType::IndexLeaf => Ok(contents.cells.remove(cell_idx)),
+                _ => Err(child_page(contents, cell_idx)),
+            }
+        };
+        let left_child = match removed {
+            Ok(cell) => {
+                self.free_overflow_chain(cell.first_overflow_page())?;
+                self.balance_after_delete(path, page_idx, page, None)?;
+                return Ok(true);
+            }
+            Err(left_child) => left_child,
+        };
+        let depth = path.len();
+        path.push(PathEntry {
+            page_idx,
+            page: page.clone(),
+            child_idx: cell_idx,
+        });
+        let mut leaf_idx = left_child;
+        let leaf = loop {
+            let child = self.read_page_sync(leaf_idx)?;
+            let next = {
+                let contents = child.contents.read().unwrap();
+                let contents = contents.as_ref().unwrap().as_btree();
+                contents
+                    .header
+                    .right_most_pointer
+                    .map(|next| (contents.cells.len(), next as usize))
+            };
+            match next {
+                Some((child_idx, next)) => {
+                    path.push(PathEntry {
+                        page_idx: leaf_idx,
+                        page: child,
+                        child_idx,
+                    });
+                    leaf_idx = next;
+                }
+                None => break child,
+            }
+        };
+        let Some(BTreeCell::IndexLeafCell(predecessor)) = leaf
+            .contents
+            .write()
+            .unwrap()
+            .as_mut()
+            .unwrap()
+            .as_btree_mut()
+            .cells
+            .pop()
+        else {
+            anyhow::bail!("index leaf page {} is empty", leaf_idx);
+        };
+        let old = {
+            let mut contents = page.contents.write().unwrap();
+            let contents = contents.as_mut().unwrap().as_btree_mut();
+            std::mem::replace(
+                &mut contents.cells[cell_idx],
+                BTreeCell::IndexInteriorCell(IndexInteriorCell {
+                    left_child_page: left_child as u32,
+                    payload: predecessor.payload,
+                    payload_size: predecessor.payload_size,
+                    first_overflow_page: predecessor.first_overflow_page,
+                }),
+            )
+        };
+        self.free_overflow_chain(old.first_overflow_page())?;
+        self.balance_after_delete(path, leaf_idx, leaf, Some(depth))?;
+        Ok(true)
+    }
+
+    /// Write back a page that lost a cell. While it is less than a third
+    /// full, it is merged with a sibling, or shares the cells of the two with
+    /// it if they do not fit in one page, which in turn changes the parent. A
+    /// root that is left with a single child takes over the child's cells.
+    /// `modified_ancestor` is the depth in `path` of an interior page whose
+    /// cell was replaced, which must be written as well.
+    fn balance_after_delete(
+        &self,
+        mut path: Vec<PathEntry>,
+        mut page_idx: usize,
+        mut page: Rc<Page>,
+        modified_ancestor: Option<usize>,
+    ) -> Result<()> {
+        let path = loop {
+            let Some(parent) = path.pop() else {
+                return self.shrink_root(page_idx, &page);
+            };
+            if !self.is_underfull(page_idx, &page) {
+                path.push(parent);
+                break self.balance(path, page_idx, page, false)?;
+            }
+            match self.rebalance_siblings(&parent, page_idx, &page)? {
+                Rebalanced::Merged => {
+                    page_idx = parent.page_idx;
+                    page = parent.page;
+                }
+                Rebalanced::Redistributed => {
+                    // The new divider cell may be larger than the old one.
+                    break self.balance(path, parent.page_idx, parent.page, false)?;
+                }
+                Rebalanced::Unchanged => {
+                    path.push(parent);
+                    break self.balance(path, page_idx, page, false)?;
+                }
+            }
+        };
+        match modified_ancestor {
+            Some(depth) if depth < path.len() => {
+                let mut path = path;
+                path.truncate(depth + 1);
+                let ancestor = path.pop().unwrap();
+                self.balance(path, ancestor.page_idx, ancestor.page, false)?;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Merge an underfull page with the sibling to its right, or to its left
+    /// if it is the right-most child of its parent. If the cells of the two
+    /// do not fit in one page, they are spread evenly between them instead.
+    /// Both pages are written, but the parent is not.
+    fn rebalance_siblings(
+        &self,
+        parent: &PathEntry,
+        page_idx: usize,
+        page: &Rc<Page>,
+    ) -> Result<Rebalanced> {
+        let mut parent_contents = parent.page.contents.write().unwrap();
+        let parent_contents = parent_contents.as_mut().unwrap().as_btree_mut();
+        if parent_contents.cells.is_empty() {
+            return Ok(Rebalanced::Unchanged);
+        }
+        // The divider between the two pages is the cell that points to the
+        // left one.
+        let divider_idx = parent.child_idx.min(parent_contents.cells.len() - 1);
+        let sibling_idx = if divider_idx == parent.child_idx {
+            child_page(parent_contents, divider_idx + 1)
+        } else {
+            child_page(parent_contents, divider_idx)
+        };
+        let sibling = self.read_page_sync(sibling_idx)?;
+        let ((left_idx, left), (right_idx, right)) = if divider_idx == parent.child_idx {
+            ((page_idx, page.clone()), (sibling_idx, sibling))
+        } else {
+            ((sibling_idx, sibling), (page_idx, page.clone()))
+        };
+
+        let Some(PageContent::BTree(left_contents)) = left.contents.write().unwrap().take() else {
+            unreachable!("expected a b-tree page");
+        };
+        let Some(PageContent::BTree(right_contents)) = right.contents.write().unwrap().take()
+        else {
+            unreachable!("expected a b-tree page");
+        };
+        let page_type = left_contents.header.page_type;
+        // On all but table leaves, the divider is an entry of its own that
+        // moves down between the cells of the two pages.
+        let moves_divider = page_type != PageType::TableLeaf;
+        let num_left_cells = left_contents.cells.len();
+        let divider = parent_contents.cells.remove(divider_idx);
+        let mut cells = left_contents.cells;
+        cells.extend(divider_to_child_cell(
+            divider,
+            page_type,
+            left_contents.header.right_most_pointer,
+        ));
+        cells.extend(right_contents.cells);
+        let right_most_pointer = right_contents.header.right_most_pointer;
+
+        let capacity = self.pager.usable_size() - left_contents.header.size();
+        let sizes: Vec<usize> = cells.iter().map(|cell| cell.size() + 2).collect();
+        let total: usize = sizes.iter().sum();
+        if total <= capacity {
+            let mut header = BTreePageHeader::new(page_type);
+            header.right_most_pointer = right_most_pointer;
+            left.contents
+                .write()
+                .unwrap()
+                .replace(PageContent::BTree(BTreePage::new(header, cells)));
+            self.pager.add_dirty(left_idx, &left);
+            self.pager.free_page(right_idx)?;
+            // The pointer to the right page, which took the divider's place,
+            // now points to the merged page.
+            set_child_page(parent_contents, divider_idx, left_idx as u32);
+            return Ok(Rebalanced::Merged);
+        }
+
+        // The first `split` cells go to the left page, except that the last
+        // of them moves up to the parent if the divider is an entry of its
+        // own. Pick the split that leaves the two pages closest in size, or
+        // failing that, keep the cells where they were.
+        let mut best: Option<(usize, usize)> = None;
+        let mut prefix = 0;
+        for (i, size) in sizes.iter().enumerate() {
+            prefix += size;
+            let split = i + 1;
+            if split == cells.len() || (moves_divider && split < 2) {
+                continue;
+            }
+            let left_size = if moves_divider { prefix - size } else { prefix };
+            let largest = left_size.max(total - prefix);
+            if largest <= capacity && best.is_none_or(|(_, best)| largest < best) {
+                best = Some((split, largest));
+            }
+        }
+        let split = best.map_or(num_left_cells + moves_divider as usize, |(split, _)| split);
+        let right_cells = cells.split_off(split);
+        let (divider, left_right_most_pointer) =
+            take_divider(&mut cells, page_type, left_idx as u32);
+        let mut left_header = BTreePageHeader::new(page_type);
+        left_header.right_most_pointer = left_right_most_pointer;
+        let mut right_header = BTreePageHeader::new(page_type);
+        right_header.right_most_pointer = right_most_pointer;
+        left.contents
+            .write()
+            .unwrap()
+            .replace(PageContent::BTree(BTreePage::new(left_header, cells)));
+        right
+            .contents
+            .write()
+            .unwrap()
+            .replace(PageContent::BTree(BTreePage::new(
+                right_header,
+                right_cells,
+            )));
+        self.pager.add_dirty(left_idx, &left);
+        self.pager.add_dirty(right_idx, &right);
+        parent_contents.cells.insert(divider_idx, divider);
+        Ok(Rebalanced::Redistributed)
+    }
+
+    /// Write back a modified page, splitting it and then its ancestors for as
+    /// long as they do not fit in a page. `appended` is set when the page
+    /// grew by a cell at its end, as happens with increasing keys. Returns
+    /// the part of `path` above the last page that was written.
+    fn balance(
+        &self,
+        mut path: Vec<PathEntry>,
+        mut page_idx: usize,
+        mut page: Rc<Page>,
+        mut appended: bool,
+    ) -> Result<Vec<PathEntry>> {
+        loop {
+            if self.fits(page_idx, &page) {
+                self.pager.add_dirty(page_idx, &page);
+                return Ok(path);
+            }
+            if path.is_empty() {
+                // The root page number must not change, so move the root's
+                // cells into a new child and split that instead.
+                let page_type = page
+                    .contents
+                    .read()
+                    .unwrap()
+                    .as_ref()
+                    .unwrap()
+                    .as_btree()
+                    .header
+                    .page_type;
+                let (child_idx, child) = self.pager.allocate_page(page_type)?;
+                let interior_type = match page_type {
+                    PageType::TableInterior | PageType::TableLeaf => PageType::TableInterior,
+                    PageType::IndexInterior | PageType::IndexLeaf => PageType::IndexInterior,
+                };
+                let mut new_root = BTreePage::new(BTreePageHeader::new(interior_type), Vec::new());
+                new_root.header.right_most_pointer = Some(child_idx as u32);
+                let old_root = page
+                    .contents
+                    .write()
+                    .unwrap()
+                    .replace(PageContent::BTree(new_root));
+                *child.contents.write().unwrap() = old_root;
+                path.push(PathEntry {
+                    page_idx,
+                    page,
+                    child_idx: 0,
+                });
+                page_idx = child_idx;
+                page = child;
+            }
+            let parent = path.pop().unwrap();
+            appended = self.split(&parent, page_idx, &page, appended)?;
+            page_idx = parent.page_idx;
+            page = parent.page;
+        }
+    }
+
+    /// Number of bytes that a b-tree page takes up in its page.
+    fn used_size(&self, page_idx: usize, page: &Page) -> usize {
+        let contents = page.contents.read().unwrap();
+        let contents = contents.as_ref().unwrap().as_btree();
+        let header_offset = if page_idx == 1 {
+            DATABASE_HEADER_SIZE
+        } else {
+            0
+        };
+        let cells_size: usize = contents.cells.iter().map(|cell| cell.size() + 2).sum();
+        header_offset + contents.header.size() + cells_size
+    }
+
+    fn fits(&self, page_idx: usize, page: &Page) -> bool {
+        self.used_size(page_idx, page) <= self.pager.usable_size()
+    }
+
+    fn is_underfull(&self, page_idx: usize, page: &Page) -> bool {
+        self.used_size(page_idx, page) < self.pager.usable_size() / 3
+    }
+
+    /// Write back the root page after a delete. A root interior page that
+    /// has no cells left takes over the cells of its only child, which lowers
+    /// the height of the b-tree, as long as they fit.
+    fn shrink_root(&self, page_idx: usize, page: &Rc<Page>) -> Result<()> {
+        loop {
+            let child_idx = {
+                let contents = page.contents.read().unwrap();
+                let contents = contents.as_ref().unwrap().as_btree();
+                match contents.header.right_most_pointer {
+                    Some(child_idx) if contents.cells.is_empty() => child_idx as usize,
+                    _ => break,
+                }
+            };
+            let child = self.read_page_sync(child_idx)?;
+            let child_contents = child.contents.write().unwrap().take();
+            let root_contents = page
+                .contents
+                .write()
+                .unwrap()
+                .replace(child_contents.unwrap());
+            if !self.fits(page_idx, page) {
+                // Page 1 has less room than the child because of the database
+                // header.
+                let child_contents = page
+                    .contents
+                    .write()
+                    .unwrap()
+                    .replace(root_contents.unwrap());
+                *child.contents.write().unwrap() = child_contents;
+                break;
+            }
+            self.pager.free_page(child_idx)?;
+        }
+        // A root whose cell was replaced by a larger one may need splitting.
+        self.balance(Vec::new(), page_idx, page.clone(), false)?;
+        Ok(())
+    }
+
+    /// Split an overfull page into as many pages as needed and add a divider
+    /// cell to the parent for each new boundary. The first part stays in the
+    /// original page. Returns whether the dividers were appended to the end of
+    /// the parent.
+    fn split(
+        &self,
+        parent: &PathEntry,
+        page_idx: usize,
+        page: &Rc<Page>,
+        appended: bool,
+    ) -> Result<bool> {
+        let Some(PageContent::BTree(old)) = page.contents.write().unwrap().take() else {
+            unreachable!("expected a b-tree page");
+        };
+        let page_type = old.header.page_type;
+        let capacity = self.pager.usable_size() - old.header.size();
+        let sizes: Vec<usize> = old.cells.iter().map(|cell| cell.size() + 2).collect();
+        // Keep pages full when keys are added in increasing order, otherwise
+        // leave room for later inserts on both sides of the split.
+        let groups = distribute_cells(&sizes, capacity, appended);
+
+        let mut pages = vec![(page_idx, page.clone())];
+        for _ in 1..groups.len() {
+            pages.push(self.pager.allocate_page(page_type)?);
+        }
+        let mut dividers = Vec::with_capacity(groups.len() - 1);
+        let mut cells = old.cells.into_iter();
+        for (i, (count, (idx, page))) in groups.iter().zip(pages.iter()).enumerate() {
+            let mut group: Vec<BTreeCell> = cells.by_ref().take(*count).collect();
+            let mut header = BTreePageHeader::new(page_type);
+            if i == groups.len() - 1 {
+                header.right_most_pointer = old.header.right_most_pointer;
+            } else {
+                let (divider, right_most_pointer) =
+                    take_divider(&mut group, page_type, *idx as u32);
+                header.right_most_pointer = right_most_pointer;
+                dividers.push(divider);
+            }
+            page.contents
+                .write()
+                .unwrap()
+                .replace(PageContent::BTree(BTreePage::new(header, group)));
+            self.pager.add_dirty(*idx, page);
+        }
+
+        let mut contents = parent.page.contents.write().unwrap();
+        let contents = contents.as_mut().unwrap().as_btree_mut();
+        let appended = parent.child_idx == contents.cells.len();
+        let last_page = pages.last().unwrap().0 as u32;
+        set_child_page(contents, parent.child_idx, last_page);
+        for (i, divider) in dividers.into_iter().enumerate() {
+            contents.cells.insert(parent.child_idx + i, divider);
+        }
+        Ok(appended)
+    }
+}
+
+/// What `rebalance_siblings` did to a page and its sibling.
+enum Rebalanced {
+    /// The pages were merged, and their divider removed from the parent.
+    Merged,
+    /// The cells were spread between the pages, and their divider replaced.
+    Redistributed,
+    /// The parent has no other child to rebalance with.
+    Unchanged,
+}
+
+/// The child that an interior page points to at `child_idx`, where an index
+/// equal to the number of cells stands for the right-most pointer.
+fn child_page(page: &BTreePage, child_idx: usize) -> usize {
+    match page.cells.get(child_idx) {
+        Some(BTreeCell::TableInteriorCell(cell)) => cell._left_child_page as usize,
+        Some(BTreeCell::IndexInteriorCell(cell)) => cell.left_child_page as usize,
+        _ => page.header.right_most_pointer.unwrap() as usize,
+    }
+}
+
+fn set_child_page(page: &mut BTreePage, child_idx: usize, child: u32) {
+    match page.cells.get_mut(child_idx) {
+        Some(BTreeCell::TableInteriorCell(cell)) => cell._left_child_page = child,
+        Some(BTreeCell::IndexInteriorCell(cell)) => cell.left_child_page = child,
+        _ => page.header.right_most_pointer = Some(child),
+    }
+}
+
+/// Make the divider cell that points to `left_page` from its parent out of
+/// the cells of that page. A table leaf keeps its last cell and the divider
+/// copies its rowid. On other pages the last cell moves up to become the
+/// divider, and on interior pages its child becomes the page's right-most
+/// child, which is returned as well.
+fn take_divider(
+    cells: &mut Vec<BTreeCell>,
+    page_type: PageType,
+    left_page: u32,
+) -> (BTreeCell, Option<u32>) {
+    if page_type == PageType::TableLeaf {
+        let divider = TableInteriorCell {
+            _left_child_page: left_page,
+            _rowid: cells.last().unwrap().rowid(),
+        };
+        return (BTreeCell::TableInteriorCell(divider), None);
+    }
+    match cells.pop() {
+        Some(BTreeCell::TableInteriorCell(cell)) => {
+            let divider = TableInteriorCell {
+                _left_child_page: left_page,
+                _rowid: cell._rowid,
+            };
+            (
+                BTreeCell::TableInteriorCell(divider),
+                Some(cell._left_child_page),
+            )
+        }
+        Some(BTreeCell::IndexInteriorCell(cell)) => {
+            let right_most_pointer = cell.left_child_page;
+            let divider = IndexInteriorCell {
+                left_child_page: left_page,
+                ..cell
+            };
+            (
+                BTreeCell::IndexInteriorCell(divider),
+                Some(right_most_pointer),
+            )
+        }
+        Some(BTreeCell::IndexLeafCell(cell)) => {
+            let divider = IndexInteriorCell {
+                left_child_page: left_page,
+                payload: cell.payload,
+                payload_size: cell.payload_size,
+                first_overflow_page: cell.first_overflow_page,
+            };
+            (BTreeCell::IndexInteriorCell(divider), None)
+        }
+        _ => unreachable!("expected a cell to move up"),
+    }
+}
+
+/// The reverse of `take_divider`: turn a divider cell that moves down from
+/// the parent into a cell of its children, which follows the cells of the
+/// left child and points to its right-most child, if any. The divider of table
+/// leaves only copies a rowid and is dropped.
+fn divider_to_child_cell(
+    divider: BTreeCell,
+    page_type: PageType,
+    right_most_pointer: Option<u32>,
+) -> Option<BTreeCell> {
+    match (divider, page_type) {
+        (_, PageType::TableLeaf) => None,
+        (BTreeCell::TableInteriorCell(cell), PageType::TableInterior) => {
+            Some(BTreeCell::TableInteriorCell(TableInteriorCell {
+                _left_child_page: right_most_pointer.unwrap(),
+                _rowid: cell._rowid,
+            }))
+        }
+        (BTreeCell::IndexInteriorCell(cell), PageType::IndexInterior) => {
+            Some(BTreeCell::IndexInteriorCell(IndexInteriorCell {
+                left_child_page: right_most_pointer.unwrap(),
+                ..cell
+            }))
+        }
+        (BTreeCell::IndexInteriorCell(cell), PageType::IndexLeaf) => {
+            Some(BTreeCell::IndexLeafCell(IndexLeafCell {
+                payload: cell.payload,
+                payload_size: cell.payload_size,
+                first_overflow_page: cell.first_overflow_page,
+            }))
+        }
+        _ => unreachable!("divider does not match the pages it divides"),
+    }
+}
+
+/// Split consecutive cells with the given sizes into groups that each fit in
+/// `capacity` bytes. With `pack_left` every group but the last is filled as
+/// much as possible, otherwise the cells are spread evenly. Returns the number
+/// of cells in each group.
+fn distribute_cells(sizes: &[usize], capacity: usize, pack_left: bool) -> Vec<usize> {
+    let total: usize = sizes.iter().sum();
+    let target = if pack_left {
+        capacity
+    } else {
+        total.div_ceil(total.div_ceil(capacity).max(2))
+    };
+    let mut groups = Vec::new();
+    let mut count = 0;
+    let mut used = 0;
+    for &size in sizes {
+        if count > 0 && (used + size > capacity || used >= target) {
+            groups.push(count);
+            count = 0;
+            used = 0;
+        }
+        count += 1;
+        used += size;
+    }
+    groups.push(count);
+    groups
+}
+
 impl Cursor for BTreeCursor {
     fn is_empty(&self) -> bool {
         self.record.borrow().is_none()
     }
 
     fn rewind(&mut self) -> Result<CursorResult<()>> {
+        self.null_flag = false;
         let mem_page = MemPage::new(None, self.root_page, 0);
         self.page.replace(Some(Rc::new(mem_page)));
         match self.get_next_record()? {
@@ -125,6 +1423,7 @@ impl Cursor for BTreeCursor {
     }
 
     fn next(&mut self) -> Result<CursorResult<()>> {
+        self.null_flag = false;
         match self.get_next_record()? {
             CursorResult::Ok((rowid, next)) => {
                 self.rowid.replace(rowid);
@@ -135,6 +1434,32 @@ impl Cursor for BTreeCursor {
         }
     }
 
+    fn last(&mut self) -> Result<CursorResult<()>> {
+        self.null_flag = false;
+        let mem_page = MemPage::new_at_end(None, self.root_page);
+        self.page.replace(Some(Rc::new(mem_page)));
+        match self.get_prev_record()? {
+            CursorResult::Ok((rowid, prev)) => {
+                self.rowid.replace(rowid);
+                self.record.replace(prev);
+                Ok(CursorResult::Ok(()))
+            }
+            CursorResult::IO => Ok(CursorResult::IO),
+        }
+    }
+
+    fn prev(&mut self) -> Result<CursorResult<()>> {
+        self.null_flag = false;
+        match self.get_prev_record()? {
+            CursorResult::Ok((rowid, prev)) => {
+                self.rowid.replace(rowid);
+                self.record.replace(prev);
+                Ok(CursorResult::Ok(()))
+            }
+            CursorResult::IO => Ok(CursorResult::IO),
+        }
+    }
+
     fn wait_for_completion(&mut self) -> Result<()> {
         // TODO: Wait for pager I/O to complete
         Ok(())
@@ -148,7 +1473,571 @@ impl Cursor for BTreeCursor {
         Ok(self.record.borrow())
     }
 
-    fn insert(&mut self, _record: &OwnedRecord) -> Result<()> {
-        unimplemented!()
+    fn insert(&mut self, key: &OwnedValue, record: &OwnedRecord) -> Result<CursorResult<()>> {
+        let OwnedValue::Integer(key) = key else {
+            unreachable!("rowid must be an integer");
+        };
+        if self.written_key.is_none() {
+            let mut payload = Vec::new();
+            write_record(record, &mut payload);
+            match self.insert_cell(*key, payload)? {
+                CursorResult::Ok(()) => self.written_key = Some(*key),
+                CursorResult::IO => return Ok(CursorResult::IO),
+            }
+        }
+        // The b-tree may have been rearranged, so find the row that follows
+        // the new one again, for `next` to read.
+        match self.move_past(*key)? {
+            CursorResult::Ok(()) => {}
+            CursorResult::IO => return Ok(CursorResult::IO),
+        }
+        self.written_key = None;
+        self.rowid.replace(Some(*key as u64));
+        self.record.replace(Some(record.clone()));
+        Ok(CursorResult::Ok(()))
+    }
+
+    fn exists(&mut self, key: &OwnedValue) -> Result<CursorResult<bool>> {
+        let OwnedValue::Integer(key) = key else {
+            unreachable!("rowid must be an integer");
+        };
+        let (path, page_idx, page) = match self.find_leaf(*key)? {
+            CursorResult::Ok(leaf) => leaf,
+            CursorResult::IO => return Ok(CursorResult::IO),
+        };
+        let cell_idx = {
+            let contents = page.contents.read().unwrap();
+            let contents = contents.as_ref().unwrap().as_btree();
+            contents
+                .cells
+                .binary_search_by_key(key, |cell| cell.rowid() as i64)
+        };
+        let Ok(cell_idx) = cell_idx else {
+            return Ok(CursorResult::Ok(false));
+        };
+        // Move the cursor to the row, so that its columns can be read.
+        self.null_flag = false;
+        self.move_to(&path, page_idx, cell_idx);
+        match self.get_next_record()? {
+            CursorResult::Ok((rowid, record)) => {
+                self.rowid.replace(rowid);
+                self.record.replace(record);
+                Ok(CursorResult::Ok(true))
+            }
+            CursorResult::IO => Ok(CursorResult::IO),
+        }
+    }
+
+    fn new_rowid(&mut self) -> Result<CursorResult<i64>> {
+        // The largest rowid is in the last cell of the right-most leaf.
+        let mut page_idx = self.root_page;
+        loop {
+            let page = self.pager.read_page(page_idx)?;
+            if page.is_locked() {
+                return Ok(CursorResult::IO);
+            }
+            let contents = page.contents.read().unwrap();
+            let contents = contents.as_ref().unwrap().as_btree();
+            match contents.header.right_most_pointer {
+                Some(right_most_pointer) => page_idx = right_most_pointer as usize,
+                None => {
+                    let max_rowid = contents.cells.last().map_or(0, |cell| cell.rowid() as i64);
+                    if max_rowid == i64::MAX {
+                        anyhow::bail!("database or disk is full");
+                    }
+                    return Ok(CursorResult::Ok(max_rowid.max(0) + 1));
+                }
+            }
+        }
+    }
+
+    fn seek(&mut self, key: &OwnedRecord, op: SeekOp) -> Result<CursorResult<bool>> {
+        self.null_flag = false;
+        match self.move_to_index_key(key, op)? {
+            CursorResult::Ok(()) => {}
+            CursorResult::IO => return Ok(CursorResult::IO),
+        }
+        match self.get_next_record()? {
+            CursorResult::Ok((rowid, record)) => {
+                self.seek.replace(None);
+                let found = record.is_some();
+                self.rowid.replace(rowid);
+                self.record.replace(record);
+                Ok(CursorResult::Ok(found))
+            }
+            CursorResult::IO => Ok(CursorResult::IO),
+        }
+    }
+
+    fn seek_rowid(&mut self, rowid: i64, op: SeekOp) -> Result<CursorResult<bool>> {
+        let key = match op {
+            SeekOp::GE => rowid,
+            SeekOp::GT => match rowid.checked_add(1) {
+                Some(key) => key,
+                None => {
+                    self.rowid.replace(None);
+                    self.record.replace(None);
+                    return Ok(CursorResult::Ok(false));
+                }
+            },
+        };
+        let (path, page_idx, page) = match self.find_leaf(key)? {
+            CursorResult::Ok(leaf) => leaf,
+            CursorResult::IO => return Ok(CursorResult::IO),
+        };
+        let cell_idx = {
+            let contents = page.contents.read().unwrap();
+            let contents = contents.as_ref().unwrap().as_btree();
+            contents
+                .cells
+                .partition_point(|cell| (cell.rowid() as i64) < key)
+        };
+        self.null_flag = false;
+        // If every row of the leaf is before the key, the row that follows
+        // them is the first one of the next leaf.
+        self.move_to(&path, page_idx, cell_idx);
+        match self.get_next_record()? {
+            CursorResult::Ok((rowid, record)) => {
+                let found = record.is_some();
+                self.rowid.replace(rowid);
+                self.record.replace(record);
+                Ok(CursorResult::Ok(found))
+            }
+            CursorResult::IO => Ok(CursorResult::IO),
+        }
+    }
+
+    fn delete(&mut self) -> Result<CursorResult<()>> {
+        if self.written_key.is_none() {
+            let Some(rowid) = *self.rowid.borrow() else {
+                anyhow::bail!("cursor is not on a row to delete");
+            };
+            match self.delete_cell(rowid as i64)? {
+                CursorResult::Ok(()) => self.written_key = Some(rowid as i64),
+                CursorResult::IO => return Ok(CursorResult::IO),
+            }
+        }
+        // The b-tree may have been rearranged, so find the row that followed
+        // the deleted one again, for `next` to read.
+        match self.move_past(self.written_key.unwrap())? {
+            CursorResult::Ok(()) => {}
+            CursorResult::IO => return Ok(CursorResult::IO),
+        }
+        self.written_key = None;
+        self.rowid.replace(None);
+        self.record.replace(None);
+        Ok(CursorResult::Ok(()))
+    }
+
+    fn index_insert(&mut self, key: &OwnedRecord) -> Result<CursorResult<()>> {
+        self.insert_index_key(key)?;
+        Ok(CursorResult::Ok(()))
+    }
+
+    fn index_delete(&mut self, key: &OwnedRecord) -> Result<CursorResult<bool>> {
+        Ok(CursorResult::Ok(self.delete_index_key(key)?))
+    }
+
+    fn set_null_flag(&mut self, flag: bool) {
+        self.null_flag = flag;
+    }
+
+    fn get_null_flag(&self) -> bool {
+        self.null_flag
+    }
+}
+
+/// Call a cursor operation until it gets past I/O.
+pub(crate) fn run_io<T>(
+    io: &Rc<dyn IO>,
+    mut f: impl FnMut() -> Result<CursorResult<T>>,
+) -> Result<T> {
+    loop {
+        match f()? {
+            CursorResult::Ok(value) => return Ok(value),
+            CursorResult::IO => io.run_once()?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDatabase;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    /// Create a database with an index on `t (b, a)`. Returns the database,
+    /// the root page of the index and its entries in key order.
+    fn create_index_db(name: &str) -> (TempDatabase, usize, Vec<OwnedRecord>) {
+        let temp = TempDatabase::new(name);
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let conn = temp.sqlite();
+        // Small pages give the index several levels of interior pages, and
+        // long keys spill into overflow pages.
+        conn.execute_batch("PRAGMA page_size = 1024; CREATE TABLE t (a, b);")
+            .unwrap();
+        for _ in 0..2000 {
+            let a = rng.gen_range(0..100);
+            let len = if rng.gen_ratio(1, 20) {
+                rng.gen_range(200..3000)
+            } else {
+                rng.gen_range(0..20)
+            };
+            let b: String = (0..len).map(|_| rng.gen_range('a'..='z')).collect();
+            conn.execute("INSERT INTO t (a, b) VALUES (?, ?)", (a, b))
+                .unwrap();
+        }
+        conn.execute("CREATE INDEX t_idx ON t (b, a)", ()).unwrap();
+        let root_page: usize = conn
+            .query_row(
+                "SELECT rootpage FROM sqlite_schema WHERE name = 't_idx'",
+                (),
+                |row| row.get(0),
+            )
+            .unwrap();
+        let mut stmt = conn
+            .prepare("SELECT b, a, rowid FROM t ORDER BY b, a, rowid")
+            .unwrap();
+        let entries: Vec<OwnedRecord> = stmt
+            .query_map((), |row| {
+                Ok(OwnedRecord::new(vec![
+                    OwnedValue::Text(Rc::new(row.get(0)?)),
+                    OwnedValue::Integer(row.get(1)?),
+                    OwnedValue::Integer(row.get(2)?),
+                ]))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        drop(stmt);
+        drop(conn);
+        (temp, root_page, entries)
+    }
+
+    #[test]
+    fn test_index_cursor_key_order() {
+        let (temp, root_page, expected) = create_index_db("index");
+
+        let (io, db) = temp.open();
+        let mut cursor = BTreeCursor::new(db.pager.clone(), root_page);
+        let mut actual = Vec::new();
+        run_io(&io, || cursor.rewind()).unwrap();
+        loop {
+            let record = cursor.record().unwrap().clone();
+            let Some(record) = record else { break };
+            let rowid = cursor.rowid().unwrap().unwrap();
+            assert_eq!(
+                record.values.last(),
+                Some(&OwnedValue::Integer(rowid as i64))
+            );
+            actual.push(record);
+            run_io(&io, || cursor.next()).unwrap();
+        }
+        assert_eq!(actual.len(), expected.len());
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_index_cursor_seek() {
+        let (temp, root_page, entries) = create_index_db("seek");
+
+        let (io, db) = temp.open();
+        let mut cursor = BTreeCursor::new(db.pager.clone(), root_page);
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+        for _ in 0..500 {
+            // Seek to existing keys, and to prefixes of them, as well as to
+            // keys that are not in the index.
+            let mut values = entries[rng.gen_range(0..entries.len())].values.clone();
+            values.truncate(rng.gen_range(1..=3));
+            if rng.gen_ratio(1, 4) {
+                let len = rng.gen_range(0..5);
+                let text: String = (0..len).map(|_| rng.gen_range('a'..='z')).collect();
+                values = vec![OwnedValue::Text(Rc::new(text))];
+            }
+            let key = OwnedRecord::new(values);
+            let op = if rng.gen() { SeekOp::GE } else { SeekOp::GT };
+            let expected = entries.iter().position(|entry| match op {
+                SeekOp::GE => entry.compare_prefix(&key) != Ordering::Less,
+                SeekOp::GT => entry.compare_prefix(&key) == Ordering::Greater,
+            });
+            let found = run_io(&io, || cursor.seek(&key, op)).unwrap();
+            assert_eq!(found, expected.is_some());
+            assert_eq!(
+                *cursor.record().unwrap(),
+                expected.map(|i| entries[i].clone())
+            );
+            if let Some(i) = expected {
+                run_io(&io, || cursor.next()).unwrap();
+                assert_eq!(*cursor.record().unwrap(), entries.get(i + 1).cloned());
+            }
+        }
+    }
+
+    /// Create a database with a table `t` with rowids scattered between -5000
+    /// and 5000. Returns the database, the root page of the table and its
+    /// rowids in order.
+    fn create_table_db(name: &str) -> (TempDatabase, usize, Vec<i64>) {
+        let temp = TempDatabase::new(name);
+        let mut rng = ChaCha8Rng::seed_from_u64(2);
+        let mut rowids: Vec<i64> = (0..3000).map(|_| rng.gen_range(-5000..5000)).collect();
+        rowids.sort();
+        rowids.dedup();
+        let conn = temp.sqlite();
+        conn.execute_batch("PRAGMA page_size = 1024; CREATE TABLE t (a);")
+            .unwrap();
+        for rowid in &rowids {
+            let len = if rng.gen_ratio(1, 20) { 2000 } else { 10 };
+            conn.execute(
+                "INSERT INTO t (rowid, a) VALUES (?, ?)",
+                (rowid, "x".repeat(len)),
+            )
+            .unwrap();
+        }
+        let root_page = conn
+            .query_row(
+                "SELECT rootpage FROM sqlite_schema WHERE name = 't'",
+                (),
+                |row| row.get(0),
+            )
+            .unwrap();
+        drop(conn);
+        (temp, root_page, rowids)
+    }
+
+    #[test]
+    fn test_table_cursor_seek_rowid() {
+        let (temp, root_page, rowids) = create_table_db("rowid");
+
+        let mut rng = ChaCha8Rng::seed_from_u64(3);
+        let (io, db) = temp.open();
+        let mut cursor = BTreeCursor::new(db.pager.clone(), root_page);
+        for _ in 0..500 {
+            let key = rng.gen_range(-5100..5100);
+            let op = if rng.gen() { SeekOp::GE } else { SeekOp::GT };
+            let expected = match op {
+                SeekOp::GE => rowids.partition_point(|rowid| *rowid < key),
+                SeekOp::GT => rowids.partition_point(|rowid| *rowid <= key),
+            };
+            let found = run_io(&io, || cursor.seek_rowid(key, op)).unwrap();
+            assert_eq!(found, expected < rowids.len());
+            let rowid = cursor.rowid().unwrap().map(|rowid| rowid as i64);
+            assert_eq!(rowid, rowids.get(expected).copied());
+            if found {
+                run_io(&io, || cursor.next()).unwrap();
+                let rowid = cursor.rowid().unwrap().map(|rowid| rowid as i64);
+                assert_eq!(rowid, rowids.get(expected + 1).copied());
+            }
+            let exists = run_io(&io, || cursor.exists(&OwnedValue::Integer(key))).unwrap();
+            assert_eq!(exists, rowids.binary_search(&key).is_ok());
+        }
+        assert!(!run_io(&io, || cursor.seek_rowid(i64::MAX, SeekOp::GT)).unwrap());
+    }
+
+    #[test]
+    fn test_cursor_backwards() {
+        let (table_temp, table_root_page, rowids) = create_table_db("last-table");
+        let (index_temp, index_root_page, entries) = create_index_db("last-index");
+
+        let (io, db) = table_temp.open();
+        let mut cursor = BTreeCursor::new(db.pager.clone(), table_root_page);
+        let mut actual = Vec::new();
+        run_io(&io, || cursor.last()).unwrap();
+        loop {
+            let rowid = *cursor.rowid().unwrap();
+            let Some(rowid) = rowid else { break };
+            assert!(cursor.record().unwrap().is_some());
+            actual.push(rowid as i64);
+            run_io(&io, || cursor.prev()).unwrap();
+        }
+        assert!(actual.iter().rev().eq(rowids.iter()));
+
+        let (io, db) = index_temp.open();
+        let mut cursor = BTreeCursor::new(db.pager.clone(), index_root_page);
+        let mut actual = Vec::new();
+        run_io(&io, || cursor.last()).unwrap();
+        loop {
+            let record = cursor.record().unwrap().clone();
+            let Some(record) = record else { break };
+            actual.push(record);
+            run_io(&io, || cursor.prev()).unwrap();
+        }
+        assert!(actual.iter().rev().eq(entries.iter()));
+    }
+
+    #[test]
+    fn test_cursor_delete() {
+        let (temp, root_page, rowids) = create_table_db("delete");
+
+        let mut rng = ChaCha8Rng::seed_from_u64(4);
+        let (io, db) = temp.open();
+        let mut cursor = BTreeCursor::new(db.pager.clone(), root_page);
+        // Delete rows one at a time, then whole runs of them while scanning,
+        // so that pages empty out and merge.
+        let mut expected = rowids.clone();
+        for _ in 0..300 {
+            let rowid = expected.remove(rng.gen_range(0..expected.len()));
+            assert!(run_io(&io, || cursor.exists(&OwnedValue::Integer(rowid))).unwrap());
+            run_io(&io, || cursor.delete()).unwrap();
+        }
+        run_io(&io, || cursor.rewind()).unwrap();
+        let mut actual = Vec::new();
+        loop {
+            let rowid = *cursor.rowid().unwrap();
+            let Some(rowid) = rowid else { break };
+            if (rowid as i64).rem_euclid(1000) < 800 {
+                run_io(&io, || cursor.delete()).unwrap();
+            } else {
+                actual.push(rowid as i64);
+            }
+            run_io(&io, || cursor.next()).unwrap();
+        }
+        expected.retain(|rowid| rowid.rem_euclid(1000) >= 800);
+        assert_eq!(actual, expected);
+        db.pager.commit().unwrap();
+
+        temp.check_integrity();
+        let conn = temp.sqlite();
+        let mut stmt = conn.prepare("SELECT rowid FROM t").unwrap();
+        let actual: Vec<i64> = stmt
+            .query_map((), |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_index_insert_delete() {
+        let (temp, root_page, mut expected) = create_index_db("index-write");
+        let table_root_page: usize = temp
+            .sqlite()
+            .query_row(
+                "SELECT rootpage FROM sqlite_schema WHERE name = 't'",
+                (),
+                |row| row.get(0),
+            )
+            .unwrap();
+
+        let mut rng = ChaCha8Rng::seed_from_u64(5);
+        let (io, db) = temp.open();
+        let mut table = BTreeCursor::new(db.pager.clone(), table_root_page);
+        let mut index = BTreeCursor::new(db.pager.clone(), root_page);
+        // Remove most of the rows along with their index entries, so that
+        // entries go from interior pages and pages merge, then put some back.
+        let mut removed = Vec::new();
+        for _ in 0..1500 {
+            let entry = expected.remove(rng.gen_range(0..expected.len()));
+            assert!(run_io(&io, || index.index_delete(&entry)).unwrap());
+            assert!(!run_io(&io, || index.index_delete(&entry)).unwrap());
+            let rowid = entry.values[2].clone();
+            assert!(run_io(&io, || table.exists(&rowid)).unwrap());
+            run_io(&io, || table.delete()).unwrap();
+            removed.push(entry);
+        }
+        for entry in removed.into_iter().step_by(3) {
+            let record = OwnedRecord::new(vec![entry.values[1].clone(), entry.values[0].clone()]);
+            run_io(&io, || table.insert(&entry.values[2], &record)).unwrap();
+            run_io(&io, || index.index_insert(&entry)).unwrap();
+            run_io(&io, || index.index_insert(&entry)).unwrap();
+            let idx = expected.partition_point(|other| other.compare_prefix(&entry).is_lt());
+            expected.insert(idx, entry);
+        }
+        run_io(&io, || index.rewind()).unwrap();
+        let mut actual = Vec::new();
+        loop {
+            let record = index.record().unwrap().clone();
+            let Some(record) = record else { break };
+            actual.push(record);
+            run_io(&io, || index.next()).unwrap();
+        }
+        assert_eq!(actual, expected);
+        db.pager.commit().unwrap();
+
+        temp.check_integrity();
+    }
+
+    #[test]
+    fn test_cursor_insert_split() {
+        let temp = TempDatabase::with_sql("insert", "PRAGMA page_size = 1024; CREATE TABLE t (a);");
+        let root_page: usize = temp
+            .sqlite()
+            .query_row(
+                "SELECT rootpage FROM sqlite_schema WHERE name = 't'",
+                (),
+                |row| row.get(0),
+            )
+            .unwrap();
+
+        let mut rng = ChaCha8Rng::seed_from_u64(6);
+        let (io, db) = temp.open();
+        let mut cursor = BTreeCursor::new(db.pager.clone(), root_page);
+        // Insert rows in random order, so that pages split in the middle as
+        // well as at the end, with some payloads large enough to spill into
+        // overflow pages, until the table has several levels of interior
+        // pages.
+        let mut rowids: Vec<i64> = (0..5000).collect();
+        for i in (1..rowids.len()).rev() {
+            rowids.swap(i, rng.gen_range(0..=i));
+        }
+        let mut expected = Vec::new();
+        for rowid in rowids {
+            let len = if rng.gen_ratio(1, 10) {
+                rng.gen_range(1000..5000)
+            } else {
+                rng.gen_range(0..40)
+            };
+            let a: String = (0..len).map(|_| rng.gen_range('a'..='z')).collect();
+            let record = OwnedRecord::new(vec![OwnedValue::Text(Rc::new(a.clone()))]);
+            run_io(&io, || cursor.insert(&OwnedValue::Integer(rowid), &record)).unwrap();
+            expected.push((rowid, a));
+        }
+        expected.sort();
+
+        let mut depth = 1;
+        let mut page_idx = root_page;
+        loop {
+            let page = db.pager.read_page(page_idx).unwrap();
+            while page.is_locked() {
+                io.run_once().unwrap();
+            }
+            let contents = page.contents.read().unwrap();
+            let contents = contents.as_ref().unwrap().as_btree();
+            if contents.header.page_type != PageType::TableInterior {
+                break;
+            }
+            depth += 1;
+            page_idx = child_page(contents, 0);
+        }
+        assert!(
+            depth >= 3,
+            "expected interior pages to split, depth {}",
+            depth
+        );
+
+        run_io(&io, || cursor.rewind()).unwrap();
+        let mut actual = Vec::new();
+        loop {
+            let rowid = *cursor.rowid().unwrap();
+            let Some(rowid) = rowid else { break };
+            let record = cursor.record().unwrap().clone().unwrap();
+            match &record.values[..] {
+                [OwnedValue::Text(a)] => actual.push((rowid as i64, a.to_string())),
+                values => panic!("unexpected record {:?}", values),
+            }
+            run_io(&io, || cursor.next()).unwrap();
+        }
+        assert_eq!(actual, expected);
+        db.pager.commit().unwrap();
+
+        temp.check_integrity();
+        let conn = temp.sqlite();
+        let mut stmt = conn.prepare("SELECT rowid, a FROM t").unwrap();
+        let actual: Vec<(i64, String)> = stmt
+            .query_map((), |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(actual, expected);
     }
 }
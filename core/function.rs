@@ -24,3 +24,78 @@ impl AggFunc {
         }
     }
 }
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScalarFunc {
+    Abs,
+    Coalesce,
+    Hex,
+    IfNull,
+    Iif,
+    Instr,
+    Length,
+    Lower,
+    LTrim,
+    Max,
+    Min,
+    NullIf,
+    Quote,
+    Random,
+    Replace,
+    Round,
+    RTrim,
+    Substr,
+    Trim,
+    Typeof,
+    Upper,
+}
+
+impl ScalarFunc {
+    pub fn to_string(&self) -> &str {
+        match self {
+            ScalarFunc::Abs => "abs",
+            ScalarFunc::Coalesce => "coalesce",
+            ScalarFunc::Hex => "hex",
+            ScalarFunc::IfNull => "ifnull",
+            ScalarFunc::Iif => "iif",
+            ScalarFunc::Instr => "instr",
+            ScalarFunc::Length => "length",
+            ScalarFunc::Lower => "lower",
+            ScalarFunc::LTrim => "ltrim",
+            ScalarFunc::Max => "max",
+            ScalarFunc::Min => "min",
+            ScalarFunc::NullIf => "nullif",
+            ScalarFunc::Quote => "quote",
+            ScalarFunc::Random => "random",
+            ScalarFunc::Replace => "replace",
+            ScalarFunc::Round => "round",
+            ScalarFunc::RTrim => "rtrim",
+            ScalarFunc::Substr => "substr",
+            ScalarFunc::Trim => "trim",
+            ScalarFunc::Typeof => "typeof",
+            ScalarFunc::Upper => "upper",
+        }
+    }
+
+    /// Whether the function can be called with `arg_count` arguments.
+    pub fn accepts_arg_count(&self, arg_count: usize) -> bool {
+        match self {
+            ScalarFunc::Abs
+            | ScalarFunc::Hex
+            | ScalarFunc::Length
+            | ScalarFunc::Lower
+            | ScalarFunc::Quote
+            | ScalarFunc::Typeof
+            | ScalarFunc::Upper => arg_count == 1,
+            ScalarFunc::IfNull | ScalarFunc::Instr | ScalarFunc::NullIf => arg_count == 2,
+            ScalarFunc::Iif | ScalarFunc::Replace => arg_count == 3,
+            ScalarFunc::LTrim | ScalarFunc::Round | ScalarFunc::RTrim | ScalarFunc::Trim => {
+                arg_count == 1 || arg_count == 2
+            }
+            ScalarFunc::Substr => arg_count == 2 || arg_count == 3,
+            // With a single argument, min() and max() are aggregates.
+            ScalarFunc::Coalesce | ScalarFunc::Max | ScalarFunc::Min => arg_count >= 2,
+            ScalarFunc::Random => arg_count == 0,
+        }
+    }
+}
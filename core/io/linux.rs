@@ -1,4 +1,4 @@
-use super::{Completion, File, WriteCompletion, IO};
+use super::{Buffer, Completion, File, OpenFlags, WriteCompletion, IO};
 use anyhow::Result;
 use log::trace;
 use std::cell::RefCell;
@@ -6,6 +6,14 @@ use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::AsRawFd;
 use std::rc::Rc;
 
+/// An I/O request submitted to the ring. The submission's user data points to
+/// it so that the completion can be dispatched to the right callback, and a
+/// write keeps its buffer alive until the kernel is done with it.
+enum Pending {
+    Read(Rc<Completion>),
+    Write(Rc<WriteCompletion>, #[allow(dead_code)] Rc<RefCell<Buffer>>),
+}
+
 pub struct LinuxIO {
     ring: Rc<RefCell<io_uring::IoUring>>,
 }
@@ -20,31 +28,56 @@ impl LinuxIO {
 }
 
 impl IO for LinuxIO {
-    fn open_file(&self, path: &str) -> Result<Rc<dyn File>> {
-        trace!("open_file(path = {})", path);
-        let file = std::fs::File::options()
-            .read(true)
-            .write(true)
-            .custom_flags(libc::O_DIRECT)
-            .open(path)?;
+    fn open_file(&self, path: &str, flags: OpenFlags) -> Result<Rc<dyn File>> {
+        trace!("open_file(path = {}, flags = {:?})", path, flags);
+        let mut options = std::fs::File::options();
+        options.custom_flags(libc::O_DIRECT);
+        let file = super::open_std_file(path, flags, options)?;
         Ok(Rc::new(LinuxFile {
             ring: self.ring.clone(),
             file,
         }))
     }
 
+    fn remove_file(&self, path: &str) -> Result<()> {
+        trace!("remove_file(path = {})", path);
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
     fn run_once(&self) -> Result<()> {
         trace!("run_once()");
         let mut ring = self.ring.borrow_mut();
         ring.submit_and_wait(1)?;
         while let Some(cqe) = ring.completion().next() {
-            let c = unsafe { Rc::from_raw(cqe.user_data() as *const Completion) };
-            c.complete();
+            let pending = unsafe { Box::from_raw(cqe.user_data() as *mut Pending) };
+            let result = cqe.result();
+            if result < 0 {
+                return Err(std::io::Error::from_raw_os_error(-result).into());
+            }
+            match *pending {
+                Pending::Read(c) => c.complete(),
+                Pending::Write(c, _) => c.complete(result as usize),
+            }
         }
         Ok(())
     }
 }
 
+/// Queue an entry on the submission queue. If the queue is full, the entries
+/// on it are submitted to the kernel first to make room.
+fn push_entry(ring: &mut io_uring::IoUring, entry: &io_uring::squeue::Entry) -> Result<()> {
+    unsafe {
+        if ring.submission().push(entry).is_err() {
+            ring.submit()?;
+            ring.submission()
+                .push(entry)
+                .expect("submission queue is full");
+        }
+    }
+    Ok(())
+}
+
 pub struct LinuxFile {
     ring: Rc<RefCell<io_uring::IoUring>>,
     file: std::fs::File,
@@ -58,19 +91,13 @@ impl File for LinuxFile {
             let mut buf = c.buf_mut();
             let len = buf.len();
             let buf = buf.as_mut_ptr();
-            let ptr = Rc::into_raw(c.clone());
+            let ptr = Box::into_raw(Box::new(Pending::Read(c.clone())));
             io_uring::opcode::Read::new(fd, buf, len as u32)
                 .offset(pos as u64)
                 .build()
                 .user_data(ptr as u64)
         };
-        let mut ring = self.ring.borrow_mut();
-        unsafe {
-            ring.submission()
-                .push(&read_e)
-                .expect("submission queue is full");
-        }
-        Ok(())
+        push_entry(&mut self.ring.borrow_mut(), &read_e)
     }
 
     fn pwrite(
@@ -79,21 +106,32 @@ impl File for LinuxFile {
         buffer: Rc<RefCell<crate::Buffer>>,
         c: Rc<WriteCompletion>,
     ) -> Result<()> {
+        trace!("pwrite(pos = {}, length = {})", pos, buffer.borrow().len());
         let fd = io_uring::types::Fd(self.file.as_raw_fd());
         let write = {
             let buf = buffer.borrow();
-            let ptr = Rc::into_raw(c.clone());
+            let ptr = Box::into_raw(Box::new(Pending::Write(c.clone(), buffer.clone())));
             io_uring::opcode::Write::new(fd, buf.as_ptr(), buf.len() as u32)
                 .offset(pos as u64)
                 .build()
                 .user_data(ptr as u64)
         };
-        let mut ring = self.ring.borrow_mut();
-        unsafe {
-            ring.submission()
-                .push(&write)
-                .expect("submission queue is full");
-        }
+        push_entry(&mut self.ring.borrow_mut(), &write)
+    }
+
+    fn sync(&self) -> Result<()> {
+        trace!("sync()");
+        self.file.sync_all()?;
+        Ok(())
+    }
+
+    fn size(&self) -> Result<usize> {
+        Ok(self.file.metadata()?.len() as usize)
+    }
+
+    fn truncate(&self, len: usize) -> Result<()> {
+        trace!("truncate(len = {})", len);
+        self.file.set_len(len as u64)?;
         Ok(())
     }
 }
@@ -1,24 +1,131 @@
 use anyhow::Result;
 use cfg_block::cfg_block;
 use std::{
-    cell::{Ref, RefCell, RefMut},
+    cell::{Cell, Ref, RefCell, RefMut},
     mem::ManuallyDrop,
     pin::Pin,
     rc::Rc,
 };
 
 pub trait File {
+    /// Read into the completion's buffer from `pos`. Reading past the end of
+    /// the file leaves the rest of the buffer as it is.
     fn pread(&self, pos: usize, c: Rc<Completion>) -> Result<()>;
     fn pwrite(&self, pos: usize, buffer: Rc<RefCell<Buffer>>, c: Rc<WriteCompletion>)
         -> Result<()>;
+    /// Flush completed writes to stable storage.
+    fn sync(&self) -> Result<()>;
+    fn size(&self) -> Result<usize>;
+    fn truncate(&self, len: usize) -> Result<()>;
+}
+
+/// How `IO::open_file` opens a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpenFlags {
+    /// Open a file that exists.
+    None,
+    /// Create the file if it does not exist.
+    Create,
 }
 
 pub trait IO {
-    fn open_file(&self, path: &str) -> Result<Rc<dyn File>>;
+    fn open_file(&self, path: &str, flags: OpenFlags) -> Result<Rc<dyn File>>;
+
+    fn remove_file(&self, path: &str) -> Result<()>;
 
     fn run_once(&self) -> Result<()>;
 }
 
+/// Whether an error opening a file for reading and writing means that the
+/// file can only be opened for reading.
+fn is_read_only(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        std::io::ErrorKind::PermissionDenied | std::io::ErrorKind::ReadOnlyFilesystem
+    )
+}
+
+/// Open `path` for reading and writing with `options`, creating it if
+/// `flags` asks for it. A file that cannot be written to is opened for
+/// reading only, so that it can still be queried.
+fn open_std_file(
+    path: &str,
+    flags: OpenFlags,
+    mut options: std::fs::OpenOptions,
+) -> Result<std::fs::File> {
+    options
+        .read(true)
+        .write(true)
+        .create(flags == OpenFlags::Create);
+    match options.open(path) {
+        Err(err) if flags == OpenFlags::None && is_read_only(&err) => {
+            Ok(options.write(false).open(path)?)
+        }
+        file => Ok(file?),
+    }
+}
+
+/// Whether an I/O error is due to a file that does not exist.
+pub fn is_not_found(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<std::io::Error>()
+        .is_some_and(|err| err.kind() == std::io::ErrorKind::NotFound)
+}
+
+/// Files are read and written in blocks of this size, at offsets that are
+/// multiples of it, as they may be opened for direct I/O.
+pub const BLOCK_SIZE: usize = 4096;
+
+/// Read `len` bytes at `pos`, in whole blocks, and wait until the reads
+/// complete. Bytes past the end of the file read as zeros.
+pub fn read_at(io: &dyn IO, file: &dyn File, pos: usize, len: usize) -> Result<Vec<u8>> {
+    let start = pos / BLOCK_SIZE * BLOCK_SIZE;
+    let num_blocks = (pos + len).div_ceil(BLOCK_SIZE) - start / BLOCK_SIZE;
+    let image = Rc::new(RefCell::new(vec![0; num_blocks * BLOCK_SIZE]));
+    let read = Rc::new(Cell::new(0));
+    for i in 0..num_blocks {
+        let buffer = Buffer::allocate(BLOCK_SIZE, Rc::new(|_| {}));
+        let complete = {
+            let image = image.clone();
+            let read = read.clone();
+            Box::new(move |buffer: &Buffer| {
+                image.borrow_mut()[i * BLOCK_SIZE..(i + 1) * BLOCK_SIZE]
+                    .copy_from_slice(buffer.as_slice());
+                read.set(read.get() + 1);
+            })
+        };
+        file.pread(
+            start + i * BLOCK_SIZE,
+            Rc::new(Completion::new(buffer, complete)),
+        )?;
+    }
+    while read.get() < num_blocks {
+        io.run_once()?;
+    }
+    let image = image.borrow();
+    Ok(image[pos - start..pos - start + len].to_vec())
+}
+
+/// Write the whole blocks of `image` at `pos`, which is a multiple of the
+/// block size, and wait until the writes complete.
+pub fn write_blocks(io: &dyn IO, file: &dyn File, pos: usize, image: &[u8]) -> Result<()> {
+    let written = Rc::new(Cell::new(0));
+    for (i, block) in image.chunks(BLOCK_SIZE).enumerate() {
+        let mut buffer = Buffer::allocate(BLOCK_SIZE, Rc::new(|_| {}));
+        buffer.as_mut_slice().copy_from_slice(block);
+        let c = {
+            let written = written.clone();
+            Rc::new(WriteCompletion::new(Box::new(move |_| {
+                written.set(written.get() + 1)
+            })))
+        };
+        file.pwrite(pos + i * BLOCK_SIZE, Rc::new(RefCell::new(buffer)), c)?;
+    }
+    while written.get() < image.len() / BLOCK_SIZE {
+        io.run_once()?;
+    }
+    Ok(())
+}
+
 pub type Complete = dyn Fn(&Buffer);
 pub type WriteComplete = dyn Fn(usize);
 
@@ -1,4 +1,4 @@
-use super::{Completion, File, WriteCompletion, IO};
+use super::{Completion, File, OpenFlags, WriteCompletion, IO};
 use anyhow::{Ok, Result};
 use std::rc::Rc;
 use std::cell::RefCell;
@@ -14,14 +14,20 @@ impl WindowsIO {
 }
 
 impl IO for WindowsIO {
-    fn open_file(&self, path: &str) -> Result<Rc<dyn File>> {
-        trace!("open_file(path = {})", path);
-        let file = std::fs::File::open(path)?;
+    fn open_file(&self, path: &str, flags: OpenFlags) -> Result<Rc<dyn File>> {
+        trace!("open_file(path = {}, flags = {:?})", path, flags);
+        let file = super::open_std_file(path, flags, std::fs::File::options())?;
         Ok(Rc::new(WindowsFile {
             file: RefCell::new(file),
         }))
     }
 
+    fn remove_file(&self, path: &str) -> Result<()> {
+        trace!("remove_file(path = {})", path);
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
     fn run_once(&self) -> Result<()> {
         Ok(())
     }
@@ -38,7 +44,14 @@ impl File for WindowsFile {
         {
             let mut buf = c.buf_mut();
             let buf = buf.as_mut_slice();
-            file.read_exact(buf)?;
+            // Stop at the end of the file, like a short read on Linux.
+            let mut read = 0;
+            while read < buf.len() {
+                match file.read(&mut buf[read..])? {
+                    0 => break,
+                    n => read += n,
+                }
+            }
         }
         c.complete();
         Ok(())
@@ -55,6 +68,21 @@ impl File for WindowsFile {
         let buf = buffer.borrow();
         let buf = buf.as_slice();
         file.write_all(buf)?;
+        c.complete(buf.len());
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.file.borrow().sync_all()?;
+        Ok(())
+    }
+
+    fn size(&self) -> Result<usize> {
+        Ok(self.file.borrow().metadata()?.len() as usize)
+    }
+
+    fn truncate(&self, len: usize) -> Result<()> {
+        self.file.borrow().set_len(len as u64)?;
         Ok(())
     }
 }
@@ -0,0 +1,390 @@
+//! Rollback journal.
+//!
+//! Before a transaction writes pages to the database file, it saves their
+//! original contents to a journal file next to it, named after the database
+//! with `-journal` appended. The transaction commits when the journal is
+//! deleted. A journal that a crash leaves behind is "hot": playing it back
+//! restores the database to its state before the transaction, which both
+//! Limbo and SQLite do when they open the database.
+//!
+//! The journal uses SQLite's format. It starts with a header, which is padded
+//! to the sector size:
+//!
+//! ```text
+//! +--------+------+------------------------------------------------+
+//! | Offset | Size | Description                                    |
+//! +--------+------+------------------------------------------------+
+//! |      0 |    8 | Magic number                                   |
+//! |      8 |    4 | Number of page records that follow             |
+//! |     12 |    4 | Nonce for the page record checksums            |
+//! |     16 |    4 | Size of the database in pages before the write |
+//! |     20 |    4 | Sector size                                    |
+//! |     24 |    4 | Page size                                      |
+//! +--------+------+------------------------------------------------+
+//! ```
+//!
+//! Each page record holds the page number, the original contents of the page
+//! and a checksum. SQLite may write further headers, each followed by its own
+//! page records, at the next multiple of the sector size.
+//!
+//! For more information, see: https://www.sqlite.org/fileformat.html#the_rollback_journal
+use crate::io::{
+    is_not_found, read_at, write_blocks, Buffer, OpenFlags, WriteCompletion, BLOCK_SIZE, IO,
+};
+use crate::PageSource;
+use anyhow::Result;
+use log::trace;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+const JOURNAL_MAGIC: [u8; 8] = [0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7];
+const JOURNAL_HEADER_SIZE: usize = 28;
+/// The sector size of the journals Limbo writes, which their header is padded
+/// to.
+const SECTOR_SIZE: usize = 512;
+/// The page records are written out once they fill this many bytes, so that
+/// a journal of a large transaction is never held in memory.
+const WRITE_SIZE: usize = 16 * BLOCK_SIZE;
+
+/// The original contents of a page, as saved in the journal.
+pub struct JournalPage {
+    pub page_idx: usize,
+    pub data: Vec<u8>,
+}
+
+/// The contents of a hot journal.
+pub struct HotJournal {
+    /// The size of the database in pages before the transaction.
+    pub database_size: usize,
+    pub page_size: usize,
+    pub pages: Vec<JournalPage>,
+}
+
+pub struct Journal {
+    io: Rc<dyn IO>,
+    path: String,
+}
+
+impl Journal {
+    pub fn new(io: Rc<dyn IO>, path: String) -> Self {
+        Self { io, path }
+    }
+
+    /// Write a journal that holds the original contents of the pages that a
+    /// transaction changes and wait until it is on stable storage. The pages
+    /// are written out as they come, a few blocks at a time.
+    pub fn write(
+        &self,
+        database_size: usize,
+        page_size: usize,
+        nonce: u32,
+        pages: impl ExactSizeIterator<Item = Result<JournalPage>>,
+    ) -> Result<()> {
+        trace!("write(path = {}, pages = {})", self.path, pages.len());
+        let mut image = vec![0; SECTOR_SIZE];
+        image[0..8].copy_from_slice(&JOURNAL_MAGIC);
+        image[8..12].copy_from_slice(&(pages.len() as u32).to_be_bytes());
+        image[12..16].copy_from_slice(&nonce.to_be_bytes());
+        image[16..20].copy_from_slice(&(database_size as u32).to_be_bytes());
+        image[20..24].copy_from_slice(&(SECTOR_SIZE as u32).to_be_bytes());
+        image[24..28].copy_from_slice(&(page_size as u32).to_be_bytes());
+
+        let file = self.io.open_file(&self.path, OpenFlags::Create)?;
+        let mut pos = 0;
+        for page in pages {
+            let page = page?;
+            assert_eq!(page.data.len(), page_size);
+            image.extend_from_slice(&(page.page_idx as u32).to_be_bytes());
+            image.extend_from_slice(&page.data);
+            image.extend_from_slice(&checksum(nonce, &page.data).to_be_bytes());
+            if image.len() >= WRITE_SIZE {
+                let len = image.len() / BLOCK_SIZE * BLOCK_SIZE;
+                write_blocks(&*self.io, &*file, pos, &image[..len])?;
+                image.drain(..len);
+                pos += len;
+            }
+        }
+        // The padding reads as the end of the journal, as it holds no header.
+        image.resize(image.len().next_multiple_of(BLOCK_SIZE), 0);
+        write_blocks(&*self.io, &*file, pos, &image)?;
+        // A journal of an earlier transaction that was rolled back may be
+        // longer.
+        file.truncate(pos + image.len())?;
+        file.sync()?;
+        Ok(())
+    }
+
+    /// Delete the journal, which commits the transaction that wrote it.
+    pub fn delete(&self) -> Result<()> {
+        trace!("delete(path = {})", self.path);
+        self.io.remove_file(&self.path)
+    }
+
+    /// Read the journal, if there is a hot one.
+    pub fn read(&self) -> Result<Option<HotJournal>> {
+        let file = match self.io.open_file(&self.path, OpenFlags::None) {
+            Ok(file) => file,
+            Err(err) if is_not_found(&err) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let image = read_at(&*self.io, &*file, 0, file.size()?)?;
+        Ok(parse_journal(&image))
+    }
+
+    /// Play back a hot journal, if there is one, which restores the database
+    /// to its state before the transaction that wrote the journal, and delete
+    /// it. Returns whether there was a hot journal.
+    pub fn recover(&self, page_source: &PageSource) -> Result<bool> {
+        let Some(journal) = self.read()? else {
+            return Ok(false);
+        };
+        trace!(
+            "recover(path = {}, pages = {})",
+            self.path,
+            journal.pages.len()
+        );
+        let written = Rc::new(Cell::new(0));
+        let mut num_writes = 0;
+        for page in &journal.pages {
+            // Pages past the original end of the database are cut off below.
+            if page.page_idx > journal.database_size {
+                continue;
+            }
+            let mut buffer = Buffer::allocate(journal.page_size, Rc::new(|_| {}));
+            buffer.as_mut_slice().copy_from_slice(&page.data);
+            let c = {
+                let written = written.clone();
+                Rc::new(WriteCompletion::new(Box::new(move |_| {
+                    written.set(written.get() + 1)
+                })))
+            };
+            page_source.write(page.page_idx, Rc::new(RefCell::new(buffer)), c)?;
+            num_writes += 1;
+        }
+        while written.get() < num_writes {
+            self.io.run_once()?;
+        }
+        page_source.truncate(journal.database_size * journal.page_size)?;
+        page_source.sync()?;
+        self.delete()?;
+        Ok(true)
+    }
+}
+
+/// Parse the contents of a journal file. Returns `None` unless the journal is
+/// hot, that is, it starts with a valid header. Reading stops at the first
+/// page record that is incomplete or has a bad checksum, like SQLite does,
+/// as the transaction had not written any pages to the database by then.
+fn parse_journal(image: &[u8]) -> Option<HotJournal> {
+    let read_u32 = |offset: usize| {
+        u32::from_be_bytes([
+            image[offset],
+            image[offset + 1],
+            image[offset + 2],
+            image[offset + 3],
+        ]) as usize
+    };
+    let mut journal: Option<HotJournal> = None;
+    let mut offset = 0;
+    'headers: while offset + JOURNAL_HEADER_SIZE <= image.len()
+        && image[offset..offset + 8] == JOURNAL_MAGIC
+    {
+        let num_records = read_u32(offset + 8);
+        let nonce = read_u32(offset + 12) as u32;
+        let database_size = read_u32(offset + 16);
+        let sector_size = read_u32(offset + 20);
+        let page_size = read_u32(offset + 24);
+        if !(512..=65536).contains(&page_size)
+            || !page_size.is_power_of_two()
+            || !(32..=65536).contains(&sector_size)
+            || !sector_size.is_power_of_two()
+        {
+            break;
+        }
+        let journal = journal.get_or_insert_with(|| HotJournal {
+            database_size,
+            page_size,
+            pages: Vec::new(),
+        });
+        if page_size != journal.page_size {
+            break;
+        }
+        offset += sector_size;
+        let record_size = page_size + 8;
+        let num_records = match num_records {
+            // The number of records was not filled in; they run to the end of
+            // the file.
+            0xffffffff => (image.len() - offset.min(image.len())) / record_size,
+            num_records => num_records,
+        };
+        for _ in 0..num_records {
+            if offset + record_size > image.len() {
+                break 'headers;
+            }
+            let page_idx = read_u32(offset);
+            let data = &image[offset + 4..offset + 4 + page_size];
+            if page_idx == 0 || read_u32(offset + 4 + page_size) as u32 != checksum(nonce, data) {
+                break 'headers;
+            }
+            // Only the first image of a page is its original contents.
+            if !journal.pages.iter().any(|page| page.page_idx == page_idx) {
+                journal.pages.push(JournalPage {
+                    page_idx,
+                    data: data.to_vec(),
+                });
+            }
+            offset += record_size;
+        }
+        offset = offset.next_multiple_of(sector_size);
+    }
+    journal
+}
+
+/// The checksum of a page record: the nonce plus every 200th byte of the
+/// page, counting back from the end.
+fn checksum(nonce: u32, data: &[u8]) -> u32 {
+    let mut checksum = nonce;
+    let mut i = data.len() as isize - 200;
+    while i > 0 {
+        checksum = checksum.wrapping_add(data[i as usize] as u32);
+        i -= 200;
+    }
+    checksum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::PlatformIO;
+    use crate::test_util::{query, TempDatabase};
+
+    fn journal_image(pages: &[JournalPage], nonce: u32) -> Vec<u8> {
+        let mut image = vec![0; SECTOR_SIZE];
+        image[0..8].copy_from_slice(&JOURNAL_MAGIC);
+        image[8..12].copy_from_slice(&(pages.len() as u32).to_be_bytes());
+        image[12..16].copy_from_slice(&nonce.to_be_bytes());
+        image[16..20].copy_from_slice(&3u32.to_be_bytes());
+        image[20..24].copy_from_slice(&(SECTOR_SIZE as u32).to_be_bytes());
+        image[24..28].copy_from_slice(&512u32.to_be_bytes());
+        for page in pages {
+            image.extend_from_slice(&(page.page_idx as u32).to_be_bytes());
+            image.extend_from_slice(&page.data);
+            image.extend_from_slice(&checksum(nonce, &page.data).to_be_bytes());
+        }
+        image
+    }
+
+    #[test]
+    fn test_parse_journal() {
+        let pages: Vec<JournalPage> = (1..=3)
+            .map(|page_idx| JournalPage {
+                page_idx,
+                data: vec![page_idx as u8; 512],
+            })
+            .collect();
+        let mut image = journal_image(&pages, 7);
+        let journal = parse_journal(&image).unwrap();
+        assert_eq!(journal.database_size, 3);
+        assert_eq!(journal.page_size, 512);
+        assert_eq!(journal.pages.len(), 3);
+        assert_eq!(journal.pages[2].data, vec![3; 512]);
+
+        // A record with a bad checksum ends the journal.
+        let last = image.len() - 1;
+        image[last] ^= 1;
+        assert_eq!(parse_journal(&image).unwrap().pages.len(), 2);
+
+        // So does a record that was cut off.
+        image.truncate(image.len() - 10);
+        assert_eq!(parse_journal(&image).unwrap().pages.len(), 2);
+
+        image[0] = 0;
+        assert!(parse_journal(&image).is_none(), "journal should not be hot");
+    }
+
+    #[test]
+    fn test_write_journal_in_blocks() {
+        let temp = TempDatabase::new("journal-blocks");
+        let io: Rc<dyn IO> = Rc::new(PlatformIO::new().unwrap());
+        let journal = Journal::new(io, temp.sibling("-journal"));
+        // The records take up several times the size that is written at once.
+        let pages = (1..301).map(|page_idx| {
+            Ok(JournalPage {
+                page_idx,
+                data: vec![page_idx as u8; 1024],
+            })
+        });
+        journal.write(400, 1024, 1234, pages).unwrap();
+        let hot = journal.read().unwrap().unwrap();
+        assert_eq!(hot.database_size, 400);
+        assert_eq!(hot.pages.len(), 300);
+        for (i, page) in hot.pages.iter().enumerate() {
+            assert_eq!(page.page_idx, i + 1);
+            assert_eq!(page.data, vec![(i + 1) as u8; 1024]);
+        }
+        journal.delete().unwrap();
+    }
+
+    /// Create a database and leave it as a crash would after a transaction
+    /// wrote its pages but before it deleted its journal, by writing the
+    /// journal of the committed transaction again.
+    fn crash_after_writing_pages() -> TempDatabase {
+        let temp = TempDatabase::with_sql(
+            "journal",
+            "PRAGMA page_size = 1024; CREATE TABLE t (a, b);
+             WITH RECURSIVE s(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM s WHERE i < 100)
+             INSERT INTO t SELECT i, 'x' FROM s;",
+        );
+        let original = std::fs::read(&temp.path).unwrap();
+
+        let (io, db) = temp.open();
+        let conn = db.connect();
+        query(&io, &conn, "BEGIN").unwrap();
+        query(&io, &conn, "DELETE FROM t WHERE a % 2 = 0").unwrap();
+        for i in 0..200 {
+            let sql = format!("INSERT INTO t VALUES ({}, '{}')", 1000 + i, "y".repeat(100));
+            query(&io, &conn, &sql).unwrap();
+        }
+        query(&io, &conn, "COMMIT").unwrap();
+        assert!(std::fs::read(&temp.path).unwrap().len() > original.len());
+
+        let pages: Vec<JournalPage> = original
+            .chunks(1024)
+            .enumerate()
+            .map(|(i, data)| JournalPage {
+                page_idx: i + 1,
+                data: data.to_vec(),
+            })
+            .collect();
+        Journal::new(io, temp.sibling("-journal"))
+            .write(pages.len(), 1024, 1234, pages.into_iter().map(Ok))
+            .unwrap();
+        temp
+    }
+
+    fn check_rolled_back(temp: &TempDatabase) {
+        temp.check_integrity();
+        let (count, sum): (i64, i64) = temp
+            .sqlite()
+            .query_row("SELECT count(*), sum(a) FROM t", (), |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!((count, sum), (100, 5050));
+    }
+
+    #[test]
+    fn test_hot_journal_recovery() {
+        // SQLite plays back the journals that Limbo writes.
+        let temp = crash_after_writing_pages();
+        check_rolled_back(&temp);
+        assert!(!std::path::Path::new(&temp.sibling("-journal")).exists());
+        drop(temp);
+
+        // And so does Limbo, when it opens the database.
+        let temp = crash_after_writing_pages();
+        temp.open();
+        assert!(!std::path::Path::new(&temp.sibling("-journal")).exists());
+        check_rolled_back(&temp);
+    }
+}
@@ -2,15 +2,20 @@ mod btree;
 mod buffer_pool;
 mod function;
 mod io;
+mod journal;
 mod pager;
 mod schema;
 mod sorter;
 mod sqlite3_ondisk;
 mod storage;
+#[cfg(test)]
+mod test_util;
 mod translate;
 mod types;
 mod util;
+mod vacuum;
 mod vdbe;
+mod wal;
 
 #[cfg(not(target_family = "wasm"))]
 #[global_allocator]
@@ -18,93 +23,117 @@ static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
 use anyhow::Result;
 use fallible_iterator::FallibleIterator;
+use journal::Journal;
 use log::trace;
 use pager::Pager;
 use schema::Schema;
 use sqlite3_ondisk::DatabaseHeader;
 use sqlite3_parser::{ast::Cmd, lexer::sql::Parser};
-use std::{cell::RefCell, rc::Rc};
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+};
+use wal::Wal;
 
 #[cfg(feature = "fs")]
 pub use io::PlatformIO;
-pub use io::{Buffer, Completion, File, WriteCompletion, IO};
+pub use io::{Buffer, Completion, File, OpenFlags, WriteCompletion, IO};
 pub use storage::{PageIO, PageSource};
 pub use types::Value;
+pub use wal::{CheckpointMode, CheckpointResult};
 
 pub struct Database {
     pager: Rc<Pager>,
-    schema: Rc<Schema>,
+    schema: Rc<RefCell<Schema>>,
     header: Rc<RefCell<DatabaseHeader>>,
+    // The id of the next connection, which tells the pager whose write
+    // transaction is in progress.
+    next_connection_id: Cell<usize>,
 }
 
 impl Database {
     #[cfg(feature = "fs")]
     pub fn open_file(io: Rc<dyn crate::io::IO>, path: &str) -> Result<Database> {
-        let file = io.open_file(path)?;
+        let file = io.open_file(path, OpenFlags::None)?;
         let storage = storage::PageSource::from_file(file);
-        Self::open(io, storage)
+        let journal = Journal::new(io.clone(), format!("{path}-journal"));
+        // A journal left behind by a crash holds the last committed state of
+        // the pages that the interrupted transaction wrote.
+        journal.recover(&storage)?;
+        let wal = Wal::new(io.clone(), format!("{path}-wal"));
+        Self::open_with(
+            io,
+            storage,
+            Some(journal),
+            Some(wal),
+            Some(path.to_string()),
+        )
     }
 
     pub fn open(io: Rc<dyn crate::io::IO>, page_source: PageSource) -> Result<Database> {
+        Self::open_with(io, page_source, None, None, None)
+    }
+
+    fn open_with(
+        io: Rc<dyn crate::io::IO>,
+        page_source: PageSource,
+        journal: Option<Journal>,
+        wal: Option<Wal>,
+        path: Option<String>,
+    ) -> Result<Database> {
         let db_header = Pager::begin_open(&page_source)?;
         io.run_once()?;
         let pager = Rc::new(Pager::finish_open(
             db_header.clone(),
             page_source,
+            journal,
+            wal,
             io.clone(),
+            path,
         )?);
-        let bootstrap_schema = Rc::new(Schema::new());
-        let conn = Connection {
-            pager: pager.clone(),
-            schema: bootstrap_schema.clone(),
-            header: db_header.clone(),
-        };
-        let mut schema = Schema::new();
-        let rows = conn.query("SELECT * FROM sqlite_schema")?;
-        if let Some(mut rows) = rows {
-            loop {
-                match rows.next()? {
-                    RowResult::Row(row) => {
-                        let ty = row.get::<&str>(0)?;
-                        if ty != "table" {
-                            continue;
-                        }
-                        let root_page: i64 = row.get::<i64>(3)?;
-                        let sql: &str = row.get::<&str>(4)?;
-                        let table = schema::BTreeTable::from_sql(sql, root_page as usize)?;
-                        schema.add_table(Rc::new(table));
-                    }
-                    RowResult::IO => {
-                        // TODO: How do we ensure that the I/O we submitted to
-                        // read the schema is actually complete?
-                        io.run_once()?;
-                    }
-                    RowResult::Done => break,
-                }
-            }
-        }
-        let schema = Rc::new(schema);
+        let schema = Rc::new(RefCell::new(Schema::read(&pager)?));
         let header = db_header;
         Ok(Database {
             pager,
             schema,
             header,
+            next_connection_id: Cell::new(0),
         })
     }
 
     pub fn connect(&self) -> Connection {
+        let id = self.next_connection_id.get();
+        self.next_connection_id.set(id + 1);
         Connection {
             pager: self.pager.clone(),
             schema: self.schema.clone(),
             header: self.header.clone(),
+            auto_commit: Rc::new(Cell::new(true)),
+            id,
         }
     }
 }
 
 pub struct Connection {
     pager: Rc<Pager>,
-    schema: Rc<Schema>,
+    schema: Rc<RefCell<Schema>>,
     header: Rc<RefCell<DatabaseHeader>>,
+    // Whether each statement runs in a transaction of its own, which is the
+    // case outside of BEGIN ... COMMIT.
+    auto_commit: Rc<Cell<bool>>,
+    id: usize,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // A transaction that is still open when its connection closes is
+        // rolled back, so that it does not lock out the other connections.
+        if self.pager.is_writer(self.id) {
+            if let Err(err) = vdbe::rollback(&self.pager, &self.schema) {
+                log::error!("failed to roll back transaction: {}", err);
+            }
+        }
+    }
 }
 
 impl Connection {
@@ -117,12 +146,19 @@ impl Connection {
             match cmd {
                 Cmd::Stmt(stmt) => {
                     let program = Rc::new(translate::translate(
-                        &self.schema,
+                        &self.schema.borrow(),
                         stmt,
+                        &sql,
                         self.header.clone(),
                         self.pager.clone(),
                     )?);
-                    Ok(Statement::new(program, self.pager.clone()))
+                    Ok(Statement::new(
+                        program,
+                        self.pager.clone(),
+                        self.schema.clone(),
+                        self.auto_commit.clone(),
+                        self.id,
+                    ))
                 }
                 Cmd::Explain(_stmt) => todo!(),
                 Cmd::ExplainQueryPlan(_stmt) => todo!(),
@@ -141,18 +177,26 @@ impl Connection {
             match cmd {
                 Cmd::Stmt(stmt) => {
                     let program = Rc::new(translate::translate(
-                        &self.schema,
+                        &self.schema.borrow(),
                         stmt,
+                        &sql,
                         self.header.clone(),
                         self.pager.clone(),
                     )?);
-                    let stmt = Statement::new(program, self.pager.clone());
+                    let stmt = Statement::new(
+                        program,
+                        self.pager.clone(),
+                        self.schema.clone(),
+                        self.auto_commit.clone(),
+                        self.id,
+                    );
                     Ok(Some(Rows { stmt }))
                 }
                 Cmd::Explain(stmt) => {
                     let program = translate::translate(
-                        &self.schema,
+                        &self.schema.borrow(),
                         stmt,
+                        &sql,
                         self.header.clone(),
                         self.pager.clone(),
                     )?;
@@ -174,8 +218,9 @@ impl Connection {
             match cmd {
                 Cmd::Explain(stmt) => {
                     let program = translate::translate(
-                        &self.schema,
+                        &self.schema.borrow(),
                         stmt,
+                        &sql,
                         self.header.clone(),
                         self.pager.clone(),
                     )?;
@@ -184,38 +229,75 @@ impl Connection {
                 Cmd::ExplainQueryPlan(_stmt) => todo!(),
                 Cmd::Stmt(stmt) => {
                     let program = translate::translate(
-                        &self.schema,
+                        &self.schema.borrow(),
                         stmt,
+                        &sql,
                         self.header.clone(),
                         self.pager.clone(),
                     )?;
                     let mut state = vdbe::ProgramState::new(program.max_registers);
-                    program.step(&mut state, self.pager.clone())?;
+                    program.step(
+                        &mut state,
+                        self.pager.clone(),
+                        self.schema.clone(),
+                        &self.auto_commit,
+                        self.id,
+                    )?;
                 }
             }
         }
         Ok(())
     }
+
+    /// Copy the pages in the write-ahead log to the database file, as
+    /// `PRAGMA wal_checkpoint` does. Returns `None` unless the database is in
+    /// WAL mode.
+    pub fn checkpoint(&self, mode: CheckpointMode) -> Result<Option<CheckpointResult>> {
+        if !self.auto_commit.get() {
+            anyhow::bail!("database table is locked");
+        }
+        self.pager.check_writer(self.id)?;
+        self.pager.checkpoint(mode)
+    }
 }
 
 pub struct Statement {
     program: Rc<vdbe::Program>,
     state: vdbe::ProgramState,
     pager: Rc<Pager>,
+    schema: Rc<RefCell<Schema>>,
+    auto_commit: Rc<Cell<bool>>,
+    // The id of the connection that prepared the statement.
+    connection_id: usize,
 }
 
 impl Statement {
-    pub fn new(program: Rc<vdbe::Program>, pager: Rc<Pager>) -> Self {
+    pub fn new(
+        program: Rc<vdbe::Program>,
+        pager: Rc<Pager>,
+        schema: Rc<RefCell<Schema>>,
+        auto_commit: Rc<Cell<bool>>,
+        connection_id: usize,
+    ) -> Self {
         let state = vdbe::ProgramState::new(program.max_registers);
         Self {
             program,
             state,
             pager,
+            schema,
+            auto_commit,
+            connection_id,
         }
     }
 
     pub fn step(&mut self) -> Result<RowResult<'_>> {
-        let result = self.program.step(&mut self.state, self.pager.clone())?;
+        let result = self.program.step(
+            &mut self.state,
+            self.pager.clone(),
+            self.schema.clone(),
+            &self.auto_commit,
+            self.connection_id,
+        )?;
         match result {
             vdbe::StepResult::Row(row) => Ok(RowResult::Row(Row { values: row.values })),
             vdbe::StepResult::IO => Ok(RowResult::IO),
@@ -224,7 +306,13 @@ impl Statement {
     }
 
     pub fn query(&mut self) -> Result<Rows> {
-        let stmt = Statement::new(self.program.clone(), self.pager.clone());
+        let stmt = Statement::new(
+            self.program.clone(),
+            self.pager.clone(),
+            self.schema.clone(),
+            self.auto_commit.clone(),
+            self.connection_id,
+        );
         Ok(Rows::new(stmt))
     }
 
@@ -1,10 +1,16 @@
 use crate::buffer_pool::BufferPool;
-use crate::sqlite3_ondisk::BTreePage;
-use crate::sqlite3_ondisk::{self, DatabaseHeader};
+use crate::io::{Buffer, Completion, WriteCompletion};
+use crate::journal::{Journal, JournalPage};
+use crate::sqlite3_ondisk::{self, DatabaseHeader, PENDING_BYTE};
+use crate::sqlite3_ondisk::{
+    BTreePage, BTreePageHeader, FreelistTrunkPage, OverflowPage, PageContent, PageType,
+};
+use crate::wal::{CheckpointMode, CheckpointResult, ReadLock, Wal, WalPageIO};
 use crate::PageSource;
 use log::trace;
 use sieve_cache::SieveCache;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
 use std::hash::Hash;
 use std::rc::Rc;
 use std::sync::atomic::{AtomicUsize, Ordering};
@@ -12,7 +18,7 @@ use std::sync::RwLock;
 
 pub struct Page {
     flags: AtomicUsize,
-    pub contents: RwLock<Option<BTreePage>>,
+    pub contents: RwLock<Option<PageContent>>,
 }
 
 /// Page is up-to-date.
@@ -21,6 +27,8 @@ const PAGE_UPTODATE: usize = 0b001;
 const PAGE_LOCKED: usize = 0b010;
 /// Page had an I/O error.
 const PAGE_ERROR: usize = 0b100;
+/// Page was modified and has not been written back yet.
+const PAGE_DIRTY: usize = 0b1000;
 
 impl Default for Page {
     fn default() -> Self {
@@ -71,6 +79,18 @@ impl Page {
     pub fn clear_error(&self) {
         self.flags.fetch_and(!PAGE_ERROR, Ordering::SeqCst);
     }
+
+    pub fn is_dirty(&self) -> bool {
+        self.flags.load(Ordering::SeqCst) & PAGE_DIRTY != 0
+    }
+
+    pub fn set_dirty(&self) {
+        self.flags.fetch_or(PAGE_DIRTY, Ordering::SeqCst);
+    }
+
+    pub fn clear_dirty(&self) {
+        self.flags.fetch_and(!PAGE_DIRTY, Ordering::SeqCst);
+    }
 }
 
 pub struct PageCache<K: Eq + Hash + Clone, V> {
@@ -93,16 +113,62 @@ impl<K: Eq + Hash + Clone, V> PageCache<K, V> {
     pub fn resize(&mut self, capacity: usize) {
         self.cache = SieveCache::new(capacity).unwrap();
     }
+
+    pub fn clear(&mut self) {
+        self.cache = SieveCache::new(self.cache.capacity()).unwrap();
+    }
+}
+
+/// How a transaction commits: through a rollback journal or the write-ahead
+/// log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JournalMode {
+    Delete,
+    Wal,
+}
+
+impl JournalMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JournalMode::Delete => "delete",
+            JournalMode::Wal => "wal",
+        }
+    }
 }
 
+/// VACUUM writes the pages of the new database to the database file this
+/// many at a time.
+const PAGES_PER_WRITE: usize = 64;
+
 /// The pager interface implements the persistence layer by providing access
 /// to pages of the database file, including caching, concurrency control, and
 /// transaction management.
 pub struct Pager {
     pub page_source: PageSource,
     page_cache: RefCell<PageCache<usize, Rc<Page>>>,
-    buffer_pool: Rc<BufferPool>,
+    pub buffer_pool: Rc<BufferPool>,
     pub io: Rc<dyn crate::io::IO>,
+    db_header: Rc<RefCell<DatabaseHeader>>,
+    // The rollback journal, if the database is a file.
+    journal: Option<Journal>,
+    // The write-ahead log, if the database is a file. It is open in WAL mode.
+    wal: Option<Rc<Wal>>,
+    // Pages modified by the current write transaction, which reach the
+    // database file on commit. They are pinned here so that the page cache
+    // cannot evict them before they are written back.
+    dirty_pages: RefCell<BTreeMap<usize, Rc<Page>>>,
+    // The database header as of the start of the current write transaction,
+    // if there is one.
+    header_snapshot: RefCell<Option<DatabaseHeader>>,
+    // The read of the log that the current write transaction holds, so that
+    // its statements see the same snapshot.
+    transaction_read: RefCell<Option<ReadLock>>,
+    // The connection that the current write transaction belongs to. The
+    // connections of a database share the pager and its dirty pages, so the
+    // others cannot use the database until the transaction ends.
+    writer: Cell<Option<usize>>,
+    // The path of the database file, if the database is a file.
+    path: Option<String>,
 }
 
 impl Pager {
@@ -113,10 +179,17 @@ impl Pager {
     pub fn finish_open(
         db_header: Rc<RefCell<DatabaseHeader>>,
         page_source: PageSource,
+        journal: Option<Journal>,
+        wal: Option<Wal>,
         io: Rc<dyn crate::io::IO>,
+        path: Option<String>,
     ) -> anyhow::Result<Self> {
-        let db_header = db_header.borrow();
-        let page_size = db_header.page_size as usize;
+        let page_size = db_header.borrow().page_size as usize;
+        let wal = wal.map(Rc::new);
+        if let Some(wal) = wal.as_ref().filter(|_| db_header.borrow().is_wal()) {
+            wal.open(page_size)?;
+            read_wal_header(&db_header, &page_source, wal, &*io)?;
+        }
         let buffer_pool = Rc::new(BufferPool::new(page_size));
         let page_cache = RefCell::new(PageCache::new(SieveCache::new(10).unwrap()));
         Ok(Self {
@@ -124,11 +197,22 @@ impl Pager {
             buffer_pool,
             page_cache,
             io,
+            db_header,
+            journal,
+            wal,
+            dirty_pages: RefCell::new(BTreeMap::new()),
+            header_snapshot: RefCell::new(None),
+            transaction_read: RefCell::new(None),
+            writer: Cell::new(None),
+            path,
         })
     }
 
     pub fn read_page(&self, page_idx: usize) -> anyhow::Result<Rc<Page>> {
         trace!("read_page(page_idx = {})", page_idx);
+        if let Some(page) = self.dirty_pages.borrow().get(&page_idx) {
+            return Ok(page.clone());
+        }
         let mut page_cache = self.page_cache.borrow_mut();
         if let Some(page) = page_cache.get(&page_idx) {
             return Ok(page.clone());
@@ -136,7 +220,51 @@ impl Pager {
         let page = Rc::new(Page::new());
         page.set_locked();
         sqlite3_ondisk::begin_read_btree_page(
-            &self.page_source,
+            &self.reader(),
+            self.buffer_pool.clone(),
+            page.clone(),
+            page_idx,
+            self.db_header.clone(),
+        )?;
+        page_cache.insert(page_idx, page.clone());
+        Ok(page)
+    }
+
+    pub fn read_overflow_page(&self, page_idx: usize) -> anyhow::Result<Rc<Page>> {
+        trace!("read_overflow_page(page_idx = {})", page_idx);
+        if let Some(page) = self.dirty_pages.borrow().get(&page_idx) {
+            return Ok(page.clone());
+        }
+        let mut page_cache = self.page_cache.borrow_mut();
+        if let Some(page) = page_cache.get(&page_idx) {
+            return Ok(page.clone());
+        }
+        let page = Rc::new(Page::new());
+        page.set_locked();
+        sqlite3_ondisk::begin_read_overflow_page(
+            &self.reader(),
+            self.buffer_pool.clone(),
+            page.clone(),
+            page_idx,
+            self.usable_size(),
+        )?;
+        page_cache.insert(page_idx, page.clone());
+        Ok(page)
+    }
+
+    fn read_freelist_trunk_page(&self, page_idx: usize) -> anyhow::Result<Rc<Page>> {
+        trace!("read_freelist_trunk_page(page_idx = {})", page_idx);
+        if let Some(page) = self.dirty_pages.borrow().get(&page_idx) {
+            return Ok(page.clone());
+        }
+        let mut page_cache = self.page_cache.borrow_mut();
+        if let Some(page) = page_cache.get(&page_idx) {
+            return Ok(page.clone());
+        }
+        let page = Rc::new(Page::new());
+        page.set_locked();
+        sqlite3_ondisk::begin_read_freelist_trunk_page(
+            &self.reader(),
             self.buffer_pool.clone(),
             page.clone(),
             page_idx,
@@ -145,6 +273,601 @@ impl Pager {
         Ok(page)
     }
 
+    /// Allocate a new, empty b-tree page.
+    pub fn allocate_page(&self, page_type: PageType) -> anyhow::Result<(usize, Rc<Page>)> {
+        self.allocate(PageContent::BTree(BTreePage::new(
+            BTreePageHeader::new(page_type),
+            Vec::new(),
+        )))
+    }
+
+    /// Allocate a new, empty overflow page.
+    pub fn allocate_overflow_page(&self) -> anyhow::Result<(usize, Rc<Page>)> {
+        self.allocate(PageContent::Overflow(OverflowPage {
+            next_page: None,
+            data: Vec::new(),
+        }))
+    }
+
+    /// Allocate a page for `contents`, taking it from the freelist if that
+    /// has one and adding it to the end of the database file otherwise.
+    fn allocate(&self, contents: PageContent) -> anyhow::Result<(usize, Rc<Page>)> {
+        self.begin_write();
+        let page_idx = match self.take_freelist_page()? {
+            Some(page_idx) => page_idx,
+            None => {
+                let pending_byte_page = PENDING_BYTE / self.page_size() + 1;
+                let mut header = self.db_header.borrow_mut();
+                header.database_size += 1;
+                if header.database_size as usize == pending_byte_page {
+                    header.database_size += 1;
+                }
+                header.database_size as usize
+            }
+        };
+        trace!("allocate_page(page_idx = {})", page_idx);
+        let page = Rc::new(Page::new());
+        page.contents.write().unwrap().replace(contents);
+        page.set_uptodate();
+        self.add_dirty(page_idx, &page);
+        Ok((page_idx, page))
+    }
+
+    /// Take a page off the freelist, as SQLite does: the first leaf of the
+    /// first trunk page, whose place the last leaf takes, or the trunk page
+    /// itself once it has no leaves.
+    fn take_freelist_page(&self) -> anyhow::Result<Option<usize>> {
+        let trunk_page_idx = self.db_header.borrow().freelist_trunk_page as usize;
+        if trunk_page_idx == 0 {
+            return Ok(None);
+        }
+        let trunk = self.read_freelist_trunk_page(trunk_page_idx)?;
+        while trunk.is_locked() {
+            self.io.run_once()?;
+        }
+        let (leaf_page_idx, next_trunk_page) = {
+            let mut contents = trunk.contents.write().unwrap();
+            let contents = contents.as_mut().unwrap().as_freelist_trunk_mut();
+            if contents.leaf_pages.is_empty() {
+                (None, contents.next_trunk_page)
+            } else {
+                (Some(contents.leaf_pages.swap_remove(0)), None)
+            }
+        };
+        let mut header = self.db_header.borrow_mut();
+        header.freelist_pages = header.freelist_pages.saturating_sub(1);
+        match leaf_page_idx {
+            Some(page_idx) => {
+                if page_idx < 2 || page_idx > header.database_size {
+                    anyhow::bail!("freelist leaf page {} is corrupt", page_idx);
+                }
+                drop(header);
+                self.add_dirty(trunk_page_idx, &trunk);
+                Ok(Some(page_idx as usize))
+            }
+            None => {
+                header.freelist_trunk_page = next_trunk_page.unwrap_or(0);
+                Ok(Some(trunk_page_idx))
+            }
+        }
+    }
+
+    /// Add a page that is no longer used to the freelist. The page is listed
+    /// as a leaf of the first trunk page if that has room, otherwise it
+    /// becomes the new first trunk page. The contents of a freelist leaf page
+    /// are left as they are.
+    pub fn free_page(&self, page_idx: usize) -> anyhow::Result<()> {
+        trace!("free_page(page_idx = {})", page_idx);
+        self.begin_write();
+        // The contents of a freed page do not need to be written back.
+        if let Some(page) = self.dirty_pages.borrow_mut().remove(&page_idx) {
+            page.clear_dirty();
+        }
+        let trunk_page_idx = self.db_header.borrow().freelist_trunk_page as usize;
+        if trunk_page_idx != 0 {
+            let trunk = self.read_freelist_trunk_page(trunk_page_idx)?;
+            while trunk.is_locked() {
+                self.io.run_once()?;
+            }
+            // Older versions of SQLite limit the number of leaves of a trunk
+            // page to this, so it is not filled any further.
+            let max_leaf_pages = self.usable_size() / 4 - 8;
+            let added = {
+                let mut contents = trunk.contents.write().unwrap();
+                let contents = contents.as_mut().unwrap().as_freelist_trunk_mut();
+                if contents.leaf_pages.len() < max_leaf_pages {
+                    contents.leaf_pages.push(page_idx as u32);
+                    true
+                } else {
+                    false
+                }
+            };
+            if added {
+                self.add_dirty(trunk_page_idx, &trunk);
+                self.db_header.borrow_mut().freelist_pages += 1;
+                return Ok(());
+            }
+        }
+        let page = Rc::new(Page::new());
+        page.contents
+            .write()
+            .unwrap()
+            .replace(PageContent::FreelistTrunk(FreelistTrunkPage {
+                next_trunk_page: (trunk_page_idx != 0).then_some(trunk_page_idx as u32),
+                leaf_pages: Vec::new(),
+            }));
+        page.set_uptodate();
+        self.add_dirty(page_idx, &page);
+        let mut header = self.db_header.borrow_mut();
+        header.freelist_trunk_page = page_idx as u32;
+        header.freelist_pages += 1;
+        Ok(())
+    }
+
+    /// Mark a modified page as dirty and pin it in memory. It is written
+    /// back when the write transaction commits.
+    pub fn add_dirty(&self, page_idx: usize, page: &Rc<Page>) {
+        trace!("add_dirty(page_idx = {})", page_idx);
+        self.begin_write();
+        page.set_dirty();
+        self.dirty_pages.borrow_mut().insert(page_idx, page.clone());
+    }
+
+    /// The number of pages modified by the current write transaction.
+    pub fn dirty_page_count(&self) -> usize {
+        self.dirty_pages.borrow().len()
+    }
+
+    /// Start writing the dirty pages back to the database file. Each page is
+    /// locked until its write completes, which makes it clean. The original
+    /// contents of the pages must be in the rollback journal first, which
+    /// `commit()` takes care of.
+    pub fn flush(&self) -> anyhow::Result<()> {
+        let dirty_pages = self.dirty_pages.borrow();
+        trace!("flush(pages = {})", dirty_pages.len());
+        for (page_idx, page) in dirty_pages.iter() {
+            if !page.is_dirty() || page.is_locked() {
+                continue;
+            }
+            page.set_locked();
+            let c = {
+                let page = page.clone();
+                Rc::new(WriteCompletion::new(Box::new(move |_| {
+                    page.clear_dirty();
+                    page.clear_locked();
+                })))
+            };
+            self.page_source
+                .write(*page_idx, self.serialize_page(*page_idx, page), c)?;
+        }
+        Ok(())
+    }
+
+    /// Write the dirty pages back to the database file and wait until they
+    /// are on stable storage.
+    fn cacheflush(&self) -> anyhow::Result<()> {
+        self.flush()?;
+        let dirty_pages = self.dirty_pages.borrow().clone();
+        for page in dirty_pages.values() {
+            while page.is_locked() {
+                self.io.run_once()?;
+            }
+        }
+        self.page_source.sync()
+    }
+
+    /// Start a write transaction, unless one is active, by remembering the
+    /// database header to go back to on rollback.
+    fn begin_write(&self) {
+        let mut snapshot = self.header_snapshot.borrow_mut();
+        if snapshot.is_none() {
+            trace!("begin_write()");
+            *snapshot = Some(self.db_header.borrow().clone());
+            let read = self.open_wal().map(|wal| wal.begin_read().0);
+            self.transaction_read.replace(read);
+        }
+    }
+
+    /// Fail unless connection `connection_id` may use the database, which it
+    /// may unless another connection has a write transaction in progress.
+    pub fn check_writer(&self, connection_id: usize) -> anyhow::Result<()> {
+        match self.writer.get() {
+            Some(writer) if writer != connection_id && self.header_snapshot.borrow().is_some() => {
+                anyhow::bail!("database is locked")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Record that the write transaction in progress, if there is one,
+    /// belongs to connection `connection_id`, which has just used the
+    /// database.
+    pub fn update_writer(&self, connection_id: usize) {
+        let writing = self.header_snapshot.borrow().is_some();
+        self.writer.set(writing.then_some(connection_id));
+    }
+
+    /// Whether connection `connection_id` has a write transaction in
+    /// progress.
+    pub fn is_writer(&self, connection_id: usize) -> bool {
+        self.writer.get() == Some(connection_id) && self.header_snapshot.borrow().is_some()
+    }
+
+    /// Start a read. In WAL mode, the read sees the transactions committed
+    /// to the log so far, and checkpoints do not overwrite the pages it may
+    /// read until the returned lock is dropped.
+    pub fn begin_read(&self) -> anyhow::Result<Option<ReadLock>> {
+        let Some(wal) = self.open_wal() else {
+            return Ok(None);
+        };
+        let (read, changed) = wal.begin_read();
+        if changed {
+            // Another handle on the database committed, so the cached pages
+            // and the header may be out of date.
+            trace!("begin_read(changed = true)");
+            self.page_cache.borrow_mut().clear();
+            read_wal_header(&self.db_header, &self.page_source, wal, &*self.io)?;
+        }
+        Ok(Some(read))
+    }
+
+    /// Commit the write transaction, if there is one. The original contents
+    /// of the dirty pages are saved in the rollback journal, then the pages
+    /// are written to the database file, and deleting the journal makes the
+    /// transaction durable.
+    pub fn commit(&self) -> anyhow::Result<()> {
+        let Some(snapshot) = self.header_snapshot.borrow().clone() else {
+            return Ok(());
+        };
+        trace!("commit(pages = {})", self.dirty_pages.borrow().len());
+        let nonce = {
+            let mut header = self.db_header.borrow_mut();
+            header.change_counter = header.change_counter.wrapping_add(1);
+            header.version_valid_for = header.change_counter;
+            header.change_counter
+        };
+        // Page 1 holds the database header.
+        let page = self.read_page(1)?;
+        while page.is_locked() {
+            self.io.run_once()?;
+        }
+        self.add_dirty(1, &page);
+
+        let dirty_pages = self.dirty_pages.borrow().clone();
+        match self.open_wal() {
+            Some(wal) => {
+                let frames = dirty_pages.iter().map(|(page_idx, page)| {
+                    let buffer = self.serialize_page(*page_idx, page);
+                    let data = buffer.borrow().as_slice().to_vec();
+                    Ok((*page_idx, data))
+                });
+                let database_size = self.db_header.borrow().database_size as usize;
+                wal.append(frames, database_size)?;
+            }
+            None => self.write_through_journal(&dirty_pages, &snapshot, nonce)?,
+        }
+        // The pages are clean now, and the page cache may evict them.
+        let mut page_cache = self.page_cache.borrow_mut();
+        for (page_idx, page) in std::mem::take(&mut *self.dirty_pages.borrow_mut()) {
+            page.clear_dirty();
+            page_cache.insert(page_idx, page);
+        }
+        self.header_snapshot.replace(None);
+        self.transaction_read.replace(None);
+        Ok(())
+    }
+
+    /// Save the original contents of pages in the rollback journal, write the
+    /// pages to the database file and delete the journal.
+    fn write_through_journal(
+        &self,
+        dirty_pages: &BTreeMap<usize, Rc<Page>>,
+        snapshot: &DatabaseHeader,
+        nonce: u32,
+    ) -> anyhow::Result<()> {
+        let database_size = snapshot.database_size as usize;
+        let new_database_size = self.db_header.borrow().database_size as usize;
+        if let Some(journal) = &self.journal {
+            // The pages that a smaller database leaves off the end of the
+            // file are saved as well.
+            let pages = self.read_original_pages(
+                dirty_pages
+                    .keys()
+                    .copied()
+                    .filter(|page_idx| *page_idx <= database_size)
+                    .chain(new_database_size + 1..=database_size),
+            )?;
+            let pages = pages.into_iter().map(Ok);
+            journal.write(database_size, self.page_size(), nonce, pages)?;
+        }
+        self.cacheflush()?;
+        if new_database_size < database_size {
+            self.page_source
+                .truncate(new_database_size * self.page_size())?;
+            self.page_source.sync()?;
+        }
+        if let Some(journal) = &self.journal {
+            journal.delete()?;
+        }
+        Ok(())
+    }
+
+    /// Replace the contents of the database with those of `source`, which
+    /// holds a database without free pages, as VACUUM does. The header stays
+    /// that of this database, apart from its size, freelist and schema
+    /// cookie. The pages are copied in a write transaction, a batch at a
+    /// time, so that the database is never held in memory: in rollback
+    /// journal mode, the journal is written first, as it holds every original
+    /// page, and the new pages are then written over them.
+    pub fn replace_pages(&self, source: &Pager) -> anyhow::Result<()> {
+        let num_pages = source.header().database_size as usize;
+        trace!("replace_pages(pages = {})", num_pages);
+        self.begin_write();
+        let snapshot = self.header_snapshot.borrow().clone().unwrap();
+        let nonce = {
+            let mut header = self.db_header.borrow_mut();
+            header.database_size = num_pages as u32;
+            header.freelist_trunk_page = 0;
+            header.freelist_pages = 0;
+            header.schema_cookie = header.schema_cookie.wrapping_add(1);
+            header.change_counter = header.change_counter.wrapping_add(1);
+            header.version_valid_for = header.change_counter;
+            header.change_counter
+        };
+        // The cached pages are those of the old contents.
+        self.page_cache.borrow_mut().clear();
+        let header = self.db_header.borrow().clone();
+        let pending_byte_page = PENDING_BYTE / self.page_size() + 1;
+        let pages = (1..=num_pages)
+            .filter(|page_idx| *page_idx != pending_byte_page)
+            .map(|page_idx| {
+                let mut page = source.read_original_pages(std::iter::once(page_idx))?;
+                let mut data = page.pop().unwrap().data;
+                if page_idx == 1 {
+                    sqlite3_ondisk::write_header_to_buf(&mut data, &header);
+                }
+                Ok((page_idx, data))
+            });
+        match self.open_wal() {
+            Some(wal) => wal.append(pages, num_pages)?,
+            None => {
+                let database_size = snapshot.database_size as usize;
+                if let Some(journal) = &self.journal {
+                    let original_pages = (1..database_size + 1).map(|page_idx| {
+                        let mut page = self.read_original_pages(std::iter::once(page_idx))?;
+                        Ok(page.pop().unwrap())
+                    });
+                    journal.write(database_size, self.page_size(), nonce, original_pages)?;
+                }
+                let mut batch = Vec::with_capacity(PAGES_PER_WRITE);
+                for page in pages {
+                    batch.push(page?);
+                    if batch.len() == PAGES_PER_WRITE {
+                        self.write_pages(&batch)?;
+                        batch.clear();
+                    }
+                }
+                self.write_pages(&batch)?;
+                self.page_source.sync()?;
+                if num_pages < database_size {
+                    self.page_source.truncate(num_pages * self.page_size())?;
+                    self.page_source.sync()?;
+                }
+                if let Some(journal) = &self.journal {
+                    journal.delete()?;
+                }
+            }
+        }
+        self.header_snapshot.replace(None);
+        self.transaction_read.replace(None);
+        Ok(())
+    }
+
+    /// Write the contents of pages to the database file and wait until the
+    /// writes complete.
+    fn write_pages(&self, pages: &[(usize, Vec<u8>)]) -> anyhow::Result<()> {
+        let written = Rc::new(Cell::new(0));
+        for (page_idx, data) in pages {
+            let mut buffer = Buffer::new(self.buffer_pool.get(), {
+                let buffer_pool = self.buffer_pool.clone();
+                Rc::new(move |buf| buffer_pool.put(buf))
+            });
+            buffer.as_mut_slice().copy_from_slice(data);
+            let c = {
+                let written = written.clone();
+                Rc::new(WriteCompletion::new(Box::new(move |_| {
+                    written.set(written.get() + 1)
+                })))
+            };
+            self.page_source
+                .write(*page_idx, Rc::new(RefCell::new(buffer)), c)?;
+        }
+        while written.get() < pages.len() {
+            self.io.run_once()?;
+        }
+        Ok(())
+    }
+
+    /// Roll back the write transaction, if there is one: forget the dirty
+    /// pages, play back the rollback journal if a failed commit left one, and
+    /// restore the database header.
+    pub fn rollback(&self) -> anyhow::Result<()> {
+        let Some(snapshot) = self.header_snapshot.take() else {
+            return Ok(());
+        };
+        trace!("rollback(pages = {})", self.dirty_pages.borrow().len());
+        for page in std::mem::take(&mut *self.dirty_pages.borrow_mut()).into_values() {
+            page.clear_dirty();
+        }
+        self.page_cache.borrow_mut().clear();
+        // Nothing reaches the log before the commit frame is written.
+        if let (Some(journal), None) = (&self.journal, self.open_wal()) {
+            journal.recover(&self.page_source)?;
+        }
+        *self.db_header.borrow_mut() = snapshot;
+        self.transaction_read.replace(None);
+        Ok(())
+    }
+
+    /// Read the contents of pages as they are in the database file.
+    fn read_original_pages(
+        &self,
+        page_idxs: impl Iterator<Item = usize>,
+    ) -> anyhow::Result<Vec<JournalPage>> {
+        let pages = Rc::new(RefCell::new(Vec::new()));
+        let mut num_reads = 0;
+        for page_idx in page_idxs {
+            let buffer = Buffer::new(self.buffer_pool.get(), {
+                let buffer_pool = self.buffer_pool.clone();
+                Rc::new(move |buf| buffer_pool.put(buf))
+            });
+            let complete = {
+                let pages = pages.clone();
+                Box::new(move |buffer: &Buffer| {
+                    pages.borrow_mut().push(JournalPage {
+                        page_idx,
+                        data: buffer.as_slice().to_vec(),
+                    })
+                })
+            };
+            self.page_source
+                .get(page_idx, Rc::new(Completion::new(buffer, complete)))?;
+            num_reads += 1;
+        }
+        while pages.borrow().len() < num_reads {
+            self.io.run_once()?;
+        }
+        Ok(pages.take())
+    }
+
+    fn serialize_page(&self, page_idx: usize, page: &Page) -> Rc<RefCell<Buffer>> {
+        let buffer_pool = self.buffer_pool.clone();
+        let drop_fn = Rc::new(move |buf| buffer_pool.put(buf));
+        let mut buffer = Buffer::new(self.buffer_pool.get(), drop_fn);
+        let mut contents = page.contents.write().unwrap();
+        match contents.as_mut().unwrap() {
+            PageContent::BTree(contents) => {
+                let header = self.db_header.borrow();
+                sqlite3_ondisk::write_btree_page(
+                    buffer.as_mut_slice(),
+                    page_idx,
+                    contents,
+                    &header,
+                );
+            }
+            PageContent::Overflow(contents) => {
+                sqlite3_ondisk::write_overflow_page(buffer.as_mut_slice(), contents);
+            }
+            PageContent::FreelistTrunk(contents) => {
+                sqlite3_ondisk::write_freelist_trunk_page(buffer.as_mut_slice(), contents);
+            }
+        }
+        Rc::new(RefCell::new(buffer))
+    }
+
+    /// The write-ahead log, if the database is in WAL mode.
+    fn open_wal(&self) -> Option<&Rc<Wal>> {
+        self.wal.as_ref().filter(|wal| wal.is_open())
+    }
+
+    /// The source to read pages from, which is the log for the pages it
+    /// holds in WAL mode.
+    fn reader(&self) -> PageSource {
+        match self.open_wal() {
+            Some(wal) => PageSource::from_io(Rc::new(WalPageIO::new(
+                self.page_source.clone(),
+                wal.clone(),
+            ))),
+            None => self.page_source.clone(),
+        }
+    }
+
+    pub fn journal_mode(&self) -> JournalMode {
+        match self.open_wal() {
+            Some(_) => JournalMode::Wal,
+            None => JournalMode::Delete,
+        }
+    }
+
+    /// Switch the journal mode outside of a write transaction. The mode of a
+    /// database that is not a file stays as it is. Returns the new mode.
+    pub fn set_journal_mode(&self, mode: JournalMode) -> anyhow::Result<JournalMode> {
+        let Some(wal) = &self.wal else {
+            return Ok(self.journal_mode());
+        };
+        assert!(self.header_snapshot.borrow().is_none());
+        match (self.journal_mode(), mode) {
+            (JournalMode::Delete, JournalMode::Wal) => {
+                trace!("set_journal_mode(mode = wal)");
+                self.begin_write();
+                self.db_header.borrow_mut().set_wal(true);
+                self.commit()?;
+                wal.open(self.page_size())?;
+            }
+            (JournalMode::Wal, JournalMode::Delete) => {
+                trace!("set_journal_mode(mode = delete)");
+                if wal
+                    .checkpoint(&self.page_source, CheckpointMode::Truncate)?
+                    .busy
+                {
+                    anyhow::bail!("database is locked");
+                }
+                wal.close();
+                wal.delete()?;
+                self.begin_write();
+                self.db_header.borrow_mut().set_wal(false);
+                self.commit()?;
+            }
+            _ => {}
+        }
+        Ok(self.journal_mode())
+    }
+
+    /// Copy the pages in the write-ahead log to the database file. Returns
+    /// `None` unless the database is in WAL mode.
+    pub fn checkpoint(&self, mode: CheckpointMode) -> anyhow::Result<Option<CheckpointResult>> {
+        match self.open_wal() {
+            Some(wal) => Ok(Some(wal.checkpoint(&self.page_source, mode)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn page_size(&self) -> usize {
+        self.db_header.borrow().page_size as usize
+    }
+
+    /// Number of bytes of each page that are available to b-tree content.
+    pub fn usable_size(&self) -> usize {
+        self.db_header.borrow().usable_size()
+    }
+
+    /// Number of bytes of a payload that are stored in its cell on a page of
+    /// the given type; see `DatabaseHeader::payload_local_size()`.
+    pub fn payload_local_size(&self, payload_size: usize, page_type: PageType) -> usize {
+        self.db_header
+            .borrow()
+            .payload_local_size(payload_size, page_type)
+    }
+
+    /// Set the schema cookie, which is written with page 1 on commit.
+    pub fn set_schema_cookie(&self, value: u32) {
+        self.begin_write();
+        self.db_header.borrow_mut().schema_cookie = value;
+    }
+
+    pub fn header(&self) -> DatabaseHeader {
+        self.db_header.borrow().clone()
+    }
+
+    /// The path of the database file, if the database is a file.
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    pub fn schema_cookie(&self) -> u32 {
+        self.db_header.borrow().schema_cookie
+    }
+
     pub fn write_database_header(&self, header: &DatabaseHeader) {
         sqlite3_ondisk::begin_write_database_header(header, self).expect("failed to write header");
     }
@@ -153,3 +876,174 @@ impl Pager {
         self.page_cache.borrow_mut().resize(capacity);
     }
 }
+
+/// Read the database header as of the snapshot of the log, from the log if
+/// it holds page 1 and from the database file otherwise.
+fn read_wal_header(
+    db_header: &RefCell<DatabaseHeader>,
+    page_source: &PageSource,
+    wal: &Rc<Wal>,
+    io: &dyn crate::io::IO,
+) -> anyhow::Result<()> {
+    let reader = PageSource::from_io(Rc::new(WalPageIO::new(page_source.clone(), wal.clone())));
+    let header = sqlite3_ondisk::begin_read_database_header(&reader)?;
+    // Reading from the log completes at once.
+    if wal.find_frame(1).is_none() {
+        io.run_once()?;
+    }
+    *db_header.borrow_mut() = header.borrow().clone();
+    if let Some(database_size) = wal.database_size() {
+        db_header.borrow_mut().database_size = database_size as u32;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{query, TempDatabase};
+    use std::rc::Rc;
+
+    #[test]
+    fn test_dirty_pages_are_pinned_until_flush() {
+        let temp = TempDatabase::with_sql(
+            "pager",
+            "PRAGMA page_size = 512;
+             CREATE TABLE t (a INTEGER PRIMARY KEY, b);
+             WITH RECURSIVE s(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM s WHERE i < 200)
+             INSERT INTO t SELECT i, zeroblob(200) FROM s;",
+        );
+        let (io, db) = temp.open();
+        let pager = db.pager.clone();
+        let database_size = pager.db_header.borrow().database_size as usize;
+        assert!(database_size > 40);
+        let read_page = |page_idx| {
+            let page = pager.read_page(page_idx).unwrap();
+            while page.is_locked() {
+                io.run_once().unwrap();
+            }
+            page
+        };
+
+        // Marking more pages dirty than the cache holds, and reading the rest
+        // of the database, evicts none of them.
+        let dirty: Vec<_> = (2..22)
+            .map(|page_idx| (page_idx, read_page(page_idx)))
+            .collect();
+        for (page_idx, page) in &dirty {
+            pager.add_dirty(*page_idx, page);
+        }
+        for page_idx in 22..=database_size {
+            assert!(!read_page(page_idx).is_dirty());
+        }
+        for (page_idx, page) in &dirty {
+            assert!(Rc::ptr_eq(&read_page(*page_idx), page));
+            assert!(page.is_dirty());
+        }
+
+        // Flushing writes them back, and they are clean once written.
+        pager.flush().unwrap();
+        for (_, page) in &dirty {
+            while page.is_locked() {
+                io.run_once().unwrap();
+            }
+            assert!(!page.is_dirty());
+        }
+        pager.commit().unwrap();
+        assert!(pager.dirty_pages.borrow().is_empty());
+        temp.check_integrity();
+    }
+
+    #[test]
+    fn test_allocate_page_reuses_freelist() {
+        let temp = TempDatabase::with_sql(
+            "freelist",
+            "PRAGMA page_size = 512;
+             CREATE TABLE t (a INTEGER PRIMARY KEY, b);
+             CREATE TABLE u (a INTEGER PRIMARY KEY, b);
+             WITH RECURSIVE s(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM s WHERE i < 200)
+             INSERT INTO t SELECT i, zeroblob(200) FROM s;
+             INSERT INTO u SELECT * FROM t;
+             DELETE FROM t;",
+        );
+        let (io, db) = temp.open();
+        let conn = db.connect();
+        // The database size in the header is kept in step with the file, and
+        // SQLite finds every page either in use or on the freelist.
+        let check = || {
+            let header = db.pager.db_header.borrow().clone();
+            let file_size = std::fs::metadata(&temp.path).unwrap().len() as usize;
+            assert_eq!(header.database_size as usize * 512, file_size);
+            temp.check_integrity();
+            let freelist_count: u32 = temp
+                .sqlite()
+                .query_row("PRAGMA freelist_count", (), |row| row.get(0))
+                .unwrap();
+            assert_eq!(freelist_count, header.freelist_pages);
+            header
+        };
+        let before = check();
+        assert!(before.freelist_pages > 100);
+
+        // The pages for the rows come off the freelist.
+        query(&io, &conn, "INSERT INTO t SELECT * FROM u WHERE a <= 50").unwrap();
+        let after = check();
+        assert_eq!(after.database_size, before.database_size);
+        assert_eq!(after.freelist_trunk_page, before.freelist_trunk_page);
+        assert!(after.freelist_pages < before.freelist_pages - 20);
+        assert_eq!(after.change_counter, before.change_counter + 1);
+
+        // The trunk page is the last one to be used, after which the file
+        // grows.
+        query(&io, &conn, "INSERT INTO t SELECT * FROM u WHERE a > 50").unwrap();
+        let after = check();
+        assert_eq!(after.freelist_pages, 0);
+        assert_eq!(after.freelist_trunk_page, 0);
+        query(&io, &conn, "INSERT INTO t SELECT a + 1000, b FROM u").unwrap();
+        let after = check();
+        assert!(after.database_size > before.database_size + 50);
+    }
+
+    #[test]
+    fn test_write_transaction_locks_other_connections() {
+        let temp = TempDatabase::with_sql(
+            "writer",
+            "CREATE TABLE t (a INTEGER PRIMARY KEY, b); INSERT INTO t VALUES (1, 'x');",
+        );
+        let (io, db) = temp.open();
+        let c1 = db.connect();
+        let c2 = db.connect();
+
+        // The other connection can neither commit nor see the changes of a
+        // write transaction, and rolling it back undoes all of them.
+        query(&io, &c1, "BEGIN").unwrap();
+        query(&io, &c1, "INSERT INTO t VALUES (2, 'c1')").unwrap();
+        for sql in ["INSERT INTO t VALUES (3, 'c2')", "SELECT a FROM t"] {
+            let err = query(&io, &c2, sql).unwrap_err();
+            assert_eq!(err.to_string(), "database is locked");
+        }
+        assert_eq!(query(&io, &c1, "SELECT a FROM t").unwrap(), [["1"], ["2"]]);
+        query(&io, &c1, "ROLLBACK").unwrap();
+        query(&io, &c2, "INSERT INTO t VALUES (3, 'c2')").unwrap();
+        assert_eq!(query(&io, &c1, "SELECT a FROM t").unwrap(), [["1"], ["3"]]);
+
+        // Closing a connection rolls back its transaction.
+        query(&io, &c1, "BEGIN").unwrap();
+        query(&io, &c1, "INSERT INTO t VALUES (4, 'c1')").unwrap();
+        drop(c1);
+        query(&io, &c2, "INSERT INTO t VALUES (5, 'c2')").unwrap();
+        let expected = [["1"], ["3"], ["5"]];
+        assert_eq!(query(&io, &c2, "SELECT a FROM t").unwrap(), expected);
+        drop(c2);
+        drop(db);
+
+        let rows: Vec<i64> = temp
+            .sqlite()
+            .prepare("SELECT a FROM t")
+            .unwrap()
+            .query_map((), |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(rows, [1, 3, 5]);
+    }
+}
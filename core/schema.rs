@@ -1,9 +1,12 @@
+use crate::btree::BTreeCursor;
+use crate::pager::Pager;
+use crate::types::{Cursor, CursorResult, OwnedValue};
 use crate::util::normalize_ident;
 use anyhow::Result;
 use core::fmt;
 use fallible_iterator::FallibleIterator;
-use log::trace;
-use sqlite3_parser::ast::{Expr, Literal, TableOptions};
+use log::{trace, warn};
+use sqlite3_parser::ast::{ColumnConstraint, Expr, Literal, SortOrder, TableOptions};
 use sqlite3_parser::{
     ast::{Cmd, CreateTableBody, QualifiedName, ResultColumn, Stmt},
     lexer::sql::Parser,
@@ -13,13 +16,18 @@ use std::rc::Rc;
 
 pub struct Schema {
     pub tables: HashMap<String, Rc<BTreeTable>>,
+    // Indexes by the name of the table they belong to.
+    pub indexes: HashMap<String, Vec<Rc<Index>>>,
 }
 
 impl Schema {
     pub fn new() -> Self {
         let mut tables: HashMap<String, Rc<BTreeTable>> = HashMap::new();
         tables.insert("sqlite_schema".to_string(), Rc::new(sqlite_schema_table()));
-        Self { tables }
+        Self {
+            tables,
+            indexes: HashMap::new(),
+        }
     }
 
     pub fn add_table(&mut self, table: Rc<BTreeTable>) {
@@ -31,6 +39,89 @@ impl Schema {
         let name = normalize_ident(name);
         self.tables.get(&name).cloned()
     }
+
+    pub fn add_index(&mut self, index: Rc<Index>) {
+        let table_name = normalize_ident(&index.table_name);
+        self.indexes.entry(table_name).or_default().push(index);
+    }
+
+    pub fn get_indexes(&self, table_name: &str) -> &[Rc<Index>] {
+        let table_name = normalize_ident(table_name);
+        self.indexes.get(&table_name).map_or(&[], |indexes| indexes)
+    }
+
+    pub fn get_index(&self, name: &str) -> Option<Rc<Index>> {
+        let name = normalize_ident(name);
+        self.indexes
+            .values()
+            .flatten()
+            .find(|index| index.name == name)
+            .cloned()
+    }
+
+    pub fn remove_index(&mut self, name: &str) {
+        let name = normalize_ident(name);
+        for indexes in self.indexes.values_mut() {
+            indexes.retain(|index| index.name != name);
+        }
+    }
+
+    /// Remove a table and its indexes.
+    pub fn remove_table(&mut self, name: &str) {
+        let name = normalize_ident(name);
+        self.tables.remove(&name);
+        self.indexes.remove(&name);
+    }
+
+    /// Add the table or index described by a row of `sqlite_schema`. Other
+    /// kinds of objects, indexes without SQL, which belong to PRIMARY KEY and
+    /// UNIQUE constraints, and indexes that cannot be used are skipped.
+    pub fn load_entry(&mut self, ty: &str, root_page: usize, sql: Option<&str>) -> Result<()> {
+        match (ty, sql) {
+            ("table", Some(sql)) => {
+                let table = BTreeTable::from_sql(sql, root_page)?;
+                self.add_table(Rc::new(table));
+            }
+            ("index", Some(sql)) => match Index::from_sql(sql, root_page) {
+                Ok(index) => self.add_index(Rc::new(index)),
+                Err(err) => warn!("Ignoring index: {}: {}", sql, err),
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Read the schema from the `sqlite_schema` table.
+    pub fn read(pager: &Rc<Pager>) -> Result<Schema> {
+        let mut schema = Schema::new();
+        let mut cursor = BTreeCursor::new(pager.clone(), 1);
+        let run_io = |f: &mut dyn FnMut() -> Result<CursorResult<()>>| -> Result<()> {
+            while let CursorResult::IO = f()? {
+                pager.io.run_once()?;
+            }
+            Ok(())
+        };
+        run_io(&mut || cursor.rewind())?;
+        loop {
+            if let Some(record) = &*cursor.record()? {
+                let (ty, root_page, sql) = match &record.values[..] {
+                    [OwnedValue::Text(ty), _, _, OwnedValue::Integer(root_page), sql] => {
+                        let sql = match sql {
+                            OwnedValue::Text(sql) => Some(sql.as_str()),
+                            _ => None,
+                        };
+                        (ty.as_str(), *root_page as usize, sql)
+                    }
+                    _ => anyhow::bail!("database disk image is malformed: bad schema row"),
+                };
+                schema.load_entry(ty, root_page, sql)?;
+            } else {
+                break;
+            }
+            run_io(&mut || cursor.next())?;
+        }
+        Ok(schema)
+    }
 }
 
 #[derive(Clone)]
@@ -130,6 +221,74 @@ impl BTreeTable {
     }
 }
 
+pub struct Index {
+    pub name: String,
+    pub table_name: String,
+    pub root_page: usize,
+    pub columns: Vec<IndexColumn>,
+    pub unique: bool,
+    /// The WHERE clause of a partial index, which only has entries for the
+    /// rows that satisfy it.
+    pub where_clause: Option<Expr>,
+}
+
+pub struct IndexColumn {
+    pub name: String,
+    pub descending: bool,
+    /// The collating sequence given in the index definition, if any.
+    pub collation: Option<String>,
+}
+
+impl Index {
+    pub fn from_sql(sql: &str, root_page: usize) -> Result<Index> {
+        let mut parser = Parser::new(sql.as_bytes());
+        let cmd = parser.next()?;
+        match cmd {
+            Some(Cmd::Stmt(Stmt::CreateIndex {
+                unique,
+                idx_name,
+                tbl_name,
+                columns,
+                where_clause,
+                ..
+            })) => {
+                let columns = columns
+                    .into_iter()
+                    .map(|column| {
+                        let (expr, collation) = match column.expr {
+                            Expr::Collate(expr, collation) => {
+                                (*expr, Some(normalize_ident(&collation)))
+                            }
+                            expr => (expr, None),
+                        };
+                        let name = match expr {
+                            Expr::Id(id) => normalize_ident(&id.0),
+                            Expr::Literal(Literal::String(value)) => {
+                                value.trim_matches('\'').to_owned()
+                            }
+                            _ => anyhow::bail!("Unsupported index expression"),
+                        };
+                        Ok(IndexColumn {
+                            name,
+                            descending: matches!(column.order, Some(SortOrder::Desc)),
+                            collation,
+                        })
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(Index {
+                    name: normalize_ident(&idx_name.name.0),
+                    table_name: normalize_ident(&tbl_name.0),
+                    root_page,
+                    columns,
+                    unique,
+                    where_clause,
+                })
+            }
+            _ => anyhow::bail!("Expected CREATE INDEX statement"),
+        }
+    }
+}
+
 pub struct PseudoTable {
     pub columns: Vec<Column>,
 }
@@ -144,6 +303,8 @@ impl PseudoTable {
             name: name.to_string(),
             ty,
             primary_key,
+            not_null: false,
+            default: None,
         });
     }
     pub fn get_column(&self, name: &str) -> Option<(usize, &Column)> {
@@ -198,7 +359,7 @@ fn create_table(
                 let name = column.col_name.0.to_string();
                 let ty = match column.col_type {
                     Some(data_type) => {
-                        let type_name = data_type.name.as_str();
+                        let type_name = data_type.name.to_uppercase();
                         if type_name.contains("INTEGER") {
                             Type::Integer
                         } else if type_name.contains("CHAR")
@@ -219,12 +380,17 @@ fn create_table(
                     }
                     None => Type::Null,
                 };
-                let mut primary_key = column.constraints.iter().any(|c| {
-                    matches!(
-                        c.constraint,
-                        sqlite3_parser::ast::ColumnConstraint::PrimaryKey { .. }
-                    )
-                });
+                let mut primary_key = false;
+                let mut not_null = false;
+                let mut default = None;
+                for c in column.constraints {
+                    match c.constraint {
+                        ColumnConstraint::PrimaryKey { .. } => primary_key = true,
+                        ColumnConstraint::NotNull { nullable, .. } => not_null = !nullable,
+                        ColumnConstraint::Default(expr) => default = Some(expr),
+                        _ => {}
+                    }
+                }
                 if primary_key {
                     primary_key_column_names.push(name.clone());
                 } else if primary_key_column_names.contains(&name) {
@@ -234,6 +400,8 @@ fn create_table(
                     name,
                     ty,
                     primary_key,
+                    not_null,
+                    default,
                 });
             }
             if options.contains(TableOptions::WITHOUT_ROWID) {
@@ -273,6 +441,9 @@ pub struct Column {
     pub name: String,
     pub ty: Type,
     pub primary_key: bool,
+    pub not_null: bool,
+    /// The value of the column in rows that are inserted without one.
+    pub default: Option<Expr>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -285,6 +456,19 @@ pub enum Type {
     Blob,
 }
 
+impl Type {
+    /// The column affinity, as the letter SQLite uses in the Affinity opcode.
+    pub fn affinity(&self) -> char {
+        match self {
+            Type::Null | Type::Blob => 'A',
+            Type::Text => 'B',
+            Type::Numeric => 'C',
+            Type::Integer => 'D',
+            Type::Real => 'E',
+        }
+    }
+}
+
 impl fmt::Display for Type {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let s = match self {
@@ -310,26 +494,36 @@ pub fn sqlite_schema_table() -> BTreeTable {
                 name: "type".to_string(),
                 ty: Type::Text,
                 primary_key: false,
+                not_null: false,
+                default: None,
             },
             Column {
                 name: "name".to_string(),
                 ty: Type::Text,
                 primary_key: false,
+                not_null: false,
+                default: None,
             },
             Column {
                 name: "tbl_name".to_string(),
                 ty: Type::Text,
                 primary_key: false,
+                not_null: false,
+                default: None,
             },
             Column {
                 name: "rootpage".to_string(),
                 ty: Type::Integer,
                 primary_key: false,
+                not_null: false,
+                default: None,
             },
             Column {
                 name: "sql".to_string(),
                 ty: Type::Text,
                 primary_key: false,
+                not_null: false,
+                default: None,
             },
         ],
     }
@@ -548,6 +742,78 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    pub fn test_index_from_sql() -> Result<()> {
+        let sql = r#"CREATE UNIQUE INDEX t1_idx ON t1 (a, "B" DESC, c COLLATE NOCASE ASC);"#;
+        let index = Index::from_sql(sql, 3)?;
+        assert_eq!(index.name, "t1_idx");
+        assert_eq!(index.table_name, "t1");
+        assert_eq!(index.root_page, 3);
+        assert!(index.unique, "index should be unique");
+        assert!(index.where_clause.is_none(), "index shouldn't be partial");
+        let columns: Vec<_> = index
+            .columns
+            .iter()
+            .map(|c| (c.name.as_str(), c.descending, c.collation.as_deref()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("a", false, None),
+                ("b", true, None),
+                ("c", false, Some("nocase"))
+            ],
+            columns
+        );
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_index_from_sql_partial() -> Result<()> {
+        let sql = r#"CREATE INDEX t1_idx ON t1 (a) WHERE b IS NOT NULL;"#;
+        let index = Index::from_sql(sql, 3)?;
+        assert!(!index.unique, "index shouldn't be unique");
+        assert!(index.where_clause.is_some(), "index should be partial");
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_index_from_sql_expression() {
+        let sql = r#"CREATE INDEX t1_idx ON t1 (a + b);"#;
+        assert!(Index::from_sql(sql, 3).is_err());
+    }
+
+    #[test]
+    pub fn test_load_entry_and_remove_table() -> Result<()> {
+        let mut schema = Schema::new();
+        schema.load_entry("table", 2, Some("CREATE TABLE \"T1\" (a, b)"))?;
+        schema.load_entry("index", 3, Some("CREATE INDEX t1_idx ON t1 (a)"))?;
+        schema.load_entry("index", 4, None)?;
+        schema.load_entry("view", 0, Some("CREATE VIEW v AS SELECT 1"))?;
+        assert_eq!(schema.get_table("t1").map(|t| t.root_page), Some(2));
+        assert_eq!(schema.get_indexes("T1").len(), 1);
+        assert!(schema.get_table("v").is_none(), "views aren't tables");
+        schema.remove_table("T1");
+        assert!(schema.get_table("t1").is_none(), "table should be removed");
+        assert!(
+            schema.get_indexes("t1").is_empty(),
+            "indexes should be removed"
+        );
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_get_and_remove_index() -> Result<()> {
+        let mut schema = Schema::new();
+        schema.load_entry("table", 2, Some("CREATE TABLE t1 (a, b)"))?;
+        schema.load_entry("index", 3, Some("CREATE INDEX \"I1\" ON t1 (a)"))?;
+        schema.load_entry("index", 4, Some("CREATE INDEX i2 ON t1 (b)"))?;
+        assert_eq!(schema.get_index("i1").map(|i| i.root_page), Some(3));
+        schema.remove_index("I1");
+        assert!(schema.get_index("i1").is_none(), "index should be removed");
+        assert_eq!(schema.get_indexes("t1").len(), 1);
+        Ok(())
+    }
+
     #[test]
     pub fn test_sqlite_schema() {
         let expected = r#"CREATE TABLE sqlite_schema (
@@ -1,78 +1,233 @@
-use crate::types::{Cursor, CursorResult, OwnedRecord, OwnedValue};
+use crate::types::{Cursor, CursorResult, OwnedRecord, OwnedValue, SeekOp};
 use anyhow::Result;
 use log::trace;
-use ordered_multimap::ListOrderedMultimap;
 use std::cell::{Ref, RefCell};
+use std::cmp::Ordering;
 
+/// Ordering of a single sort key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SortOrder {
+    pub descending: bool,
+    pub nulls_first: bool,
+}
+
+/// A sorter cursor. Records are inserted in any order; `rewind()` sorts them
+/// by their leading key columns and positions the cursor on the first one.
 pub struct Sorter {
-    records: ListOrderedMultimap<String, OwnedRecord>,
+    records: Vec<OwnedRecord>,
+    order: Vec<SortOrder>,
     current: RefCell<Option<OwnedRecord>>,
 }
 
 impl Sorter {
-    pub fn new() -> Self {
+    pub fn new(order: Vec<SortOrder>) -> Self {
         Self {
-            records: ListOrderedMultimap::new(),
+            records: Vec::new(),
+            order,
             current: RefCell::new(None),
         }
     }
 
-    pub fn insert(&mut self, key: String, record: OwnedRecord) {
-        self.records.insert(key, record);
+    fn sort(&mut self) {
+        let order = &self.order;
+        self.records.sort_by(|a, b| compare_keys(a, b, order));
+        // Rows are handed out by popping from the back.
+        self.records.reverse();
     }
 }
 
+/// Compare two records by their leading key columns. NULLs are placed
+/// according to `nulls_first` regardless of the sort direction; all other
+/// values use SQLite's cross-type ordering.
+fn compare_keys(a: &OwnedRecord, b: &OwnedRecord, order: &[SortOrder]) -> Ordering {
+    for (i, key) in order.iter().enumerate() {
+        let ordering = match (&a.values[i], &b.values[i]) {
+            (OwnedValue::Null, OwnedValue::Null) => Ordering::Equal,
+            (OwnedValue::Null, _) if key.nulls_first => Ordering::Less,
+            (OwnedValue::Null, _) => Ordering::Greater,
+            (_, OwnedValue::Null) if key.nulls_first => Ordering::Greater,
+            (_, OwnedValue::Null) => Ordering::Less,
+            (lhs, rhs) => {
+                let ordering = lhs.partial_cmp(rhs).unwrap_or(Ordering::Equal);
+                if key.descending {
+                    ordering.reverse()
+                } else {
+                    ordering
+                }
+            }
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
 impl Cursor for Sorter {
     fn is_empty(&self) -> bool {
         self.current.borrow().is_none()
     }
 
     fn rewind(&mut self) -> Result<CursorResult<()>> {
-        let current = self.records.pop_front();
-        match current {
-            Some((_, record)) => {
-                *self.current.borrow_mut() = Some(record);
-            }
-            None => {
-                *self.current.borrow_mut() = None;
-            }
-        };
+        self.sort();
+        *self.current.borrow_mut() = self.records.pop();
         Ok(CursorResult::Ok(()))
     }
 
     fn next(&mut self) -> Result<CursorResult<()>> {
-        let current = self.records.pop_front();
-        match current {
-            Some((_, record)) => {
-                *self.current.borrow_mut() = Some(record);
-            }
-            None => {
-                *self.current.borrow_mut() = None;
-            }
-        };
+        *self.current.borrow_mut() = self.records.pop();
         Ok(CursorResult::Ok(()))
     }
 
+    fn last(&mut self) -> Result<CursorResult<()>> {
+        unimplemented!()
+    }
+
+    fn prev(&mut self) -> Result<CursorResult<()>> {
+        unimplemented!()
+    }
+
     fn wait_for_completion(&mut self) -> Result<()> {
         Ok(())
     }
 
-    fn rowid(&self) -> Result<Ref<Option<u64>>> {
+    fn rowid(&self) -> Result<Ref<'_, Option<u64>>> {
         todo!();
     }
 
-    fn record(&self) -> Result<Ref<Option<OwnedRecord>>> {
+    fn record(&self) -> Result<Ref<'_, Option<OwnedRecord>>> {
         Ok(self.current.borrow())
     }
 
-    fn insert(&mut self, record: &OwnedRecord) -> Result<()> {
-        let key = match record.values[0] {
-            OwnedValue::Integer(i) => i.to_string(),
-            OwnedValue::Text(ref s) => s.to_string(),
-            _ => todo!(),
-        };
-        trace!("Inserting record with key: {}", key);
-        self.insert(key, record.clone());
+    fn insert(&mut self, _key: &OwnedValue, record: &OwnedRecord) -> Result<CursorResult<()>> {
+        trace!("Inserting record into sorter: {:?}", record);
+        self.records.push(record.clone());
+        Ok(CursorResult::Ok(()))
+    }
+
+    fn delete(&mut self) -> Result<CursorResult<()>> {
+        unimplemented!()
+    }
+
+    fn index_insert(&mut self, _key: &OwnedRecord) -> Result<CursorResult<()>> {
+        unimplemented!()
+    }
+
+    fn index_delete(&mut self, _key: &OwnedRecord) -> Result<CursorResult<bool>> {
+        unimplemented!()
+    }
+
+    fn exists(&mut self, _key: &OwnedValue) -> Result<CursorResult<bool>> {
+        unimplemented!()
+    }
+
+    fn new_rowid(&mut self) -> Result<CursorResult<i64>> {
+        unimplemented!()
+    }
+
+    fn seek(&mut self, _key: &OwnedRecord, _op: SeekOp) -> Result<CursorResult<bool>> {
+        unimplemented!()
+    }
+
+    fn seek_rowid(&mut self, _rowid: i64, _op: SeekOp) -> Result<CursorResult<bool>> {
+        unimplemented!()
+    }
+
+    fn set_null_flag(&mut self, _flag: bool) {
+        todo!();
+    }
+
+    fn get_null_flag(&self) -> bool {
+        false
+    }
+}
+
+/// A cursor over a pseudo-table that holds a single row. Inserting a record
+/// replaces the current row.
+pub struct PseudoCursor {
+    current: RefCell<Option<OwnedRecord>>,
+}
+
+impl PseudoCursor {
+    pub fn new() -> Self {
+        Self {
+            current: RefCell::new(None),
+        }
+    }
+}
+
+impl Cursor for PseudoCursor {
+    fn is_empty(&self) -> bool {
+        self.current.borrow().is_none()
+    }
+
+    fn rewind(&mut self) -> Result<CursorResult<()>> {
+        Ok(CursorResult::Ok(()))
+    }
+
+    fn next(&mut self) -> Result<CursorResult<()>> {
+        *self.current.borrow_mut() = None;
+        Ok(CursorResult::Ok(()))
+    }
+
+    fn last(&mut self) -> Result<CursorResult<()>> {
+        unimplemented!()
+    }
+
+    fn prev(&mut self) -> Result<CursorResult<()>> {
+        unimplemented!()
+    }
+
+    fn wait_for_completion(&mut self) -> Result<()> {
         Ok(())
     }
+
+    fn rowid(&self) -> Result<Ref<'_, Option<u64>>> {
+        todo!();
+    }
+
+    fn record(&self) -> Result<Ref<'_, Option<OwnedRecord>>> {
+        Ok(self.current.borrow())
+    }
+
+    fn insert(&mut self, _key: &OwnedValue, record: &OwnedRecord) -> Result<CursorResult<()>> {
+        *self.current.borrow_mut() = Some(record.clone());
+        Ok(CursorResult::Ok(()))
+    }
+
+    fn delete(&mut self) -> Result<CursorResult<()>> {
+        unimplemented!()
+    }
+
+    fn index_insert(&mut self, _key: &OwnedRecord) -> Result<CursorResult<()>> {
+        unimplemented!()
+    }
+
+    fn index_delete(&mut self, _key: &OwnedRecord) -> Result<CursorResult<bool>> {
+        unimplemented!()
+    }
+
+    fn exists(&mut self, _key: &OwnedValue) -> Result<CursorResult<bool>> {
+        unimplemented!()
+    }
+
+    fn new_rowid(&mut self) -> Result<CursorResult<i64>> {
+        unimplemented!()
+    }
+
+    fn seek(&mut self, _key: &OwnedRecord, _op: SeekOp) -> Result<CursorResult<bool>> {
+        unimplemented!()
+    }
+
+    fn seek_rowid(&mut self, _rowid: i64, _op: SeekOp) -> Result<CursorResult<bool>> {
+        unimplemented!()
+    }
+
+    fn set_null_flag(&mut self, _flag: bool) {
+        todo!();
+    }
+
+    fn get_null_flag(&self) -> bool {
+        false
+    }
 }
@@ -31,10 +31,14 @@ use crate::PageSource;
 use anyhow::{anyhow, Result};
 use log::trace;
 use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
 use std::rc::Rc;
 
 /// The size of the database header in bytes.
 pub const DATABASE_HEADER_SIZE: usize = 100;
+/// The offset of the bytes that file locks are taken on. The page that holds
+/// them is never used.
+pub const PENDING_BYTE: usize = 0x4000_0000;
 // DEFAULT_CACHE_SIZE negative values mean that we store the amount of pages a XKiB of memory can hold.
 // We can calculate "real" cache size by diving by page size.
 const DEFAULT_CACHE_SIZE: i32 = -2000;
@@ -47,15 +51,15 @@ pub struct DatabaseHeader {
     pub page_size: u16,
     write_version: u8,
     read_version: u8,
-    unused_space: u8,
+    pub unused_space: u8,
     max_embed_frac: u8,
     min_embed_frac: u8,
     min_leaf_frac: u8,
-    change_counter: u32,
-    database_size: u32,
-    freelist_trunk_page: u32,
-    freelist_pages: u32,
-    schema_cookie: u32,
+    pub change_counter: u32,
+    pub database_size: u32,
+    pub freelist_trunk_page: u32,
+    pub freelist_pages: u32,
+    pub schema_cookie: u32,
     schema_format: u32,
     pub default_cache_size: i32,
     vacuum: u32,
@@ -64,10 +68,59 @@ pub struct DatabaseHeader {
     incremental_vacuum: u32,
     application_id: u32,
     reserved: [u8; 20],
-    version_valid_for: u32,
+    pub version_valid_for: u32,
     version_number: u32,
 }
 
+impl DatabaseHeader {
+    /// Whether the database is in WAL mode.
+    pub fn is_wal(&self) -> bool {
+        self.write_version == 2
+    }
+
+    /// Switch the file format versions between WAL mode and legacy rollback
+    /// journal mode.
+    pub fn set_wal(&mut self, wal: bool) {
+        let version = if wal { 2 } else { 1 };
+        self.write_version = version;
+        self.read_version = version;
+    }
+
+    /// Number of bytes of each page that are available to b-tree content.
+    pub fn usable_size(&self) -> usize {
+        self.page_size as usize - self.unused_space as usize
+    }
+
+    /// Number of bytes of a payload of `payload_size` bytes that are stored
+    /// in the cell itself on a page of the given type. The rest is stored in
+    /// a chain of overflow pages.
+    pub fn payload_local_size(&self, payload_size: usize, page_type: PageType) -> usize {
+        let usable_size = self.usable_size();
+        let (max_local, min_local) = match page_type {
+            PageType::TableLeaf => (
+                usable_size - 35,
+                (usable_size - 12) * self.min_leaf_frac as usize / 255 - 23,
+            ),
+            PageType::IndexInterior | PageType::IndexLeaf => (
+                (usable_size - 12) * self.max_embed_frac as usize / 255 - 23,
+                (usable_size - 12) * self.min_embed_frac as usize / 255 - 23,
+            ),
+            PageType::TableInterior => unreachable!("table interior cells have no payload"),
+        };
+        if payload_size <= max_local {
+            return payload_size;
+        }
+        // Fill the last overflow page as much as possible, unless that leaves
+        // too much on the b-tree page.
+        let local = min_local + (payload_size - min_local) % (usable_size - 4);
+        if local <= max_local {
+            local
+        } else {
+            min_local
+        }
+    }
+}
+
 pub fn begin_read_database_header(page_source: &PageSource) -> Result<Rc<RefCell<DatabaseHeader>>> {
     let drop_fn = Rc::new(|_buf| {});
     let buf = Buffer::allocate(512, drop_fn);
@@ -130,31 +183,7 @@ pub fn begin_write_database_header(header: &DatabaseHeader, pager: &Pager) -> Re
         {
             let mut buf_mut = std::cell::RefCell::borrow_mut(&buffer);
             let buf = buf_mut.as_mut_slice();
-            buf[0..16].copy_from_slice(&header.magic);
-            buf[16..18].copy_from_slice(&header.page_size.to_be_bytes());
-            buf[18] = header.write_version;
-            buf[19] = header.read_version;
-            buf[20] = header.unused_space;
-            buf[21] = header.max_embed_frac;
-            buf[22] = header.min_embed_frac;
-            buf[23] = header.min_leaf_frac;
-            buf[24..28].copy_from_slice(&header.change_counter.to_be_bytes());
-            buf[28..32].copy_from_slice(&header.database_size.to_be_bytes());
-            buf[32..36].copy_from_slice(&header.freelist_trunk_page.to_be_bytes());
-            buf[36..40].copy_from_slice(&header.freelist_pages.to_be_bytes());
-            buf[40..44].copy_from_slice(&header.schema_cookie.to_be_bytes());
-            buf[44..48].copy_from_slice(&header.schema_format.to_be_bytes());
-            buf[48..52].copy_from_slice(&header.default_cache_size.to_be_bytes());
-
-            buf[52..56].copy_from_slice(&header.vacuum.to_be_bytes());
-            buf[56..60].copy_from_slice(&header.text_encoding.to_be_bytes());
-            buf[60..64].copy_from_slice(&header.user_version.to_be_bytes());
-            buf[64..68].copy_from_slice(&header.incremental_vacuum.to_be_bytes());
-
-            buf[68..72].copy_from_slice(&header.application_id.to_be_bytes());
-            buf[72..92].copy_from_slice(&header.reserved);
-            buf[92..96].copy_from_slice(&header.version_valid_for.to_be_bytes());
-            buf[96..100].copy_from_slice(&header.version_number.to_be_bytes());
+            write_header_to_buf(buf, &header);
             let mut buffer_to_copy = std::cell::RefCell::borrow_mut(&buffer_to_copy_in_cb);
             let buffer_to_copy_slice = buffer_to_copy.as_mut_slice();
 
@@ -179,14 +208,42 @@ pub fn begin_write_database_header(header: &DatabaseHeader, pager: &Pager) -> Re
         // finish_read_database_header(buf, header).unwrap();
     });
     let c = Rc::new(WriteCompletion::new(write_complete));
-    page_source.write(0, buffer_to_copy.clone(), c).unwrap();
+    page_source.write(1, buffer_to_copy.clone(), c).unwrap();
 
     Ok(())
 }
 
+pub fn write_header_to_buf(buf: &mut [u8], header: &DatabaseHeader) {
+    buf[0..16].copy_from_slice(&header.magic);
+    buf[16..18].copy_from_slice(&header.page_size.to_be_bytes());
+    buf[18] = header.write_version;
+    buf[19] = header.read_version;
+    buf[20] = header.unused_space;
+    buf[21] = header.max_embed_frac;
+    buf[22] = header.min_embed_frac;
+    buf[23] = header.min_leaf_frac;
+    buf[24..28].copy_from_slice(&header.change_counter.to_be_bytes());
+    buf[28..32].copy_from_slice(&header.database_size.to_be_bytes());
+    buf[32..36].copy_from_slice(&header.freelist_trunk_page.to_be_bytes());
+    buf[36..40].copy_from_slice(&header.freelist_pages.to_be_bytes());
+    buf[40..44].copy_from_slice(&header.schema_cookie.to_be_bytes());
+    buf[44..48].copy_from_slice(&header.schema_format.to_be_bytes());
+    buf[48..52].copy_from_slice(&header.default_cache_size.to_be_bytes());
+
+    buf[52..56].copy_from_slice(&header.vacuum.to_be_bytes());
+    buf[56..60].copy_from_slice(&header.text_encoding.to_be_bytes());
+    buf[60..64].copy_from_slice(&header.user_version.to_be_bytes());
+    buf[64..68].copy_from_slice(&header.incremental_vacuum.to_be_bytes());
+
+    buf[68..72].copy_from_slice(&header.application_id.to_be_bytes());
+    buf[72..92].copy_from_slice(&header.reserved);
+    buf[92..96].copy_from_slice(&header.version_valid_for.to_be_bytes());
+    buf[96..100].copy_from_slice(&header.version_number.to_be_bytes());
+}
+
 #[derive(Debug)]
 pub struct BTreePageHeader {
-    page_type: PageType,
+    pub(crate) page_type: PageType,
     _first_freeblock_offset: u16,
     num_cells: u16,
     _cell_content_area: u16,
@@ -194,8 +251,31 @@ pub struct BTreePageHeader {
     pub(crate) right_most_pointer: Option<u32>,
 }
 
+impl BTreePageHeader {
+    /// Header of a page that has no cells yet.
+    pub fn new(page_type: PageType) -> Self {
+        Self {
+            page_type,
+            _first_freeblock_offset: 0,
+            num_cells: 0,
+            _cell_content_area: 0,
+            _num_frag_free_bytes: 0,
+            right_most_pointer: None,
+        }
+    }
+
+    /// Size of the page header in bytes: interior pages also store the
+    /// right-most pointer.
+    pub fn size(&self) -> usize {
+        match self.page_type {
+            PageType::IndexInterior | PageType::TableInterior => 12,
+            PageType::IndexLeaf | PageType::TableLeaf => 8,
+        }
+    }
+}
+
 #[repr(u8)]
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PageType {
     IndexInterior = 2,
     TableInterior = 5,
@@ -221,15 +301,83 @@ impl TryFrom<u8> for PageType {
 pub struct BTreePage {
     pub header: BTreePageHeader,
     pub cells: Vec<BTreeCell>,
+    /// The page as it was last read or written, and its page number, so that
+    /// writing the page again keeps its layout.
+    image: Option<(usize, Vec<u8>)>,
 }
 
-pub fn begin_read_btree_page(
+impl BTreePage {
+    /// A page that is laid out from scratch when it is written.
+    pub fn new(header: BTreePageHeader, cells: Vec<BTreeCell>) -> Self {
+        Self {
+            header,
+            cells,
+            image: None,
+        }
+    }
+}
+
+/// The contents of an overflow page: the next page of the chain, if any,
+/// followed by part of a payload that did not fit in its cell.
+#[derive(Debug)]
+pub struct OverflowPage {
+    pub next_page: Option<u32>,
+    pub data: Vec<u8>,
+}
+
+/// The contents of a freelist trunk page: the next trunk page, if any,
+/// followed by the numbers of the free pages that this trunk lists.
+#[derive(Debug)]
+pub struct FreelistTrunkPage {
+    pub next_trunk_page: Option<u32>,
+    pub leaf_pages: Vec<u32>,
+}
+
+/// The parsed contents of a database page.
+#[derive(Debug)]
+pub enum PageContent {
+    BTree(BTreePage),
+    Overflow(OverflowPage),
+    FreelistTrunk(FreelistTrunkPage),
+}
+
+impl PageContent {
+    pub fn as_btree(&self) -> &BTreePage {
+        match self {
+            PageContent::BTree(page) => page,
+            _ => unreachable!("expected a b-tree page"),
+        }
+    }
+
+    pub fn as_btree_mut(&mut self) -> &mut BTreePage {
+        match self {
+            PageContent::BTree(page) => page,
+            _ => unreachable!("expected a b-tree page"),
+        }
+    }
+
+    pub fn as_overflow(&self) -> &OverflowPage {
+        match self {
+            PageContent::Overflow(page) => page,
+            _ => unreachable!("expected an overflow page"),
+        }
+    }
+
+    pub fn as_freelist_trunk_mut(&mut self) -> &mut FreelistTrunkPage {
+        match self {
+            PageContent::FreelistTrunk(page) => page,
+            _ => unreachable!("expected a freelist trunk page"),
+        }
+    }
+}
+
+fn begin_read_page(
     page_source: &PageSource,
     buffer_pool: Rc<BufferPool>,
     page: Rc<Page>,
     page_idx: usize,
+    finish: impl Fn(&Buffer, Rc<Page>) -> Result<()> + 'static,
 ) -> Result<()> {
-    trace!("begin_read_btree_page(page_idx = {})", page_idx);
     let buf = buffer_pool.get();
     let drop_fn = Rc::new(move |buf| {
         let buffer_pool = buffer_pool.clone();
@@ -238,7 +386,7 @@ pub fn begin_read_btree_page(
     let buf = Buffer::new(buf, drop_fn);
     let complete = Box::new(move |buf: &Buffer| {
         let page = page.clone();
-        if finish_read_btree_page(page_idx, buf, page.clone()).is_err() {
+        if finish(buf, page.clone()).is_err() {
             page.set_error();
         }
     });
@@ -247,7 +395,98 @@ pub fn begin_read_btree_page(
     Ok(())
 }
 
-fn finish_read_btree_page(page_idx: usize, buf: &Buffer, page: Rc<Page>) -> Result<()> {
+pub fn begin_read_btree_page(
+    page_source: &PageSource,
+    buffer_pool: Rc<BufferPool>,
+    page: Rc<Page>,
+    page_idx: usize,
+    db_header: Rc<RefCell<DatabaseHeader>>,
+) -> Result<()> {
+    trace!("begin_read_btree_page(page_idx = {})", page_idx);
+    begin_read_page(
+        page_source,
+        buffer_pool,
+        page,
+        page_idx,
+        move |buf, page| finish_read_btree_page(page_idx, buf, page, &db_header.borrow()),
+    )
+}
+
+pub fn begin_read_overflow_page(
+    page_source: &PageSource,
+    buffer_pool: Rc<BufferPool>,
+    page: Rc<Page>,
+    page_idx: usize,
+    usable_size: usize,
+) -> Result<()> {
+    trace!("begin_read_overflow_page(page_idx = {})", page_idx);
+    begin_read_page(
+        page_source,
+        buffer_pool,
+        page,
+        page_idx,
+        move |buf, page| {
+            let buf = buf.as_slice();
+            let next_page = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+            let inner = OverflowPage {
+                next_page: (next_page != 0).then_some(next_page),
+                data: buf[4..usable_size].to_vec(),
+            };
+            page.contents
+                .write()
+                .unwrap()
+                .replace(PageContent::Overflow(inner));
+            page.set_uptodate();
+            page.clear_locked();
+            Ok(())
+        },
+    )
+}
+
+pub fn begin_read_freelist_trunk_page(
+    page_source: &PageSource,
+    buffer_pool: Rc<BufferPool>,
+    page: Rc<Page>,
+    page_idx: usize,
+) -> Result<()> {
+    trace!("begin_read_freelist_trunk_page(page_idx = {})", page_idx);
+    begin_read_page(
+        page_source,
+        buffer_pool,
+        page,
+        page_idx,
+        move |buf, page| {
+            let buf = buf.as_slice();
+            let next_trunk_page = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+            let num_leaf_pages = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]) as usize;
+            if 8 + num_leaf_pages * 4 > buf.len() {
+                return Err(anyhow!("freelist trunk page {} is corrupt", page_idx));
+            }
+            let leaf_pages = buf[8..8 + num_leaf_pages * 4]
+                .chunks_exact(4)
+                .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+                .collect();
+            let inner = FreelistTrunkPage {
+                next_trunk_page: (next_trunk_page != 0).then_some(next_trunk_page),
+                leaf_pages,
+            };
+            page.contents
+                .write()
+                .unwrap()
+                .replace(PageContent::FreelistTrunk(inner));
+            page.set_uptodate();
+            page.clear_locked();
+            Ok(())
+        },
+    )
+}
+
+fn finish_read_btree_page(
+    page_idx: usize,
+    buf: &Buffer,
+    page: Rc<Page>,
+    db_header: &DatabaseHeader,
+) -> Result<()> {
     trace!("finish_read_btree_page(page_idx = {})", page_idx);
     let mut pos = if page_idx == 1 {
         DATABASE_HEADER_SIZE
@@ -277,20 +516,521 @@ fn finish_read_btree_page(page_idx: usize, buf: &Buffer, page: Rc<Page>) -> Resu
     for _ in 0..header.num_cells {
         let cell_pointer = u16::from_be_bytes([buf[pos], buf[pos + 1]]);
         pos += 2;
-        let cell = read_btree_cell(buf, &header.page_type, cell_pointer as usize)?;
+        let cell = read_btree_cell(buf, &header.page_type, cell_pointer as usize, db_header)?;
         cells.push(cell);
     }
-    let inner = BTreePage { header, cells };
-    page.contents.write().unwrap().replace(inner);
+    let inner = BTreePage {
+        header,
+        cells,
+        image: Some((page_idx, buf.to_vec())),
+    };
+    page.contents
+        .write()
+        .unwrap()
+        .replace(PageContent::BTree(inner));
     page.set_uptodate();
     page.clear_locked();
     Ok(())
 }
 
+/// Serialize a b-tree page into `buf`, which must be a whole page, the way
+/// SQLite lays it out. Page 1 starts with the database header.
+///
+/// A page that was read or written before keeps its layout: the cells that
+/// are still on it stay where they are, the space of the cells that are gone
+/// is freed, and new cells take space from the freeblocks or from the
+/// unallocated space in front of the cell content area, defragmenting the
+/// page if neither has room. The cells of a new page are laid out
+/// contiguously at the end of the usable space, the first cell last. The
+/// page remembers the result for the next time it is written.
+pub fn write_btree_page(
+    buf: &mut [u8],
+    page_idx: usize,
+    page: &mut BTreePage,
+    db_header: &DatabaseHeader,
+) {
+    let hdr = if page_idx == 1 {
+        DATABASE_HEADER_SIZE
+    } else {
+        0
+    };
+    let page_type = page.header.page_type;
+    let cells: Vec<Vec<u8>> = page
+        .cells
+        .iter()
+        .map(|cell| {
+            let mut cell_buf = Vec::new();
+            cell.serialize(&mut cell_buf);
+            cell_buf
+        })
+        .collect();
+    let sizes: Vec<usize> = cells
+        .iter()
+        .map(|cell| cell.len().max(MIN_CELL_SIZE))
+        .collect();
+    let mut layout = PageLayout {
+        buf,
+        hdr,
+        cell_offset: hdr + page.header.size(),
+        usable_size: db_header.usable_size(),
+    };
+    let mut offsets = match &page.image {
+        Some((image_idx, image))
+            if (*image_idx == 1) == (page_idx == 1)
+                && image.len() == layout.buf.len()
+                && image[hdr] == page_type as u8 =>
+        {
+            layout.buf.copy_from_slice(image);
+            let rowids: Vec<Option<u64>> = page
+                .cells
+                .iter()
+                .map(|cell| match cell {
+                    BTreeCell::TableLeafCell(cell) => Some(cell._rowid),
+                    _ => None,
+                })
+                .collect();
+            layout.keep_cells(&cells, &rowids, page_type, db_header)
+        }
+        _ => {
+            layout.buf.fill(0);
+            layout.set_content_area(layout.usable_size);
+            vec![None; cells.len()]
+        }
+    };
+    for i in 0..cells.len() {
+        if offsets[i].is_none() {
+            let offset = layout.allocate(sizes[i], &mut offsets, &sizes);
+            layout.buf[offset..offset + cells[i].len()].copy_from_slice(&cells[i]);
+            offsets[i] = Some(offset);
+        }
+    }
+
+    let buf = layout.buf;
+    if page_idx == 1 {
+        write_header_to_buf(buf, db_header);
+    }
+    buf[hdr] = page_type as u8;
+    buf[hdr + 3..hdr + 5].copy_from_slice(&(cells.len() as u16).to_be_bytes());
+    if let Some(right_most_pointer) = page.header.right_most_pointer {
+        buf[hdr + 8..hdr + 12].copy_from_slice(&right_most_pointer.to_be_bytes());
+    }
+    let mut cell_pointer = hdr + page.header.size();
+    for offset in offsets {
+        buf[cell_pointer..cell_pointer + 2]
+            .copy_from_slice(&(offset.unwrap() as u16).to_be_bytes());
+        cell_pointer += 2;
+    }
+    page.image = Some((page_idx, buf.to_vec()));
+}
+
+/// A cell takes at least this many bytes on a page, so that its space can
+/// hold a freeblock header once the cell is gone.
+const MIN_CELL_SIZE: usize = 4;
+
+/// The layout of the cells of a b-tree page that is being written, following
+/// `freeSpace()`, `pageFindSlot()`, `allocateSpace()` and
+/// `defragmentPage()` in SQLite's btree.c.
+struct PageLayout<'a> {
+    buf: &'a mut [u8],
+    /// The offset of the page header: 100 on page 1, 0 on other pages.
+    hdr: usize,
+    /// The offset of the cell pointer array.
+    cell_offset: usize,
+    usable_size: usize,
+}
+
+impl PageLayout<'_> {
+    fn get_u16(&self, pos: usize) -> usize {
+        u16::from_be_bytes([self.buf[pos], self.buf[pos + 1]]) as usize
+    }
+
+    fn put_u16(&mut self, pos: usize, value: usize) {
+        self.buf[pos..pos + 2].copy_from_slice(&(value as u16).to_be_bytes());
+    }
+
+    fn first_freeblock(&self) -> usize {
+        self.get_u16(self.hdr + 1)
+    }
+
+    fn content_area(&self) -> usize {
+        // A cell content area that starts at offset 65536 is stored as zero.
+        match self.get_u16(self.hdr + 5) {
+            0 => 65536,
+            offset => offset,
+        }
+    }
+
+    fn set_content_area(&mut self, offset: usize) {
+        self.put_u16(self.hdr + 5, offset);
+    }
+
+    fn num_frag_free_bytes(&self) -> usize {
+        self.buf[self.hdr + 7] as usize
+    }
+
+    fn set_num_frag_free_bytes(&mut self, value: usize) {
+        self.buf[self.hdr + 7] = value as u8;
+    }
+
+    /// Find the cells that are on the page already, by their encoding, and
+    /// free the space of the others. A table leaf cell whose row was updated
+    /// without changing its size is overwritten in place, as SQLite does.
+    /// Returns the offset of each cell that is on the page.
+    fn keep_cells(
+        &mut self,
+        cells: &[Vec<u8>],
+        rowids: &[Option<u64>],
+        page_type: PageType,
+        db_header: &DatabaseHeader,
+    ) -> Vec<Option<usize>> {
+        let num_old_cells = self.get_u16(self.hdr + 3);
+        // The offset, size and rowid of each old cell, in the order of the
+        // cell pointer array.
+        let mut old_cells = Vec::with_capacity(num_old_cells);
+        let mut by_encoding: HashMap<Vec<u8>, VecDeque<usize>> = HashMap::new();
+        let mut cell_buf = Vec::new();
+        for i in 0..num_old_cells {
+            let offset = self.get_u16(self.cell_offset + 2 * i);
+            let cell = match read_btree_cell(self.buf, &page_type, offset, db_header) {
+                Ok(cell) => cell,
+                // A cell that cannot be read leaves the page to be laid out
+                // from scratch.
+                Err(_) => return self.clear(cells.len()),
+            };
+            cell_buf.clear();
+            cell.serialize(&mut cell_buf);
+            let rowid = match page_type {
+                PageType::TableLeaf => Some(cell.rowid()),
+                _ => None,
+            };
+            old_cells.push(Some((offset, cell_buf.len().max(MIN_CELL_SIZE), rowid)));
+            by_encoding
+                .entry(cell_buf.clone())
+                .or_default()
+                .push_back(i);
+        }
+        let mut offsets: Vec<Option<usize>> = Vec::with_capacity(cells.len());
+        for cell in cells {
+            let offset = by_encoding
+                .get_mut(cell)
+                .and_then(|old| old.pop_front())
+                .and_then(|i| old_cells[i].take())
+                .map(|(offset, _, _)| offset);
+            offsets.push(offset);
+        }
+        for (i, cell) in cells.iter().enumerate() {
+            if offsets[i].is_some() || rowids[i].is_none() {
+                continue;
+            }
+            let size = cell.len().max(MIN_CELL_SIZE);
+            let same = old_cells.iter_mut().find(|old| {
+                matches!(old, Some((_, old_size, rowid)) if *old_size == size && *rowid == rowids[i])
+            });
+            if let Some((offset, _, _)) = same.and_then(|old| old.take()) {
+                self.buf[offset..offset + cell.len()].copy_from_slice(cell);
+                offsets[i] = Some(offset);
+            }
+        }
+        for (offset, size, _) in old_cells.into_iter().flatten() {
+            self.free_space(offset, size);
+        }
+        offsets
+    }
+
+    /// Empty the page.
+    fn clear(&mut self, num_cells: usize) -> Vec<Option<usize>> {
+        self.buf[self.hdr + 1..self.hdr + 8].fill(0);
+        self.set_content_area(self.usable_size);
+        vec![None; num_cells]
+    }
+
+    /// Free `size` bytes at `start`, joining neighbouring freeblocks, and the
+    /// unallocated space if the bytes are at the start of the cell content
+    /// area. Gaps of up to 3 bytes between them stop being fragmented.
+    fn free_space(&mut self, mut start: usize, mut size: usize) {
+        let mut end = start + size;
+        let mut ptr = self.hdr + 1;
+        let mut next = self.first_freeblock();
+        if next != 0 {
+            while next != 0 && next < start {
+                ptr = next;
+                next = self.get_u16(ptr);
+            }
+            let mut frag = 0;
+            if next != 0 && end + 3 >= next {
+                frag = next - end;
+                end = next + self.get_u16(next + 2);
+                size = end - start;
+                next = self.get_u16(next);
+            }
+            if ptr > self.hdr + 1 {
+                let ptr_end = ptr + self.get_u16(ptr + 2);
+                if ptr_end + 3 >= start {
+                    frag += start - ptr_end;
+                    size = end - ptr;
+                    start = ptr;
+                }
+            }
+            let num_frag_free_bytes = self.num_frag_free_bytes().saturating_sub(frag);
+            self.set_num_frag_free_bytes(num_frag_free_bytes);
+        }
+        if start <= self.content_area() {
+            self.put_u16(self.hdr + 1, next);
+            self.set_content_area(end);
+        } else {
+            self.put_u16(ptr, start);
+            self.put_u16(start, next);
+            self.put_u16(start + 2, size);
+        }
+    }
+
+    /// Take `size` bytes from the end of the first freeblock that is large
+    /// enough. What remains of a freeblock that is smaller than 4 bytes
+    /// becomes fragmented, unless the page has too many fragmented bytes.
+    fn find_slot(&mut self, size: usize) -> Option<usize> {
+        let mut ptr = self.hdr + 1;
+        let mut pc = self.first_freeblock();
+        let max_pc = self.usable_size - size;
+        while pc != 0 && pc <= max_pc {
+            let block_size = self.get_u16(pc + 2);
+            if block_size >= size {
+                let rest = block_size - size;
+                if rest < 4 {
+                    if self.num_frag_free_bytes() > 57 {
+                        return None;
+                    }
+                    let next = self.get_u16(pc);
+                    self.put_u16(ptr, next);
+                    self.set_num_frag_free_bytes(self.num_frag_free_bytes() + rest);
+                    return Some(pc);
+                }
+                if pc + block_size > self.usable_size {
+                    return None;
+                }
+                self.put_u16(pc + 2, rest);
+                return Some(pc + rest);
+            }
+            ptr = pc;
+            pc = self.get_u16(pc);
+            if pc != 0 && pc <= ptr {
+                return None;
+            }
+        }
+        None
+    }
+
+    /// Allocate `size` bytes for a cell, with room for a pointer to it after
+    /// the pointers to the cells in `offsets` that are placed already.
+    fn allocate(&mut self, size: usize, offsets: &mut [Option<usize>], sizes: &[usize]) -> usize {
+        let num_cells = offsets.iter().flatten().count();
+        let gap = self.cell_offset + 2 * num_cells;
+        let mut top = self.content_area();
+        if self.first_freeblock() != 0 && gap + 2 <= top {
+            if let Some(offset) = self.find_slot(size) {
+                return offset;
+            }
+        }
+        if gap + 2 + size > top {
+            let used: usize = offsets
+                .iter()
+                .zip(sizes)
+                .filter_map(|(offset, size)| offset.map(|_| size))
+                .sum();
+            let num_free_bytes = self.usable_size - gap - used;
+            let max_frag = num_free_bytes.saturating_sub(2 + size).min(4);
+            self.defragment(max_frag, offsets, sizes);
+            top = self.content_area();
+            assert!(gap + 2 + size <= top, "cell does not fit in the page");
+        }
+        top -= size;
+        self.set_content_area(top);
+        top
+    }
+
+    /// Move the cells in `offsets` that are placed to the end of the page, so
+    /// that all free space is unallocated space. A page with at most two
+    /// freeblocks and at most `max_frag` fragmented bytes has its freeblocks
+    /// merged into the unallocated space, and its fragmented bytes kept.
+    fn defragment(&mut self, max_frag: usize, offsets: &mut [Option<usize>], sizes: &[usize]) {
+        let num_cells = offsets.iter().flatten().count();
+        let cell_first = self.cell_offset + 2 * num_cells;
+        let brk = match self.defragment_freeblocks(max_frag, offsets) {
+            Some(brk) => brk,
+            None => {
+                let image = self.buf.to_vec();
+                let mut brk = self.usable_size;
+                for (offset, size) in offsets.iter_mut().zip(sizes) {
+                    if let Some(offset) = offset {
+                        brk -= size;
+                        self.buf[brk..brk + size].copy_from_slice(&image[*offset..*offset + size]);
+                        *offset = brk;
+                    }
+                }
+                self.set_num_frag_free_bytes(0);
+                brk
+            }
+        };
+        self.set_content_area(brk);
+        self.put_u16(self.hdr + 1, 0);
+        self.buf[cell_first..brk].fill(0);
+    }
+
+    /// Merge at most two freeblocks into the unallocated space by moving the
+    /// cells in front of them. Returns the new start of the cell content
+    /// area, or `None` if the page does not qualify.
+    fn defragment_freeblocks(
+        &mut self,
+        max_frag: usize,
+        offsets: &mut [Option<usize>],
+    ) -> Option<usize> {
+        if self.num_frag_free_bytes() > max_frag {
+            return None;
+        }
+        let free = self.first_freeblock();
+        if free == 0 {
+            return None;
+        }
+        let free2 = self.get_u16(free);
+        if free2 != 0 && self.get_u16(free2) != 0 {
+            return None;
+        }
+        let top = self.content_area();
+        let mut size = self.get_u16(free + 2);
+        let mut size2 = 0;
+        if free2 != 0 {
+            size2 = self.get_u16(free2 + 2);
+            self.buf
+                .copy_within(free + size..free2, free + size + size2);
+            size += size2;
+        }
+        let brk = top + size;
+        self.buf.copy_within(top..free, brk);
+        for offset in offsets.iter_mut().flatten() {
+            if *offset < free {
+                *offset += size;
+            } else if *offset < free2 {
+                *offset += size2;
+            }
+        }
+        Some(brk)
+    }
+}
+
+/// Serialize an overflow page into `buf`, which must be a whole page.
+pub fn write_overflow_page(buf: &mut [u8], page: &OverflowPage) {
+    buf[0..4].copy_from_slice(&page.next_page.unwrap_or(0).to_be_bytes());
+    buf[4..4 + page.data.len()].copy_from_slice(&page.data);
+    buf[4 + page.data.len()..].fill(0);
+}
+
+/// Serialize a freelist trunk page into `buf`, which must be a whole page.
+pub fn write_freelist_trunk_page(buf: &mut [u8], page: &FreelistTrunkPage) {
+    buf[0..4].copy_from_slice(&page.next_trunk_page.unwrap_or(0).to_be_bytes());
+    buf[4..8].copy_from_slice(&(page.leaf_pages.len() as u32).to_be_bytes());
+    let mut pos = 8;
+    for leaf_page in &page.leaf_pages {
+        buf[pos..pos + 4].copy_from_slice(&leaf_page.to_be_bytes());
+        pos += 4;
+    }
+    buf[pos..].fill(0);
+}
+
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum BTreeCell {
     TableInteriorCell(TableInteriorCell),
     TableLeafCell(TableLeafCell),
+    IndexInteriorCell(IndexInteriorCell),
+    IndexLeafCell(IndexLeafCell),
+}
+
+impl BTreeCell {
+    /// Append the on-disk encoding of the cell to `buf`.
+    pub fn serialize(&self, buf: &mut Vec<u8>) {
+        let mut varint = [0; 9];
+        match self {
+            BTreeCell::TableInteriorCell(cell) => {
+                buf.extend_from_slice(&cell._left_child_page.to_be_bytes());
+                let n = write_varint(&mut varint, cell._rowid);
+                buf.extend_from_slice(&varint[..n]);
+            }
+            BTreeCell::TableLeafCell(cell) => {
+                let n = write_varint(&mut varint, cell.payload_size);
+                buf.extend_from_slice(&varint[..n]);
+                let n = write_varint(&mut varint, cell._rowid);
+                buf.extend_from_slice(&varint[..n]);
+                buf.extend_from_slice(&cell._payload);
+                if let Some(first_overflow_page) = cell.first_overflow_page {
+                    buf.extend_from_slice(&first_overflow_page.to_be_bytes());
+                }
+            }
+            BTreeCell::IndexInteriorCell(cell) => {
+                buf.extend_from_slice(&cell.left_child_page.to_be_bytes());
+                let n = write_varint(&mut varint, cell.payload_size);
+                buf.extend_from_slice(&varint[..n]);
+                buf.extend_from_slice(&cell.payload);
+                if let Some(first_overflow_page) = cell.first_overflow_page {
+                    buf.extend_from_slice(&first_overflow_page.to_be_bytes());
+                }
+            }
+            BTreeCell::IndexLeafCell(cell) => {
+                let n = write_varint(&mut varint, cell.payload_size);
+                buf.extend_from_slice(&varint[..n]);
+                buf.extend_from_slice(&cell.payload);
+                if let Some(first_overflow_page) = cell.first_overflow_page {
+                    buf.extend_from_slice(&first_overflow_page.to_be_bytes());
+                }
+            }
+        }
+    }
+
+    /// Number of bytes the cell takes on a page: the size of its on-disk
+    /// encoding, but at least `MIN_CELL_SIZE`.
+    pub fn size(&self) -> usize {
+        let size = match self {
+            BTreeCell::TableInteriorCell(cell) => 4 + varint_len(cell._rowid),
+            BTreeCell::TableLeafCell(cell) => {
+                varint_len(cell.payload_size)
+                    + varint_len(cell._rowid)
+                    + cell._payload.len()
+                    + cell.first_overflow_page.map_or(0, |_| 4)
+            }
+            BTreeCell::IndexInteriorCell(cell) => {
+                4 + varint_len(cell.payload_size)
+                    + cell.payload.len()
+                    + cell.first_overflow_page.map_or(0, |_| 4)
+            }
+            BTreeCell::IndexLeafCell(cell) => {
+                varint_len(cell.payload_size)
+                    + cell.payload.len()
+                    + cell.first_overflow_page.map_or(0, |_| 4)
+            }
+        };
+        size.max(MIN_CELL_SIZE)
+    }
+
+    /// The first page of the overflow chain that stores the rest of the
+    /// cell's payload, if any.
+    pub fn first_overflow_page(&self) -> Option<u32> {
+        match self {
+            BTreeCell::TableInteriorCell(_) => None,
+            BTreeCell::TableLeafCell(cell) => cell.first_overflow_page,
+            BTreeCell::IndexInteriorCell(cell) => cell.first_overflow_page,
+            BTreeCell::IndexLeafCell(cell) => cell.first_overflow_page,
+        }
+    }
+
+    /// The rowid key of a table b-tree cell. Index cells are keyed by their
+    /// payload instead, which ends with the rowid of the indexed row.
+    pub fn rowid(&self) -> u64 {
+        match self {
+            BTreeCell::TableInteriorCell(cell) => cell._rowid,
+            BTreeCell::TableLeafCell(cell) => cell._rowid,
+            BTreeCell::IndexInteriorCell(_) | BTreeCell::IndexLeafCell(_) => {
+                unreachable!("index cells have no rowid key")
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -299,15 +1039,62 @@ pub struct TableInteriorCell {
     pub _rowid: u64,
 }
 
+/// A table leaf cell. Only the first part of a large payload is stored in the
+/// cell, and the rest in a chain of overflow pages.
 #[derive(Debug)]
 pub struct TableLeafCell {
     pub _rowid: u64,
+    /// The part of the payload that is stored in the cell.
     pub _payload: Vec<u8>,
+    /// Size of the whole payload in bytes.
+    pub payload_size: u64,
+    pub first_overflow_page: Option<u32>,
 }
 
-pub fn read_btree_cell(page: &[u8], page_type: &PageType, pos: usize) -> Result<BTreeCell> {
+/// An index interior cell. Unlike in a table b-tree, the key is stored in
+/// the interior cell itself and sorts after every key in its left child.
+#[derive(Debug)]
+pub struct IndexInteriorCell {
+    pub left_child_page: u32,
+    /// The part of the key record that is stored in the cell.
+    pub payload: Vec<u8>,
+    /// Size of the whole key record in bytes.
+    pub payload_size: u64,
+    pub first_overflow_page: Option<u32>,
+}
+
+#[derive(Debug)]
+pub struct IndexLeafCell {
+    /// The part of the key record that is stored in the cell.
+    pub payload: Vec<u8>,
+    /// Size of the whole key record in bytes.
+    pub payload_size: u64,
+    pub first_overflow_page: Option<u32>,
+}
+
+pub fn read_btree_cell(
+    page: &[u8],
+    page_type: &PageType,
+    pos: usize,
+    db_header: &DatabaseHeader,
+) -> Result<BTreeCell> {
     match page_type {
-        PageType::IndexInterior => todo!(),
+        PageType::IndexInterior => {
+            let mut pos = pos;
+            let left_child_page =
+                u32::from_be_bytes([page[pos], page[pos + 1], page[pos + 2], page[pos + 3]]);
+            pos += 4;
+            let (payload_size, nr) = read_varint(&page[pos..])?;
+            pos += nr;
+            let (payload, first_overflow_page) =
+                read_cell_payload(page, *page_type, pos, payload_size, db_header);
+            Ok(BTreeCell::IndexInteriorCell(IndexInteriorCell {
+                left_child_page,
+                payload,
+                payload_size,
+                first_overflow_page,
+            }))
+        }
         PageType::TableInterior => {
             let mut pos = pos;
             let left_child_page =
@@ -319,23 +1106,61 @@ pub fn read_btree_cell(page: &[u8], page_type: &PageType, pos: usize) -> Result<
                 _rowid: rowid,
             }))
         }
-        PageType::IndexLeaf => todo!(),
+        PageType::IndexLeaf => {
+            let mut pos = pos;
+            let (payload_size, nr) = read_varint(&page[pos..])?;
+            pos += nr;
+            let (payload, first_overflow_page) =
+                read_cell_payload(page, *page_type, pos, payload_size, db_header);
+            Ok(BTreeCell::IndexLeafCell(IndexLeafCell {
+                payload,
+                payload_size,
+                first_overflow_page,
+            }))
+        }
         PageType::TableLeaf => {
             let mut pos = pos;
             let (payload_size, nr) = read_varint(&page[pos..])?;
             pos += nr;
             let (rowid, nr) = read_varint(&page[pos..])?;
             pos += nr;
-            let payload = &page[pos..pos + payload_size as usize];
-            // FIXME: page overflows if the payload is too large
+            let (payload, first_overflow_page) =
+                read_cell_payload(page, *page_type, pos, payload_size, db_header);
             Ok(BTreeCell::TableLeafCell(TableLeafCell {
                 _rowid: rowid,
-                _payload: payload.to_vec(),
+                _payload: payload,
+                payload_size,
+                first_overflow_page,
             }))
         }
     }
 }
 
+/// Read the local part of a cell's payload starting at `pos`, followed by the
+/// number of the first overflow page if the payload does not fit in the cell.
+fn read_cell_payload(
+    page: &[u8],
+    page_type: PageType,
+    pos: usize,
+    payload_size: u64,
+    db_header: &DatabaseHeader,
+) -> (Vec<u8>, Option<u32>) {
+    let local_size = db_header.payload_local_size(payload_size as usize, page_type);
+    let payload = page[pos..pos + local_size].to_vec();
+    let pos = pos + local_size;
+    let first_overflow_page = if local_size < payload_size as usize {
+        Some(u32::from_be_bytes([
+            page[pos],
+            page[pos + 1],
+            page[pos + 2],
+            page[pos + 3],
+        ]))
+    } else {
+        None
+    };
+    (payload, first_overflow_page)
+}
+
 #[derive(Debug, PartialEq)]
 pub enum SerialType {
     Null,
@@ -367,13 +1192,70 @@ impl TryFrom<u64> for SerialType {
             7 => Ok(Self::BEFloat64),
             8 => Ok(Self::ConstInt0),
             9 => Ok(Self::ConstInt1),
-            n if value > 12 && value % 2 == 0 => Ok(Self::Blob(((n - 12) / 2) as usize)),
-            n if value > 13 && value % 2 == 1 => Ok(Self::String(((n - 13) / 2) as usize)),
+            n if value >= 12 && value % 2 == 0 => Ok(Self::Blob(((n - 12) / 2) as usize)),
+            n if value >= 13 && value % 2 == 1 => Ok(Self::String(((n - 13) / 2) as usize)),
             _ => Err(anyhow!("Invalid serial type: {}", value)),
         }
     }
 }
 
+impl SerialType {
+    /// The serial type SQLite stores a value with: the smallest integer type
+    /// that holds it, with 0 and 1 needing no body bytes at all.
+    pub fn for_value(value: &OwnedValue) -> Self {
+        match value {
+            OwnedValue::Null => Self::Null,
+            OwnedValue::Integer(0) => Self::ConstInt0,
+            OwnedValue::Integer(1) => Self::ConstInt1,
+            OwnedValue::Integer(i) => match *i {
+                -0x80..=0x7f => Self::UInt8,
+                -0x8000..=0x7fff => Self::BEInt16,
+                -0x800000..=0x7fffff => Self::BEInt24,
+                -0x80000000..=0x7fffffff => Self::BEInt32,
+                -0x800000000000..=0x7fffffffffff => Self::BEInt48,
+                _ => Self::BEInt64,
+            },
+            OwnedValue::Float(_) => Self::BEFloat64,
+            OwnedValue::Text(text) => Self::String(text.len()),
+            OwnedValue::Blob(blob) => Self::Blob(blob.len()),
+            OwnedValue::Agg(_) | OwnedValue::Record(_) => unreachable!(),
+        }
+    }
+
+    /// Number of bytes a value of this type takes in the record body.
+    pub fn size(&self) -> usize {
+        match self {
+            Self::Null | Self::ConstInt0 | Self::ConstInt1 => 0,
+            Self::UInt8 => 1,
+            Self::BEInt16 => 2,
+            Self::BEInt24 => 3,
+            Self::BEInt32 => 4,
+            Self::BEInt48 => 6,
+            Self::BEInt64 | Self::BEFloat64 => 8,
+            Self::Blob(n) | Self::String(n) => *n,
+        }
+    }
+}
+
+impl From<&SerialType> for u64 {
+    fn from(serial_type: &SerialType) -> u64 {
+        match serial_type {
+            SerialType::Null => 0,
+            SerialType::UInt8 => 1,
+            SerialType::BEInt16 => 2,
+            SerialType::BEInt24 => 3,
+            SerialType::BEInt32 => 4,
+            SerialType::BEInt48 => 5,
+            SerialType::BEInt64 => 6,
+            SerialType::BEFloat64 => 7,
+            SerialType::ConstInt0 => 8,
+            SerialType::ConstInt1 => 9,
+            SerialType::Blob(n) => *n as u64 * 2 + 12,
+            SerialType::String(n) => *n as u64 * 2 + 13,
+        }
+    }
+}
+
 pub fn read_record(payload: &[u8]) -> Result<OwnedRecord> {
     let mut pos = 0;
     let (header_size, nr) = read_varint(payload)?;
@@ -385,7 +1267,7 @@ pub fn read_record(payload: &[u8]) -> Result<OwnedRecord> {
         let (serial_type, nr) = read_varint(&payload[pos..])?;
         let serial_type = SerialType::try_from(serial_type)?;
         serial_types.push(serial_type);
-        assert!(pos + nr < payload.len());
+        assert!(pos + nr <= payload.len());
         pos += nr;
         assert!(header_size >= nr);
         header_size -= nr;
@@ -406,7 +1288,7 @@ pub fn read_value(buf: &[u8], serial_type: &SerialType) -> Result<(OwnedValue, u
             if buf.is_empty() {
                 return Err(anyhow!("Invalid UInt8 value"));
             }
-            Ok((OwnedValue::Integer(buf[0] as i64), 1))
+            Ok((OwnedValue::Integer(buf[0] as i8 as i64), 1))
         }
         SerialType::BEInt16 => {
             if buf.len() < 2 {
@@ -421,8 +1303,9 @@ pub fn read_value(buf: &[u8], serial_type: &SerialType) -> Result<(OwnedValue, u
             if buf.len() < 3 {
                 return Err(anyhow!("Invalid BEInt24 value"));
             }
+            // Shift the sign bit into place and back to sign-extend.
             Ok((
-                OwnedValue::Integer(i32::from_be_bytes([0, buf[0], buf[1], buf[2]]) as i64),
+                OwnedValue::Integer((i32::from_be_bytes([buf[0], buf[1], buf[2], 0]) >> 8) as i64),
                 3,
             ))
         }
@@ -440,9 +1323,10 @@ pub fn read_value(buf: &[u8], serial_type: &SerialType) -> Result<(OwnedValue, u
                 return Err(anyhow!("Invalid BEInt48 value"));
             }
             Ok((
-                OwnedValue::Integer(i64::from_be_bytes([
-                    0, 0, buf[0], buf[1], buf[2], buf[3], buf[4], buf[5],
-                ])),
+                OwnedValue::Integer(
+                    i64::from_be_bytes([buf[0], buf[1], buf[2], buf[3], buf[4], buf[5], 0, 0])
+                        >> 16,
+                ),
                 6,
             ))
         }
@@ -506,9 +1390,107 @@ fn read_varint(buf: &[u8]) -> Result<(u64, usize)> {
     Ok((v, 9))
 }
 
+/// Encode a record in SQLite's record format and append it to `buf`.
+pub fn write_record(record: &OwnedRecord, buf: &mut Vec<u8>) {
+    let serial_types: Vec<SerialType> = record.values.iter().map(SerialType::for_value).collect();
+    let types_size: usize = serial_types
+        .iter()
+        .map(|serial_type| varint_len(serial_type.into()))
+        .sum();
+    // The header size includes the varint that stores it.
+    let mut header_size = types_size + 1;
+    while types_size + varint_len(header_size as u64) != header_size {
+        header_size = types_size + varint_len(header_size as u64);
+    }
+    let body_size: usize = serial_types
+        .iter()
+        .map(|serial_type| serial_type.size())
+        .sum();
+    buf.reserve(header_size + body_size);
+    let mut varint = [0; 9];
+    let n = write_varint(&mut varint, header_size as u64);
+    buf.extend_from_slice(&varint[..n]);
+    for serial_type in &serial_types {
+        let n = write_varint(&mut varint, serial_type.into());
+        buf.extend_from_slice(&varint[..n]);
+    }
+    for (value, serial_type) in record.values.iter().zip(&serial_types) {
+        write_value(buf, value, serial_type);
+    }
+}
+
+/// Append the body bytes of `value` stored with the given serial type.
+pub fn write_value(buf: &mut Vec<u8>, value: &OwnedValue, serial_type: &SerialType) {
+    match value {
+        OwnedValue::Null => {}
+        OwnedValue::Integer(i) => {
+            let bytes = i.to_be_bytes();
+            buf.extend_from_slice(&bytes[bytes.len() - serial_type.size()..]);
+        }
+        OwnedValue::Float(f) => buf.extend_from_slice(&f.to_be_bytes()),
+        OwnedValue::Text(text) => buf.extend_from_slice(text.as_bytes()),
+        OwnedValue::Blob(blob) => buf.extend_from_slice(blob),
+        OwnedValue::Agg(_) | OwnedValue::Record(_) => unreachable!(),
+    }
+}
+
+/// Encode `value` as a varint into `buf`, which must have room for 9 bytes.
+/// Returns the number of bytes written.
+pub fn write_varint(buf: &mut [u8], value: u64) -> usize {
+    if value <= 0x7f {
+        buf[0] = value as u8;
+        return 1;
+    }
+    if value <= 0x3fff {
+        buf[0] = (((value >> 7) & 0x7f) | 0x80) as u8;
+        buf[1] = (value & 0x7f) as u8;
+        return 2;
+    }
+    if value & (0xff000000_u64 << 32) != 0 {
+        // The ninth byte holds all 8 of the lowest bits.
+        buf[8] = value as u8;
+        let mut value = value >> 8;
+        for byte in buf[..8].iter_mut().rev() {
+            *byte = ((value & 0x7f) | 0x80) as u8;
+            value >>= 7;
+        }
+        return 9;
+    }
+    let mut encoded = [0u8; 9];
+    let mut n = 0;
+    let mut value = value;
+    while value != 0 {
+        encoded[n] = ((value & 0x7f) | 0x80) as u8;
+        value >>= 7;
+        n += 1;
+    }
+    encoded[0] &= 0x7f;
+    for i in 0..n {
+        buf[i] = encoded[n - 1 - i];
+    }
+    n
+}
+
+/// Number of bytes `write_varint` uses to encode `value`.
+pub fn varint_len(value: u64) -> usize {
+    if value & (0xff000000_u64 << 32) != 0 {
+        return 9;
+    }
+    let mut n = 1;
+    let mut value = value >> 7;
+    while value != 0 {
+        n += 1;
+        value >>= 7;
+    }
+    n
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::{query, TempDatabase};
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
     use rstest::rstest;
 
     #[rstest]
@@ -537,11 +1519,14 @@ mod tests {
 
     #[rstest]
     #[case(&[], SerialType::Null, OwnedValue::Null)]
-    #[case(&[255], SerialType::UInt8, OwnedValue::Integer(255))]
+    #[case(&[127], SerialType::UInt8, OwnedValue::Integer(127))]
+    #[case(&[255], SerialType::UInt8, OwnedValue::Integer(-1))]
     #[case(&[0x12, 0x34], SerialType::BEInt16, OwnedValue::Integer(0x1234))]
     #[case(&[0x12, 0x34, 0x56], SerialType::BEInt24, OwnedValue::Integer(0x123456))]
+    #[case(&[0xff, 0xff, 0xfe], SerialType::BEInt24, OwnedValue::Integer(-2))]
     #[case(&[0x12, 0x34, 0x56, 0x78], SerialType::BEInt32, OwnedValue::Integer(0x12345678))]
     #[case(&[0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC], SerialType::BEInt48, OwnedValue::Integer(0x123456789ABC))]
+    #[case(&[0x80, 0, 0, 0, 0, 0], SerialType::BEInt48, OwnedValue::Integer(-0x800000000000))]
     #[case(&[0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xFF], SerialType::BEInt64, OwnedValue::Integer(0x123456789ABCDEFF))]
     #[case(&[64, 9, 33, 251, 84, 68, 45, 24], SerialType::BEFloat64, OwnedValue::Float(3.141592653589793))]
     #[case(&[], SerialType::ConstInt0, OwnedValue::Integer(0))]
@@ -588,10 +1573,311 @@ mod tests {
         assert_eq!(result, expected);
     }
 
+    #[rstest]
+    #[case(1, &[0x01])]
+    #[case(129, &[0x81, 0x01])]
+    #[case(16513, &[0x81, 0x81, 0x01])]
+    #[case(2113665, &[0x81, 0x81, 0x81, 0x01])]
+    #[case(145249953336295681, &[0x81, 0x81, 0x81, 0x81, 0x81, 0x81, 0x81, 0x81, 0x01])]
+    #[case(u64::MAX, &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff])]
+    fn write_varint_test(#[case] input: u64, #[case] expected: &[u8]) {
+        let mut buf = [0; 9];
+        let n = write_varint(&mut buf, input);
+        assert_eq!(&buf[..n], expected);
+        assert_eq!(varint_len(input), n);
+    }
+
+    #[rstest]
+    #[case(OwnedValue::Null, SerialType::Null)]
+    #[case(OwnedValue::Integer(0), SerialType::ConstInt0)]
+    #[case(OwnedValue::Integer(1), SerialType::ConstInt1)]
+    #[case(OwnedValue::Integer(2), SerialType::UInt8)]
+    #[case(OwnedValue::Integer(-1), SerialType::UInt8)]
+    #[case(OwnedValue::Integer(127), SerialType::UInt8)]
+    #[case(OwnedValue::Integer(-128), SerialType::UInt8)]
+    #[case(OwnedValue::Integer(128), SerialType::BEInt16)]
+    #[case(OwnedValue::Integer(-129), SerialType::BEInt16)]
+    #[case(OwnedValue::Integer(32767), SerialType::BEInt16)]
+    #[case(OwnedValue::Integer(32768), SerialType::BEInt24)]
+    #[case(OwnedValue::Integer(-8388608), SerialType::BEInt24)]
+    #[case(OwnedValue::Integer(8388608), SerialType::BEInt32)]
+    #[case(OwnedValue::Integer(i32::MIN as i64), SerialType::BEInt32)]
+    #[case(OwnedValue::Integer(i32::MAX as i64 + 1), SerialType::BEInt48)]
+    #[case(OwnedValue::Integer(-0x800000000000), SerialType::BEInt48)]
+    #[case(OwnedValue::Integer(0x800000000000), SerialType::BEInt64)]
+    #[case(OwnedValue::Integer(i64::MIN), SerialType::BEInt64)]
+    #[case(OwnedValue::Float(1.0), SerialType::BEFloat64)]
+    #[case(OwnedValue::Text("".to_string().into()), SerialType::String(0))]
+    #[case(OwnedValue::Blob(vec![1, 2].into()), SerialType::Blob(2))]
+    fn test_serial_type_for_value(#[case] value: OwnedValue, #[case] expected: SerialType) {
+        let serial_type = SerialType::for_value(&value);
+        assert_eq!(
+            SerialType::try_from(u64::from(&serial_type)).unwrap(),
+            serial_type
+        );
+        assert_eq!(serial_type, expected);
+    }
+
+    #[rstest]
+    #[case(vec![], &[0x01])]
+    #[case(vec![OwnedValue::Null], &[0x02, 0x00])]
+    #[case(vec![OwnedValue::Integer(0), OwnedValue::Integer(1)], &[0x03, 0x08, 0x09])]
+    #[case(vec![OwnedValue::Integer(-2), OwnedValue::Integer(300)], &[0x03, 0x01, 0x02, 0xfe, 0x01, 0x2c])]
+    #[case(vec![OwnedValue::Integer(0x123456)], &[0x02, 0x03, 0x12, 0x34, 0x56])]
+    #[case(vec![OwnedValue::Float(0.5)], &[0x02, 0x07, 0x3f, 0xe0, 0, 0, 0, 0, 0, 0])]
+    #[case(vec![OwnedValue::Text("ab".to_string().into()), OwnedValue::Blob(vec![0xff].into())], &[0x03, 0x11, 0x0e, b'a', b'b', 0xff])]
+    fn test_write_record(#[case] values: Vec<OwnedValue>, #[case] expected: &[u8]) {
+        let mut buf = Vec::new();
+        write_record(&OwnedRecord::new(values), &mut buf);
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn test_write_record_long_header() {
+        // With 127 columns the header size no longer fits in a one-byte
+        // varint, which makes the header one byte longer still.
+        let record = OwnedRecord::new(vec![OwnedValue::Integer(2); 127]);
+        let mut buf = Vec::new();
+        write_record(&record, &mut buf);
+        assert_eq!(&buf[..2], &[0x81, 0x01]);
+        assert_eq!(buf.len(), 129 + 127);
+        assert_eq!(read_record(&buf).unwrap(), record);
+    }
+
+    fn random_value(rng: &mut ChaCha8Rng) -> OwnedValue {
+        match rng.gen_range(0..6) {
+            0 => OwnedValue::Null,
+            // Integers of every width, including the edges of each range.
+            1 => {
+                let bits = rng.gen_range(0..64);
+                OwnedValue::Integer(rng.gen::<i64>() >> bits)
+            }
+            2 => OwnedValue::Integer(rng.gen_range(-2..=2)),
+            3 => OwnedValue::Float(rng.gen::<f64>() * 1e10 - 5e9),
+            4 => {
+                let len = rng.gen_range(0..100);
+                let text: String = (0..len).map(|_| rng.gen_range('a'..='z')).collect();
+                OwnedValue::Text(text.into())
+            }
+            _ => {
+                let len = rng.gen_range(0..100);
+                let blob: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+                OwnedValue::Blob(blob.into())
+            }
+        }
+    }
+
+    #[test]
+    fn test_write_record_round_trip() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        for _ in 0..10000 {
+            let len = rng.gen_range(0..20);
+            let record = OwnedRecord::new((0..len).map(|_| random_value(&mut rng)).collect());
+            let mut buf = Vec::new();
+            write_record(&record, &mut buf);
+            assert_eq!(read_record(&buf).unwrap(), record);
+        }
+    }
+
+    #[test]
+    fn test_write_record_matches_sqlite() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        // Besides random values, whole and fractional reals and numeric text,
+        // which the typed columns convert.
+        let special = [
+            OwnedValue::Float(0.0),
+            OwnedValue::Float(2.0),
+            OwnedValue::Float(-3.5),
+            OwnedValue::Float(140737488355327.0),
+            OwnedValue::Float(140737488355328.0),
+            OwnedValue::Float(1e20),
+            OwnedValue::Integer(-140737488355328),
+            OwnedValue::Integer(-140737488355329),
+            OwnedValue::Text("12".to_string().into()),
+            OwnedValue::Text(" 2.0 ".to_string().into()),
+            OwnedValue::Text("1e3".to_string().into()),
+        ];
+        let records: Vec<OwnedRecord> = (0..40)
+            .map(|_| {
+                OwnedRecord::new(
+                    (0..5)
+                        .map(|_| {
+                            if rng.gen() {
+                                random_value(&mut rng)
+                            } else {
+                                special[rng.gen_range(0..special.len())].clone()
+                            }
+                        })
+                        .collect(),
+                )
+            })
+            .collect();
+        let temp = TempDatabase::new("record");
+        {
+            let conn = temp.sqlite();
+            conn.execute(
+                "CREATE TABLE t (a, b REAL, c INTEGER, d TEXT, e NUMERIC)",
+                (),
+            )
+            .unwrap();
+            for (rowid, record) in records.iter().enumerate() {
+                let params: Vec<rusqlite::types::Value> = record
+                    .values
+                    .iter()
+                    .map(|value| match value {
+                        OwnedValue::Null => rusqlite::types::Value::Null,
+                        OwnedValue::Integer(i) => rusqlite::types::Value::Integer(*i),
+                        OwnedValue::Float(f) => rusqlite::types::Value::Real(*f),
+                        OwnedValue::Text(text) => rusqlite::types::Value::Text(text.to_string()),
+                        OwnedValue::Blob(blob) => rusqlite::types::Value::Blob(blob.to_vec()),
+                        _ => unreachable!(),
+                    })
+                    .collect();
+                conn.execute(
+                    "INSERT INTO t (rowid, a, b, c, d, e) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    rusqlite::params_from_iter(
+                        std::iter::once(rusqlite::types::Value::Integer(rowid as i64))
+                            .chain(params),
+                    ),
+                )
+                .unwrap();
+            }
+        }
+        let file = std::fs::read(&temp.path).unwrap();
+        // The rows all fit in the table's root, which is page 2.
+        let page_size = u16::from_be_bytes([file[16], file[17]]) as usize;
+        let page = &file[page_size..2 * page_size];
+        assert_eq!(page[0], PageType::TableLeaf as u8);
+        let num_cells = u16::from_be_bytes([page[3], page[4]]) as usize;
+        assert_eq!(num_cells, records.len());
+        for i in 0..num_cells {
+            let mut pos = u16::from_be_bytes([page[8 + 2 * i], page[9 + 2 * i]]) as usize;
+            let (payload_size, n) = read_varint(&page[pos..]).unwrap();
+            pos += n;
+            let (rowid, n) = read_varint(&page[pos..]).unwrap();
+            pos += n;
+            // The values are stored with the affinities of the columns.
+            let record = OwnedRecord::new(
+                records[rowid as usize]
+                    .values
+                    .iter()
+                    .zip("AEDBC".chars())
+                    .map(|(value, affinity)| value.apply_affinity(affinity))
+                    .collect(),
+            );
+            let mut buf = Vec::new();
+            write_record(&record, &mut buf);
+            assert_eq!(buf, &page[pos..pos + payload_size as usize]);
+        }
+    }
+
+    #[rstest]
+    #[case(4096, 0, 4061, 4061)]
+    #[case(4096, 0, 4062, 489)]
+    #[case(4096, 0, 10000, 1816)]
+    #[case(4096, 0, 8153, 4061)]
+    #[case(4096, 0, 8154, 489)]
+    #[case(4096, 8, 4053, 4053)]
+    #[case(4096, 8, 4054, 488)]
+    #[case(512, 0, 477, 477)]
+    #[case(512, 0, 1000, 39)]
+    #[case(512, 0, 1100, 84)]
+    fn test_payload_local_size(
+        #[case] page_size: u16,
+        #[case] unused_space: u8,
+        #[case] payload_size: usize,
+        #[case] expected: usize,
+    ) {
+        let header = DatabaseHeader {
+            page_size,
+            unused_space,
+            max_embed_frac: 64,
+            min_embed_frac: 32,
+            min_leaf_frac: 32,
+            ..Default::default()
+        };
+        assert_eq!(
+            header.payload_local_size(payload_size, PageType::TableLeaf),
+            expected
+        );
+    }
+
     #[test]
     fn test_read_invalid_varint() {
         let buf = [0b11111110];
         let result = read_varint(&buf);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_write_btree_page_like_sqlite() {
+        let temp = TempDatabase::with_sql(
+            "layout",
+            "PRAGMA page_size = 1024; CREATE TABLE t (a INTEGER PRIMARY KEY, b);
+             WITH RECURSIVE s(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM s WHERE i < 20)
+             INSERT INTO t SELECT i, printf('%0*d', 10 + i % 7, i) FROM s;",
+        );
+        let copy = TempDatabase::new("layout-copy");
+        std::fs::copy(&temp.path, &copy.path).unwrap();
+
+        // Deleted cells leave freeblocks and fragments behind, which the later
+        // cells are allocated from.
+        let statements = [
+            "DELETE FROM t WHERE a IN (3, 4, 9, 13, 15)",
+            "INSERT INTO t VALUES (30, 'abcdefgh')",
+            "UPDATE t SET b = 'ABCDEFGHIJKLMNOPQRS' WHERE a = 7",
+            "UPDATE t SET b = 'xyzxyzxyzxyzxyz' WHERE a = 12",
+            "UPDATE t SET b = 'abcdefghijkl' WHERE a = 2",
+            "DELETE FROM t WHERE a = 16",
+            "INSERT INTO t VALUES (31, 'abcdefghijklmnopqrstuvwxyz')",
+        ];
+        let conn = copy.sqlite();
+        for sql in statements {
+            conn.execute(sql, ()).unwrap();
+        }
+        drop(conn);
+        let (io, db) = temp.open();
+        let conn = db.connect();
+        for sql in statements {
+            query(&io, &conn, sql).unwrap();
+        }
+        drop(conn);
+        drop(db);
+
+        let expected = std::fs::read(&copy.path).unwrap();
+        let actual = std::fs::read(&temp.path).unwrap();
+        assert_eq!(
+            live_bytes(&actual[1024..2048]),
+            live_bytes(&expected[1024..2048])
+        );
+    }
+
+    /// A copy of a table leaf page with only the bytes that are in use: the
+    /// page header, the cell pointer array, the cells and the freeblock
+    /// headers. Freed space holds stale bytes, or zeros in a build of SQLite
+    /// with secure delete.
+    fn live_bytes(page: &[u8]) -> Vec<u8> {
+        let header = DatabaseHeader {
+            page_size: page.len() as u16,
+            max_embed_frac: 64,
+            min_embed_frac: 32,
+            min_leaf_frac: 32,
+            ..Default::default()
+        };
+        let mut live = vec![0; page.len()];
+        let num_cells = u16::from_be_bytes([page[3], page[4]]) as usize;
+        live[..8 + 2 * num_cells].copy_from_slice(&page[..8 + 2 * num_cells]);
+        for i in 0..num_cells {
+            let offset = u16::from_be_bytes([page[8 + 2 * i], page[9 + 2 * i]]) as usize;
+            let cell = read_btree_cell(page, &PageType::TableLeaf, offset, &header).unwrap();
+            let size = cell.size();
+            live[offset..offset + size].copy_from_slice(&page[offset..offset + size]);
+        }
+        let mut freeblock = u16::from_be_bytes([page[1], page[2]]) as usize;
+        while freeblock != 0 {
+            live[freeblock..freeblock + 4].copy_from_slice(&page[freeblock..freeblock + 4]);
+            freeblock = u16::from_be_bytes([page[freeblock], page[freeblock + 1]]) as usize;
+        }
+        live
+    }
 }
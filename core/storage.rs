@@ -50,6 +50,14 @@ impl PageSource {
     ) -> Result<()> {
         self.io.write(page_idx, buffer, c)
     }
+
+    pub fn sync(&self) -> Result<()> {
+        self.io.sync()
+    }
+
+    pub fn truncate(&self, len: usize) -> Result<()> {
+        self.io.truncate(len)
+    }
 }
 
 pub trait PageIO {
@@ -60,6 +68,10 @@ pub trait PageIO {
         buffer: Rc<RefCell<Buffer>>,
         c: Rc<WriteCompletion>,
     ) -> Result<()>;
+    /// Flush completed writes to stable storage.
+    fn sync(&self) -> Result<()>;
+    /// Cut the database off after `len` bytes.
+    fn truncate(&self, len: usize) -> Result<()>;
 }
 
 #[cfg(feature = "fs")]
@@ -91,9 +103,19 @@ impl PageIO for FileStorage {
         assert!(buffer_size >= 512);
         assert!(buffer_size <= 65536);
         assert!((buffer_size & (buffer_size - 1)) == 0);
-        self.file.pwrite(page_idx, buffer, c)?;
+        assert!(page_idx > 0);
+        let pos = (page_idx - 1) * buffer_size;
+        self.file.pwrite(pos, buffer, c)?;
         Ok(())
     }
+
+    fn sync(&self) -> Result<()> {
+        self.file.sync()
+    }
+
+    fn truncate(&self, len: usize) -> Result<()> {
+        self.file.truncate(len)
+    }
 }
 
 #[cfg(feature = "fs")]
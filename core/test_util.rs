@@ -0,0 +1,97 @@
+//! Helpers for the tests that work with database files.
+
+use crate::io::{PlatformIO, IO};
+use crate::{Connection, Database, RowResult, Rows};
+use anyhow::Result;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+/// A database file in the temporary directory, which is deleted, along with
+/// its journal and log, when the guard is dropped, even if the test fails.
+pub struct TempDatabase {
+    pub path: PathBuf,
+}
+
+impl TempDatabase {
+    /// A database named after `name` that does not exist yet.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("limbo-{}-{}.db", name, std::process::id()));
+        let db = Self { path };
+        // Left behind by a test run that was killed.
+        db.remove_files();
+        db
+    }
+
+    /// A database that SQLite creates by running `sql`.
+    pub fn with_sql(name: &str, sql: &str) -> Self {
+        let db = Self::new(name);
+        db.sqlite().execute_batch(sql).unwrap();
+        db
+    }
+
+    pub fn path_str(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+
+    /// The path of the journal or log next to the database, such as `-wal`.
+    pub fn sibling(&self, suffix: &str) -> String {
+        format!("{}{}", self.path.display(), suffix)
+    }
+
+    /// Open the database with Limbo, on a new I/O backend.
+    pub fn open(&self) -> (Rc<dyn IO>, Database) {
+        let io: Rc<dyn IO> = Rc::new(PlatformIO::new().unwrap());
+        let db = Database::open_file(io.clone(), self.path_str()).unwrap();
+        (io, db)
+    }
+
+    /// Open the database with SQLite.
+    pub fn sqlite(&self) -> rusqlite::Connection {
+        rusqlite::Connection::open(&self.path).unwrap()
+    }
+
+    /// Check that SQLite finds the database intact.
+    pub fn check_integrity(&self) {
+        let integrity: String = self
+            .sqlite()
+            .query_row("PRAGMA integrity_check", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(integrity, "ok");
+    }
+
+    fn remove_files(&self) {
+        for suffix in ["", "-journal", "-wal", "-shm", "-vacuum"] {
+            let _ = std::fs::remove_file(self.sibling(suffix));
+        }
+    }
+}
+
+impl Drop for TempDatabase {
+    fn drop(&mut self) {
+        self.remove_files();
+    }
+}
+
+/// Step `rows` until the next row, and return its values as text.
+pub fn next_row(io: &Rc<dyn IO>, rows: &mut Rows) -> Result<Option<Vec<String>>> {
+    loop {
+        match rows.next()? {
+            RowResult::Row(row) => {
+                return Ok(Some(row.values.iter().map(|v| v.to_string()).collect()))
+            }
+            RowResult::IO => io.run_once()?,
+            RowResult::Done => return Ok(None),
+        }
+    }
+}
+
+/// Run `sql` to the end, and return its rows with their values as text.
+pub fn query(io: &Rc<dyn IO>, conn: &Connection, sql: &str) -> Result<Vec<Vec<String>>> {
+    let mut result = Vec::new();
+    if let Some(mut rows) = conn.query(sql)? {
+        while let Some(row) = next_row(io, &mut rows)? {
+            result.push(row);
+        }
+    }
+    Ok(result)
+}
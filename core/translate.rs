@@ -1,21 +1,37 @@
 use std::cell::RefCell;
 use std::rc::Rc;
 
-use crate::function::AggFunc;
-use crate::pager::Pager;
-use crate::schema::{Column, Schema, Table};
+use crate::function::{AggFunc, ScalarFunc};
+use crate::pager::{JournalMode, Pager};
+use crate::schema::{BTreeTable, Column, Index, Schema, Table};
+use crate::sorter::SortOrder;
 use crate::sqlite3_ondisk::{DatabaseHeader, MIN_PAGE_CACHE_SIZE};
 use crate::util::normalize_ident;
-use crate::vdbe::{Insn, Program, ProgramBuilder};
+use crate::vdbe::{BranchOffset, Insn, Program, ProgramBuilder};
+use crate::wal::CheckpointMode;
 use anyhow::Result;
+use log::trace;
 use sqlite3_parser::ast::{self, Expr};
+use sqlite3_parser::dialect::TokenType;
+use sqlite3_parser::lexer::sql::Tokenizer;
+use sqlite3_parser::lexer::Scanner;
 
 struct Select {
     columns: Vec<ast::ResultColumn>,
     column_info: Vec<ColumnInfo>,
     src_tables: Vec<SrcTable>, // Tables we use to get data from. This includes "from" and "joins"
     limit: Option<ast::Limit>,
+    where_clause: Option<ast::Expr>,
+    order_by: Option<Vec<ast::SortedColumn>>,
+    group_by: Option<ast::GroupBy>,
     exist_aggregation: bool,
+    /// Set when the aggregate that is the only result column is determined
+    /// by the first row the loop visits, so that the loop can stop there.
+    first_row_only: bool,
+    /// Expressions whose values are already held in registers, such as
+    /// aggregates and group keys while emitting the rows of a GROUP BY.
+    /// `translate_expr` copies these registers instead of evaluating them.
+    expr_registers: Vec<(ast::Expr, usize)>,
     /// Ordered list of opened read table loops
     /// Used for generating a loop that looks like this:
     /// cursor 0 = open table 0
@@ -26,22 +42,216 @@ struct Select {
     ///     end cursor 1
     /// end cursor 0
     loops: Vec<LoopInfo>,
+    dest: SelectDest,
+}
+
+impl Select {
+    /// A select with no tables or result columns, which serves as the
+    /// context for translating expressions that cannot refer to any columns.
+    fn empty(dest: SelectDest) -> Self {
+        Self {
+            columns: Vec::new(),
+            column_info: Vec::new(),
+            src_tables: Vec::new(),
+            limit: None,
+            where_clause: None,
+            order_by: None,
+            group_by: None,
+            exist_aggregation: false,
+            first_row_only: false,
+            expr_registers: Vec::new(),
+            loops: Vec::new(),
+            dest,
+        }
+    }
+
+    /// A select over the rows of `table` that satisfy `where_clause`, for
+    /// the statements that modify a table.
+    fn for_table(table: Rc<BTreeTable>, where_clause: Option<ast::Expr>, dest: SelectDest) -> Self {
+        Self {
+            src_tables: vec![SrcTable {
+                identifier: normalize_ident(&table.name),
+                table: Table::BTree(table),
+                join_info: None,
+                using_columns: Vec::new(),
+                index_scan: None,
+                rowid_scan: None,
+                reverse: false,
+            }],
+            where_clause,
+            ..Self::empty(dest)
+        }
+    }
+}
+
+/// Where the rows produced by a SELECT go.
+enum SelectDest {
+    /// Return them as result rows.
+    Output,
+    /// Insert them into a table, for INSERT INTO ... SELECT.
+    Insert(InsertTarget),
+    /// Delete the row of the only table that each one comes from, and its
+    /// entries in the given indexes, for DELETE.
+    Delete(Vec<IndexTarget>),
+    /// Rewrite the row of the only table that each one comes from, for UPDATE.
+    Update(UpdateTarget),
+}
+
+/// The table an INSERT writes to.
+struct InsertTarget {
+    table: Rc<BTreeTable>,
+    cursor_id: usize,
+    /// For each column of the table, the index of the inserted value that
+    /// supplies it, or `None` if the column is not in the column list and
+    /// gets its default value.
+    column_map: Vec<Option<usize>>,
+    /// The indexes of the table, which get an entry for each inserted row.
+    indexes: Vec<IndexTarget>,
+}
+
+/// The table an UPDATE writes to.
+struct UpdateTarget {
+    table: Rc<BTreeTable>,
+    /// For each column of the table, the expression it is set to, if any. The
+    /// rowid alias column is set through `rowid_value` instead.
+    values: Vec<Option<ast::Expr>>,
+    /// The expression the rowid is set to, if any.
+    rowid_value: Option<ast::Expr>,
+    /// The indexes whose entries may change.
+    indexes: Vec<IndexTarget>,
+    /// The sorter that collects the rowids of the rows to update, when the
+    /// rowid is set.
+    rowid_sorter: Option<SortInfo>,
+}
+
+/// An index that an INSERT, UPDATE or DELETE maintains the entries of.
+struct IndexTarget {
+    index: Rc<Index>,
+    /// For each column of the index, the index of the table column it holds.
+    columns: Vec<usize>,
+    cursor_id: usize,
+}
+
+impl IndexTarget {
+    fn new(table: &BTreeTable, index: &Rc<Index>) -> Result<Self> {
+        let mut columns = Vec::with_capacity(index.columns.len());
+        for column in &index.columns {
+            match table.get_column(&column.name) {
+                Some((idx, _)) => columns.push(idx),
+                None => anyhow::bail!(
+                    "Parse error: index {} refers to unknown column {}",
+                    index.name,
+                    column.name
+                ),
+            }
+        }
+        Ok(Self {
+            index: index.clone(),
+            columns,
+            cursor_id: 0,
+        })
+    }
 }
 
 struct LoopInfo {
-    table: Table,
+    // Offset that NextAwait jumps back to: the RewindAwait of a table scan, or
+    // the start of the loop body of an index scan.
     rewind_offset: usize,
     open_cursor: usize,
+    // Cursor on the index, for a loop that visits its table through an index.
+    index_cursor: Option<usize>,
+    // Label of the end of the loop, where an index scan jumps when it runs
+    // out of entries.
+    exit_label: BranchOffset,
+    // Label of the NextAsync instruction that advances this loop; rows that
+    // fail a condition jump here.
+    next_row_label: BranchOffset,
+    // For the right-hand table of a LEFT JOIN, the register that records
+    // whether any row matched the join constraint, and the offset of the loop
+    // body that follows the constraint check.
+    left_join_match_flag: Option<usize>,
+    body_offset: BranchOffset,
+}
+
+/// Cursors and registers used to sort the result rows of a SELECT with an
+/// ORDER BY clause. Each sorter record holds the sort keys followed by the
+/// result columns.
+struct SortInfo {
+    sorter_cursor: usize,
+    pseudo_cursor: usize,
+    // Register that SorterData writes the current sorter record into.
+    content_reg: usize,
+    num_keys: usize,
+    num_columns: usize,
 }
 
 struct SrcTable {
     table: Table,
+    // Name the table is referred to by in the query: its alias, if any,
+    // otherwise its name.
+    identifier: String,
     join_info: Option<ast::JoinedSelectTable>, // FIXME: preferably this should be a reference with lifetime == Select ast expr
+    // Columns matched by a USING or NATURAL join.
+    using_columns: Vec<UsingColumn>,
+    // The index used to find the rows of the table, if any.
+    index_scan: Option<IndexScan>,
+    // The rowids to seek to to find the rows of the table, if the loop does
+    // not scan the whole table or go through an index.
+    rowid_scan: Option<RowidScan>,
+    // Whether the loop visits the rows backwards, in descending rowid order.
+    reverse: bool,
+}
+
+impl SrcTable {
+    fn is_left_join(&self) -> bool {
+        matches!(
+            self.join_info,
+            Some(ast::JoinedSelectTable {
+                operator: ast::JoinOperator::TypedJoin {
+                    join_type: Some(ast::JoinType::Left | ast::JoinType::LeftOuter),
+                    ..
+                },
+                ..
+            })
+        )
+    }
+}
+
+/// An index that a loop uses to visit only the rows of its table that may
+/// satisfy the WHERE clause or join constraint, instead of the whole table.
+struct IndexScan {
+    index: Rc<Index>,
+    // Values that the leading columns of the index must equal, and the
+    // affinity each is compared with ('A' for none).
+    eq_values: Vec<ast::Expr>,
+    eq_affinities: String,
+    // Bounds on the column that follows them, whether each is inclusive and
+    // the affinity it is compared with.
+    lower_bound: Option<(ast::Expr, bool, char)>,
+    upper_bound: Option<(ast::Expr, bool, char)>,
+}
+
+/// The rowids that a loop seeks to instead of scanning its whole table: a
+/// single one, or a range of them.
+struct RowidScan {
+    eq_value: Option<ast::Expr>,
+    // Bounds of the range, and whether each is inclusive.
+    lower_bound: Option<(ast::Expr, bool)>,
+    upper_bound: Option<(ast::Expr, bool)>,
+}
+
+/// A column that a USING or NATURAL join matches on: column `right_column` of
+/// the joined table must equal column `left_column` of `src_tables[left_table]`.
+struct UsingColumn {
+    left_table: usize,
+    left_column: usize,
+    right_column: usize,
 }
 
 struct ColumnInfo {
     func: Option<AggFunc>,
     args: Option<Vec<ast::Expr>>,
+    distinct: bool, /* whether the aggregate only takes distinct arguments */
     columns_to_allocate: usize, /* number of result columns this col will result on */
 }
 
@@ -50,6 +260,7 @@ impl ColumnInfo {
         Self {
             func: None,
             args: None,
+            distinct: false,
             columns_to_allocate: 1,
         }
     }
@@ -63,6 +274,7 @@ impl ColumnInfo {
 pub fn translate(
     schema: &Schema,
     stmt: ast::Stmt,
+    sql: &str,
     database_header: Rc<RefCell<DatabaseHeader>>,
     pager: Rc<Pager>,
 ) -> Result<Program> {
@@ -72,6 +284,216 @@ pub fn translate(
             translate_select(select)
         }
         ast::Stmt::Pragma(name, body) => translate_pragma(&name, body, database_header, pager),
+        ast::Stmt::Insert {
+            with,
+            or_conflict,
+            tbl_name,
+            columns,
+            body,
+            returning,
+        } => {
+            if with.is_some() || or_conflict.is_some() || returning.is_some() {
+                anyhow::bail!("Parse error: WITH, OR and RETURNING are not supported in INSERT");
+            }
+            translate_insert(schema, &tbl_name, columns, body)
+        }
+        ast::Stmt::Delete {
+            with,
+            tbl_name,
+            indexed,
+            where_clause,
+            returning,
+            order_by,
+            limit,
+        } => {
+            if with.is_some()
+                || indexed.is_some()
+                || returning.is_some()
+                || order_by.is_some()
+                || limit.is_some()
+            {
+                anyhow::bail!(
+                    "Parse error: WITH, INDEXED BY, RETURNING, ORDER BY and LIMIT are not supported in DELETE"
+                );
+            }
+            translate_delete(schema, &tbl_name, where_clause)
+        }
+        ast::Stmt::Update {
+            with,
+            or_conflict,
+            tbl_name,
+            indexed,
+            sets,
+            from,
+            where_clause,
+            returning,
+            order_by,
+            limit,
+        } => {
+            if with.is_some()
+                || or_conflict.is_some()
+                || indexed.is_some()
+                || from.is_some()
+                || returning.is_some()
+                || order_by.is_some()
+                || limit.is_some()
+            {
+                anyhow::bail!(
+                    "Parse error: WITH, OR, INDEXED BY, FROM, RETURNING, ORDER BY and LIMIT are not supported in UPDATE"
+                );
+            }
+            translate_update(schema, &tbl_name, sets, where_clause)
+        }
+        ast::Stmt::CreateTable {
+            temporary,
+            if_not_exists,
+            tbl_name,
+            body,
+        } => {
+            if temporary {
+                anyhow::bail!("Parse error: TEMP tables are not supported");
+            }
+            translate_create_table(schema, sql, tbl_name, body, if_not_exists, database_header)
+        }
+        ast::Stmt::DropTable {
+            if_exists,
+            tbl_name,
+        } => translate_drop_table(schema, &tbl_name, if_exists, database_header),
+        ast::Stmt::CreateIndex {
+            unique,
+            if_not_exists,
+            idx_name,
+            tbl_name,
+            where_clause,
+            ..
+        } => {
+            if where_clause.is_some() {
+                // TODO: evaluate the WHERE clause to pick the rows to index
+                anyhow::bail!("Parse error: partial indexes are not supported");
+            }
+            translate_create_index(
+                schema,
+                sql,
+                unique,
+                idx_name,
+                tbl_name,
+                if_not_exists,
+                database_header,
+            )
+        }
+        ast::Stmt::DropIndex {
+            if_exists,
+            idx_name,
+        } => translate_drop_index(schema, &idx_name, if_exists, database_header),
+        // Without locking, DEFERRED, IMMEDIATE and EXCLUSIVE transactions are
+        // the same.
+        ast::Stmt::Begin(_, _) => Ok(translate_auto_commit(false, false)),
+        ast::Stmt::Commit(_) => Ok(translate_auto_commit(true, false)),
+        ast::Stmt::Rollback {
+            savepoint_name: None,
+            ..
+        } => Ok(translate_auto_commit(true, true)),
+        ast::Stmt::Rollback {
+            savepoint_name: Some(_),
+            ..
+        } => anyhow::bail!("Parse error: ROLLBACK TO is not supported"),
+        ast::Stmt::Vacuum(name, into) => translate_vacuum(name, into),
+        _ => todo!(),
+    }
+}
+
+/// Find the columns that `table` is joined on with USING or NATURAL, matching
+/// each against the first of the tables to its left that has it.
+fn resolve_using_columns(
+    left_tables: &[SrcTable],
+    table: &Table,
+    join: &ast::JoinedSelectTable,
+) -> Result<Vec<UsingColumn>> {
+    let natural = match &join.operator {
+        ast::JoinOperator::TypedJoin {
+            join_type:
+                Some(
+                    ast::JoinType::Right
+                    | ast::JoinType::RightOuter
+                    | ast::JoinType::Full
+                    | ast::JoinType::FullOuter,
+                ),
+            ..
+        } => {
+            anyhow::bail!("Parse error: RIGHT and FULL OUTER JOINs are not currently supported")
+        }
+        ast::JoinOperator::TypedJoin { natural, .. } => *natural,
+        ast::JoinOperator::Comma => false,
+    };
+    let find_left = |name: &str| {
+        left_tables.iter().enumerate().find_map(|(i, src)| {
+            src.table
+                .get_column(name)
+                .map(|(left_column, _)| (i, left_column))
+        })
+    };
+    let mut using_columns = Vec::new();
+    match &join.constraint {
+        Some(_) if natural => {
+            anyhow::bail!("Parse error: a NATURAL join may not have an ON or USING clause")
+        }
+        Some(ast::JoinConstraint::Using(names)) => {
+            for name in names {
+                match (find_left(&name.0), table.get_column(&name.0)) {
+                    (Some((left_table, left_column)), Some((right_column, _))) => {
+                        using_columns.push(UsingColumn {
+                            left_table,
+                            left_column,
+                            right_column,
+                        })
+                    }
+                    _ => anyhow::bail!(
+                        "Parse error: cannot join using column {} - column not present in both tables",
+                        name.0
+                    ),
+                }
+            }
+        }
+        Some(ast::JoinConstraint::On(_)) => {}
+        None if natural => {
+            for (right_column, col) in table.columns().iter().enumerate() {
+                if let Some((left_table, left_column)) = find_left(&col.name) {
+                    using_columns.push(UsingColumn {
+                        left_table,
+                        left_column,
+                        right_column,
+                    });
+                }
+            }
+        }
+        None => {}
+    }
+    Ok(using_columns)
+}
+
+/// Look up a table in the FROM clause. Returns the table and the name it is
+/// referred to by in the query.
+fn resolve_select_table(
+    schema: &Schema,
+    select_table: &ast::SelectTable,
+) -> Result<(Table, String)> {
+    match select_table {
+        ast::SelectTable::Table(name, alias, _) => {
+            if let Some(db_name) = &name.db_name {
+                if normalize_ident(&db_name.0) != "main" {
+                    anyhow::bail!("Parse error: unknown database {}", db_name.0);
+                }
+            }
+            let table = match schema.get_table(&name.name.0) {
+                Some(table) => table,
+                None => anyhow::bail!("Parse error: no such table: {}", name.name.0),
+            };
+            let identifier = match alias {
+                Some(ast::As::As(alias) | ast::As::Elided(alias)) => normalize_ident(&alias.0),
+                None => normalize_ident(&name.name.0),
+            };
+            Ok((Table::BTree(table), identifier))
+        }
         _ => todo!(),
     }
 }
@@ -81,61 +503,69 @@ fn build_select(schema: &Schema, select: ast::Select) -> Result<Select> {
         ast::OneSelect::Select {
             columns,
             from: Some(from),
+            where_clause,
+            group_by,
             ..
         } => {
-            let table_name = match from.select {
-                Some(select_table) => match *select_table {
-                    ast::SelectTable::Table(name, ..) => name.name,
-                    _ => todo!(),
-                },
+            let (table, identifier) = match from.select {
+                Some(select_table) => resolve_select_table(schema, &select_table)?,
                 None => todo!(),
             };
-            let table_name = table_name.0;
-            let table = match schema.get_table(&table_name) {
-                Some(table) => table,
-                None => anyhow::bail!("Parse error: no such table: {}", table_name),
-            };
             let mut joins = Vec::new();
             joins.push(SrcTable {
-                table: Table::BTree(table.clone()),
+                table,
+                identifier,
                 join_info: None,
+                using_columns: Vec::new(),
+                index_scan: None,
+                rowid_scan: None,
+                reverse: false,
             });
             match from.joins {
                 Some(selected_joins) => {
                     for join in selected_joins {
-                        let table_name = match &join.table {
-                            ast::SelectTable::Table(name, ..) => name.name.clone(),
-                            _ => todo!(),
-                        };
-                        let table_name = &table_name.0;
-                        let table = match schema.get_table(table_name) {
-                            Some(table) => table,
-                            None => anyhow::bail!("Parse error: no such table: {}", table_name),
-                        };
+                        let (table, identifier) = resolve_select_table(schema, &join.table)?;
+                        let using_columns = resolve_using_columns(&joins, &table, &join)?;
                         joins.push(SrcTable {
-                            table: Table::BTree(table),
+                            table,
+                            identifier,
                             join_info: Some(join.clone()),
+                            using_columns,
+                            index_scan: None,
+                            rowid_scan: None,
+                            reverse: false,
                         });
                     }
                 }
                 None => {}
             };
 
-            let table = Table::BTree(table);
             let column_info = analyze_columns(&columns, &joins);
             let exist_aggregation = column_info.iter().any(|info| info.func.is_some());
-            Ok(Select {
+            let mut select = Select {
                 columns,
                 column_info,
                 src_tables: joins,
                 limit: select.limit.clone(),
+                where_clause,
+                order_by: select.order_by.clone(),
+                group_by,
                 exist_aggregation,
-                loops: Vec::new(),
-            })
+                ..Select::empty(SelectDest::Output)
+            };
+            for src_idx in 0..select.src_tables.len() {
+                let (rowid_scan, index_scan) = choose_scan(schema, &select, src_idx);
+                select.src_tables[src_idx].rowid_scan = rowid_scan;
+                select.src_tables[src_idx].index_scan = index_scan;
+            }
+            choose_scan_order(&mut select);
+            Ok(select)
         }
         ast::OneSelect::Select {
             columns,
             from: None,
+            where_clause,
+            group_by,
             ..
         } => {
             let column_info = analyze_columns(&columns, &Vec::new());
@@ -143,21 +573,406 @@ fn build_select(schema: &Schema, select: ast::Select) -> Result<Select> {
             Ok(Select {
                 columns,
                 column_info,
-                src_tables: Vec::new(),
                 limit: select.limit.clone(),
+                where_clause,
+                order_by: select.order_by.clone(),
+                group_by,
                 exist_aggregation,
-                loops: Vec::new(),
+                ..Select::empty(SelectDest::Output)
             })
         }
         _ => todo!(),
     }
 }
 
+/// A column of a table, or its rowid.
+#[derive(Clone, Copy, PartialEq)]
+enum TableColumn {
+    Column(usize),
+    Rowid,
+}
+
+/// A term of the WHERE clause or of a join constraint that compares a column
+/// of a table with a value that is known before the table's loop starts.
+struct ColumnTerm {
+    column: TableColumn,
+    op: ast::Operator,
+    value: ast::Expr,
+    // The affinity that the comparison converts its operands with.
+    affinity: Option<char>,
+}
+
+/// Split a condition into the terms that are ANDed together.
+fn collect_and_terms<'a>(expr: &'a ast::Expr, terms: &mut Vec<&'a ast::Expr>) {
+    match expr {
+        ast::Expr::Binary(lhs, ast::Operator::And, rhs) => {
+            collect_and_terms(lhs, terms);
+            collect_and_terms(rhs, terms);
+        }
+        ast::Expr::Parenthesized(exprs) if exprs.len() == 1 => {
+            collect_and_terms(&exprs[0], terms);
+        }
+        _ => terms.push(expr),
+    }
+}
+
+/// The column of the `src_idx`th table that `expr` refers to, if any. The
+/// rowid alias column is taken to be the rowid.
+fn src_table_column(select: &Select, src_idx: usize, expr: &ast::Expr) -> Option<TableColumn> {
+    let (table_name, ident) = match expr {
+        ast::Expr::Id(ident) => (None, &ident.0),
+        ast::Expr::Qualified(table_name, ident) => (Some(table_name.0.as_str()), &ident.0),
+        ast::Expr::Parenthesized(exprs) if exprs.len() == 1 => {
+            return src_table_column(select, src_idx, &exprs[0]);
+        }
+        _ => return None,
+    };
+    match resolve_ident(table_name, ident, select).ok()? {
+        (idx, Some(column)) if idx == src_idx => {
+            let table = &select.src_tables[idx].table;
+            if table.column_is_rowid_alias(&table.columns()[column]) {
+                Some(TableColumn::Rowid)
+            } else {
+                Some(TableColumn::Column(column))
+            }
+        }
+        (idx, None) if idx == src_idx => Some(TableColumn::Rowid),
+        _ => None,
+    }
+}
+
+/// Whether `expr` only refers to tables whose loops enclose the loop of the
+/// `src_idx`th table, so that it can be evaluated before that loop starts.
+fn is_known_before_loop(select: &Select, src_idx: usize, expr: &ast::Expr) -> bool {
+    match expr {
+        ast::Expr::Literal(_) => true,
+        ast::Expr::Id(ident) => {
+            matches!(resolve_ident(None, &ident.0, select), Ok((idx, _)) if idx < src_idx)
+        }
+        ast::Expr::Qualified(table_name, ident) => matches!(
+            resolve_ident(Some(&table_name.0), &ident.0, select),
+            Ok((idx, _)) if idx < src_idx
+        ),
+        ast::Expr::Binary(lhs, _, rhs) => {
+            is_known_before_loop(select, src_idx, lhs) && is_known_before_loop(select, src_idx, rhs)
+        }
+        ast::Expr::Unary(_, expr) => is_known_before_loop(select, src_idx, expr),
+        ast::Expr::Parenthesized(exprs) => exprs
+            .iter()
+            .all(|expr| is_known_before_loop(select, src_idx, expr)),
+        ast::Expr::FunctionCall { name, args, .. } => {
+            let args: &[ast::Expr] = args.as_deref().unwrap_or_default();
+            resolve_agg_func(name, args.len()).is_none()
+                && args
+                    .iter()
+                    .all(|arg| is_known_before_loop(select, src_idx, arg))
+        }
+        _ => false,
+    }
+}
+
+/// The comparisons of a column of the `src_idx`th table that `term` makes.
+fn column_terms(select: &Select, src_idx: usize, term: &ast::Expr) -> Vec<ColumnTerm> {
+    let mut terms = Vec::new();
+    match term {
+        ast::Expr::Binary(lhs, op, rhs)
+            if matches!(
+                op,
+                ast::Operator::Equals
+                    | ast::Operator::Less
+                    | ast::Operator::LessEquals
+                    | ast::Operator::Greater
+                    | ast::Operator::GreaterEquals
+            ) =>
+        {
+            if let Some(column) = src_table_column(select, src_idx, lhs) {
+                if is_known_before_loop(select, src_idx, rhs) {
+                    terms.push(ColumnTerm {
+                        column,
+                        op: *op,
+                        value: *rhs.clone(),
+                        affinity: comparison_affinity(select, lhs, rhs),
+                    });
+                }
+            } else if let Some(column) = src_table_column(select, src_idx, rhs) {
+                if is_known_before_loop(select, src_idx, lhs) {
+                    let op = match op {
+                        ast::Operator::Less => ast::Operator::Greater,
+                        ast::Operator::LessEquals => ast::Operator::GreaterEquals,
+                        ast::Operator::Greater => ast::Operator::Less,
+                        ast::Operator::GreaterEquals => ast::Operator::LessEquals,
+                        op => *op,
+                    };
+                    terms.push(ColumnTerm {
+                        column,
+                        op,
+                        value: *lhs.clone(),
+                        affinity: comparison_affinity(select, lhs, rhs),
+                    });
+                }
+            }
+        }
+        ast::Expr::Between {
+            lhs,
+            not: false,
+            start,
+            end,
+        } => {
+            if let Some(column) = src_table_column(select, src_idx, lhs) {
+                if is_known_before_loop(select, src_idx, start)
+                    && is_known_before_loop(select, src_idx, end)
+                {
+                    terms.push(ColumnTerm {
+                        column,
+                        op: ast::Operator::GreaterEquals,
+                        value: *start.clone(),
+                        affinity: comparison_affinity(select, lhs, start),
+                    });
+                    terms.push(ColumnTerm {
+                        column,
+                        op: ast::Operator::LessEquals,
+                        value: *end.clone(),
+                        affinity: comparison_affinity(select, lhs, end),
+                    });
+                }
+            }
+        }
+        _ => {}
+    }
+    terms
+}
+
+/// Choose how the loop of the `src_idx`th table finds its rows, if not by
+/// scanning the whole table: by seeking to a rowid, through an index with
+/// equality terms, by seeking to a range of rowids or through an index with a
+/// range, in that order of preference.
+fn choose_scan(
+    schema: &Schema,
+    select: &Select,
+    src_idx: usize,
+) -> (Option<RowidScan>, Option<IndexScan>) {
+    let src_table = &select.src_tables[src_idx];
+    let Table::BTree(table) = &src_table.table else {
+        return (None, None);
+    };
+    let mut terms = Vec::new();
+    // The WHERE clause cannot narrow down the rows that the right-hand table
+    // of a LEFT JOIN is matched against.
+    if !src_table.is_left_join() {
+        if let Some(where_clause) = &select.where_clause {
+            collect_and_terms(where_clause, &mut terms);
+        }
+    }
+    if let Some(ast::JoinedSelectTable {
+        constraint: Some(ast::JoinConstraint::On(expr)),
+        ..
+    }) = &src_table.join_info
+    {
+        collect_and_terms(expr, &mut terms);
+    }
+    let column_terms: Vec<ColumnTerm> = terms
+        .iter()
+        .flat_map(|term| column_terms(select, src_idx, term))
+        .collect();
+    let rowid_scan = choose_rowid_scan(&column_terms);
+    let index_scan = choose_index(schema, table, &terms, &column_terms);
+    match (rowid_scan, index_scan) {
+        (Some(rowid_scan), Some(index_scan))
+            if rowid_scan.eq_value.is_none() && !index_scan.eq_values.is_empty() =>
+        {
+            (None, Some(index_scan))
+        }
+        (Some(rowid_scan), _) => (Some(rowid_scan), None),
+        (None, index_scan) => (None, index_scan),
+    }
+}
+
+/// Choose the rowids to seek to from the terms that compare the rowid of a
+/// table: a single one if there is an equality term, otherwise the range the
+/// other terms bound it to.
+fn choose_rowid_scan(column_terms: &[ColumnTerm]) -> Option<RowidScan> {
+    let find = |ops: &[ast::Operator]| {
+        column_terms
+            .iter()
+            .find(|term| term.column == TableColumn::Rowid && ops.contains(&term.op))
+    };
+    let scan = match find(&[ast::Operator::Equals]) {
+        Some(term) => RowidScan {
+            eq_value: Some(term.value.clone()),
+            lower_bound: None,
+            upper_bound: None,
+        },
+        None => RowidScan {
+            eq_value: None,
+            lower_bound: find(&[ast::Operator::Greater, ast::Operator::GreaterEquals])
+                .map(|term| (term.value.clone(), term.op == ast::Operator::GreaterEquals)),
+            upper_bound: find(&[ast::Operator::Less, ast::Operator::LessEquals])
+                .map(|term| (term.value.clone(), term.op == ast::Operator::LessEquals)),
+        },
+    };
+    if scan.eq_value.is_none() && scan.lower_bound.is_none() && scan.upper_bound.is_none() {
+        return None;
+    }
+    Some(scan)
+}
+
+/// Choose the index that best narrows down the rows of `table`, given the
+/// terms of the conditions its rows are checked against: the one with the
+/// most leading columns constrained by equality terms, followed by a range on
+/// the next column. Ties go to a unique index whose columns are all
+/// constrained by equality terms, as it matches at most one row. A term can
+/// only use an index whose order agrees with the affinity it compares with.
+fn choose_index(
+    schema: &Schema,
+    table: &BTreeTable,
+    terms: &[&ast::Expr],
+    column_terms: &[ColumnTerm],
+) -> Option<IndexScan> {
+    let score = |scan: &IndexScan| {
+        (
+            scan.eq_values.len(),
+            scan.lower_bound.is_some() as usize + scan.upper_bound.is_some() as usize,
+            scan.index.unique && scan.eq_values.len() == scan.index.columns.len(),
+        )
+    };
+    let mut best: Option<IndexScan> = None;
+    for index in schema.get_indexes(&table.name) {
+        // A partial index can only be used if the query implies its WHERE
+        // clause, which is checked here by looking for each of its terms.
+        if let Some(where_clause) = &index.where_clause {
+            let mut index_terms = Vec::new();
+            collect_and_terms(where_clause, &mut index_terms);
+            if !index_terms.iter().all(|term| terms.contains(term)) {
+                continue;
+            }
+        }
+        let mut scan = IndexScan {
+            index: index.clone(),
+            eq_values: Vec::new(),
+            eq_affinities: String::new(),
+            lower_bound: None,
+            upper_bound: None,
+        };
+        for index_column in &index.columns {
+            // Seeks compare keys in ascending binary order, so only the
+            // columns before a descending or non-binary one can be used.
+            if index_column.descending
+                || index_column
+                    .collation
+                    .as_ref()
+                    .is_some_and(|collation| collation != "binary")
+            {
+                break;
+            }
+            let Some((column, table_column)) = table.get_column(&index_column.name) else {
+                break;
+            };
+            let column_affinity = table_column.ty.affinity();
+            let find = |ops: &[ast::Operator]| {
+                column_terms.iter().find(|term| {
+                    term.column == TableColumn::Column(column)
+                        && ops.contains(&term.op)
+                        && index_affinity_ok(term.affinity, column_affinity)
+                })
+            };
+            if let Some(term) = find(&[ast::Operator::Equals]) {
+                scan.eq_values.push(term.value.clone());
+                scan.eq_affinities.push(term.affinity.unwrap_or('A'));
+                continue;
+            }
+            let bound = |term: &ColumnTerm, inclusive_op| {
+                let affinity = term.affinity.unwrap_or('A');
+                (term.value.clone(), term.op == inclusive_op, affinity)
+            };
+            scan.lower_bound = find(&[ast::Operator::Greater, ast::Operator::GreaterEquals])
+                .map(|term| bound(term, ast::Operator::GreaterEquals));
+            scan.upper_bound = find(&[ast::Operator::Less, ast::Operator::LessEquals])
+                .map(|term| bound(term, ast::Operator::LessEquals));
+            break;
+        }
+        if score(&scan) > (0, 0, false)
+            && best.as_ref().is_none_or(|best| score(&scan) > score(best))
+        {
+            best = Some(scan);
+        }
+    }
+    if let Some(scan) = &best {
+        trace!("Using index {} for table {}", scan.index.name, table.name);
+    }
+    best
+}
+
+/// Whether an index on a column with `column_affinity` orders its keys the way
+/// that a comparison converting its operands with `affinity` orders them.
+fn index_affinity_ok(affinity: Option<char>, column_affinity: char) -> bool {
+    let is_numeric = |affinity: char| matches!(affinity, 'C' | 'D' | 'E');
+    match affinity {
+        None | Some('A') => true,
+        Some('B') => column_affinity == 'B',
+        Some(affinity) => is_numeric(affinity) && is_numeric(column_affinity),
+    }
+}
+
+/// Make the loop of a single-table SELECT visit the rows in the order that is
+/// needed when that is rowid order: for an ORDER BY on the rowid, which then
+/// needs no sorting, or for min() or max() of the rowid as the only result
+/// column, which then only needs the first row the loop visits.
+fn choose_scan_order(select: &mut Select) {
+    if select.src_tables.len() != 1 || select.group_by.is_some() {
+        return;
+    }
+    let src_table = &select.src_tables[0];
+    if src_table.index_scan.is_some() {
+        return;
+    }
+    // Seeking to rowids only works forwards.
+    let can_reverse = src_table.rowid_scan.is_none();
+    if !select.exist_aggregation {
+        let Some([term]) = select.order_by.as_deref() else {
+            return;
+        };
+        // Terms that refer to result columns are left to the sorter.
+        if !matches!(resolve_order_by_term(select, &term.expr, 0), Ok(None))
+            || src_table_column(select, 0, &term.expr) != Some(TableColumn::Rowid)
+        {
+            return;
+        }
+        let reverse = term.order == Some(ast::SortOrder::Desc);
+        if !reverse || can_reverse {
+            select.src_tables[0].reverse = reverse;
+            select.order_by = None;
+        }
+        return;
+    }
+    let [info] = select.column_info.as_slice() else {
+        return;
+    };
+    let (Some(func), Some([arg])) = (&info.func, info.args.as_deref()) else {
+        return;
+    };
+    let reverse = match func {
+        AggFunc::Min => false,
+        AggFunc::Max => true,
+        _ => return,
+    };
+    if src_table_column(select, 0, arg) != Some(TableColumn::Rowid) || (reverse && !can_reverse) {
+        return;
+    }
+    select.src_tables[0].reverse = reverse;
+    select.first_row_only = true;
+}
+
 /// Generate code for a SELECT statement.
 fn translate_select(mut select: Select) -> Result<Program> {
     let mut program = ProgramBuilder::new();
     let init_offset = program.emit_placeholder();
     let start_offset = program.offset();
+    match &mut select.dest {
+        SelectDest::Insert(target) => translate_insert_open(&mut program, target),
+        SelectDest::Delete(indexes) => translate_indexes_open(&mut program, indexes),
+        SelectDest::Update(target) => translate_update_open(&mut program, target),
+        SelectDest::Output => {}
+    }
     let limit_reg = if let Some(limit) = &select.limit {
         assert!(limit.offset.is_none());
         let target_register = program.alloc_register();
@@ -181,22 +996,66 @@ fn translate_select(mut select: Select) -> Result<Program> {
         Some(0) => Some(program.emit_placeholder()),
         _ => None,
     };
-    let limit_insn = if !select.src_tables.is_empty() {
-        translate_tables_begin(&mut program, &mut select);
+    let limit_insn = if select.group_by.is_some() && !select.src_tables.is_empty() {
+        translate_group_by(&mut program, &mut select, limit_reg)?
+    } else if !select.src_tables.is_empty() {
+        // A single aggregate row needs no sorting.
+        let sort_info = match &select.order_by {
+            Some(order_by) if !select.exist_aggregation => {
+                Some(translate_sorter_open(&mut program, &select, order_by))
+            }
+            _ => None,
+        };
+
+        translate_tables_begin(&mut program, &mut select)?;
+
+        if let Some(where_clause) = &select.where_clause {
+            let jump_target_when_false = select.loops.last().unwrap().next_row_label;
+            translate_where(&mut program, &select, where_clause, jump_target_when_false)?;
+        }
 
         let (register_start, register_end) = translate_columns(&mut program, &select)?;
+        if select.first_row_only {
+            let exit_label = select.loops[0].exit_label;
+            program.emit_insn_with_label_dependency(
+                Insn::Goto {
+                    target_pc: exit_label,
+                },
+                exit_label,
+            );
+        }
 
         let mut limit_insn: Option<usize> = None;
-        if !select.exist_aggregation {
-            program.emit_insn(Insn::ResultRow {
-                start_reg: register_start,
-                count: register_end - register_start,
-            });
+        if let Some(sort_info) = &sort_info {
+            translate_sorter_insert(&mut program, &select, sort_info, register_start)?;
+        } else if !select.exist_aggregation {
+            emit_result_row(
+                &mut program,
+                &select,
+                register_start,
+                register_end - register_start,
+            )?;
             limit_insn = limit_reg.map(|_| program.emit_placeholder());
         }
 
         translate_tables_end(&mut program, &select);
 
+        if let SelectDest::Update(target) = &select.dest {
+            if let Some(sorter) = &target.rowid_sorter {
+                translate_update_sorted_rows(&mut program, &select, target, sorter)?;
+            }
+        }
+
+        if let Some(sort_info) = &sort_info {
+            limit_insn = translate_sorter_output(
+                &mut program,
+                &select,
+                sort_info,
+                register_start,
+                limit_reg,
+            )?;
+        }
+
         if select.exist_aggregation {
             let mut target = register_start;
             for info in &select.column_info {
@@ -209,23 +1068,36 @@ fn translate_select(mut select: Select) -> Result<Program> {
                 target += info.columns_to_allocate;
             }
             // only one result row
-            program.emit_insn(Insn::ResultRow {
-                start_reg: register_start,
-                count: register_end - register_start,
-            });
+            emit_result_row(
+                &mut program,
+                &select,
+                register_start,
+                register_end - register_start,
+            )?;
             limit_insn = limit_reg.map(|_| program.emit_placeholder());
         }
         limit_insn
     } else {
         assert!(!select.exist_aggregation);
+        let where_label = program.alloc_label();
+        if let Some(where_clause) = &select.where_clause {
+            translate_where(&mut program, &select, where_clause, where_label)?;
+        }
         let (register_start, register_end) = translate_columns(&mut program, &select)?;
-        program.emit_insn(Insn::ResultRow {
-            start_reg: register_start,
-            count: register_end - register_start,
-        });
-        limit_reg.map(|_| program.emit_placeholder())
+        emit_result_row(
+            &mut program,
+            &select,
+            register_start,
+            register_end - register_start,
+        )?;
+        let limit_insn = limit_reg.map(|_| program.emit_placeholder());
+        program.resolve_label(where_label, program.offset());
+        limit_insn
     };
-    program.emit_insn(Insn::Halt);
+    program.emit_insn(Insn::Halt {
+        err_code: 0,
+        description: String::new(),
+    });
     let halt_offset = program.offset() - 1;
     if let Some(limit_goto) = limit_goto {
         program.fixup_insn(
@@ -260,62 +1132,2717 @@ fn translate_select(mut select: Select) -> Result<Program> {
     Ok(program.build())
 }
 
-fn translate_tables_begin(program: &mut ProgramBuilder, select: &mut Select) {
-    for join in &select.src_tables {
-        let table = &join.table;
-        let loop_info = translate_table_open_cursor(program, table);
-        select.loops.push(loop_info);
-    }
-
-    for loop_info in &mut select.loops {
-        translate_table_open_loop(program, loop_info);
-    }
-}
-
-fn translate_tables_end(program: &mut ProgramBuilder, select: &Select) {
-    // iterate in reverse order as we open cursors in order
-    for table_loop in select.loops.iter().rev() {
-        let cursor_id = table_loop.open_cursor;
-        program.emit_insn(Insn::NextAsync { cursor_id });
-        program.emit_insn(Insn::NextAwait {
-            cursor_id,
-            pc_if_next: table_loop.rewind_offset,
-        });
-        program.fixup_insn(
-            table_loop.rewind_offset,
-            Insn::RewindAwait {
-                cursor_id: table_loop.open_cursor,
-                pc_if_empty: program.offset(),
-            },
-        );
+/// Emit a row produced by a SELECT to its destination.
+fn emit_result_row(
+    program: &mut ProgramBuilder,
+    select: &Select,
+    start_reg: usize,
+    count: usize,
+) -> Result<()> {
+    match &select.dest {
+        SelectDest::Output => program.emit_insn(Insn::ResultRow { start_reg, count }),
+        SelectDest::Insert(target) => translate_insert_row(program, target, start_reg)?,
+        SelectDest::Delete(indexes) => {
+            let cursor_id = select.loops[0].open_cursor;
+            for index in indexes {
+                let key_start =
+                    translate_index_key(program, &select.src_tables[0].table, cursor_id, index);
+                translate_index_delete(program, index, key_start);
+            }
+            program.emit_insn(Insn::DeleteAsync { cursor_id });
+            program.emit_insn(Insn::DeleteAwait { cursor_id });
+        }
+        SelectDest::Update(target) => match &target.rowid_sorter {
+            Some(sorter) => {
+                // The rows are updated once the loop is over, as a row that
+                // moves to a new rowid could be visited again.
+                let rowid_reg = program.alloc_register();
+                program.emit_insn(Insn::RowId {
+                    cursor_id: select.loops[0].open_cursor,
+                    dest: rowid_reg,
+                });
+                let record_reg = program.alloc_register();
+                program.emit_insn(Insn::MakeRecord {
+                    start_reg: rowid_reg,
+                    count: 1,
+                    dest_reg: record_reg,
+                });
+                program.emit_insn(Insn::SorterInsert {
+                    cursor_id: sorter.sorter_cursor,
+                    record_reg,
+                });
+            }
+            None => {
+                let next_row_label = select.loops[0].next_row_label;
+                translate_update_row(program, select, target, next_row_label)?;
+            }
+        },
     }
+    Ok(())
 }
 
-fn translate_table_open_cursor(program: &mut ProgramBuilder, table: &Table) -> LoopInfo {
-    let cursor_id = program.alloc_cursor_id();
-    let root_page = match table {
-        Table::BTree(btree) => btree.root_page,
-        Table::Pseudo(_) => todo!(),
+/// Look up the table that an INSERT, UPDATE or DELETE modifies.
+fn resolve_modified_table(
+    schema: &Schema,
+    tbl_name: &ast::QualifiedName,
+) -> Result<Rc<BTreeTable>> {
+    check_main_database(tbl_name)?;
+    let table = match schema.get_table(&tbl_name.name.0) {
+        Some(table) => table,
+        None => anyhow::bail!("Parse error: no such table: {}", tbl_name.name.0),
     };
-    program.emit_insn(Insn::OpenReadAsync {
-        cursor_id,
-        root_page,
-    });
-    program.emit_insn(Insn::OpenReadAwait);
-    LoopInfo {
-        table: table.clone(),
-        open_cursor: cursor_id,
-        rewind_offset: 0,
+    if table.name == "sqlite_schema" {
+        anyhow::bail!("Parse error: table {} may not be modified", table.name);
     }
+    Ok(table)
 }
 
-fn translate_table_open_loop(program: &mut ProgramBuilder, loop_info: &mut LoopInfo) {
-    program.emit_insn(Insn::RewindAsync {
-        cursor_id: loop_info.open_cursor,
-    });
-    let rewind_await_offset = program.emit_placeholder();
-    loop_info.rewind_offset = rewind_await_offset;
-}
+/// Generate code for an INSERT statement.
+fn translate_insert(
+    schema: &Schema,
+    tbl_name: &ast::QualifiedName,
+    columns: Option<Vec<ast::Name>>,
+    body: ast::InsertBody,
+) -> Result<Program> {
+    let table = resolve_modified_table(schema, tbl_name)?;
+    let (column_map, num_values) = match &columns {
+        Some(columns) => {
+            let mut column_map = vec![None; table.columns.len()];
+            for (i, name) in columns.iter().enumerate() {
+                match table.get_column(&name.0) {
+                    Some((idx, _)) => column_map[idx] = Some(i),
+                    None => anyhow::bail!(
+                        "Parse error: table {} has no column named {}",
+                        table.name,
+                        name.0
+                    ),
+                }
+            }
+            (column_map, columns.len())
+        }
+        None => (
+            (0..table.columns.len()).map(Some).collect(),
+            table.columns.len(),
+        ),
+    };
+    let check_value_count = |count: usize| -> Result<()> {
+        if count == num_values {
+            Ok(())
+        } else if columns.is_some() {
+            anyhow::bail!("Parse error: {} values for {} columns", count, num_values)
+        } else {
+            anyhow::bail!(
+                "Parse error: table {} has {} columns but {} values were supplied",
+                table.name,
+                num_values,
+                count
+            )
+        }
+    };
+
+    let mut indexes = Vec::new();
+    for index in schema.get_indexes(&table.name) {
+        if !is_plain_index(index) {
+            anyhow::bail!(
+                "Parse error: INSERT into table {} with index {} is not supported",
+                table.name,
+                index.name
+            );
+        }
+        indexes.push(IndexTarget::new(&table, index)?);
+    }
+    let mut target = InsertTarget {
+        table: table.clone(),
+        cursor_id: 0,
+        column_map,
+        indexes,
+    };
+    let rows = match body {
+        ast::InsertBody::Select(select, upsert) => {
+            if upsert.is_some() {
+                anyhow::bail!("Parse error: UPSERT is not supported");
+            }
+            match select.body.select {
+                ast::OneSelect::Values(rows)
+                    if select.body.compounds.is_none()
+                        && select.order_by.is_none()
+                        && select.limit.is_none() =>
+                {
+                    rows
+                }
+                _ => {
+                    let mut select = build_select(schema, select)?;
+                    if select.src_tables.iter().any(|src| match &src.table {
+                        Table::BTree(src) => Rc::ptr_eq(src, &table),
+                        Table::Pseudo(_) => false,
+                    }) {
+                        // TODO: copy the rows to a temporary table first
+                        anyhow::bail!(
+                            "Parse error: INSERT INTO {} SELECT from the same table is not supported",
+                            table.name
+                        );
+                    }
+                    check_value_count(
+                        select
+                            .column_info
+                            .iter()
+                            .map(|info| info.columns_to_allocate)
+                            .sum(),
+                    )?;
+                    select.dest = SelectDest::Insert(target);
+                    return translate_select(select);
+                }
+            }
+        }
+        ast::InsertBody::DefaultValues => {
+            target.column_map = vec![None; table.columns.len()];
+            vec![Vec::new()]
+        }
+    };
+    let mut program = ProgramBuilder::new();
+    let init_offset = program.emit_placeholder();
+    let start_offset = program.offset();
+    translate_insert_open(&mut program, &mut target);
+    // VALUES rows cannot refer to any columns.
+    let select = Select::empty(SelectDest::Output);
+    for row in rows {
+        if !row.is_empty() {
+            check_value_count(row.len())?;
+        }
+        let values_start = program.alloc_registers(row.len());
+        for (i, expr) in row.iter().enumerate() {
+            let _ = translate_expr(&mut program, &select, expr, values_start + i)?;
+        }
+        translate_insert_row(&mut program, &target, values_start)?;
+    }
+    program.emit_insn(Insn::Halt {
+        err_code: 0,
+        description: String::new(),
+    });
+    program.fixup_insn(
+        init_offset,
+        Insn::Init {
+            target_pc: program.offset(),
+        },
+    );
+    program.emit_insn(Insn::Transaction);
+    program.emit_insn(Insn::Goto {
+        target_pc: start_offset,
+    });
+    Ok(program.build())
+}
+
+/// Generate code for a DELETE statement: a loop over the rows of the table
+/// that satisfy the WHERE clause, like that of a SELECT, which deletes each
+/// row it visits.
+fn translate_delete(
+    schema: &Schema,
+    tbl_name: &ast::QualifiedName,
+    where_clause: Option<ast::Expr>,
+) -> Result<Program> {
+    let table = resolve_modified_table(schema, tbl_name)?;
+    let mut indexes = Vec::new();
+    for index in schema.get_indexes(&table.name) {
+        if !is_plain_index(index) {
+            anyhow::bail!(
+                "Parse error: DELETE from table {} with index {} is not supported",
+                table.name,
+                index.name
+            );
+        }
+        indexes.push(IndexTarget::new(&table, index)?);
+    }
+    let mut select = Select::for_table(table, where_clause, SelectDest::Delete(indexes));
+    let (rowid_scan, _) = choose_scan(schema, &select, 0);
+    select.src_tables[0].rowid_scan = rowid_scan;
+    translate_select(select)
+}
+
+/// Generate code for an UPDATE statement: a loop over the rows of the table
+/// that satisfy the WHERE clause, like that of a SELECT, which rewrites each
+/// row it visits. If the rowid is set, the loop only collects the rowids of
+/// the rows, which are then rewritten in a second loop.
+fn translate_update(
+    schema: &Schema,
+    tbl_name: &ast::QualifiedName,
+    sets: Vec<ast::Set>,
+    where_clause: Option<ast::Expr>,
+) -> Result<Program> {
+    let table = resolve_modified_table(schema, tbl_name)?;
+    if !table.has_rowid {
+        anyhow::bail!(
+            "Parse error: UPDATE of WITHOUT ROWID table {} is not supported",
+            table.name
+        );
+    }
+    let mut values = vec![None; table.columns.len()];
+    let mut rowid_value = None;
+    for set in sets {
+        let exprs = match set.expr {
+            ast::Expr::Parenthesized(exprs) if set.col_names.len() > 1 => exprs,
+            expr if set.col_names.len() == 1 => vec![expr],
+            _ => anyhow::bail!(
+                "Parse error: only a list of values can be assigned to a list of columns"
+            ),
+        };
+        if exprs.len() != set.col_names.len() {
+            anyhow::bail!(
+                "Parse error: {} columns assigned {} values",
+                set.col_names.len(),
+                exprs.len()
+            );
+        }
+        // A column that is set more than once gets the last value.
+        for (name, expr) in set.col_names.iter().zip(exprs) {
+            match table.get_column(&name.0) {
+                Some((_, column)) if table.column_is_rowid_alias(column) => {
+                    rowid_value = Some(expr)
+                }
+                Some((idx, _)) => values[idx] = Some(expr),
+                None if matches!(
+                    normalize_ident(&name.0).as_str(),
+                    "rowid" | "oid" | "_rowid_"
+                ) =>
+                {
+                    rowid_value = Some(expr)
+                }
+                None => anyhow::bail!("Parse error: no such column: {}", name.0),
+            }
+        }
+    }
+    // Every entry of an index holds the rowid, so all of them change along
+    // with it.
+    let mut indexes = Vec::new();
+    for index in schema.get_indexes(&table.name) {
+        let affected = rowid_value.is_some()
+            || index.columns.iter().any(|column| {
+                matches!(table.get_column(&column.name), Some((idx, _)) if values[idx].is_some())
+            });
+        if !affected {
+            continue;
+        }
+        if !is_plain_index(index) {
+            anyhow::bail!(
+                "Parse error: UPDATE of the columns of index {} is not supported",
+                index.name
+            );
+        }
+        indexes.push(IndexTarget::new(&table, index)?);
+    }
+    let mut select = Select::for_table(
+        table.clone(),
+        where_clause,
+        SelectDest::Update(UpdateTarget {
+            table,
+            values,
+            rowid_value,
+            indexes,
+            rowid_sorter: None,
+        }),
+    );
+    // An index scan could visit a row again after its entry moves ahead.
+    let (rowid_scan, _) = choose_scan(schema, &select, 0);
+    select.src_tables[0].rowid_scan = rowid_scan;
+    translate_select(select)
+}
+
+fn translate_update_open(program: &mut ProgramBuilder, target: &mut UpdateTarget) {
+    translate_indexes_open(program, &mut target.indexes);
+    if target.rowid_value.is_some() {
+        let sorter_cursor = program.alloc_cursor_id();
+        let pseudo_cursor = program.alloc_cursor_id();
+        let content_reg = program.alloc_register();
+        program.emit_insn(Insn::SorterOpen {
+            cursor_id: sorter_cursor,
+            columns: 1,
+            order: vec![SortOrder {
+                descending: false,
+                nulls_first: true,
+            }],
+        });
+        program.emit_insn(Insn::OpenPseudo {
+            cursor_id: pseudo_cursor,
+            content_reg,
+            num_fields: 1,
+        });
+        target.rowid_sorter = Some(SortInfo {
+            sorter_cursor,
+            pseudo_cursor,
+            content_reg,
+            num_keys: 1,
+            num_columns: 0,
+        });
+    }
+}
+
+/// Generate code that rewrites the rows whose rowids the loop of an UPDATE
+/// collected in the sorter.
+fn translate_update_sorted_rows(
+    program: &mut ProgramBuilder,
+    select: &Select,
+    target: &UpdateTarget,
+    sorter: &SortInfo,
+) -> Result<()> {
+    let end_label = program.alloc_label();
+    program.emit_insn_with_label_dependency(
+        Insn::SorterSort {
+            cursor_id: sorter.sorter_cursor,
+            pc_if_empty: end_label,
+        },
+        end_label,
+    );
+    let loop_start = program.offset();
+    program.emit_insn(Insn::SorterData {
+        cursor_id: sorter.sorter_cursor,
+        dest_reg: sorter.content_reg,
+        pseudo_cursor: sorter.pseudo_cursor,
+    });
+    let rowid_reg = program.alloc_register();
+    program.emit_insn(Insn::Column {
+        cursor_id: sorter.pseudo_cursor,
+        column: 0,
+        dest: rowid_reg,
+    });
+    let next_label = program.alloc_label();
+    program.emit_insn_with_label_dependency(
+        Insn::NotExists {
+            cursor_id: select.loops[0].open_cursor,
+            rowid_reg,
+            target_pc: next_label,
+        },
+        next_label,
+    );
+    translate_update_row(program, select, target, next_label)?;
+    program.resolve_label(next_label, program.offset());
+    program.emit_insn(Insn::SorterNext {
+        cursor_id: sorter.sorter_cursor,
+        pc_if_next: loop_start,
+    });
+    program.resolve_label(end_label, program.offset());
+    Ok(())
+}
+
+/// Generate code that rewrites the row the table cursor of an UPDATE is on,
+/// along with its index entries. `skip_label` is where to go if the row
+/// cannot be found again after checking that its new rowid is free.
+fn translate_update_row(
+    program: &mut ProgramBuilder,
+    select: &Select,
+    target: &UpdateTarget,
+    skip_label: BranchOffset,
+) -> Result<()> {
+    let table = &target.table;
+    let cursor_id = select.loops[0].open_cursor;
+    let old_rowid_reg = program.alloc_register();
+    program.emit_insn(Insn::RowId {
+        cursor_id,
+        dest: old_rowid_reg,
+    });
+    // All new values are computed from the row as it was.
+    let record_start = program.alloc_registers(table.columns.len());
+    let mut rowid_column = None;
+    for (i, (column, value)) in table.columns.iter().zip(&target.values).enumerate() {
+        if table.column_is_rowid_alias(column) {
+            rowid_column = Some(i);
+            continue;
+        }
+        match value {
+            Some(expr) => {
+                let _ = translate_expr(program, select, expr, record_start + i)?;
+            }
+            None => program.emit_insn(Insn::Column {
+                cursor_id,
+                column: i,
+                dest: record_start + i,
+            }),
+        }
+    }
+    let new_rowid_reg = match &target.rowid_value {
+        Some(expr) => {
+            let new_rowid_reg = program.alloc_register();
+            let _ = translate_expr(program, select, expr, new_rowid_reg)?;
+            program.emit_insn(Insn::MustBeInt { reg: new_rowid_reg });
+            new_rowid_reg
+        }
+        None => old_rowid_reg,
+    };
+    if let Some(i) = rowid_column {
+        program.emit_insn(Insn::Copy {
+            src_reg: new_rowid_reg,
+            dst_reg: record_start + i,
+            amount: 0,
+        });
+    }
+    translate_not_null_checks(program, table, record_start);
+    program.emit_insn(Insn::Affinity {
+        start_reg: record_start,
+        count: table.columns.len(),
+        affinities: table.columns.iter().map(|col| col.ty.affinity()).collect(),
+    });
+
+    // The old and new keys of each index: the values of its columns followed
+    // by the rowid.
+    let mut keys = Vec::with_capacity(target.indexes.len());
+    for index in &target.indexes {
+        let old_key_start =
+            translate_index_key(program, &select.src_tables[0].table, cursor_id, index);
+        let new_key_start =
+            translate_record_index_key(program, table, index, record_start, new_rowid_reg);
+        keys.push((old_key_start, new_key_start));
+    }
+
+    if target.rowid_value.is_some() {
+        let rowid_ok_label = program.alloc_label();
+        program.emit_insn_with_label_dependency(
+            Insn::Eq {
+                lhs: new_rowid_reg,
+                rhs: old_rowid_reg,
+                target_pc: rowid_ok_label,
+                jump_if_null: false,
+                affinity: None,
+            },
+            rowid_ok_label,
+        );
+        program.emit_insn_with_label_dependency(
+            Insn::NotExists {
+                cursor_id,
+                rowid_reg: new_rowid_reg,
+                target_pc: rowid_ok_label,
+            },
+            rowid_ok_label,
+        );
+        let column_name = rowid_column.map_or("rowid", |i| &table.columns[i].name);
+        program.emit_insn(Insn::Halt {
+            err_code: 1555, // SQLITE_CONSTRAINT_PRIMARYKEY
+            description: format!("UNIQUE constraint failed: {}.{}", table.name, column_name),
+        });
+        program.resolve_label(rowid_ok_label, program.offset());
+    }
+    for (index, (_, new_key_start)) in target.indexes.iter().zip(&keys) {
+        translate_unique_check(program, table, index, *new_key_start, Some(old_rowid_reg));
+    }
+
+    for (index, (old_key_start, _)) in target.indexes.iter().zip(&keys) {
+        translate_index_delete(program, index, *old_key_start);
+    }
+    if target.rowid_value.is_some() {
+        // Checking the new rowid moved the cursor off the row.
+        program.emit_insn_with_label_dependency(
+            Insn::NotExists {
+                cursor_id,
+                rowid_reg: old_rowid_reg,
+                target_pc: skip_label,
+            },
+            skip_label,
+        );
+        program.emit_insn(Insn::DeleteAsync { cursor_id });
+        program.emit_insn(Insn::DeleteAwait { cursor_id });
+    }
+    // The rowid alias column is stored as NULL; its value is the key. A row
+    // that keeps its rowid replaces the old one in place.
+    if let Some(i) = rowid_column {
+        program.emit_insn(Insn::Null {
+            dest: record_start + i,
+        });
+    }
+    let record_reg = program.alloc_register();
+    program.emit_insn(Insn::MakeRecord {
+        start_reg: record_start,
+        count: table.columns.len(),
+        dest_reg: record_reg,
+    });
+    program.emit_insn(Insn::InsertAsync {
+        cursor_id,
+        key_reg: new_rowid_reg,
+        record_reg,
+        flag: 0,
+    });
+    program.emit_insn(Insn::InsertAwait { cursor_id });
+    for (index, (_, new_key_start)) in target.indexes.iter().zip(&keys) {
+        translate_index_insert(program, index, *new_key_start);
+    }
+    Ok(())
+}
+
+/// Whether the entries of an index hold the plain values of its columns in
+/// ascending order, which is the only kind of index that statements that
+/// write to it can maintain.
+fn is_plain_index(index: &Index) -> bool {
+    // TODO: evaluate the WHERE clause of partial indexes and compare with the
+    // index's sort order and collation
+    index.where_clause.is_none()
+        && index.columns.iter().all(|column| {
+            !column.descending
+                && column
+                    .collation
+                    .as_ref()
+                    .is_none_or(|collation| collation.eq_ignore_ascii_case("binary"))
+        })
+}
+
+fn translate_indexes_open(program: &mut ProgramBuilder, indexes: &mut [IndexTarget]) {
+    for index in indexes {
+        index.cursor_id = program.alloc_cursor_id();
+        program.emit_insn(Insn::OpenWriteAsync {
+            cursor_id: index.cursor_id,
+            root_page: index.index.root_page,
+        });
+        program.emit_insn(Insn::OpenWriteAwait);
+    }
+}
+
+/// Generate code that loads the key of the index entry of the row a table
+/// cursor is on: the values of the index's columns followed by the rowid.
+/// Returns the first register of the key.
+fn translate_index_key(
+    program: &mut ProgramBuilder,
+    table: &Table,
+    cursor_id: usize,
+    index: &IndexTarget,
+) -> usize {
+    let key_start = program.alloc_registers(index.columns.len() + 1);
+    for (i, column) in index.columns.iter().enumerate() {
+        translate_table_column(program, table, cursor_id, *column, key_start + i);
+    }
+    program.emit_insn(Insn::RowId {
+        cursor_id,
+        dest: key_start + index.columns.len(),
+    });
+    key_start
+}
+
+/// Generate code that builds the key of the index entry of a row that is
+/// about to be written from the values in the registers starting at
+/// `record_start` and the rowid in `rowid_reg`. Returns the first register
+/// of the key.
+fn translate_record_index_key(
+    program: &mut ProgramBuilder,
+    table: &BTreeTable,
+    index: &IndexTarget,
+    record_start: usize,
+    rowid_reg: usize,
+) -> usize {
+    let key_start = program.alloc_registers(index.columns.len() + 1);
+    for (i, column) in index.columns.iter().enumerate() {
+        let src_reg = if table.column_is_rowid_alias(&table.columns[*column]) {
+            rowid_reg
+        } else {
+            record_start + column
+        };
+        program.emit_insn(Insn::Copy {
+            src_reg,
+            dst_reg: key_start + i,
+            amount: 0,
+        });
+    }
+    program.emit_insn(Insn::Copy {
+        src_reg: rowid_reg,
+        dst_reg: key_start + index.columns.len(),
+        amount: 0,
+    });
+    key_start
+}
+
+/// Generate code that fails with a constraint error if a unique index has an
+/// entry with the same values as the key starting at `key_start`, other than
+/// that of the row with the rowid in `own_rowid_reg`.
+fn translate_unique_check(
+    program: &mut ProgramBuilder,
+    table: &BTreeTable,
+    index: &IndexTarget,
+    key_start: usize,
+    own_rowid_reg: Option<usize>,
+) {
+    if !index.index.unique {
+        return;
+    }
+    let unique_ok_label = program.alloc_label();
+    program.emit_insn_with_label_dependency(
+        Insn::NoConflict {
+            cursor_id: index.cursor_id,
+            start_reg: key_start,
+            num_regs: index.columns.len(),
+            target_pc: unique_ok_label,
+        },
+        unique_ok_label,
+    );
+    if let Some(own_rowid_reg) = own_rowid_reg {
+        let conflict_rowid_reg = program.alloc_register();
+        program.emit_insn(Insn::IdxRowid {
+            cursor_id: index.cursor_id,
+            dest: conflict_rowid_reg,
+        });
+        program.emit_insn_with_label_dependency(
+            Insn::Eq {
+                lhs: conflict_rowid_reg,
+                rhs: own_rowid_reg,
+                target_pc: unique_ok_label,
+                jump_if_null: false,
+                affinity: None,
+            },
+            unique_ok_label,
+        );
+    }
+    program.emit_insn(unique_constraint_failed(&table.name, &index.index));
+    program.resolve_label(unique_ok_label, program.offset());
+}
+
+/// The instruction that fails a statement that would add a duplicate entry
+/// to a unique index.
+fn unique_constraint_failed(table_name: &str, index: &Index) -> Insn {
+    let columns: Vec<String> = index
+        .columns
+        .iter()
+        .map(|column| format!("{}.{}", table_name, column.name))
+        .collect();
+    Insn::Halt {
+        err_code: 2067, // SQLITE_CONSTRAINT_UNIQUE
+        description: format!("UNIQUE constraint failed: {}", columns.join(", ")),
+    }
+}
+
+fn translate_index_insert(program: &mut ProgramBuilder, index: &IndexTarget, key_start: usize) {
+    let key_reg = program.alloc_register();
+    program.emit_insn(Insn::MakeRecord {
+        start_reg: key_start,
+        count: index.columns.len() + 1,
+        dest_reg: key_reg,
+    });
+    program.emit_insn(Insn::IdxInsertAsync {
+        cursor_id: index.cursor_id,
+        record_reg: key_reg,
+    });
+    program.emit_insn(Insn::IdxInsertAwait {
+        cursor_id: index.cursor_id,
+    });
+}
+
+fn translate_index_delete(program: &mut ProgramBuilder, index: &IndexTarget, key_start: usize) {
+    program.emit_insn(Insn::IdxDeleteAsync {
+        cursor_id: index.cursor_id,
+        start_reg: key_start,
+        num_regs: index.columns.len() + 1,
+    });
+    program.emit_insn(Insn::IdxDeleteAwait {
+        cursor_id: index.cursor_id,
+    });
+}
+
+/// The name of a schema object as it is stored in `sqlite_schema`: without
+/// quotes, but in the case it was written in.
+fn schema_object_name(name: &ast::Name) -> String {
+    let name = name.0.as_str();
+    match name.chars().next() {
+        Some(quote @ ('"' | '\'' | '`')) if name.len() >= 2 && name.ends_with(quote) => {
+            name[1..name.len() - 1].replace(&format!("{quote}{quote}"), &quote.to_string())
+        }
+        Some('[') if name.ends_with(']') => name[1..name.len() - 1].to_string(),
+        _ => name.to_string(),
+    }
+}
+
+/// Check that a statement that changes the schema refers to the main
+/// database.
+fn check_main_database(name: &ast::QualifiedName) -> Result<()> {
+    match &name.db_name {
+        Some(db_name) if normalize_ident(&db_name.0) != "main" => {
+            anyhow::bail!("Parse error: unknown database {}", db_name.0)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The text that sqlite_schema stores for the CREATE statement in `sql`. Like
+/// SQLite, this is `prefix` in place of the words that come before the name
+/// of the object, which drops TEMP, IF NOT EXISTS and the database name,
+/// followed by the statement as it is written from the name on. The text of a
+/// table ends with its last token, and that of an index, as in SQLite, where
+/// the statement ends, including any whitespace before the semicolon.
+fn schema_sql(sql: &str, prefix: &str, is_index: bool) -> Result<String> {
+    let input = sql.as_bytes();
+    let mut scanner = Scanner::new(Tokenizer::new());
+    let mut tokens = Vec::new();
+    let stmt_end = loop {
+        match scanner.scan(input)? {
+            (_, None, _) => break input.len(),
+            (start, Some((_, TokenType::TK_SEMI)), _) if !tokens.is_empty() => break start,
+            (_, Some((_, TokenType::TK_SEMI)), _) => {}
+            (start, Some((_, token_type)), end) => tokens.push((token_type, start, end)),
+        }
+    };
+    let mut tokens = tokens
+        .iter()
+        .skip_while(|(token_type, _, _)| *token_type != TokenType::TK_CREATE)
+        .skip(1)
+        .peekable();
+    while tokens
+        .next_if(|(token_type, _, _)| {
+            matches!(token_type, TokenType::TK_TEMP | TokenType::TK_UNIQUE)
+        })
+        .is_some()
+    {}
+    tokens.next_if(|(token_type, _, _)| {
+        matches!(token_type, TokenType::TK_TABLE | TokenType::TK_INDEX)
+    });
+    let rest: Vec<_> = tokens.collect();
+    let mut name = 0;
+    if rest.len() > 3
+        && rest[0].0 == TokenType::TK_IF
+        && rest[1].0 == TokenType::TK_NOT
+        && rest[2].0 == TokenType::TK_EXISTS
+    {
+        name = 3;
+    }
+    if rest.len() > name + 2 && rest[name + 1].0 == TokenType::TK_DOT {
+        name += 2;
+    }
+    match (rest.get(name), rest.last()) {
+        (Some((_, start, _)), Some((_, _, end))) => {
+            let end = if is_index { stmt_end } else { *end };
+            Ok(format!("{} {}", prefix, &sql[*start..end]))
+        }
+        _ => anyhow::bail!("Parse error: incomplete CREATE statement"),
+    }
+}
+
+/// Generate a program that does nothing, for a statement whose IF EXISTS or
+/// IF NOT EXISTS clause makes it a no-op.
+fn translate_noop() -> Program {
+    let mut program = ProgramBuilder::new();
+    program.emit_insn(Insn::Halt {
+        err_code: 0,
+        description: String::new(),
+    });
+    program.build()
+}
+
+/// Generate code for BEGIN, COMMIT or ROLLBACK, which set the auto-commit flag
+/// of the connection.
+fn translate_auto_commit(auto_commit: bool, rollback: bool) -> Program {
+    let mut program = ProgramBuilder::new();
+    let init_offset = program.emit_placeholder();
+    let start_offset = program.offset();
+    program.emit_insn(Insn::AutoCommit {
+        auto_commit,
+        rollback,
+    });
+    program.emit_insn(Insn::Halt {
+        err_code: 0,
+        description: String::new(),
+    });
+    program.fixup_insn(
+        init_offset,
+        Insn::Init {
+            target_pc: program.offset(),
+        },
+    );
+    program.emit_insn(Insn::Goto {
+        target_pc: start_offset,
+    });
+    program.build()
+}
+
+/// Generate code for VACUUM, or VACUUM INTO the file that the expression
+/// names.
+fn translate_vacuum(name: Option<ast::Name>, into: Option<ast::Expr>) -> Result<Program> {
+    if let Some(name) = name {
+        if normalize_ident(&name.0) != "main" {
+            anyhow::bail!("Parse error: unknown database {}", name.0);
+        }
+    }
+    let mut program = ProgramBuilder::new();
+    let init_offset = program.emit_placeholder();
+    let start_offset = program.offset();
+    let into = match into {
+        Some(expr) => {
+            // The file name cannot refer to any columns.
+            let select = Select::empty(SelectDest::Output);
+            let reg = program.alloc_register();
+            translate_expr(&mut program, &select, &expr, reg)?;
+            Some(reg)
+        }
+        None => None,
+    };
+    program.emit_insn(Insn::Vacuum { into });
+    program.emit_insn(Insn::Halt {
+        err_code: 0,
+        description: String::new(),
+    });
+    program.fixup_insn(
+        init_offset,
+        Insn::Init {
+            target_pc: program.offset(),
+        },
+    );
+    program.emit_insn(Insn::Goto {
+        target_pc: start_offset,
+    });
+    Ok(program.build())
+}
+
+/// Generate code for a CREATE TABLE statement, which allocates the root page
+/// of the table, adds its row to `sqlite_schema` and then to the schema.
+fn translate_create_table(
+    schema: &Schema,
+    sql: &str,
+    tbl_name: ast::QualifiedName,
+    body: ast::CreateTableBody,
+    if_not_exists: bool,
+    database_header: Rc<RefCell<DatabaseHeader>>,
+) -> Result<Program> {
+    check_main_database(&tbl_name)?;
+    let name = schema_object_name(&tbl_name.name);
+    if name.to_lowercase().starts_with("sqlite_") {
+        anyhow::bail!(
+            "Parse error: object name reserved for internal use: {}",
+            name
+        );
+    }
+    if schema.get_table(&name).is_some() {
+        if if_not_exists {
+            return Ok(translate_noop());
+        }
+        anyhow::bail!("Parse error: table {} already exists", name);
+    }
+    if schema.get_index(&name).is_some() {
+        anyhow::bail!("Parse error: there is already an index named {}", name);
+    }
+    if let ast::CreateTableBody::ColumnsAndConstraints {
+        columns,
+        constraints,
+        ..
+    } = &body
+    {
+        // TODO: create the indexes that enforce these constraints
+        let unique = columns.iter().any(|column| {
+            column
+                .constraints
+                .iter()
+                .any(|c| matches!(c.constraint, ast::ColumnConstraint::Unique(_)))
+        }) || constraints
+            .iter()
+            .flatten()
+            .any(|c| matches!(c.constraint, ast::TableConstraint::Unique { .. }));
+        if unique {
+            anyhow::bail!("Parse error: UNIQUE constraints are not supported in CREATE TABLE");
+        }
+    } else {
+        anyhow::bail!("Parse error: CREATE TABLE ... AS SELECT is not supported");
+    }
+    let sql = schema_sql(sql, "CREATE TABLE", false)?;
+    let table = BTreeTable::from_sql(&sql, 0)?;
+    if !table.has_rowid {
+        anyhow::bail!("Parse error: WITHOUT ROWID tables are not supported");
+    }
+    if !table.primary_key_column_names.is_empty()
+        && !table
+            .columns
+            .iter()
+            .any(|column| table.column_is_rowid_alias(column))
+    {
+        // TODO: create the index that enforces the primary key
+        anyhow::bail!(
+            "Parse error: PRIMARY KEY that is not an INTEGER PRIMARY KEY is not supported"
+        );
+    }
+
+    let mut program = ProgramBuilder::new();
+    let init_offset = program.emit_placeholder();
+    let start_offset = program.offset();
+    let row_start = program.alloc_registers(5);
+    program.emit_insn(Insn::CreateBtree {
+        root_reg: row_start + 3,
+        is_index: false,
+    });
+    let cursor_id = program.alloc_cursor_id();
+    program.emit_insn(Insn::OpenWriteAsync {
+        cursor_id,
+        root_page: 1,
+    });
+    program.emit_insn(Insn::OpenWriteAwait);
+    for (value, offset) in [("table", 0), (name.as_str(), 1), (name.as_str(), 2)] {
+        program.emit_insn(Insn::String8 {
+            value: value.to_string(),
+            dest: row_start + offset,
+        });
+    }
+    program.emit_insn(Insn::String8 {
+        value: sql,
+        dest: row_start + 4,
+    });
+    let rowid_reg = program.alloc_register();
+    program.emit_insn(Insn::NewRowid {
+        cursor_id,
+        rowid_reg,
+    });
+    let record_reg = program.alloc_register();
+    program.emit_insn(Insn::MakeRecord {
+        start_reg: row_start,
+        count: 5,
+        dest_reg: record_reg,
+    });
+    program.emit_insn(Insn::InsertAsync {
+        cursor_id,
+        key_reg: rowid_reg,
+        record_reg,
+        flag: 0,
+    });
+    program.emit_insn(Insn::InsertAwait { cursor_id });
+    program.emit_insn(Insn::SetCookie {
+        value: database_header.borrow().schema_cookie.wrapping_add(1),
+    });
+    program.emit_insn(Insn::ParseSchema {
+        start_reg: row_start,
+    });
+    program.emit_insn(Insn::Halt {
+        err_code: 0,
+        description: String::new(),
+    });
+    program.fixup_insn(
+        init_offset,
+        Insn::Init {
+            target_pc: program.offset(),
+        },
+    );
+    program.emit_insn(Insn::Transaction);
+    program.emit_insn(Insn::Goto {
+        target_pc: start_offset,
+    });
+    Ok(program.build())
+}
+
+/// Generate code for a DROP TABLE statement, which frees the pages of the
+/// table and of its indexes, and removes their rows from `sqlite_schema`
+/// and the schema.
+fn translate_drop_table(
+    schema: &Schema,
+    tbl_name: &ast::QualifiedName,
+    if_exists: bool,
+    database_header: Rc<RefCell<DatabaseHeader>>,
+) -> Result<Program> {
+    check_main_database(tbl_name)?;
+    let name = match normalize_ident(&tbl_name.name.0).as_str() {
+        "sqlite_master" => "sqlite_schema".to_string(),
+        name => name.to_string(),
+    };
+    let table = match schema.get_table(&name) {
+        Some(table) => table,
+        None if if_exists => return Ok(translate_noop()),
+        None => anyhow::bail!("Parse error: no such table: {}", tbl_name.name.0),
+    };
+    if table.name.starts_with("sqlite_") {
+        let name = match table.name.as_str() {
+            "sqlite_schema" => "sqlite_master",
+            name => name,
+        };
+        anyhow::bail!("Parse error: table {} may not be dropped", name);
+    }
+
+    let mut program = ProgramBuilder::new();
+    let init_offset = program.emit_placeholder();
+    let start_offset = program.offset();
+    let cursor_id = program.alloc_cursor_id();
+    program.emit_insn(Insn::OpenWriteAsync {
+        cursor_id,
+        root_page: 1,
+    });
+    program.emit_insn(Insn::OpenWriteAwait);
+    let name_reg = program.alloc_register();
+    program.emit_insn(Insn::String8 {
+        value: table.name.clone(),
+        dest: name_reg,
+    });
+    // Delete every row that belongs to the table, including those of indexes
+    // without SQL that schema does not know about.
+    translate_schema_rows_delete(&mut program, cursor_id, None, 2, name_reg);
+    program.emit_insn(Insn::SetCookie {
+        value: database_header.borrow().schema_cookie.wrapping_add(1),
+    });
+    program.emit_insn(Insn::DropTable {
+        table_name: table.name.clone(),
+    });
+    program.emit_insn(Insn::Halt {
+        err_code: 0,
+        description: String::new(),
+    });
+    program.fixup_insn(
+        init_offset,
+        Insn::Init {
+            target_pc: program.offset(),
+        },
+    );
+    program.emit_insn(Insn::Transaction);
+    program.emit_insn(Insn::Goto {
+        target_pc: start_offset,
+    });
+    Ok(program.build())
+}
+
+/// Generate code that deletes the rows of `sqlite_schema` whose `column`, in
+/// lower case, equals the name in `name_reg`, and whose type is `ty` if one is
+/// given, and frees the pages of the objects they describe.
+fn translate_schema_rows_delete(
+    program: &mut ProgramBuilder,
+    cursor_id: usize,
+    ty: Option<&str>,
+    column: usize,
+    name_reg: usize,
+) {
+    let ty_reg = ty.map(|ty| {
+        let ty_reg = program.alloc_register();
+        program.emit_insn(Insn::String8 {
+            value: ty.to_string(),
+            dest: ty_reg,
+        });
+        ty_reg
+    });
+    let end_label = program.alloc_label();
+    program.emit_insn(Insn::RewindAsync { cursor_id });
+    program.emit_insn_with_label_dependency(
+        Insn::RewindAwait {
+            cursor_id,
+            pc_if_empty: end_label,
+        },
+        end_label,
+    );
+    let loop_start = program.offset();
+    let next_label = program.alloc_label();
+    if let Some(ty_reg) = ty_reg {
+        let row_ty_reg = program.alloc_register();
+        program.emit_insn(Insn::Column {
+            cursor_id,
+            column: 0,
+            dest: row_ty_reg,
+        });
+        program.emit_insn_with_label_dependency(
+            Insn::Ne {
+                lhs: row_ty_reg,
+                rhs: ty_reg,
+                target_pc: next_label,
+                jump_if_null: true,
+                affinity: None,
+            },
+            next_label,
+        );
+    }
+    let row_name_reg = program.alloc_register();
+    program.emit_insn(Insn::Column {
+        cursor_id,
+        column,
+        dest: row_name_reg,
+    });
+    program.emit_insn(Insn::Function {
+        start_reg: row_name_reg,
+        arg_count: 1,
+        dest: row_name_reg,
+        func: ScalarFunc::Lower,
+    });
+    program.emit_insn_with_label_dependency(
+        Insn::Ne {
+            lhs: row_name_reg,
+            rhs: name_reg,
+            target_pc: next_label,
+            jump_if_null: true,
+            affinity: None,
+        },
+        next_label,
+    );
+    // Objects without pages, such as triggers, have a root page of 0.
+    let root_reg = program.alloc_register();
+    program.emit_insn(Insn::Column {
+        cursor_id,
+        column: 3,
+        dest: root_reg,
+    });
+    let delete_label = program.alloc_label();
+    program.emit_insn_with_label_dependency(
+        Insn::IfNot {
+            reg: root_reg,
+            target_pc: delete_label,
+            jump_if_null: true,
+        },
+        delete_label,
+    );
+    program.emit_insn(Insn::Destroy { root_reg });
+    program.resolve_label(delete_label, program.offset());
+    program.emit_insn(Insn::DeleteAsync { cursor_id });
+    program.emit_insn(Insn::DeleteAwait { cursor_id });
+    program.resolve_label(next_label, program.offset());
+    program.emit_insn(Insn::NextAsync { cursor_id });
+    program.emit_insn(Insn::NextAwait {
+        cursor_id,
+        pc_if_next: loop_start,
+    });
+    program.resolve_label(end_label, program.offset());
+}
+
+/// Generate code for a CREATE INDEX statement. The keys of the rows of the
+/// table are collected in a sorter and added to the new index b-tree in
+/// order, after which the index is added to `sqlite_schema` and the schema.
+fn translate_create_index(
+    schema: &Schema,
+    sql: &str,
+    unique: bool,
+    idx_name: ast::QualifiedName,
+    tbl_name: ast::Name,
+    if_not_exists: bool,
+    database_header: Rc<RefCell<DatabaseHeader>>,
+) -> Result<Program> {
+    check_main_database(&idx_name)?;
+    let table = match schema.get_table(&tbl_name.0) {
+        Some(table) => table,
+        None => anyhow::bail!("Parse error: no such table: main.{}", tbl_name.0),
+    };
+    if table.name.starts_with("sqlite_") {
+        let name = match table.name.as_str() {
+            "sqlite_schema" => "sqlite_master",
+            name => name,
+        };
+        anyhow::bail!("Parse error: table {} may not be indexed", name);
+    }
+    let name = schema_object_name(&idx_name.name);
+    if name.to_lowercase().starts_with("sqlite_") {
+        anyhow::bail!(
+            "Parse error: object name reserved for internal use: {}",
+            name
+        );
+    }
+    if schema.get_table(&name).is_some() {
+        anyhow::bail!("Parse error: there is already a table named {}", name);
+    }
+    if schema.get_index(&name).is_some() {
+        if if_not_exists {
+            return Ok(translate_noop());
+        }
+        anyhow::bail!("Parse error: index {} already exists", name);
+    }
+    let sql = schema_sql(
+        sql,
+        if unique {
+            "CREATE UNIQUE INDEX"
+        } else {
+            "CREATE INDEX"
+        },
+        true,
+    )?;
+    let index = match Index::from_sql(&sql, 0) {
+        Ok(index) => Rc::new(index),
+        Err(_) => anyhow::bail!("Parse error: indexes on expressions are not supported"),
+    };
+    for column in &index.columns {
+        if table.get_column(&column.name).is_none() {
+            anyhow::bail!("Parse error: no such column: {}", column.name);
+        }
+    }
+    if !is_plain_index(&index) {
+        // TODO: sort and compare the keys in the index's order and collation
+        anyhow::bail!("Parse error: DESC and COLLATE are not supported in CREATE INDEX");
+    }
+    let mut index = IndexTarget::new(&table, &index)?;
+    let num_regs = index.columns.len() + 1;
+
+    let mut program = ProgramBuilder::new();
+    let init_offset = program.emit_placeholder();
+    let start_offset = program.offset();
+    let row_start = program.alloc_registers(5);
+    let root_reg = row_start + 3;
+    program.emit_insn(Insn::CreateBtree {
+        root_reg,
+        is_index: true,
+    });
+
+    // Collect the keys of the rows of the table in the sorter.
+    let sorter_cursor = program.alloc_cursor_id();
+    let pseudo_cursor = program.alloc_cursor_id();
+    let content_reg = program.alloc_register();
+    program.emit_insn(Insn::SorterOpen {
+        cursor_id: sorter_cursor,
+        columns: num_regs,
+        order: vec![
+            SortOrder {
+                descending: false,
+                nulls_first: true,
+            };
+            num_regs
+        ],
+    });
+    program.emit_insn(Insn::OpenPseudo {
+        cursor_id: pseudo_cursor,
+        content_reg,
+        num_fields: num_regs,
+    });
+    let table_cursor = program.alloc_cursor_id();
+    program.emit_insn(Insn::OpenReadAsync {
+        cursor_id: table_cursor,
+        root_page: table.root_page,
+    });
+    program.emit_insn(Insn::OpenReadAwait);
+    let sort_label = program.alloc_label();
+    program.emit_insn(Insn::RewindAsync {
+        cursor_id: table_cursor,
+    });
+    program.emit_insn_with_label_dependency(
+        Insn::RewindAwait {
+            cursor_id: table_cursor,
+            pc_if_empty: sort_label,
+        },
+        sort_label,
+    );
+    let scan_start = program.offset();
+    let key_start = translate_index_key(
+        &mut program,
+        &Table::BTree(table.clone()),
+        table_cursor,
+        &index,
+    );
+    let record_reg = program.alloc_register();
+    program.emit_insn(Insn::MakeRecord {
+        start_reg: key_start,
+        count: num_regs,
+        dest_reg: record_reg,
+    });
+    program.emit_insn(Insn::SorterInsert {
+        cursor_id: sorter_cursor,
+        record_reg,
+    });
+    program.emit_insn(Insn::NextAsync {
+        cursor_id: table_cursor,
+    });
+    program.emit_insn(Insn::NextAwait {
+        cursor_id: table_cursor,
+        pc_if_next: scan_start,
+    });
+    program.resolve_label(sort_label, program.offset());
+
+    // Add the keys to the index in order, so that each one goes to the end.
+    index.cursor_id = program.alloc_cursor_id();
+    program.emit_insn(Insn::OpenWriteRegAsync {
+        cursor_id: index.cursor_id,
+        root_reg,
+    });
+    program.emit_insn(Insn::OpenWriteAwait);
+    let build_end_label = program.alloc_label();
+    program.emit_insn_with_label_dependency(
+        Insn::SorterSort {
+            cursor_id: sorter_cursor,
+            pc_if_empty: build_end_label,
+        },
+        build_end_label,
+    );
+    let build_start = program.offset();
+    program.emit_insn(Insn::SorterData {
+        cursor_id: sorter_cursor,
+        dest_reg: content_reg,
+        pseudo_cursor,
+    });
+    if unique {
+        // A duplicate key follows the key it is equal to. The new b-tree is
+        // freed before failing, as nothing refers to it yet.
+        let key_start = program.alloc_registers(index.columns.len());
+        for i in 0..index.columns.len() {
+            program.emit_insn(Insn::Column {
+                cursor_id: pseudo_cursor,
+                column: i,
+                dest: key_start + i,
+            });
+        }
+        let unique_ok_label = program.alloc_label();
+        program.emit_insn_with_label_dependency(
+            Insn::NoConflict {
+                cursor_id: index.cursor_id,
+                start_reg: key_start,
+                num_regs: index.columns.len(),
+                target_pc: unique_ok_label,
+            },
+            unique_ok_label,
+        );
+        program.emit_insn(Insn::Destroy { root_reg });
+        program.emit_insn(unique_constraint_failed(&table.name, &index.index));
+        program.resolve_label(unique_ok_label, program.offset());
+    }
+    program.emit_insn(Insn::IdxInsertAsync {
+        cursor_id: index.cursor_id,
+        record_reg: content_reg,
+    });
+    program.emit_insn(Insn::IdxInsertAwait {
+        cursor_id: index.cursor_id,
+    });
+    program.emit_insn(Insn::SorterNext {
+        cursor_id: sorter_cursor,
+        pc_if_next: build_start,
+    });
+    program.resolve_label(build_end_label, program.offset());
+
+    let schema_cursor = program.alloc_cursor_id();
+    program.emit_insn(Insn::OpenWriteAsync {
+        cursor_id: schema_cursor,
+        root_page: 1,
+    });
+    program.emit_insn(Insn::OpenWriteAwait);
+    for (value, offset) in [
+        ("index", 0),
+        (name.as_str(), 1),
+        (table.name.as_str(), 2),
+        (sql.as_str(), 4),
+    ] {
+        program.emit_insn(Insn::String8 {
+            value: value.to_string(),
+            dest: row_start + offset,
+        });
+    }
+    let rowid_reg = program.alloc_register();
+    program.emit_insn(Insn::NewRowid {
+        cursor_id: schema_cursor,
+        rowid_reg,
+    });
+    let record_reg = program.alloc_register();
+    program.emit_insn(Insn::MakeRecord {
+        start_reg: row_start,
+        count: 5,
+        dest_reg: record_reg,
+    });
+    program.emit_insn(Insn::InsertAsync {
+        cursor_id: schema_cursor,
+        key_reg: rowid_reg,
+        record_reg,
+        flag: 0,
+    });
+    program.emit_insn(Insn::InsertAwait {
+        cursor_id: schema_cursor,
+    });
+    program.emit_insn(Insn::SetCookie {
+        value: database_header.borrow().schema_cookie.wrapping_add(1),
+    });
+    program.emit_insn(Insn::ParseSchema {
+        start_reg: row_start,
+    });
+    program.emit_insn(Insn::Halt {
+        err_code: 0,
+        description: String::new(),
+    });
+    program.fixup_insn(
+        init_offset,
+        Insn::Init {
+            target_pc: program.offset(),
+        },
+    );
+    program.emit_insn(Insn::Transaction);
+    program.emit_insn(Insn::Goto {
+        target_pc: start_offset,
+    });
+    Ok(program.build())
+}
+
+/// Generate code for a DROP INDEX statement, which frees the pages of the
+/// index and removes it from `sqlite_schema` and the schema.
+fn translate_drop_index(
+    schema: &Schema,
+    idx_name: &ast::QualifiedName,
+    if_exists: bool,
+    database_header: Rc<RefCell<DatabaseHeader>>,
+) -> Result<Program> {
+    check_main_database(idx_name)?;
+    let index = match schema.get_index(&idx_name.name.0) {
+        Some(index) => index,
+        None if normalize_ident(&idx_name.name.0).starts_with("sqlite_autoindex_") => {
+            anyhow::bail!(
+                "Parse error: index associated with UNIQUE or PRIMARY KEY constraint cannot be dropped"
+            )
+        }
+        None if if_exists => return Ok(translate_noop()),
+        None => anyhow::bail!("Parse error: no such index: {}", idx_name.name.0),
+    };
+
+    let mut program = ProgramBuilder::new();
+    let init_offset = program.emit_placeholder();
+    let start_offset = program.offset();
+    let cursor_id = program.alloc_cursor_id();
+    program.emit_insn(Insn::OpenWriteAsync {
+        cursor_id,
+        root_page: 1,
+    });
+    program.emit_insn(Insn::OpenWriteAwait);
+    let name_reg = program.alloc_register();
+    program.emit_insn(Insn::String8 {
+        value: index.name.clone(),
+        dest: name_reg,
+    });
+    translate_schema_rows_delete(&mut program, cursor_id, Some("index"), 1, name_reg);
+    program.emit_insn(Insn::SetCookie {
+        value: database_header.borrow().schema_cookie.wrapping_add(1),
+    });
+    program.emit_insn(Insn::DropIndex {
+        index_name: index.name.clone(),
+    });
+    program.emit_insn(Insn::Halt {
+        err_code: 0,
+        description: String::new(),
+    });
+    program.fixup_insn(
+        init_offset,
+        Insn::Init {
+            target_pc: program.offset(),
+        },
+    );
+    program.emit_insn(Insn::Transaction);
+    program.emit_insn(Insn::Goto {
+        target_pc: start_offset,
+    });
+    Ok(program.build())
+}
+
+fn translate_insert_open(program: &mut ProgramBuilder, target: &mut InsertTarget) {
+    target.cursor_id = program.alloc_cursor_id();
+    program.emit_insn(Insn::OpenWriteAsync {
+        cursor_id: target.cursor_id,
+        root_page: target.table.root_page,
+    });
+    program.emit_insn(Insn::OpenWriteAwait);
+    translate_indexes_open(program, &mut target.indexes);
+}
+
+/// Generate code that inserts a row into the target table, taking the
+/// inserted values from consecutive registers starting at `values_start`.
+fn translate_insert_row(
+    program: &mut ProgramBuilder,
+    target: &InsertTarget,
+    values_start: usize,
+) -> Result<()> {
+    let table = &target.table;
+    // Default values cannot refer to any columns.
+    let select = Select::empty(SelectDest::Output);
+    let rowid_reg = program.alloc_register();
+    let record_start = program.alloc_registers(table.columns.len());
+    let mut rowid_value = None;
+    for (i, (column, value)) in table.columns.iter().zip(&target.column_map).enumerate() {
+        // The rowid alias column is stored as NULL; its value is the key.
+        if table.column_is_rowid_alias(column) {
+            rowid_value = Some((column, *value));
+            program.emit_insn(Insn::Null {
+                dest: record_start + i,
+            });
+            continue;
+        }
+        match value {
+            Some(value) => program.emit_insn(Insn::Copy {
+                src_reg: values_start + value,
+                dst_reg: record_start + i,
+                amount: 0,
+            }),
+            None => match &column.default {
+                Some(default) => {
+                    translate_expr(program, &select, default, record_start + i)?;
+                }
+                None => program.emit_insn(Insn::Null {
+                    dest: record_start + i,
+                }),
+            },
+        }
+    }
+    translate_not_null_checks(program, table, record_start);
+    let make_record_label = program.alloc_label();
+    if let Some((column, Some(value))) = rowid_value {
+        // An explicit rowid is used as is, unless it is NULL.
+        program.emit_insn(Insn::Copy {
+            src_reg: values_start + value,
+            dst_reg: rowid_reg,
+            amount: 0,
+        });
+        let check_rowid_label = program.alloc_label();
+        program.emit_insn_with_label_dependency(
+            Insn::NotNull {
+                src: rowid_reg,
+                target_pc: check_rowid_label,
+            },
+            check_rowid_label,
+        );
+        program.emit_insn(Insn::NewRowid {
+            cursor_id: target.cursor_id,
+            rowid_reg,
+        });
+        program.emit_insn_with_label_dependency(
+            Insn::Goto {
+                target_pc: make_record_label,
+            },
+            make_record_label,
+        );
+        program.resolve_label(check_rowid_label, program.offset());
+        program.emit_insn(Insn::MustBeInt { reg: rowid_reg });
+        program.emit_insn_with_label_dependency(
+            Insn::NotExists {
+                cursor_id: target.cursor_id,
+                rowid_reg,
+                target_pc: make_record_label,
+            },
+            make_record_label,
+        );
+        program.emit_insn(Insn::Halt {
+            err_code: 1555, // SQLITE_CONSTRAINT_PRIMARYKEY
+            description: format!("UNIQUE constraint failed: {}.{}", table.name, column.name),
+        });
+    } else {
+        program.emit_insn(Insn::NewRowid {
+            cursor_id: target.cursor_id,
+            rowid_reg,
+        });
+    }
+    program.resolve_label(make_record_label, program.offset());
+    program.emit_insn(Insn::Affinity {
+        start_reg: record_start,
+        count: table.columns.len(),
+        affinities: table.columns.iter().map(|col| col.ty.affinity()).collect(),
+    });
+    let keys: Vec<usize> = target
+        .indexes
+        .iter()
+        .map(|index| translate_record_index_key(program, table, index, record_start, rowid_reg))
+        .collect();
+    for (index, key_start) in target.indexes.iter().zip(&keys) {
+        translate_unique_check(program, table, index, *key_start, None);
+    }
+    let record_reg = program.alloc_register();
+    program.emit_insn(Insn::MakeRecord {
+        start_reg: record_start,
+        count: table.columns.len(),
+        dest_reg: record_reg,
+    });
+    program.emit_insn(Insn::InsertAsync {
+        cursor_id: target.cursor_id,
+        key_reg: rowid_reg,
+        record_reg,
+        flag: 0,
+    });
+    program.emit_insn(Insn::InsertAwait {
+        cursor_id: target.cursor_id,
+    });
+    for (index, key_start) in target.indexes.iter().zip(&keys) {
+        translate_index_insert(program, index, *key_start);
+    }
+    Ok(())
+}
+
+/// Generate code that fails the statement if a NOT NULL column of the record
+/// in the registers starting at `record_start` is NULL. The rowid alias
+/// column is stored as NULL and is not checked.
+fn translate_not_null_checks(
+    program: &mut ProgramBuilder,
+    table: &BTreeTable,
+    record_start: usize,
+) {
+    for (i, column) in table.columns.iter().enumerate() {
+        if column.not_null && !table.column_is_rowid_alias(column) {
+            program.emit_insn(Insn::HaltIfNull {
+                reg: record_start + i,
+                err_code: 1299, // SQLITE_CONSTRAINT_NOTNULL
+                description: format!("NOT NULL constraint failed: {}.{}", table.name, column.name),
+            });
+        }
+    }
+}
+
+fn translate_sorter_open(
+    program: &mut ProgramBuilder,
+    select: &Select,
+    order_by: &[ast::SortedColumn],
+) -> SortInfo {
+    let sorter_cursor = program.alloc_cursor_id();
+    let pseudo_cursor = program.alloc_cursor_id();
+    let content_reg = program.alloc_register();
+    let num_keys = order_by.len();
+    let num_columns = select
+        .column_info
+        .iter()
+        .map(|col| col.columns_to_allocate)
+        .sum();
+    let order = order_by
+        .iter()
+        .map(|col| {
+            let descending = matches!(col.order, Some(ast::SortOrder::Desc));
+            // NULLs are the smallest values unless NULLS FIRST/LAST says otherwise.
+            let nulls_first = match col.nulls {
+                Some(ast::NullsOrder::First) => true,
+                Some(ast::NullsOrder::Last) => false,
+                None => !descending,
+            };
+            SortOrder {
+                descending,
+                nulls_first,
+            }
+        })
+        .collect();
+    program.emit_insn(Insn::SorterOpen {
+        cursor_id: sorter_cursor,
+        columns: num_keys + num_columns,
+        order,
+    });
+    program.emit_insn(Insn::OpenPseudo {
+        cursor_id: pseudo_cursor,
+        content_reg,
+        num_fields: num_keys + num_columns,
+    });
+    SortInfo {
+        sorter_cursor,
+        pseudo_cursor,
+        content_reg,
+        num_keys,
+        num_columns,
+    }
+}
+
+/// Generate code that inserts the current result row, prefixed with its sort
+/// keys, into the sorter.
+fn translate_sorter_insert(
+    program: &mut ProgramBuilder,
+    select: &Select,
+    sort_info: &SortInfo,
+    register_start: usize,
+) -> Result<()> {
+    let order_by = select.order_by.as_ref().unwrap();
+    let num_fields = sort_info.num_keys + sort_info.num_columns;
+    let key_start = program.alloc_registers(num_fields);
+    for (i, col) in order_by.iter().enumerate() {
+        let key_reg = key_start + i;
+        match resolve_order_by_term(select, &col.expr, i)? {
+            Some(column_reg) => program.emit_insn(Insn::Copy {
+                src_reg: register_start + column_reg,
+                dst_reg: key_reg,
+                amount: 0,
+            }),
+            None => {
+                let _ = translate_expr(program, select, &col.expr, key_reg)?;
+            }
+        }
+    }
+    if sort_info.num_columns > 0 {
+        program.emit_insn(Insn::Copy {
+            src_reg: register_start,
+            dst_reg: key_start + sort_info.num_keys,
+            amount: sort_info.num_columns - 1,
+        });
+    }
+    let record_reg = program.alloc_register();
+    program.emit_insn(Insn::MakeRecord {
+        start_reg: key_start,
+        count: num_fields,
+        dest_reg: record_reg,
+    });
+    program.emit_insn(Insn::SorterInsert {
+        cursor_id: sort_info.sorter_cursor,
+        record_reg,
+    });
+    Ok(())
+}
+
+/// Resolve an ORDER BY term that refers to a result column, either by its
+/// 1-based position or by its alias. Returns the offset of the result column
+/// register, or `None` if the term is an ordinary expression.
+fn resolve_order_by_term(select: &Select, expr: &ast::Expr, idx: usize) -> Result<Option<usize>> {
+    let num_columns: usize = select
+        .column_info
+        .iter()
+        .map(|col| col.columns_to_allocate)
+        .sum();
+    match expr {
+        ast::Expr::Literal(ast::Literal::Numeric(num)) => match num.parse::<i64>() {
+            Ok(pos) if pos >= 1 && pos as usize <= num_columns => Ok(Some(pos as usize - 1)),
+            Ok(_) => anyhow::bail!(
+                "Parse error: {} ORDER BY term out of range - should be between 1 and {}",
+                ordinal(idx + 1),
+                num_columns
+            ),
+            Err(_) => Ok(None),
+        },
+        ast::Expr::Id(ident) => {
+            let ident = normalize_ident(&ident.0);
+            let mut offset = 0;
+            for (col, info) in select.columns.iter().zip(select.column_info.iter()) {
+                if let ast::ResultColumn::Expr(
+                    _,
+                    Some(ast::As::As(alias) | ast::As::Elided(alias)),
+                ) = col
+                {
+                    if normalize_ident(&alias.0) == ident {
+                        return Ok(Some(offset));
+                    }
+                }
+                offset += info.columns_to_allocate;
+            }
+            Ok(None)
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Resolve a GROUP BY term that refers to a result column by its 1-based
+/// position, or by an alias that is not also a column name. Returns the
+/// expression of that result column, or `None` if the term is an ordinary
+/// expression.
+fn resolve_group_by_term(
+    select: &Select,
+    expr: &ast::Expr,
+    idx: usize,
+) -> Result<Option<ast::Expr>> {
+    match expr {
+        ast::Expr::Literal(ast::Literal::Numeric(num)) => match num.parse::<usize>() {
+            Ok(pos) if pos >= 1 && pos <= select.columns.len() => match &select.columns[pos - 1] {
+                ast::ResultColumn::Expr(expr, _) => Ok(Some(expr.clone())),
+                // Stars are expanded into their columns before GROUP BY terms
+                // are resolved.
+                _ => unreachable!(),
+            },
+            Ok(_) => anyhow::bail!(
+                "Parse error: {} GROUP BY term out of range - should be between 1 and {}",
+                ordinal(idx + 1),
+                select.columns.len()
+            ),
+            Err(_) => Ok(None),
+        },
+        ast::Expr::Id(ident) => Ok(resolve_alias(select, &ident.0).cloned()),
+        _ => Ok(None),
+    }
+}
+
+/// Replace the `*` and `table.*` result columns of `select` with a reference
+/// to each of the columns they stand for, in the order they are output.
+fn expand_star_columns(select: &mut Select) -> Result<()> {
+    let column_ref = |src_table: &SrcTable, column: &Column| {
+        ast::ResultColumn::Expr(
+            ast::Expr::Qualified(
+                ast::Name(src_table.identifier.clone()),
+                ast::Name(column.name.clone()),
+            ),
+            None,
+        )
+    };
+    let mut columns = Vec::new();
+    for col in &select.columns {
+        match col {
+            ast::ResultColumn::Expr(..) => columns.push(col.clone()),
+            ast::ResultColumn::Star => {
+                for src_table in &select.src_tables {
+                    for (i, column) in src_table.table.columns().iter().enumerate() {
+                        // Columns matched by a USING or NATURAL join are only
+                        // output for the left-hand table.
+                        if !src_table
+                            .using_columns
+                            .iter()
+                            .any(|using| using.right_column == i)
+                        {
+                            columns.push(column_ref(src_table, column));
+                        }
+                    }
+                }
+            }
+            ast::ResultColumn::TableStar(name) => {
+                let identifier = normalize_ident(&name.0);
+                let Some(src_table) = select
+                    .src_tables
+                    .iter()
+                    .find(|src| src.identifier == identifier)
+                else {
+                    anyhow::bail!("Parse error: no such table: {}", name.0);
+                };
+                for column in src_table.table.columns() {
+                    columns.push(column_ref(src_table, column));
+                }
+            }
+        }
+    }
+    select.column_info = analyze_columns(&columns, &select.src_tables);
+    select.columns = columns;
+    Ok(())
+}
+
+/// Find the expression of the result column aliased `name`, unless `name`
+/// is also a column of one of the source tables.
+fn resolve_alias<'a>(select: &'a Select, name: &str) -> Option<&'a ast::Expr> {
+    if select
+        .src_tables
+        .iter()
+        .any(|src| src.table.get_column(name).is_some())
+    {
+        return None;
+    }
+    let name = normalize_ident(name);
+    select.columns.iter().find_map(|col| match col {
+        ast::ResultColumn::Expr(expr, Some(ast::As::As(alias) | ast::As::Elided(alias)))
+            if normalize_ident(&alias.0) == name =>
+        {
+            Some(expr)
+        }
+        _ => None,
+    })
+}
+
+/// Replace references to result column aliases in `expr` with the aliased
+/// expressions.
+fn replace_aliases(expr: &mut ast::Expr, select: &Select) {
+    match expr {
+        ast::Expr::Id(ident) => {
+            if let Some(aliased) = resolve_alias(select, &ident.0) {
+                *expr = aliased.clone();
+            }
+        }
+        ast::Expr::FunctionCall { args, .. } => {
+            for arg in args.iter_mut().flatten() {
+                replace_aliases(arg, select);
+            }
+        }
+        ast::Expr::Binary(lhs, _, rhs) | ast::Expr::Like { lhs, rhs, .. } => {
+            replace_aliases(lhs, select);
+            replace_aliases(rhs, select);
+        }
+        ast::Expr::Unary(_, expr)
+        | ast::Expr::IsNull(expr)
+        | ast::Expr::NotNull(expr)
+        | ast::Expr::Cast { expr, .. }
+        | ast::Expr::Collate(expr, _) => replace_aliases(expr, select),
+        ast::Expr::Parenthesized(exprs) => {
+            for expr in exprs {
+                replace_aliases(expr, select);
+            }
+        }
+        ast::Expr::Between {
+            lhs, start, end, ..
+        } => {
+            replace_aliases(lhs, select);
+            replace_aliases(start, select);
+            replace_aliases(end, select);
+        }
+        ast::Expr::InList { lhs, rhs, .. } => {
+            replace_aliases(lhs, select);
+            for expr in rhs.iter_mut().flatten() {
+                replace_aliases(expr, select);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn ordinal(n: usize) -> String {
+    let suffix = match (n % 10, n % 100) {
+        (1, 11) | (2, 12) | (3, 13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    };
+    format!("{}{}", n, suffix)
+}
+
+/// Generate the loop that reads sorted rows back out of the sorter and emits
+/// them as result rows. Returns the placeholder for the LIMIT check, if any.
+fn translate_sorter_output(
+    program: &mut ProgramBuilder,
+    select: &Select,
+    sort_info: &SortInfo,
+    register_start: usize,
+    limit_reg: Option<usize>,
+) -> Result<Option<usize>> {
+    let end_label = program.alloc_label();
+    program.emit_insn_with_label_dependency(
+        Insn::SorterSort {
+            cursor_id: sort_info.sorter_cursor,
+            pc_if_empty: end_label,
+        },
+        end_label,
+    );
+    let loop_start = program.offset();
+    program.emit_insn(Insn::SorterData {
+        cursor_id: sort_info.sorter_cursor,
+        dest_reg: sort_info.content_reg,
+        pseudo_cursor: sort_info.pseudo_cursor,
+    });
+    for i in 0..sort_info.num_columns {
+        program.emit_insn(Insn::Column {
+            cursor_id: sort_info.pseudo_cursor,
+            column: sort_info.num_keys + i,
+            dest: register_start + i,
+        });
+    }
+    emit_result_row(program, select, register_start, sort_info.num_columns)?;
+    let limit_insn = limit_reg.map(|_| program.emit_placeholder());
+    program.emit_insn(Insn::SorterNext {
+        cursor_id: sort_info.sorter_cursor,
+        pc_if_next: loop_start,
+    });
+    program.resolve_label(end_label, program.offset());
+    Ok(limit_insn)
+}
+
+/// Generate code for a SELECT with a GROUP BY clause.
+///
+/// The table loops insert one sorter record per row holding the group keys
+/// followed by the aggregate arguments and the column references the output
+/// needs. The sorted records are then scanned in key order, accumulating
+/// aggregates; whenever the keys change, a subroutine emits the row of the
+/// group that just ended. Returns the placeholder for the LIMIT check, if any.
+fn translate_group_by(
+    program: &mut ProgramBuilder,
+    select: &mut Select,
+    limit_reg: Option<usize>,
+) -> Result<Option<usize>> {
+    expand_star_columns(select)?;
+    let mut group_by = select.group_by.clone().unwrap();
+    for (i, expr) in group_by.exprs.iter_mut().enumerate() {
+        if let Some(term) = resolve_group_by_term(select, expr, i)? {
+            *expr = term;
+        }
+    }
+    if let Some(having) = &mut group_by.having {
+        replace_aliases(having, select);
+    }
+    let mut aggregates = Vec::new();
+    let mut columns = Vec::new();
+    for col in &select.columns {
+        match col {
+            ast::ResultColumn::Expr(expr, _) => {
+                collect_aggregates_and_columns(expr, &mut aggregates, &mut columns)
+            }
+            ast::ResultColumn::Star | ast::ResultColumn::TableStar(_) => unreachable!(),
+        }
+    }
+    if let Some(having) = &group_by.having {
+        collect_aggregates_and_columns(having, &mut aggregates, &mut columns);
+    }
+    if let Some(order_by) = &select.order_by {
+        for (i, col) in order_by.iter().enumerate() {
+            if resolve_order_by_term(select, &col.expr, i)?.is_none() {
+                collect_aggregates_and_columns(&col.expr, &mut aggregates, &mut columns);
+            }
+        }
+    }
+    let num_keys = group_by.exprs.len();
+    let agg_args: Vec<ast::Expr> = aggregates
+        .iter()
+        .flat_map(|(_, info)| info.args.iter().flatten().cloned())
+        .collect();
+    // Sorter record layout: group keys, aggregate arguments, columns.
+    let inputs: Vec<&ast::Expr> = group_by
+        .exprs
+        .iter()
+        .chain(agg_args.iter())
+        .chain(columns.iter())
+        .collect();
+    let columns_start = num_keys + agg_args.len();
+
+    let sort_info = select
+        .order_by
+        .as_ref()
+        .map(|order_by| translate_sorter_open(program, select, order_by));
+    let sorter_cursor = program.alloc_cursor_id();
+    let pseudo_cursor = program.alloc_cursor_id();
+    let content_reg = program.alloc_register();
+    program.emit_insn(Insn::SorterOpen {
+        cursor_id: sorter_cursor,
+        columns: inputs.len(),
+        order: vec![
+            SortOrder {
+                descending: false,
+                nulls_first: true,
+            };
+            num_keys
+        ],
+    });
+    program.emit_insn(Insn::OpenPseudo {
+        cursor_id: pseudo_cursor,
+        content_reg,
+        num_fields: inputs.len(),
+    });
+
+    translate_tables_begin(program, select)?;
+    if let Some(where_clause) = &select.where_clause {
+        let jump_target_when_false = select.loops.last().unwrap().next_row_label;
+        translate_where(program, select, where_clause, jump_target_when_false)?;
+    }
+    let input_start = program.alloc_registers(inputs.len());
+    for (i, expr) in inputs.iter().enumerate() {
+        let _ = translate_expr(program, select, expr, input_start + i)?;
+    }
+    let record_reg = program.alloc_register();
+    program.emit_insn(Insn::MakeRecord {
+        start_reg: input_start,
+        count: inputs.len(),
+        dest_reg: record_reg,
+    });
+    program.emit_insn(Insn::SorterInsert {
+        cursor_id: sorter_cursor,
+        record_reg,
+    });
+    translate_tables_end(program, select);
+
+    // Scan the sorted records one group at a time.
+    let prev_keys = program.alloc_registers(num_keys);
+    let row_start = program.alloc_registers(inputs.len());
+    let acc_start = program.alloc_registers(aggregates.len());
+    let columns_reg = program.alloc_registers(columns.len());
+    // Set once the current group has at least one row.
+    let data_flag = program.alloc_register();
+    let return_reg = program.alloc_register();
+    let output_label = program.alloc_label();
+    let end_label = program.alloc_label();
+    program.emit_insn(Insn::Integer {
+        value: 0,
+        dest: data_flag,
+    });
+    program.emit_insn_with_label_dependency(
+        Insn::SorterSort {
+            cursor_id: sorter_cursor,
+            pc_if_empty: end_label,
+        },
+        end_label,
+    );
+    let loop_start = program.offset();
+    program.emit_insn(Insn::SorterData {
+        cursor_id: sorter_cursor,
+        dest_reg: content_reg,
+        pseudo_cursor,
+    });
+    for i in 0..inputs.len() {
+        program.emit_insn(Insn::Column {
+            cursor_id: pseudo_cursor,
+            column: i,
+            dest: row_start + i,
+        });
+    }
+    let new_group_label = program.alloc_label();
+    let accumulate_label = program.alloc_label();
+    program.emit_insn(Insn::Compare {
+        start_reg_a: prev_keys,
+        start_reg_b: row_start,
+        count: num_keys,
+    });
+    program.emit_insn_with_label_dependency(
+        Insn::Jump {
+            target_pc_lt: new_group_label,
+            target_pc_eq: program.offset() + 1,
+            target_pc_gt: new_group_label,
+        },
+        new_group_label,
+    );
+    // The keys of the very first row may compare equal to the initial NULLs.
+    program.emit_insn_with_label_dependency(
+        Insn::If {
+            reg: data_flag,
+            target_pc: accumulate_label,
+            jump_if_null: false,
+        },
+        accumulate_label,
+    );
+    program.resolve_label(new_group_label, program.offset());
+    program.emit_insn_with_label_dependency(
+        Insn::Gosub {
+            target_pc: output_label,
+            return_reg,
+        },
+        output_label,
+    );
+    program.emit_insn(Insn::Copy {
+        src_reg: row_start,
+        dst_reg: prev_keys,
+        amount: num_keys - 1,
+    });
+    for i in 0..aggregates.len() {
+        program.emit_insn(Insn::Null {
+            dest: acc_start + i,
+        });
+    }
+    // Column references take their values from the first row of the group.
+    if !columns.is_empty() {
+        program.emit_insn(Insn::Copy {
+            src_reg: row_start + columns_start,
+            dst_reg: columns_reg,
+            amount: columns.len() - 1,
+        });
+    }
+    program.emit_insn(Insn::Integer {
+        value: 1,
+        dest: data_flag,
+    });
+    program.resolve_label(accumulate_label, program.offset());
+    select.expr_registers = inputs
+        .iter()
+        .enumerate()
+        .map(|(i, expr)| ((*expr).clone(), row_start + i))
+        .collect();
+    for (i, (expr, info)) in aggregates.iter().enumerate() {
+        let _ = translate_aggregation(program, select, expr, info, acc_start + i)?;
+    }
+    program.emit_insn(Insn::SorterNext {
+        cursor_id: sorter_cursor,
+        pc_if_next: loop_start,
+    });
+    program.emit_insn_with_label_dependency(
+        Insn::Gosub {
+            target_pc: output_label,
+            return_reg,
+        },
+        output_label,
+    );
+    program.emit_insn_with_label_dependency(
+        Insn::Goto {
+            target_pc: end_label,
+        },
+        end_label,
+    );
+
+    // Subroutine that emits the row of the group that just ended.
+    program.resolve_label(output_label, program.offset());
+    let return_label = program.alloc_label();
+    program.emit_insn_with_label_dependency(
+        Insn::IfNot {
+            reg: data_flag,
+            target_pc: return_label,
+            jump_if_null: true,
+        },
+        return_label,
+    );
+    for (i, (_, info)) in aggregates.iter().enumerate() {
+        program.emit_insn(Insn::AggFinal {
+            register: acc_start + i,
+            func: info.func.clone().unwrap(),
+        });
+    }
+    select.expr_registers = group_by
+        .exprs
+        .iter()
+        .enumerate()
+        .map(|(i, expr)| (expr.clone(), prev_keys + i))
+        .chain(
+            aggregates
+                .iter()
+                .enumerate()
+                .map(|(i, (expr, _))| (expr.clone(), acc_start + i)),
+        )
+        .chain(
+            columns
+                .iter()
+                .enumerate()
+                .map(|(i, expr)| (expr.clone(), columns_reg + i)),
+        )
+        .collect();
+    if let Some(having) = &group_by.having {
+        translate_where(program, select, having, return_label)?;
+    }
+    let register_start = program.alloc_registers(select.columns.len());
+    for (i, col) in select.columns.iter().enumerate() {
+        if let ast::ResultColumn::Expr(expr, _) = col {
+            let _ = translate_expr(program, select, expr, register_start + i)?;
+        }
+    }
+    let mut limit_insn = None;
+    if let Some(sort_info) = &sort_info {
+        translate_sorter_insert(program, select, sort_info, register_start)?;
+    } else {
+        emit_result_row(program, select, register_start, select.columns.len())?;
+        limit_insn = limit_reg.map(|_| program.emit_placeholder());
+    }
+    program.resolve_label(return_label, program.offset());
+    program.emit_insn(Insn::Return { return_reg });
+    select.expr_registers.clear();
+    program.resolve_label(end_label, program.offset());
+
+    if let Some(sort_info) = &sort_info {
+        limit_insn =
+            translate_sorter_output(program, select, sort_info, register_start, limit_reg)?;
+    }
+    Ok(limit_insn)
+}
+
+fn translate_tables_begin(program: &mut ProgramBuilder, select: &mut Select) -> Result<()> {
+    let write = matches!(select.dest, SelectDest::Delete(_) | SelectDest::Update(_));
+    for join in &select.src_tables {
+        let loop_info = translate_table_open_cursor(program, join, write);
+        select.loops.push(loop_info);
+    }
+
+    for i in 0..select.loops.len() {
+        if select.loops[i].index_cursor.is_some() {
+            let body_offset = translate_index_open_loop(program, select, i)?;
+            select.loops[i].rewind_offset = body_offset;
+        } else if select.src_tables[i].rowid_scan.is_some() {
+            let body_offset = translate_rowid_open_loop(program, select, i)?;
+            select.loops[i].rewind_offset = body_offset;
+        } else {
+            let reverse = select.src_tables[i].reverse;
+            translate_table_open_loop(program, &mut select.loops[i], reverse);
+        }
+        translate_join_constraint(program, select, i)?;
+        if let Some(match_flag) = select.loops[i].left_join_match_flag {
+            program.emit_insn(Insn::Integer {
+                value: 1,
+                dest: match_flag,
+            });
+        }
+        select.loops[i].body_offset = program.offset();
+    }
+    Ok(())
+}
+
+/// Generate code that skips rows of the `idx`th table that do not satisfy
+/// its join constraint.
+fn translate_join_constraint(
+    program: &mut ProgramBuilder,
+    select: &Select,
+    idx: usize,
+) -> Result<()> {
+    let src_table = &select.src_tables[idx];
+    let next_row_label = select.loops[idx].next_row_label;
+    if let Some(ast::JoinedSelectTable {
+        constraint: Some(ast::JoinConstraint::On(expr)),
+        ..
+    }) = &src_table.join_info
+    {
+        translate_where(program, select, expr, next_row_label)?;
+    }
+    for using in &src_table.using_columns {
+        let left_reg = program.alloc_register();
+        let right_reg = program.alloc_register();
+        let left_table = &select.src_tables[using.left_table].table;
+        translate_table_column(
+            program,
+            left_table,
+            select.loops[using.left_table].open_cursor,
+            using.left_column,
+            left_reg,
+        );
+        translate_table_column(
+            program,
+            &src_table.table,
+            select.loops[idx].open_cursor,
+            using.right_column,
+            right_reg,
+        );
+        // Both sides are columns, so they compare numerically if either is.
+        let affinity = [
+            left_table.columns()[using.left_column].ty.affinity(),
+            src_table.table.columns()[using.right_column].ty.affinity(),
+        ]
+        .into_iter()
+        .find(|affinity| matches!(affinity, 'C' | 'D' | 'E'))
+        .map(|_| 'C');
+        let jump_target_when_true = program.alloc_label();
+        emit_comparison(
+            program,
+            &ast::Operator::Equals,
+            left_reg,
+            right_reg,
+            affinity,
+            ConditionMetadata {
+                jump_if_condition_is_true: false,
+                jump_target_when_true,
+                jump_target_when_false: next_row_label,
+            },
+        );
+        program.resolve_label(jump_target_when_true, program.offset());
+    }
+    Ok(())
+}
+
+fn translate_tables_end(program: &mut ProgramBuilder, select: &Select) {
+    // iterate in reverse order as we open cursors in order
+    for (table_loop, src_table) in select.loops.iter().zip(&select.src_tables).rev() {
+        let cursor_id = table_loop.open_cursor;
+        let loop_cursor_id = table_loop.index_cursor.unwrap_or(cursor_id);
+        program.resolve_label(table_loop.next_row_label, program.offset());
+        // A loop that seeks to a single rowid visits at most one row.
+        let single_row = matches!(
+            src_table.rowid_scan,
+            Some(RowidScan {
+                eq_value: Some(_),
+                ..
+            })
+        );
+        let pc_if_next = table_loop.rewind_offset as BranchOffset;
+        if src_table.reverse {
+            program.emit_insn(Insn::PrevAsync {
+                cursor_id: loop_cursor_id,
+            });
+            program.emit_insn(Insn::PrevAwait {
+                cursor_id: loop_cursor_id,
+                pc_if_next,
+            });
+        } else if !single_row {
+            program.emit_insn(Insn::NextAsync {
+                cursor_id: loop_cursor_id,
+            });
+            program.emit_insn(Insn::NextAwait {
+                cursor_id: loop_cursor_id,
+                pc_if_next,
+            });
+        }
+        if src_table.index_scan.is_none() && src_table.rowid_scan.is_none() {
+            let pc_if_empty = program.offset();
+            program.fixup_insn(
+                table_loop.rewind_offset,
+                if src_table.reverse {
+                    Insn::LastAwait {
+                        cursor_id,
+                        pc_if_empty,
+                    }
+                } else {
+                    Insn::RewindAwait {
+                        cursor_id,
+                        pc_if_empty,
+                    }
+                },
+            );
+        }
+        program.resolve_label(table_loop.exit_label, program.offset());
+        if let Some(match_flag) = table_loop.left_join_match_flag {
+            // No row matched the join constraint: run the loop body once more
+            // with this table's columns set to NULL.
+            let matched_label = program.alloc_label();
+            program.emit_insn_with_label_dependency(
+                Insn::If {
+                    reg: match_flag,
+                    target_pc: matched_label,
+                    jump_if_null: false,
+                },
+                matched_label,
+            );
+            program.emit_insn(Insn::NullRow { cursor_id });
+            program.emit_insn(Insn::Integer {
+                value: 1,
+                dest: match_flag,
+            });
+            program.emit_insn(Insn::Goto {
+                target_pc: table_loop.body_offset,
+            });
+            program.resolve_label(matched_label, program.offset());
+        }
+    }
+}
+
+fn translate_table_open_cursor(
+    program: &mut ProgramBuilder,
+    src_table: &SrcTable,
+    write: bool,
+) -> LoopInfo {
+    let table = &src_table.table;
+    let cursor_id = program.alloc_cursor_id();
+    let root_page = match table {
+        Table::BTree(btree) => btree.root_page,
+        Table::Pseudo(_) => todo!(),
+    };
+    if write {
+        program.emit_insn(Insn::OpenWriteAsync {
+            cursor_id,
+            root_page,
+        });
+        program.emit_insn(Insn::OpenWriteAwait);
+    } else {
+        program.emit_insn(Insn::OpenReadAsync {
+            cursor_id,
+            root_page,
+        });
+        program.emit_insn(Insn::OpenReadAwait);
+    }
+    let index_cursor = src_table.index_scan.as_ref().map(|scan| {
+        let index_cursor = program.alloc_cursor_id();
+        program.emit_insn(Insn::OpenReadAsync {
+            cursor_id: index_cursor,
+            root_page: scan.index.root_page,
+        });
+        program.emit_insn(Insn::OpenReadAwait);
+        index_cursor
+    });
+    let left_join_match_flag = if src_table.is_left_join() {
+        Some(program.alloc_register())
+    } else {
+        None
+    };
+    LoopInfo {
+        open_cursor: cursor_id,
+        index_cursor,
+        exit_label: program.alloc_label(),
+        rewind_offset: 0,
+        next_row_label: program.alloc_label(),
+        left_join_match_flag,
+        body_offset: 0,
+    }
+}
+
+fn translate_table_open_loop(
+    program: &mut ProgramBuilder,
+    loop_info: &mut LoopInfo,
+    reverse: bool,
+) {
+    if let Some(match_flag) = loop_info.left_join_match_flag {
+        program.emit_insn(Insn::Integer {
+            value: 0,
+            dest: match_flag,
+        });
+    }
+    let cursor_id = loop_info.open_cursor;
+    program.emit_insn(if reverse {
+        Insn::LastAsync { cursor_id }
+    } else {
+        Insn::RewindAsync { cursor_id }
+    });
+    let rewind_await_offset = program.emit_placeholder();
+    loop_info.rewind_offset = rewind_await_offset;
+}
+
+/// Generate the start of a loop that visits the rows of the `idx`th table
+/// through its index scan, moving the table cursor to each row in turn. The
+/// index only narrows down the rows; the WHERE clause is still checked for
+/// each of them. Returns the offset of the loop body.
+fn translate_index_open_loop(
+    program: &mut ProgramBuilder,
+    select: &Select,
+    idx: usize,
+) -> Result<usize> {
+    let loop_info = &select.loops[idx];
+    let scan = select.src_tables[idx].index_scan.as_ref().unwrap();
+    let index_cursor = loop_info.index_cursor.unwrap();
+    if let Some(match_flag) = loop_info.left_join_match_flag {
+        program.emit_insn(Insn::Integer {
+            value: 0,
+            dest: match_flag,
+        });
+    }
+    let num_eq = scan.eq_values.len();
+    // The key to seek to is the equality values followed by the lower bound.
+    let key_reg = program.alloc_registers(num_eq + 1);
+    for (i, value) in scan.eq_values.iter().enumerate() {
+        translate_expr(program, select, value, key_reg + i)?;
+    }
+    // The keys hold the values as the comparisons see them after converting
+    // them with their affinities.
+    if num_eq > 0 {
+        program.emit_insn(Insn::Affinity {
+            start_reg: key_reg,
+            count: num_eq,
+            affinities: scan.eq_affinities.clone(),
+        });
+    }
+    // The key that ends the scan is the equality values followed by the upper
+    // bound.
+    let end_key = match &scan.upper_bound {
+        Some((value, inclusive, affinity)) => {
+            let end_key_reg = program.alloc_registers(num_eq + 1);
+            if num_eq > 0 {
+                program.emit_insn(Insn::Copy {
+                    src_reg: key_reg,
+                    dst_reg: end_key_reg,
+                    amount: num_eq - 1,
+                });
+            }
+            translate_expr(program, select, value, end_key_reg + num_eq)?;
+            program.emit_insn(Insn::Affinity {
+                start_reg: end_key_reg + num_eq,
+                count: 1,
+                affinities: affinity.to_string(),
+            });
+            Some((end_key_reg, num_eq + 1, *inclusive))
+        }
+        None if num_eq > 0 => Some((key_reg, num_eq, true)),
+        None => None,
+    };
+    match &scan.lower_bound {
+        Some((value, inclusive, affinity)) => {
+            translate_expr(program, select, value, key_reg + num_eq)?;
+            program.emit_insn(Insn::Affinity {
+                start_reg: key_reg + num_eq,
+                count: 1,
+                affinities: affinity.to_string(),
+            });
+            let (cursor_id, start_reg, num_regs, target_pc) =
+                (index_cursor, key_reg, num_eq + 1, loop_info.exit_label);
+            program.emit_insn_with_label_dependency(
+                if *inclusive {
+                    Insn::SeekGE {
+                        is_index: true,
+                        cursor_id,
+                        start_reg,
+                        num_regs,
+                        target_pc,
+                    }
+                } else {
+                    Insn::SeekGT {
+                        is_index: true,
+                        cursor_id,
+                        start_reg,
+                        num_regs,
+                        target_pc,
+                    }
+                },
+                loop_info.exit_label,
+            );
+        }
+        None if num_eq > 0 => {
+            program.emit_insn_with_label_dependency(
+                Insn::SeekGE {
+                    is_index: true,
+                    cursor_id: index_cursor,
+                    start_reg: key_reg,
+                    num_regs: num_eq,
+                    target_pc: loop_info.exit_label,
+                },
+                loop_info.exit_label,
+            );
+        }
+        None => {
+            program.emit_insn(Insn::RewindAsync {
+                cursor_id: index_cursor,
+            });
+            program.emit_insn_with_label_dependency(
+                Insn::RewindAwait {
+                    cursor_id: index_cursor,
+                    pc_if_empty: loop_info.exit_label,
+                },
+                loop_info.exit_label,
+            );
+        }
+    }
+    let body_offset = program.offset() as usize;
+    if let Some((start_reg, num_regs, inclusive)) = end_key {
+        let (cursor_id, target_pc) = (index_cursor, loop_info.exit_label);
+        program.emit_insn_with_label_dependency(
+            if inclusive {
+                Insn::IdxGT {
+                    cursor_id,
+                    start_reg,
+                    num_regs,
+                    target_pc,
+                }
+            } else {
+                Insn::IdxGE {
+                    cursor_id,
+                    start_reg,
+                    num_regs,
+                    target_pc,
+                }
+            },
+            loop_info.exit_label,
+        );
+    }
+    let rowid_reg = program.alloc_register();
+    program.emit_insn(Insn::IdxRowid {
+        cursor_id: index_cursor,
+        dest: rowid_reg,
+    });
+    program.emit_insn_with_label_dependency(
+        Insn::NotExists {
+            cursor_id: loop_info.open_cursor,
+            rowid_reg,
+            target_pc: loop_info.next_row_label,
+        },
+        loop_info.next_row_label,
+    );
+    Ok(body_offset)
+}
+
+/// Seek the cursor of the `idx`th table straight to the row with a given
+/// rowid, or to the first row of a rowid range and stop at the first row
+/// past its end. The WHERE clause is still checked for each row. Returns the
+/// offset of the loop body.
+fn translate_rowid_open_loop(
+    program: &mut ProgramBuilder,
+    select: &Select,
+    idx: usize,
+) -> Result<usize> {
+    let loop_info = &select.loops[idx];
+    let scan = select.src_tables[idx].rowid_scan.as_ref().unwrap();
+    let cursor_id = loop_info.open_cursor;
+    if let Some(match_flag) = loop_info.left_join_match_flag {
+        program.emit_insn(Insn::Integer {
+            value: 0,
+            dest: match_flag,
+        });
+    }
+    if let Some(value) = &scan.eq_value {
+        let rowid_reg = program.alloc_register();
+        translate_expr(program, select, value, rowid_reg)?;
+        program.emit_insn_with_label_dependency(
+            Insn::SeekRowid {
+                cursor_id,
+                src_reg: rowid_reg,
+                target_pc: loop_info.exit_label,
+            },
+            loop_info.exit_label,
+        );
+        return Ok(program.offset() as usize);
+    }
+    let end_reg = match &scan.upper_bound {
+        Some((value, inclusive)) => {
+            let end_reg = program.alloc_register();
+            translate_expr(program, select, value, end_reg)?;
+            Some((end_reg, *inclusive))
+        }
+        None => None,
+    };
+    match &scan.lower_bound {
+        Some((value, inclusive)) => {
+            let start_reg = program.alloc_register();
+            translate_expr(program, select, value, start_reg)?;
+            let target_pc = loop_info.exit_label;
+            program.emit_insn_with_label_dependency(
+                if *inclusive {
+                    Insn::SeekGE {
+                        is_index: false,
+                        cursor_id,
+                        start_reg,
+                        num_regs: 1,
+                        target_pc,
+                    }
+                } else {
+                    Insn::SeekGT {
+                        is_index: false,
+                        cursor_id,
+                        start_reg,
+                        num_regs: 1,
+                        target_pc,
+                    }
+                },
+                loop_info.exit_label,
+            );
+        }
+        None => {
+            program.emit_insn(Insn::RewindAsync { cursor_id });
+            program.emit_insn_with_label_dependency(
+                Insn::RewindAwait {
+                    cursor_id,
+                    pc_if_empty: loop_info.exit_label,
+                },
+                loop_info.exit_label,
+            );
+        }
+    }
+    let body_offset = program.offset() as usize;
+    if let Some((end_reg, inclusive)) = end_reg {
+        // Rows are visited in rowid order, so the loop ends at the first one
+        // past the upper bound. No row is below a NULL bound.
+        let rowid_reg = program.alloc_register();
+        program.emit_insn(Insn::RowId {
+            cursor_id,
+            dest: rowid_reg,
+        });
+        let (lhs, rhs, target_pc) = (rowid_reg, end_reg, loop_info.exit_label);
+        program.emit_insn_with_label_dependency(
+            if inclusive {
+                Insn::Gt {
+                    lhs,
+                    rhs,
+                    target_pc,
+                    jump_if_null: true,
+                    affinity: Some('C'),
+                }
+            } else {
+                Insn::Ge {
+                    lhs,
+                    rhs,
+                    target_pc,
+                    jump_if_null: true,
+                    affinity: Some('C'),
+                }
+            },
+            loop_info.exit_label,
+        );
+    }
+    Ok(body_offset)
+}
 
 fn translate_columns(program: &mut ProgramBuilder, select: &Select) -> Result<(usize, usize)> {
     let register_start = program.next_free_register();
@@ -329,135 +3856,915 @@ fn translate_columns(program: &mut ProgramBuilder, select: &Select) -> Result<(u
     program.alloc_registers(registers);
     let register_end = program.next_free_register();
 
-    let mut target = register_start;
-    for (col, info) in select.columns.iter().zip(select.column_info.iter()) {
-        translate_column(program, select, col, info, target)?;
-        target += info.columns_to_allocate;
-    }
-    Ok((register_start, register_end))
+    let mut target = register_start;
+    for (col, info) in select.columns.iter().zip(select.column_info.iter()) {
+        translate_column(program, select, col, info, target)?;
+        target += info.columns_to_allocate;
+    }
+    Ok((register_start, register_end))
+}
+
+fn translate_column(
+    program: &mut ProgramBuilder,
+    select: &Select,
+    col: &ast::ResultColumn,
+    info: &ColumnInfo,
+    target_register: usize, // where to store the result, in case of star it will be the start of registers added
+) -> Result<()> {
+    match col {
+        ast::ResultColumn::Expr(expr, _) => {
+            if info.is_aggregation_function() {
+                let _ = translate_aggregation(program, select, expr, info, target_register)?;
+            } else {
+                let _ = translate_expr(program, select, expr, target_register)?;
+            }
+        }
+        ast::ResultColumn::Star => {
+            let mut target_register = target_register;
+            for idx in 0..select.src_tables.len() {
+                target_register += translate_table_star(idx, program, select, target_register);
+            }
+        }
+        ast::ResultColumn::TableStar(name) => {
+            let identifier = normalize_ident(&name.0);
+            let Some(src_idx) = select
+                .src_tables
+                .iter()
+                .position(|src| src.identifier == identifier)
+            else {
+                anyhow::bail!("Parse error: no such table: {}", name.0);
+            };
+            for column in 0..select.src_tables[src_idx].table.columns().len() {
+                translate_src_column(program, select, src_idx, column, target_register + column);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Generate code for the columns of the `idx`th source table in a `*` result
+/// column. Columns matched by a USING or NATURAL join are only output for the
+/// left-hand table. Returns the number of columns generated.
+fn translate_table_star(
+    idx: usize,
+    program: &mut ProgramBuilder,
+    select: &Select,
+    target_register: usize,
+) -> usize {
+    let src_table = &select.src_tables[idx];
+    let table_cursor = select.loops[idx].open_cursor;
+    let mut col_target_register = target_register;
+    for i in 0..src_table.table.columns().len() {
+        if src_table
+            .using_columns
+            .iter()
+            .any(|using| using.right_column == i)
+        {
+            continue;
+        }
+        translate_table_column(
+            program,
+            &src_table.table,
+            table_cursor,
+            i,
+            col_target_register,
+        );
+        col_target_register += 1;
+    }
+    col_target_register - target_register
+}
+
+fn translate_table_column(
+    program: &mut ProgramBuilder,
+    table: &Table,
+    cursor_id: usize,
+    column: usize,
+    target_register: usize,
+) {
+    let col = &table.columns()[column];
+    if table.column_is_rowid_alias(col) {
+        program.emit_insn(Insn::RowId {
+            cursor_id,
+            dest: target_register,
+        });
+    } else {
+        program.emit_insn(Insn::Column {
+            column,
+            dest: target_register,
+            cursor_id,
+        });
+        maybe_apply_affinity(col, target_register, program);
+    }
+}
+
+fn analyze_columns(columns: &Vec<ast::ResultColumn>, joins: &Vec<SrcTable>) -> Vec<ColumnInfo> {
+    let mut column_information_list = Vec::with_capacity(columns.len());
+    for column in columns {
+        let mut info = ColumnInfo::new();
+        if let ast::ResultColumn::Star = column {
+            info.columns_to_allocate = 0;
+            for join in joins {
+                info.columns_to_allocate += join.table.columns().len() - join.using_columns.len();
+            }
+        } else if let ast::ResultColumn::TableStar(name) = column {
+            let identifier = normalize_ident(&name.0);
+            info.columns_to_allocate = joins
+                .iter()
+                .find(|join| join.identifier == identifier)
+                .map_or(0, |join| join.table.columns().len());
+        } else {
+            info.columns_to_allocate = 1;
+            analyze_column(column, &mut info);
+        }
+        column_information_list.push(info);
+    }
+    column_information_list
+}
+
+/// Analyze a column expression.
+///
+/// This function will walk all columns and find information about:
+/// * Aggregation functions.
+fn analyze_column(column: &ast::ResultColumn, column_info_out: &mut ColumnInfo) {
+    match column {
+        ast::ResultColumn::Expr(expr, _) => analyze_expr(expr, column_info_out),
+        ast::ResultColumn::Star => {}
+        ast::ResultColumn::TableStar(_) => {}
+    }
+}
+
+fn analyze_expr(expr: &Expr, column_info_out: &mut ColumnInfo) {
+    match expr {
+        ast::Expr::FunctionCall {
+            name,
+            distinctness,
+            args,
+            filter_over: _,
+        } => {
+            let func_type = resolve_agg_func(name, args.as_ref().map_or(0, |args| args.len()));
+            if func_type.is_none() {
+                if let Some(arg) = args.as_ref().and_then(|args| args.first()) {
+                    analyze_expr(arg, column_info_out);
+                }
+            } else {
+                column_info_out.func = func_type;
+                // TODO(pere): use lifetimes for args? Arenas would be lovely here :(
+                column_info_out.args.clone_from(args);
+                column_info_out.distinct =
+                    matches!(distinctness, Some(ast::Distinctness::Distinct));
+            }
+        }
+        ast::Expr::FunctionCallStar { name, .. } => {
+            if let Some(AggFunc::Count) = resolve_agg_func(name, 0) {
+                column_info_out.func = Some(AggFunc::Count);
+            } else {
+                todo!();
+            }
+        }
+        _ => {}
+    }
+}
+
+fn resolve_agg_func(name: &ast::Id, arg_count: usize) -> Option<AggFunc> {
+    match normalize_ident(name.0.as_str()).as_str() {
+        "avg" => Some(AggFunc::Avg),
+        "count" => Some(AggFunc::Count),
+        "group_concat" => Some(AggFunc::GroupConcat),
+        // min() and max() with more than one argument are scalar functions.
+        "max" if arg_count <= 1 => Some(AggFunc::Max),
+        "min" if arg_count <= 1 => Some(AggFunc::Min),
+        "string_agg" => Some(AggFunc::StringAgg),
+        "sum" => Some(AggFunc::Sum),
+        "total" => Some(AggFunc::Total),
+        _ => None,
+    }
+}
+
+fn resolve_scalar_func(name: &ast::Id) -> Option<ScalarFunc> {
+    match normalize_ident(name.0.as_str()).as_str() {
+        "abs" => Some(ScalarFunc::Abs),
+        "coalesce" => Some(ScalarFunc::Coalesce),
+        "hex" => Some(ScalarFunc::Hex),
+        "ifnull" => Some(ScalarFunc::IfNull),
+        "iif" => Some(ScalarFunc::Iif),
+        "instr" => Some(ScalarFunc::Instr),
+        "length" => Some(ScalarFunc::Length),
+        "lower" => Some(ScalarFunc::Lower),
+        "ltrim" => Some(ScalarFunc::LTrim),
+        "max" => Some(ScalarFunc::Max),
+        "min" => Some(ScalarFunc::Min),
+        "nullif" => Some(ScalarFunc::NullIf),
+        "quote" => Some(ScalarFunc::Quote),
+        "random" => Some(ScalarFunc::Random),
+        "replace" => Some(ScalarFunc::Replace),
+        "round" => Some(ScalarFunc::Round),
+        "rtrim" => Some(ScalarFunc::RTrim),
+        "substr" | "substring" => Some(ScalarFunc::Substr),
+        "trim" => Some(ScalarFunc::Trim),
+        "typeof" => Some(ScalarFunc::Typeof),
+        "upper" => Some(ScalarFunc::Upper),
+        _ => None,
+    }
+}
+
+/// Collect the aggregate function calls in `expr` and the column references
+/// that appear outside of them. Duplicates are only collected once.
+fn collect_aggregates_and_columns(
+    expr: &ast::Expr,
+    aggregates: &mut Vec<(ast::Expr, ColumnInfo)>,
+    columns: &mut Vec<ast::Expr>,
+) {
+    match expr {
+        ast::Expr::FunctionCall {
+            name,
+            distinctness,
+            args,
+            ..
+        } => match resolve_agg_func(name, args.as_ref().map_or(0, |args| args.len())) {
+            Some(func) => {
+                if !aggregates.iter().any(|(e, _)| e == expr) {
+                    let mut info = ColumnInfo::new();
+                    info.func = Some(func);
+                    info.args.clone_from(args);
+                    info.distinct = matches!(distinctness, Some(ast::Distinctness::Distinct));
+                    aggregates.push((expr.clone(), info));
+                }
+            }
+            None => {
+                for arg in args.iter().flatten() {
+                    collect_aggregates_and_columns(arg, aggregates, columns);
+                }
+            }
+        },
+        ast::Expr::FunctionCallStar { name, .. } => {
+            if let Some(AggFunc::Count) = resolve_agg_func(name, 0) {
+                if !aggregates.iter().any(|(e, _)| e == expr) {
+                    let mut info = ColumnInfo::new();
+                    info.func = Some(AggFunc::Count);
+                    aggregates.push((expr.clone(), info));
+                }
+            }
+        }
+        ast::Expr::Id(_) | ast::Expr::Qualified(..) | ast::Expr::DoublyQualified(..)
+            if !columns.contains(expr) =>
+        {
+            columns.push(expr.clone());
+        }
+        ast::Expr::Binary(lhs, _, rhs) => {
+            collect_aggregates_and_columns(lhs, aggregates, columns);
+            collect_aggregates_and_columns(rhs, aggregates, columns);
+        }
+        ast::Expr::Unary(_, expr)
+        | ast::Expr::IsNull(expr)
+        | ast::Expr::NotNull(expr)
+        | ast::Expr::Cast { expr, .. }
+        | ast::Expr::Collate(expr, _) => collect_aggregates_and_columns(expr, aggregates, columns),
+        ast::Expr::Parenthesized(exprs) => {
+            for expr in exprs {
+                collect_aggregates_and_columns(expr, aggregates, columns);
+            }
+        }
+        ast::Expr::Between {
+            lhs, start, end, ..
+        } => {
+            collect_aggregates_and_columns(lhs, aggregates, columns);
+            collect_aggregates_and_columns(start, aggregates, columns);
+            collect_aggregates_and_columns(end, aggregates, columns);
+        }
+        ast::Expr::InList { lhs, rhs, .. } => {
+            collect_aggregates_and_columns(lhs, aggregates, columns);
+            for expr in rhs.iter().flatten() {
+                collect_aggregates_and_columns(expr, aggregates, columns);
+            }
+        }
+        ast::Expr::Like { lhs, rhs, .. } => {
+            collect_aggregates_and_columns(lhs, aggregates, columns);
+            collect_aggregates_and_columns(rhs, aggregates, columns);
+        }
+        _ => {}
+    }
+}
+
+/// Where to jump after evaluating a condition.
+///
+/// A condition either jumps to `jump_target_when_true` and falls through
+/// when false (`jump_if_condition_is_true`), or jumps to
+/// `jump_target_when_false` and falls through when true. A NULL result is
+/// always treated as false.
+#[derive(Clone, Copy)]
+struct ConditionMetadata {
+    jump_if_condition_is_true: bool,
+    jump_target_when_true: BranchOffset,
+    jump_target_when_false: BranchOffset,
+}
+
+/// Generate code for a WHERE clause. Rows for which the condition is not
+/// true jump to `jump_target_when_false`; the rest fall through.
+fn translate_where(
+    program: &mut ProgramBuilder,
+    select: &Select,
+    where_clause: &ast::Expr,
+    jump_target_when_false: BranchOffset,
+) -> Result<()> {
+    let jump_target_when_true = program.alloc_label();
+    translate_condition_expr(
+        program,
+        select,
+        where_clause,
+        ConditionMetadata {
+            jump_if_condition_is_true: false,
+            jump_target_when_true,
+            jump_target_when_false,
+        },
+    )?;
+    program.resolve_label(jump_target_when_true, program.offset());
+    Ok(())
+}
+
+fn translate_condition_expr(
+    program: &mut ProgramBuilder,
+    select: &Select,
+    expr: &ast::Expr,
+    condition_metadata: ConditionMetadata,
+) -> Result<()> {
+    match expr {
+        ast::Expr::Binary(lhs, ast::Operator::And, rhs) => {
+            // If the left side is false, the whole AND is false; otherwise the
+            // right side decides.
+            translate_condition_expr(
+                program,
+                select,
+                lhs,
+                ConditionMetadata {
+                    jump_if_condition_is_true: false,
+                    ..condition_metadata
+                },
+            )?;
+            translate_condition_expr(program, select, rhs, condition_metadata)?;
+        }
+        ast::Expr::Binary(lhs, ast::Operator::Or, rhs) => {
+            // If the left side is true, the whole OR is true; otherwise the
+            // right side decides.
+            let jump_target_when_false = program.alloc_label();
+            translate_condition_expr(
+                program,
+                select,
+                lhs,
+                ConditionMetadata {
+                    jump_if_condition_is_true: true,
+                    jump_target_when_false,
+                    ..condition_metadata
+                },
+            )?;
+            program.resolve_label(jump_target_when_false, program.offset());
+            translate_condition_expr(program, select, rhs, condition_metadata)?;
+        }
+        ast::Expr::Binary(lhs, op, rhs) if is_comparison_op(op) => {
+            let lhs_reg = program.alloc_register();
+            let rhs_reg = program.alloc_register();
+            let _ = translate_expr(program, select, lhs, lhs_reg)?;
+            let _ = translate_expr(program, select, rhs, rhs_reg)?;
+            let affinity = comparison_affinity(select, lhs, rhs);
+            emit_comparison(program, op, lhs_reg, rhs_reg, affinity, condition_metadata);
+        }
+        ast::Expr::Between {
+            lhs,
+            not,
+            start,
+            end,
+        } => {
+            let lhs_reg = program.alloc_register();
+            let start_reg = program.alloc_register();
+            let end_reg = program.alloc_register();
+            let _ = translate_expr(program, select, lhs, lhs_reg)?;
+            let _ = translate_expr(program, select, start, start_reg)?;
+            let _ = translate_expr(program, select, end, end_reg)?;
+            let start_affinity = comparison_affinity(select, lhs, start);
+            let end_affinity = comparison_affinity(select, lhs, end);
+            if !*not {
+                // lhs >= start AND lhs <= end
+                emit_comparison(
+                    program,
+                    &ast::Operator::GreaterEquals,
+                    lhs_reg,
+                    start_reg,
+                    start_affinity,
+                    ConditionMetadata {
+                        jump_if_condition_is_true: false,
+                        ..condition_metadata
+                    },
+                );
+                emit_comparison(
+                    program,
+                    &ast::Operator::LessEquals,
+                    lhs_reg,
+                    end_reg,
+                    end_affinity,
+                    condition_metadata,
+                );
+            } else {
+                // lhs < start OR lhs > end
+                let jump_target_when_false = program.alloc_label();
+                emit_comparison(
+                    program,
+                    &ast::Operator::Less,
+                    lhs_reg,
+                    start_reg,
+                    start_affinity,
+                    ConditionMetadata {
+                        jump_if_condition_is_true: true,
+                        jump_target_when_false,
+                        ..condition_metadata
+                    },
+                );
+                program.resolve_label(jump_target_when_false, program.offset());
+                emit_comparison(
+                    program,
+                    &ast::Operator::Greater,
+                    lhs_reg,
+                    end_reg,
+                    end_affinity,
+                    condition_metadata,
+                );
+            }
+        }
+        ast::Expr::InList { lhs, not, rhs } => {
+            let lhs_reg = program.alloc_register();
+            let _ = translate_expr(program, select, lhs, lhs_reg)?;
+            let affinity = in_list_affinity(select, lhs);
+            let empty_list = Vec::new();
+            let rhs = rhs.as_ref().unwrap_or(&empty_list);
+            let rhs_reg = program.alloc_register();
+            if !*not {
+                // Jump to the "true" target on the first match.
+                let jump_target_when_true = if condition_metadata.jump_if_condition_is_true {
+                    condition_metadata.jump_target_when_true
+                } else {
+                    program.alloc_label()
+                };
+                for expr in rhs {
+                    let _ = translate_expr(program, select, expr, rhs_reg)?;
+                    program.emit_insn_with_label_dependency(
+                        Insn::Eq {
+                            lhs: lhs_reg,
+                            rhs: rhs_reg,
+                            target_pc: jump_target_when_true,
+                            jump_if_null: false,
+                            affinity,
+                        },
+                        jump_target_when_true,
+                    );
+                }
+                if !condition_metadata.jump_if_condition_is_true {
+                    program.emit_insn_with_label_dependency(
+                        Insn::Goto {
+                            target_pc: condition_metadata.jump_target_when_false,
+                        },
+                        condition_metadata.jump_target_when_false,
+                    );
+                    program.resolve_label(jump_target_when_true, program.offset());
+                }
+            } else {
+                // Any match, or any comparison with NULL, makes NOT IN not true.
+                let jump_target_when_false = if condition_metadata.jump_if_condition_is_true {
+                    program.alloc_label()
+                } else {
+                    condition_metadata.jump_target_when_false
+                };
+                for expr in rhs {
+                    let _ = translate_expr(program, select, expr, rhs_reg)?;
+                    program.emit_insn_with_label_dependency(
+                        Insn::Eq {
+                            lhs: lhs_reg,
+                            rhs: rhs_reg,
+                            target_pc: jump_target_when_false,
+                            jump_if_null: true,
+                            affinity,
+                        },
+                        jump_target_when_false,
+                    );
+                }
+                if condition_metadata.jump_if_condition_is_true {
+                    program.emit_insn_with_label_dependency(
+                        Insn::Goto {
+                            target_pc: condition_metadata.jump_target_when_true,
+                        },
+                        condition_metadata.jump_target_when_true,
+                    );
+                    program.resolve_label(jump_target_when_false, program.offset());
+                }
+            }
+        }
+        ast::Expr::IsNull(inner) => {
+            emit_null_check(program, select, inner, true, condition_metadata)?;
+        }
+        ast::Expr::NotNull(inner) => {
+            emit_null_check(program, select, inner, false, condition_metadata)?;
+        }
+        ast::Expr::Binary(inner, ast::Operator::Is, null)
+            if matches!(null.as_ref(), ast::Expr::Literal(ast::Literal::Null)) =>
+        {
+            emit_null_check(program, select, inner, true, condition_metadata)?;
+        }
+        ast::Expr::Binary(inner, ast::Operator::IsNot, null)
+            if matches!(null.as_ref(), ast::Expr::Literal(ast::Literal::Null)) =>
+        {
+            emit_null_check(program, select, inner, false, condition_metadata)?;
+        }
+        ast::Expr::Parenthesized(exprs) => {
+            if exprs.len() != 1 {
+                anyhow::bail!("Parse error: row value misused");
+            }
+            translate_condition_expr(program, select, &exprs[0], condition_metadata)?;
+        }
+        _ => {
+            let reg = program.alloc_register();
+            let _ = translate_expr(program, select, expr, reg)?;
+            if condition_metadata.jump_if_condition_is_true {
+                program.emit_insn_with_label_dependency(
+                    Insn::If {
+                        reg,
+                        target_pc: condition_metadata.jump_target_when_true,
+                        jump_if_null: false,
+                    },
+                    condition_metadata.jump_target_when_true,
+                );
+            } else {
+                program.emit_insn_with_label_dependency(
+                    Insn::IfNot {
+                        reg,
+                        target_pc: condition_metadata.jump_target_when_false,
+                        jump_if_null: true,
+                    },
+                    condition_metadata.jump_target_when_false,
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Emit a conditional jump for `expr IS NULL` (or `expr IS NOT NULL` when
+/// `is_null` is false). Neither check can evaluate to NULL, so the inverse
+/// check is used directly when jumping on false.
+fn emit_null_check(
+    program: &mut ProgramBuilder,
+    select: &Select,
+    expr: &ast::Expr,
+    is_null: bool,
+    condition_metadata: ConditionMetadata,
+) -> Result<()> {
+    let reg = program.alloc_register();
+    let _ = translate_expr(program, select, expr, reg)?;
+    let (jump_on_null, target_pc) = if condition_metadata.jump_if_condition_is_true {
+        (is_null, condition_metadata.jump_target_when_true)
+    } else {
+        (!is_null, condition_metadata.jump_target_when_false)
+    };
+    if jump_on_null {
+        program.emit_insn_with_label_dependency(
+            Insn::IsNull {
+                src: reg,
+                target_pc,
+            },
+            target_pc,
+        );
+    } else {
+        program.emit_insn_with_label_dependency(
+            Insn::NotNull {
+                src: reg,
+                target_pc,
+            },
+            target_pc,
+        );
+    }
+    Ok(())
+}
+
+/// Generate code that stores the result of `lhs <op> rhs`, with the operands
+/// converted with `affinity`, into `dest`: 1 if true, 0 if false and NULL if
+/// either operand is NULL.
+fn translate_comparison_value(
+    program: &mut ProgramBuilder,
+    op: &ast::Operator,
+    lhs: usize,
+    rhs: usize,
+    affinity: Option<char>,
+    dest: usize,
+) {
+    let end_label = program.alloc_label();
+    program.emit_insn(Insn::Null { dest });
+    program.emit_insn_with_label_dependency(
+        Insn::IsNull {
+            src: lhs,
+            target_pc: end_label,
+        },
+        end_label,
+    );
+    program.emit_insn_with_label_dependency(
+        Insn::IsNull {
+            src: rhs,
+            target_pc: end_label,
+        },
+        end_label,
+    );
+    program.emit_insn(Insn::Integer { value: 1, dest });
+    emit_comparison(
+        program,
+        op,
+        lhs,
+        rhs,
+        affinity,
+        ConditionMetadata {
+            jump_if_condition_is_true: true,
+            jump_target_when_true: end_label,
+            jump_target_when_false: end_label,
+        },
+    );
+    program.emit_insn(Insn::Integer { value: 0, dest });
+    program.resolve_label(end_label, program.offset());
+}
+
+/// Generate code that stores the result of `lhs IS rhs` (or `lhs IS NOT rhs`
+/// when `is_not` is set), with the operands converted with `affinity`, into
+/// `dest`. Unlike `=`, two NULLs compare equal and the result is never NULL.
+fn translate_is_value(
+    program: &mut ProgramBuilder,
+    is_not: bool,
+    lhs: usize,
+    rhs: usize,
+    affinity: Option<char>,
+    dest: usize,
+) {
+    let lhs_null_label = program.alloc_label();
+    let not_equal_label = program.alloc_label();
+    let end_label = program.alloc_label();
+    program.emit_insn(Insn::Integer {
+        value: !is_not as i64,
+        dest,
+    });
+    program.emit_insn_with_label_dependency(
+        Insn::IsNull {
+            src: lhs,
+            target_pc: lhs_null_label,
+        },
+        lhs_null_label,
+    );
+    program.emit_insn_with_label_dependency(
+        Insn::IsNull {
+            src: rhs,
+            target_pc: not_equal_label,
+        },
+        not_equal_label,
+    );
+    program.emit_insn_with_label_dependency(
+        Insn::Eq {
+            lhs,
+            rhs,
+            target_pc: end_label,
+            jump_if_null: false,
+            affinity,
+        },
+        end_label,
+    );
+    program.emit_insn_with_label_dependency(
+        Insn::Goto {
+            target_pc: not_equal_label,
+        },
+        not_equal_label,
+    );
+    program.resolve_label(lhs_null_label, program.offset());
+    program.emit_insn_with_label_dependency(
+        Insn::IsNull {
+            src: rhs,
+            target_pc: end_label,
+        },
+        end_label,
+    );
+    program.resolve_label(not_equal_label, program.offset());
+    program.emit_insn(Insn::Integer {
+        value: is_not as i64,
+        dest,
+    });
+    program.resolve_label(end_label, program.offset());
+}
+
+/// Generate code that stores the result of `src IS NULL` (or `src IS NOT NULL`
+/// when `is_null` is false) into `dest`, which is never NULL.
+fn translate_null_check_value(
+    program: &mut ProgramBuilder,
+    is_null: bool,
+    src: usize,
+    dest: usize,
+) {
+    let end_label = program.alloc_label();
+    program.emit_insn(Insn::Integer { value: 1, dest });
+    let target_pc = end_label;
+    program.emit_insn_with_label_dependency(
+        if is_null {
+            Insn::IsNull { src, target_pc }
+        } else {
+            Insn::NotNull { src, target_pc }
+        },
+        end_label,
+    );
+    program.emit_insn(Insn::Integer { value: 0, dest });
+    program.resolve_label(end_label, program.offset());
 }
 
-fn translate_column(
+/// Generate code that stores the result of `lhs IN (rhs, ...)` into `dest`:
+/// 1 if `lhs` equals any of the values, otherwise NULL if `lhs` or any of the
+/// values is NULL, and 0 if not. The values are compared with `lhs` after
+/// converting both with `affinity`.
+fn translate_in_list_value(
     program: &mut ProgramBuilder,
     select: &Select,
-    col: &ast::ResultColumn,
-    info: &ColumnInfo,
-    target_register: usize, // where to store the result, in case of star it will be the start of registers added
+    lhs: usize,
+    rhs: &[ast::Expr],
+    affinity: Option<char>,
+    dest: usize,
 ) -> Result<()> {
-    match col {
-        ast::ResultColumn::Expr(expr, _) => {
-            if info.is_aggregation_function() {
-                let _ = translate_aggregation(program, select, expr, info, target_register)?;
-            } else {
-                let _ = translate_expr(program, select, expr, target_register)?;
-            }
-        }
-        ast::ResultColumn::Star => {
-            let mut target_register = target_register;
-            for join in &select.src_tables {
-                let table = &join.table;
-                translate_table_star(table, program, &select, target_register);
-                target_register += table.columns().len();
-            }
-        }
-        ast::ResultColumn::TableStar(_) => todo!(),
+    let true_label = program.alloc_label();
+    let end_label = program.alloc_label();
+    program.emit_insn(Insn::Integer { value: 0, dest });
+    if rhs.is_empty() {
+        // Nothing is in an empty list, not even NULL.
+        return Ok(());
+    }
+    let null_label = program.alloc_label();
+    program.emit_insn_with_label_dependency(
+        Insn::IsNull {
+            src: lhs,
+            target_pc: null_label,
+        },
+        null_label,
+    );
+    let rhs_reg = program.alloc_register();
+    for expr in rhs {
+        let _ = translate_expr(program, select, expr, rhs_reg)?;
+        program.emit_insn_with_label_dependency(
+            Insn::Eq {
+                lhs,
+                rhs: rhs_reg,
+                target_pc: true_label,
+                jump_if_null: false,
+                affinity,
+            },
+            true_label,
+        );
+        // A NULL value makes the result NULL unless a later value matches.
+        let next_label = program.alloc_label();
+        program.emit_insn_with_label_dependency(
+            Insn::NotNull {
+                src: rhs_reg,
+                target_pc: next_label,
+            },
+            next_label,
+        );
+        program.emit_insn(Insn::Null { dest });
+        program.resolve_label(next_label, program.offset());
     }
+    program.emit_insn_with_label_dependency(
+        Insn::Goto {
+            target_pc: end_label,
+        },
+        end_label,
+    );
+    program.resolve_label(null_label, program.offset());
+    program.emit_insn(Insn::Null { dest });
+    program.emit_insn_with_label_dependency(
+        Insn::Goto {
+            target_pc: end_label,
+        },
+        end_label,
+    );
+    program.resolve_label(true_label, program.offset());
+    program.emit_insn(Insn::Integer { value: 1, dest });
+    program.resolve_label(end_label, program.offset());
     Ok(())
 }
 
-fn translate_table_star(
-    table: &Table,
-    program: &mut ProgramBuilder,
-    select: &Select,
-    target_register: usize,
-) {
-    let table_cursor = select
-        .loops
-        .iter()
-        .find(|v| v.table == *table)
-        .unwrap()
-        .open_cursor;
-    for (i, col) in table.columns().iter().enumerate() {
-        let col_target_register = target_register + i;
-        if table.column_is_rowid_alias(col) {
-            program.emit_insn(Insn::RowId {
-                cursor_id: table_cursor,
-                dest: col_target_register,
-            });
-        } else {
-            program.emit_insn(Insn::Column {
-                column: i,
-                dest: col_target_register,
-                cursor_id: table_cursor,
-            });
-            maybe_apply_affinity(col, col_target_register, program);
+/// The affinity of an expression, as SQLite's letter for it: that of the
+/// column it refers to, or none for other expressions.
+fn expr_affinity(select: &Select, expr: &ast::Expr) -> Option<char> {
+    let (table_name, ident) = match expr {
+        ast::Expr::Id(ident) => (None, &ident.0),
+        ast::Expr::Qualified(table_name, ident) => (Some(table_name.0.as_str()), &ident.0),
+        ast::Expr::DoublyQualified(_, table_name, ident) => (Some(table_name.0.as_str()), &ident.0),
+        ast::Expr::Parenthesized(exprs) if exprs.len() == 1 => {
+            return expr_affinity(select, &exprs[0]);
         }
+        _ => return None,
+    };
+    match resolve_ident(table_name, ident, select).ok()? {
+        (idx, Some(column)) => Some(select.src_tables[idx].table.columns()[column].ty.affinity()),
+        (_, None) => Some('D'),
     }
 }
 
-fn analyze_columns(columns: &Vec<ast::ResultColumn>, joins: &Vec<SrcTable>) -> Vec<ColumnInfo> {
-    let mut column_information_list = Vec::with_capacity(columns.len());
-    for column in columns {
-        let mut info = ColumnInfo::new();
-        if let ast::ResultColumn::Star = column {
-            info.columns_to_allocate = 0;
-            for join in joins {
-                info.columns_to_allocate += join.table.columns().len();
-            }
-        } else {
-            info.columns_to_allocate = 1;
-            analyze_column(column, &mut info);
-        }
-        column_information_list.push(info);
+/// The affinity that a comparison of `lhs` and `rhs` converts its operands
+/// with, following SQLite: numeric if either side is a column with a numeric
+/// affinity and the other is a column too, otherwise the affinity of the side
+/// that is a column, if only one is. BLOB affinity converts nothing.
+fn comparison_affinity(select: &Select, lhs: &ast::Expr, rhs: &ast::Expr) -> Option<char> {
+    let is_numeric = |affinity: char| matches!(affinity, 'C' | 'D' | 'E');
+    match (expr_affinity(select, lhs), expr_affinity(select, rhs)) {
+        (Some(lhs), Some(rhs)) if is_numeric(lhs) || is_numeric(rhs) => Some('C'),
+        (Some(_), Some(_)) => None,
+        (Some(affinity), None) | (None, Some(affinity)) if affinity != 'A' => Some(affinity),
+        _ => None,
     }
-    column_information_list
 }
 
-/// Analyze a column expression.
-///
-/// This function will walk all columns and find information about:
-/// * Aggregation functions.
-fn analyze_column(column: &ast::ResultColumn, column_info_out: &mut ColumnInfo) {
-    match column {
-        ast::ResultColumn::Expr(expr, _) => analyze_expr(expr, column_info_out),
-        ast::ResultColumn::Star => {}
-        ast::ResultColumn::TableStar(_) => {}
-    }
+/// The affinity that `lhs IN (...)` converts `lhs` and the values with, which
+/// is that of `lhs` alone.
+fn in_list_affinity(select: &Select, lhs: &ast::Expr) -> Option<char> {
+    expr_affinity(select, lhs).filter(|affinity| *affinity != 'A')
 }
 
-fn analyze_expr(expr: &Expr, column_info_out: &mut ColumnInfo) {
-    match expr {
-        ast::Expr::FunctionCall {
-            name,
-            distinctness: _,
-            args,
-            filter_over: _,
-        } => {
-            let func_type = match normalize_ident(name.0.as_str()).as_str() {
-                "avg" => Some(AggFunc::Avg),
-                "count" => Some(AggFunc::Count),
-                "group_concat" => Some(AggFunc::GroupConcat),
-                "max" => Some(AggFunc::Max),
-                "min" => Some(AggFunc::Min),
-                "string_agg" => Some(AggFunc::StringAgg),
-                "sum" => Some(AggFunc::Sum),
-                "total" => Some(AggFunc::Total),
-                _ => None,
-            };
-            if func_type.is_none() {
-                let args = args.as_ref().unwrap();
-                if args.len() > 0 {
-                    analyze_expr(&args.get(0).unwrap(), column_info_out);
-                }
-            } else {
-                column_info_out.func = func_type;
-                // TODO(pere): use lifetimes for args? Arenas would be lovely here :(
-                column_info_out.args.clone_from(args);
-            }
-        }
-        ast::Expr::FunctionCallStar { .. } => todo!(),
-        _ => {}
-    }
+fn is_comparison_op(op: &ast::Operator) -> bool {
+    matches!(
+        op,
+        ast::Operator::Equals
+            | ast::Operator::NotEquals
+            | ast::Operator::Less
+            | ast::Operator::LessEquals
+            | ast::Operator::Greater
+            | ast::Operator::GreaterEquals
+    )
+}
+
+/// Emit a conditional jump for `lhs <op> rhs`, converting the operands with
+/// `affinity` first. When jumping on false, the inverse comparison is emitted
+/// and a NULL operand also takes the jump.
+fn emit_comparison(
+    program: &mut ProgramBuilder,
+    op: &ast::Operator,
+    lhs: usize,
+    rhs: usize,
+    affinity: Option<char>,
+    condition_metadata: ConditionMetadata,
+) {
+    let (op, target_pc, jump_if_null) = if condition_metadata.jump_if_condition_is_true {
+        (op, condition_metadata.jump_target_when_true, false)
+    } else {
+        let inverse = match op {
+            ast::Operator::Equals => &ast::Operator::NotEquals,
+            ast::Operator::NotEquals => &ast::Operator::Equals,
+            ast::Operator::Less => &ast::Operator::GreaterEquals,
+            ast::Operator::LessEquals => &ast::Operator::Greater,
+            ast::Operator::Greater => &ast::Operator::LessEquals,
+            ast::Operator::GreaterEquals => &ast::Operator::Less,
+            _ => unreachable!("not a comparison operator"),
+        };
+        (inverse, condition_metadata.jump_target_when_false, true)
+    };
+    let insn = match op {
+        ast::Operator::Equals => Insn::Eq {
+            lhs,
+            rhs,
+            target_pc,
+            jump_if_null,
+            affinity,
+        },
+        ast::Operator::NotEquals => Insn::Ne {
+            lhs,
+            rhs,
+            target_pc,
+            jump_if_null,
+            affinity,
+        },
+        ast::Operator::Less => Insn::Lt {
+            lhs,
+            rhs,
+            target_pc,
+            jump_if_null,
+            affinity,
+        },
+        ast::Operator::LessEquals => Insn::Le {
+            lhs,
+            rhs,
+            target_pc,
+            jump_if_null,
+            affinity,
+        },
+        ast::Operator::Greater => Insn::Gt {
+            lhs,
+            rhs,
+            target_pc,
+            jump_if_null,
+            affinity,
+        },
+        ast::Operator::GreaterEquals => Insn::Ge {
+            lhs,
+            rhs,
+            target_pc,
+            jump_if_null,
+            affinity,
+        },
+        _ => unreachable!("not a comparison operator"),
+    };
+    program.emit_insn_with_label_dependency(insn, target_pc);
 }
 
 fn translate_expr(
@@ -466,38 +4773,186 @@ fn translate_expr(
     expr: &ast::Expr,
     target_register: usize,
 ) -> Result<usize> {
+    if let Some((_, reg)) = select.expr_registers.iter().find(|(e, _)| e == expr) {
+        program.emit_insn(Insn::Copy {
+            src_reg: *reg,
+            dst_reg: target_register,
+            amount: 0,
+        });
+        return Ok(target_register);
+    }
     match expr {
-        ast::Expr::Between { .. } => todo!(),
-        ast::Expr::Binary(_, _, _) => todo!(),
+        ast::Expr::Between {
+            lhs,
+            not,
+            start,
+            end,
+        } => {
+            // lhs >= start AND lhs <= end, with lhs evaluated once.
+            let lhs_reg = program.alloc_register();
+            let start_reg = program.alloc_register();
+            let end_reg = program.alloc_register();
+            let _ = translate_expr(program, select, lhs, lhs_reg)?;
+            let _ = translate_expr(program, select, start, start_reg)?;
+            let _ = translate_expr(program, select, end, end_reg)?;
+            let lower_reg = program.alloc_register();
+            let upper_reg = program.alloc_register();
+            translate_comparison_value(
+                program,
+                &ast::Operator::GreaterEquals,
+                lhs_reg,
+                start_reg,
+                comparison_affinity(select, lhs, start),
+                lower_reg,
+            );
+            translate_comparison_value(
+                program,
+                &ast::Operator::LessEquals,
+                lhs_reg,
+                end_reg,
+                comparison_affinity(select, lhs, end),
+                upper_reg,
+            );
+            program.emit_insn(Insn::And {
+                lhs: lower_reg,
+                rhs: upper_reg,
+                dest: target_register,
+            });
+            if *not {
+                program.emit_insn(Insn::Not {
+                    reg: target_register,
+                    dest: target_register,
+                });
+            }
+            Ok(target_register)
+        }
+        ast::Expr::Binary(lhs, op, rhs) => {
+            let lhs_reg = program.alloc_register();
+            let rhs_reg = program.alloc_register();
+            let _ = translate_expr(program, select, lhs, lhs_reg)?;
+            let _ = translate_expr(program, select, rhs, rhs_reg)?;
+            let affinity = comparison_affinity(select, lhs, rhs);
+            match op {
+                op if is_comparison_op(op) => {
+                    translate_comparison_value(
+                        program,
+                        op,
+                        lhs_reg,
+                        rhs_reg,
+                        affinity,
+                        target_register,
+                    );
+                }
+                ast::Operator::Is | ast::Operator::IsNot => {
+                    let is_not = matches!(op, ast::Operator::IsNot);
+                    translate_is_value(
+                        program,
+                        is_not,
+                        lhs_reg,
+                        rhs_reg,
+                        affinity,
+                        target_register,
+                    );
+                }
+                ast::Operator::ArrowRight | ast::Operator::ArrowRightShift => todo!(),
+                _ => {
+                    let (lhs, rhs, dest) = (lhs_reg, rhs_reg, target_register);
+                    program.emit_insn(match op {
+                        ast::Operator::Add => Insn::Add { lhs, rhs, dest },
+                        ast::Operator::Substract => Insn::Subtract { lhs, rhs, dest },
+                        ast::Operator::Multiply => Insn::Multiply { lhs, rhs, dest },
+                        ast::Operator::Divide => Insn::Divide { lhs, rhs, dest },
+                        ast::Operator::Modulus => Insn::Remainder { lhs, rhs, dest },
+                        ast::Operator::Concat => Insn::Concat { lhs, rhs, dest },
+                        ast::Operator::BitwiseAnd => Insn::BitAnd { lhs, rhs, dest },
+                        ast::Operator::BitwiseOr => Insn::BitOr { lhs, rhs, dest },
+                        ast::Operator::LeftShift => Insn::ShiftLeft { lhs, rhs, dest },
+                        ast::Operator::RightShift => Insn::ShiftRight { lhs, rhs, dest },
+                        ast::Operator::And => Insn::And { lhs, rhs, dest },
+                        ast::Operator::Or => Insn::Or { lhs, rhs, dest },
+                        _ => unreachable!(),
+                    });
+                }
+            }
+            Ok(target_register)
+        }
         ast::Expr::Case { .. } => todo!(),
         ast::Expr::Cast { .. } => todo!(),
         ast::Expr::Collate(_, _) => todo!(),
-        ast::Expr::DoublyQualified(_, _, _) => todo!(),
+        ast::Expr::DoublyQualified(db_name, table_name, ident) => {
+            if normalize_ident(&db_name.0) != "main" {
+                anyhow::bail!(
+                    "Parse error: no such column: {}.{}.{}",
+                    db_name.0,
+                    table_name.0,
+                    ident.0
+                );
+            }
+            translate_ident(
+                program,
+                select,
+                Some(&table_name.0),
+                &ident.0,
+                target_register,
+            )
+        }
         ast::Expr::Exists(_) => todo!(),
-        ast::Expr::FunctionCall { .. } => todo!(),
-        ast::Expr::FunctionCallStar { .. } => todo!(),
-        ast::Expr::Id(ident) => {
-            // let (idx, col) = table.unwrap().get_column(&ident.0).unwrap();
-            let (idx, col, cursor_id) = resolve_ident_table(&ident.0, select)?;
-            if col.primary_key {
-                program.emit_insn(Insn::RowId {
-                    cursor_id,
-                    dest: target_register,
-                });
-            } else {
-                program.emit_insn(Insn::Column {
-                    column: idx,
+        ast::Expr::FunctionCall { name, args, .. } => {
+            let args: &[ast::Expr] = args.as_deref().unwrap_or_default();
+            if resolve_agg_func(name, args.len()).is_some() {
+                anyhow::bail!("Parse error: misuse of aggregate function {}()", name.0);
+            }
+            let func = match resolve_scalar_func(name) {
+                Some(func) => func,
+                None => anyhow::bail!("Parse error: no such function: {}", name.0),
+            };
+            if !func.accepts_arg_count(args.len()) {
+                anyhow::bail!(
+                    "Parse error: wrong number of arguments to function {}()",
+                    name.0
+                );
+            }
+            let start_reg = program.alloc_registers(args.len());
+            for (i, arg) in args.iter().enumerate() {
+                translate_expr(program, select, arg, start_reg + i)?;
+            }
+            program.emit_insn(Insn::Function {
+                start_reg,
+                arg_count: args.len(),
+                dest: target_register,
+                func,
+            });
+            Ok(target_register)
+        }
+        ast::Expr::FunctionCallStar { name, .. } => {
+            if resolve_agg_func(name, 0).is_some() {
+                anyhow::bail!("Parse error: misuse of aggregate function {}()", name.0);
+            }
+            anyhow::bail!("Parse error: no such function: {}", name.0);
+        }
+        ast::Expr::Id(ident) => translate_ident(program, select, None, &ident.0, target_register),
+        ast::Expr::InList { lhs, not, rhs } => {
+            let lhs_reg = program.alloc_register();
+            let _ = translate_expr(program, select, lhs, lhs_reg)?;
+            let rhs = rhs.as_deref().unwrap_or_default();
+            let affinity = in_list_affinity(select, lhs);
+            translate_in_list_value(program, select, lhs_reg, rhs, affinity, target_register)?;
+            if *not {
+                program.emit_insn(Insn::Not {
+                    reg: target_register,
                     dest: target_register,
-                    cursor_id,
                 });
             }
-            maybe_apply_affinity(col, target_register, program);
             Ok(target_register)
         }
-        ast::Expr::InList { .. } => todo!(),
         ast::Expr::InSelect { .. } => todo!(),
         ast::Expr::InTable { .. } => todo!(),
-        ast::Expr::IsNull(_) => todo!(),
+        ast::Expr::IsNull(expr) => {
+            let reg = program.alloc_register();
+            let _ = translate_expr(program, select, expr, reg)?;
+            translate_null_check_value(program, true, reg, target_register);
+            Ok(target_register)
+        }
         ast::Expr::Like { .. } => todo!(),
         ast::Expr::Literal(lit) => match lit {
             ast::Literal::Numeric(val) => {
@@ -518,52 +4973,232 @@ fn translate_expr(
             }
             ast::Literal::String(s) => {
                 program.emit_insn(Insn::String8 {
-                    value: s[1..s.len() - 1].to_string(),
+                    value: s[1..s.len() - 1].replace("''", "'"),
+                    dest: target_register,
+                });
+                Ok(target_register)
+            }
+            ast::Literal::Blob(hex) => {
+                // The lexer only accepts an even number of hex digits.
+                let value = (0..hex.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+                    .collect();
+                program.emit_insn(Insn::Blob {
+                    value,
                     dest: target_register,
                 });
                 Ok(target_register)
             }
-            ast::Literal::Blob(_) => todo!(),
             ast::Literal::Keyword(_) => todo!(),
-            ast::Literal::Null => todo!(),
+            ast::Literal::Null => {
+                program.emit_insn(Insn::Null {
+                    dest: target_register,
+                });
+                Ok(target_register)
+            }
             ast::Literal::CurrentDate => todo!(),
             ast::Literal::CurrentTime => todo!(),
             ast::Literal::CurrentTimestamp => todo!(),
         },
         ast::Expr::Name(_) => todo!(),
-        ast::Expr::NotNull(_) => todo!(),
-        ast::Expr::Parenthesized(_) => todo!(),
-        ast::Expr::Qualified(_, _) => todo!(),
+        ast::Expr::NotNull(expr) => {
+            let reg = program.alloc_register();
+            let _ = translate_expr(program, select, expr, reg)?;
+            translate_null_check_value(program, false, reg, target_register);
+            Ok(target_register)
+        }
+        ast::Expr::Parenthesized(exprs) => {
+            if exprs.len() != 1 {
+                anyhow::bail!("Parse error: row value misused");
+            }
+            translate_expr(program, select, &exprs[0], target_register)
+        }
+        ast::Expr::Qualified(table_name, ident) => translate_ident(
+            program,
+            select,
+            Some(&table_name.0),
+            &ident.0,
+            target_register,
+        ),
         ast::Expr::Raise(_, _) => todo!(),
         ast::Expr::Subquery(_) => todo!(),
-        ast::Expr::Unary(_, _) => todo!(),
+        ast::Expr::Unary(op, expr) => match (op, expr.as_ref()) {
+            (ast::UnaryOperator::Negative, ast::Expr::Literal(ast::Literal::Numeric(val))) => {
+                // Fold negative literals so that i64::MIN stays an integer.
+                let val = format!("-{}", val);
+                if let Ok(value) = val.parse::<i64>() {
+                    program.emit_insn(Insn::Integer {
+                        value,
+                        dest: target_register,
+                    });
+                } else {
+                    program.emit_insn(Insn::Real {
+                        value: val.parse()?,
+                        dest: target_register,
+                    });
+                }
+                Ok(target_register)
+            }
+            (ast::UnaryOperator::Negative, _) => {
+                let zero_reg = program.alloc_register();
+                let reg = program.alloc_register();
+                program.emit_insn(Insn::Integer {
+                    value: 0,
+                    dest: zero_reg,
+                });
+                let _ = translate_expr(program, select, expr, reg)?;
+                program.emit_insn(Insn::Subtract {
+                    lhs: zero_reg,
+                    rhs: reg,
+                    dest: target_register,
+                });
+                Ok(target_register)
+            }
+            (ast::UnaryOperator::Positive, _) => {
+                translate_expr(program, select, expr, target_register)
+            }
+            (ast::UnaryOperator::Not, _) => {
+                let reg = program.alloc_register();
+                let _ = translate_expr(program, select, expr, reg)?;
+                program.emit_insn(Insn::Not {
+                    reg,
+                    dest: target_register,
+                });
+                Ok(target_register)
+            }
+            (ast::UnaryOperator::BitwiseNot, _) => {
+                let reg = program.alloc_register();
+                let _ = translate_expr(program, select, expr, reg)?;
+                program.emit_insn(Insn::BitNot {
+                    reg,
+                    dest: target_register,
+                });
+                Ok(target_register)
+            }
+        },
         ast::Expr::Variable(_) => todo!(),
     }
 }
 
-fn resolve_ident_table<'a>(
-    ident: &String,
-    select: &'a Select,
-) -> Result<(usize, &'a Column, usize)> {
-    for join in &select.src_tables {
-        let res = join
-            .table
-            .columns()
-            .iter()
-            .enumerate()
-            .find(|(_, col)| col.name == *ident);
-        if res.is_some() {
-            let (idx, col) = res.unwrap();
-            let cursor_id = select
-                .loops
-                .iter()
-                .find(|l| l.table == join.table)
-                .unwrap()
-                .open_cursor;
-            return Ok((idx, col, cursor_id));
+/// Find the source table and column that an unqualified column name refers
+/// to. Returns the index of the table in `src_tables` and of the column.
+fn resolve_ident_table(ident: &str, select: &Select) -> Result<(usize, usize)> {
+    let mut found = None;
+    for (src_idx, src) in select.src_tables.iter().enumerate() {
+        if let Some((column, _)) = src.table.get_column(ident) {
+            // A column matched by USING or NATURAL refers to the left-hand table.
+            if src.using_columns.iter().any(|u| u.right_column == column) {
+                continue;
+            }
+            if found.is_some() {
+                anyhow::bail!("Parse error: ambiguous column name: {}", ident);
+            }
+            found = Some((src_idx, column));
+        }
+    }
+    match found {
+        Some(found) => Ok(found),
+        None => anyhow::bail!("Parse error: column with name {} not found", ident),
+    }
+}
+
+/// Find the source table and column that `table_name.ident` refers to.
+fn resolve_qualified_ident(
+    table_name: &str,
+    ident: &str,
+    select: &Select,
+) -> Result<(usize, usize)> {
+    let identifier = normalize_ident(table_name);
+    select
+        .src_tables
+        .iter()
+        .enumerate()
+        .filter(|(_, src)| src.identifier == identifier)
+        .find_map(|(src_idx, src)| {
+            src.table
+                .get_column(ident)
+                .map(|(column, _)| (src_idx, column))
+        })
+        .ok_or_else(|| anyhow::anyhow!("Parse error: no such column: {}.{}", table_name, ident))
+}
+
+/// Find the source table and column that `table_name.ident`, or `ident`
+/// alone, refers to. The column is `None` for the rowid, which can also be
+/// referred to as `rowid`, `oid` or `_rowid_` unless a column has that name.
+fn resolve_ident(
+    table_name: Option<&str>,
+    ident: &str,
+    select: &Select,
+) -> Result<(usize, Option<usize>)> {
+    let resolved = match table_name {
+        Some(table_name) => resolve_qualified_ident(table_name, ident, select),
+        None => resolve_ident_table(ident, select),
+    };
+    let err = match resolved {
+        Ok((src_idx, column)) => return Ok((src_idx, Some(column))),
+        Err(err) => err,
+    };
+    if !["rowid", "oid", "_rowid_"].contains(&normalize_ident(ident).as_str()) {
+        return Err(err);
+    }
+    let identifier = table_name.map(normalize_ident);
+    let mut found = None;
+    for (src_idx, src) in select.src_tables.iter().enumerate() {
+        if identifier
+            .as_ref()
+            .is_some_and(|identifier| *identifier != src.identifier)
+        {
+            continue;
+        }
+        if matches!(&src.table, Table::BTree(table) if table.has_rowid) {
+            if found.is_some() {
+                anyhow::bail!("Parse error: ambiguous column name: {}", ident);
+            }
+            found = Some(src_idx);
+        }
+    }
+    match found {
+        Some(src_idx) => Ok((src_idx, None)),
+        None => Err(err),
+    }
+}
+
+/// Generate code that reads the column or rowid that `table_name.ident`, or
+/// `ident` alone, refers to.
+fn translate_ident(
+    program: &mut ProgramBuilder,
+    select: &Select,
+    table_name: Option<&str>,
+    ident: &str,
+    target_register: usize,
+) -> Result<usize> {
+    match resolve_ident(table_name, ident, select)? {
+        (src_idx, Some(column)) => {
+            translate_src_column(program, select, src_idx, column, target_register)
         }
+        (src_idx, None) => program.emit_insn(Insn::RowId {
+            cursor_id: select.loops[src_idx].open_cursor,
+            dest: target_register,
+        }),
     }
-    anyhow::bail!("Parse error: column with name {} not found", ident.as_str());
+    Ok(target_register)
+}
+
+fn translate_src_column(
+    program: &mut ProgramBuilder,
+    select: &Select,
+    src_idx: usize,
+    column: usize,
+    target_register: usize,
+) {
+    translate_table_column(
+        program,
+        &select.src_tables[src_idx].table,
+        select.loops[src_idx].open_cursor,
+        column,
+        target_register,
+    );
 }
 
 fn translate_aggregation(
@@ -576,6 +5211,9 @@ fn translate_aggregation(
     let _ = expr;
     assert!(info.func.is_some());
     let func = info.func.as_ref().unwrap();
+    if info.distinct {
+        anyhow::bail!("Parse error: DISTINCT aggregates are not supported");
+    }
     let empty_args = &Vec::<ast::Expr>::new();
     let args = info.args.as_ref().unwrap_or(empty_args);
     let dest = match func {
@@ -595,7 +5233,13 @@ fn translate_aggregation(
         }
         AggFunc::Count => {
             let expr_reg = if args.is_empty() {
-                program.alloc_register()
+                // count(*) counts every row, so feed it a non-NULL value.
+                let expr_reg = program.alloc_register();
+                program.emit_insn(Insn::Integer {
+                    value: 1,
+                    dest: expr_reg,
+                });
+                expr_reg
             } else {
                 let expr = &args[0];
                 let expr_reg = program.alloc_register();
@@ -680,8 +5324,48 @@ fn translate_pragma(
     let mut program = ProgramBuilder::new();
     let init_offset = program.emit_placeholder();
     let start_offset = program.offset();
-    match body {
-        None => {
+    match (normalize_ident(&name.name.0).as_str(), body) {
+        ("journal_mode", body) => {
+            let mode = match body {
+                None => None,
+                Some(ast::PragmaBody::Equals(value) | ast::PragmaBody::Call(value)) => {
+                    match pragma_argument(&value).as_deref() {
+                        Some("delete") => Some(JournalMode::Delete),
+                        Some("wal") => Some(JournalMode::Wal),
+                        _ => anyhow::bail!("Parse error: journal mode {} is not supported", value),
+                    }
+                }
+            };
+            let dest = program.alloc_register();
+            program.emit_insn(Insn::JournalMode { mode, dest });
+            program.emit_insn(Insn::ResultRow {
+                start_reg: dest,
+                count: 1,
+            });
+        }
+        ("wal_checkpoint", body) => {
+            let mode = match body {
+                None => CheckpointMode::Passive,
+                Some(ast::PragmaBody::Equals(value) | ast::PragmaBody::Call(value)) => {
+                    match pragma_argument(&value).as_deref() {
+                        Some("passive") => CheckpointMode::Passive,
+                        Some("full") => CheckpointMode::Full,
+                        Some("restart") => CheckpointMode::Restart,
+                        Some("truncate") => CheckpointMode::Truncate,
+                        _ => {
+                            anyhow::bail!("Parse error: checkpoint mode {} is not supported", value)
+                        }
+                    }
+                }
+            };
+            let dest = program.alloc_registers(3);
+            program.emit_insn(Insn::Checkpoint { mode, dest });
+            program.emit_insn(Insn::ResultRow {
+                start_reg: dest,
+                count: 3,
+            });
+        }
+        (_, None) => {
             let pragma_result = program.alloc_register();
 
             program.emit_insn(Insn::Integer {
@@ -695,7 +5379,7 @@ fn translate_pragma(
                 count: pragma_result_end - pragma_result,
             });
         }
-        Some(ast::PragmaBody::Equals(value)) => {
+        (_, Some(ast::PragmaBody::Equals(value))) => {
             let value_to_update = match value {
                 ast::Expr::Literal(ast::Literal::Numeric(numeric_value)) => {
                     numeric_value.parse::<i64>().unwrap()
@@ -710,11 +5394,14 @@ fn translate_pragma(
             };
             update_pragma(&name.name.0, value_to_update, database_header, pager);
         }
-        Some(ast::PragmaBody::Call(_)) => {
+        (_, Some(ast::PragmaBody::Call(_))) => {
             todo!()
         }
     };
-    program.emit_insn(Insn::Halt);
+    program.emit_insn(Insn::Halt {
+        err_code: 0,
+        description: String::new(),
+    });
     program.fixup_insn(
         init_offset,
         Insn::Init {
@@ -728,6 +5415,18 @@ fn translate_pragma(
     Ok(program.build())
 }
 
+/// The argument of a pragma that takes a keyword, such as `WAL` in
+/// `PRAGMA journal_mode = WAL`, in lower case.
+fn pragma_argument(value: &ast::Expr) -> Option<String> {
+    let name = match value {
+        ast::Expr::Id(ast::Id(name))
+        | ast::Expr::Name(ast::Name(name))
+        | ast::Expr::Literal(ast::Literal::Keyword(name) | ast::Literal::String(name)) => name,
+        _ => return None,
+    };
+    Some(name.trim_matches(['\'', '"']).to_lowercase())
+}
+
 fn update_pragma(name: &str, value: i64, header: Rc<RefCell<DatabaseHeader>>, pager: Rc<Pager>) {
     match name {
         "cache_size" => {
@@ -768,3 +5467,48 @@ fn maybe_apply_affinity(col: &Column, target_register: usize, program: &mut Prog
         _ => {}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schema_sql_like_sqlite() -> Result<()> {
+        // The expected texts are what SQLite stores for these statements.
+        for (sql, prefix, is_index, expected) in [
+            (
+                "create   table if not exists main.t1 ( a INTEGER primary key,  -- id\n b text ) ;",
+                "CREATE TABLE",
+                false,
+                "CREATE TABLE t1 ( a INTEGER primary key,  -- id\n b text )",
+            ),
+            (
+                "CREATE TABLE \"t 2\"(x)",
+                "CREATE TABLE",
+                false,
+                "CREATE TABLE \"t 2\"(x)",
+            ),
+            (
+                "create table t3(a, b) ; create table t4(a)",
+                "CREATE TABLE",
+                false,
+                "CREATE TABLE t3(a, b)",
+            ),
+            (
+                "create unique index if not exists i1 on t1 ( b ) ;  ",
+                "CREATE UNIQUE INDEX",
+                true,
+                "CREATE UNIQUE INDEX i1 on t1 ( b ) ",
+            ),
+            (
+                "create index main.i2 on t1(b,a)  ",
+                "CREATE INDEX",
+                true,
+                "CREATE INDEX i2 on t1(b,a)  ",
+            ),
+        ] {
+            assert_eq!(schema_sql(sql, prefix, is_index)?, expected);
+        }
+        Ok(())
+    }
+}
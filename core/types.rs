@@ -55,6 +55,40 @@ impl Display for OwnedValue {
     }
 }
 
+impl PartialOrd<OwnedValue> for OwnedValue {
+    /// Compare two values using SQLite's ordering rules: NULL sorts before
+    /// numbers, which sort before text, which sorts before blobs. Integers and
+    /// floats are compared numerically, text and blobs byte by byte.
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        match (self, other) {
+            (OwnedValue::Integer(int_left), OwnedValue::Integer(int_right)) => {
+                int_left.partial_cmp(int_right)
+            }
+            (OwnedValue::Integer(int_left), OwnedValue::Float(float_right)) => {
+                (*int_left as f64).partial_cmp(float_right)
+            }
+            (OwnedValue::Float(float_left), OwnedValue::Integer(int_right)) => {
+                float_left.partial_cmp(&(*int_right as f64))
+            }
+            (OwnedValue::Float(float_left), OwnedValue::Float(float_right)) => {
+                float_left.partial_cmp(float_right)
+            }
+            (OwnedValue::Text(text_left), OwnedValue::Text(text_right)) => {
+                text_left.as_bytes().partial_cmp(text_right.as_bytes())
+            }
+            (OwnedValue::Blob(blob_left), OwnedValue::Blob(blob_right)) => {
+                blob_left.partial_cmp(blob_right)
+            }
+            (OwnedValue::Null, OwnedValue::Null) => Some(std::cmp::Ordering::Equal),
+            (OwnedValue::Agg(_), _)
+            | (_, OwnedValue::Agg(_))
+            | (OwnedValue::Record(_), _)
+            | (_, OwnedValue::Record(_)) => None,
+            (lhs, rhs) => lhs.type_order().partial_cmp(&rhs.type_order()),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum AggContext {
     Avg(OwnedValue, OwnedValue), // acc and count
@@ -64,13 +98,240 @@ pub enum AggContext {
     Min(OwnedValue),
 }
 
+impl AggContext {
+    /// The value of the aggregate. Only meaningful once `AggFinal` has run.
+    pub fn final_value(&self) -> &OwnedValue {
+        match self {
+            AggContext::Avg(acc, _count) => acc,
+            AggContext::Sum(acc) => acc,
+            AggContext::Count(count) => count,
+            AggContext::Max(max) => max,
+            AggContext::Min(min) => min,
+        }
+    }
+}
+
+impl OwnedValue {
+    /// Convert the value to a number the way SQLite does for arithmetic:
+    /// text (and blobs, which are read as text) yields its longest numeric
+    /// prefix, or 0 if there is none. NULL stays NULL.
+    pub fn to_numeric(&self) -> OwnedValue {
+        match self {
+            OwnedValue::Integer(_) | OwnedValue::Float(_) | OwnedValue::Null => self.clone(),
+            OwnedValue::Text(text) => parse_numeric_prefix(text),
+            OwnedValue::Blob(blob) => parse_numeric_prefix(&String::from_utf8_lossy(blob)),
+            OwnedValue::Agg(_) | OwnedValue::Record(_) => unreachable!(),
+        }
+    }
+
+    /// Convert the value to an integer the way SQLite's bitwise operators do.
+    /// Returns `None` for NULL.
+    pub fn to_integer(&self) -> Option<i64> {
+        match self.to_numeric() {
+            OwnedValue::Integer(i) => Some(i),
+            OwnedValue::Float(f) => Some(f as i64),
+            _ => None,
+        }
+    }
+
+    /// Render the value as text, as SQLite does when a number is used where
+    /// a string is expected. Returns `None` for NULL.
+    pub fn to_text(&self) -> Option<String> {
+        match self {
+            OwnedValue::Null => None,
+            OwnedValue::Integer(i) => Some(i.to_string()),
+            OwnedValue::Float(f) => Some(format_float(*f)),
+            OwnedValue::Text(text) => Some(text.to_string()),
+            OwnedValue::Blob(blob) => Some(String::from_utf8_lossy(blob).to_string()),
+            OwnedValue::Agg(_) | OwnedValue::Record(_) => unreachable!(),
+        }
+    }
+
+    /// Convert the value for storage in a column with the given affinity, the
+    /// way SQLite does on insert. Affinities use SQLite's letters: 'A' for
+    /// BLOB (no conversion), 'B' TEXT, 'C' NUMERIC, 'D' INTEGER and 'E' REAL.
+    ///
+    /// Like SQLite, REAL affinity leaves whole numbers that fit in 48 bits as
+    /// integers, so that the record stores them in fewer bytes; the column
+    /// reads them back as reals through the RealAffinity opcode.
+    pub fn apply_affinity(&self, affinity: char) -> OwnedValue {
+        const INT_REAL_RANGE: std::ops::Range<i64> = -(1 << 47)..(1 << 47);
+        match (affinity, self) {
+            ('B', OwnedValue::Integer(_) | OwnedValue::Float(_)) => {
+                OwnedValue::Text(Rc::new(self.to_text().unwrap()))
+            }
+            ('C' | 'D' | 'E', OwnedValue::Text(text)) => match parse_numeric(text) {
+                Some(value) => value.apply_affinity(affinity),
+                None => self.clone(),
+            },
+            ('C' | 'D', OwnedValue::Float(f))
+                if f.fract() == 0.0 && (i64::MIN as f64..i64::MAX as f64).contains(f) =>
+            {
+                OwnedValue::Integer(*f as i64)
+            }
+            ('E', OwnedValue::Integer(i)) if !INT_REAL_RANGE.contains(i) => {
+                OwnedValue::Float(*i as f64)
+            }
+            ('E', OwnedValue::Float(f))
+                if f.fract() == 0.0
+                    && (INT_REAL_RANGE.start as f64..INT_REAL_RANGE.end as f64).contains(f) =>
+            {
+                OwnedValue::Integer(*f as i64)
+            }
+            _ => self.clone(),
+        }
+    }
+
+    /// Rank of the value's storage class in SQLite's cross-type ordering.
+    fn type_order(&self) -> u8 {
+        match self {
+            OwnedValue::Null => 0,
+            OwnedValue::Integer(_) | OwnedValue::Float(_) => 1,
+            OwnedValue::Text(_) => 2,
+            OwnedValue::Blob(_) => 3,
+            OwnedValue::Agg(_) | OwnedValue::Record(_) => unreachable!(),
+        }
+    }
+}
+
+fn parse_numeric_prefix(text: &str) -> OwnedValue {
+    let text = text.trim_start();
+    match numeric_prefix(text) {
+        Some((prefix, is_float)) => parse_number(prefix, is_float),
+        None => OwnedValue::Integer(0),
+    }
+}
+
+/// Parse text that is a well-formed number apart from surrounding whitespace.
+fn parse_numeric(text: &str) -> Option<OwnedValue> {
+    let text = text.trim();
+    match numeric_prefix(text) {
+        Some((prefix, is_float)) if prefix.len() == text.len() => {
+            Some(parse_number(prefix, is_float))
+        }
+        _ => None,
+    }
+}
+
+/// Find the longest prefix of the text that is a number, and whether it is
+/// written as a real number.
+fn numeric_prefix(text: &str) -> Option<(&str, bool)> {
+    let bytes = text.as_bytes();
+    let mut end = 0;
+    if end < bytes.len() && (bytes[end] == b'+' || bytes[end] == b'-') {
+        end += 1;
+    }
+    let digits_start = end;
+    while end < bytes.len() && bytes[end].is_ascii_digit() {
+        end += 1;
+    }
+    let mut is_float = false;
+    if end < bytes.len() && bytes[end] == b'.' {
+        let mut frac_end = end + 1;
+        while frac_end < bytes.len() && bytes[frac_end].is_ascii_digit() {
+            frac_end += 1;
+        }
+        if frac_end > end + 1 || end > digits_start {
+            is_float = true;
+            end = frac_end;
+        }
+    }
+    if end == digits_start {
+        return None;
+    }
+    if end < bytes.len() && (bytes[end] == b'e' || bytes[end] == b'E') {
+        let mut exp_end = end + 1;
+        if exp_end < bytes.len() && (bytes[exp_end] == b'+' || bytes[exp_end] == b'-') {
+            exp_end += 1;
+        }
+        let exp_digits_start = exp_end;
+        while exp_end < bytes.len() && bytes[exp_end].is_ascii_digit() {
+            exp_end += 1;
+        }
+        if exp_end > exp_digits_start {
+            is_float = true;
+            end = exp_end;
+        }
+    }
+    Some((&text[..end], is_float))
+}
+
+fn parse_number(prefix: &str, is_float: bool) -> OwnedValue {
+    if !is_float {
+        if let Ok(i) = prefix.parse::<i64>() {
+            return OwnedValue::Integer(i);
+        }
+    }
+    OwnedValue::Float(prefix.parse::<f64>().unwrap_or(0.0))
+}
+
+/// Format a float the way SQLite renders REAL values as text: 15 significant
+/// digits, switching to exponent notation for very large or small values,
+/// and always with a decimal point.
+pub fn format_float(f: f64) -> String {
+    if f.is_nan() {
+        return String::new();
+    }
+    if f.is_infinite() {
+        return if f > 0.0 { "Inf" } else { "-Inf" }.to_string();
+    }
+    if f == 0.0 {
+        return "0.0".to_string();
+    }
+    let formatted = format!("{:.14e}", f);
+    let (mantissa, exponent) = formatted.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    let (sign, mantissa) = match mantissa.strip_prefix('-') {
+        Some(m) => ("-", m),
+        None => ("", mantissa),
+    };
+    let digits: String = mantissa.chars().filter(|c| *c != '.').collect();
+    let digits = digits.trim_end_matches('0');
+    if !(-4..15).contains(&exponent) {
+        let (first, rest) = digits.split_at(1);
+        let rest = if rest.is_empty() { "0" } else { rest };
+        let exp_sign = if exponent < 0 { '-' } else { '+' };
+        return format!(
+            "{}{}.{}e{}{:02}",
+            sign,
+            first,
+            rest,
+            exp_sign,
+            exponent.abs()
+        );
+    }
+    let (int_part, frac_part) = if exponent >= 0 {
+        let int_len = exponent as usize + 1;
+        if digits.len() > int_len {
+            (digits[..int_len].to_string(), digits[int_len..].to_string())
+        } else {
+            (
+                format!("{:0<width$}", digits, width = int_len),
+                String::new(),
+            )
+        }
+    } else {
+        let zeros = "0".repeat((-exponent - 1) as usize);
+        ("0".to_string(), format!("{}{}", zeros, digits))
+    };
+    let frac_part = if frac_part.is_empty() {
+        "0".to_string()
+    } else {
+        frac_part
+    };
+    format!("{}{}.{}", sign, int_part, frac_part)
+}
+
 impl std::ops::Add<OwnedValue> for OwnedValue {
     type Output = OwnedValue;
 
     fn add(self, rhs: Self) -> Self::Output {
-        match (self, rhs) {
+        match (self.to_numeric(), rhs.to_numeric()) {
             (OwnedValue::Integer(int_left), OwnedValue::Integer(int_right)) => {
-                OwnedValue::Integer(int_left + int_right)
+                match int_left.checked_add(int_right) {
+                    Some(sum) => OwnedValue::Integer(sum),
+                    None => OwnedValue::Float(int_left as f64 + int_right as f64),
+                }
             }
             (OwnedValue::Integer(int_left), OwnedValue::Float(float_right)) => {
                 OwnedValue::Float(int_left as f64 + float_right)
@@ -81,9 +342,7 @@ impl std::ops::Add<OwnedValue> for OwnedValue {
             (OwnedValue::Float(float_left), OwnedValue::Float(float_right)) => {
                 OwnedValue::Float(float_left + float_right)
             }
-            (lhs, OwnedValue::Null) => lhs,
-            (OwnedValue::Null, rhs) => rhs,
-            _ => OwnedValue::Float(0.0),
+            _ => OwnedValue::Null,
         }
     }
 }
@@ -130,24 +389,91 @@ impl std::ops::AddAssign<f64> for OwnedValue {
     }
 }
 
+impl std::ops::Sub<OwnedValue> for OwnedValue {
+    type Output = OwnedValue;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        match (self.to_numeric(), rhs.to_numeric()) {
+            (OwnedValue::Integer(int_left), OwnedValue::Integer(int_right)) => {
+                match int_left.checked_sub(int_right) {
+                    Some(diff) => OwnedValue::Integer(diff),
+                    None => OwnedValue::Float(int_left as f64 - int_right as f64),
+                }
+            }
+            (OwnedValue::Integer(int_left), OwnedValue::Float(float_right)) => {
+                OwnedValue::Float(int_left as f64 - float_right)
+            }
+            (OwnedValue::Float(float_left), OwnedValue::Integer(int_right)) => {
+                OwnedValue::Float(float_left - int_right as f64)
+            }
+            (OwnedValue::Float(float_left), OwnedValue::Float(float_right)) => {
+                OwnedValue::Float(float_left - float_right)
+            }
+            _ => OwnedValue::Null,
+        }
+    }
+}
+
+impl std::ops::Mul<OwnedValue> for OwnedValue {
+    type Output = OwnedValue;
+
+    fn mul(self, rhs: Self) -> Self::Output {
+        match (self.to_numeric(), rhs.to_numeric()) {
+            (OwnedValue::Integer(int_left), OwnedValue::Integer(int_right)) => {
+                match int_left.checked_mul(int_right) {
+                    Some(product) => OwnedValue::Integer(product),
+                    None => OwnedValue::Float(int_left as f64 * int_right as f64),
+                }
+            }
+            (OwnedValue::Integer(int_left), OwnedValue::Float(float_right)) => {
+                OwnedValue::Float(int_left as f64 * float_right)
+            }
+            (OwnedValue::Float(float_left), OwnedValue::Integer(int_right)) => {
+                OwnedValue::Float(float_left * int_right as f64)
+            }
+            (OwnedValue::Float(float_left), OwnedValue::Float(float_right)) => {
+                OwnedValue::Float(float_left * float_right)
+            }
+            _ => OwnedValue::Null,
+        }
+    }
+}
+
 impl std::ops::Div<OwnedValue> for OwnedValue {
     type Output = OwnedValue;
 
+    /// Division by zero yields NULL, as in SQLite.
     fn div(self, rhs: OwnedValue) -> Self::Output {
-        match (self, rhs) {
+        match (self.to_numeric(), rhs.to_numeric()) {
+            (OwnedValue::Integer(_), OwnedValue::Integer(0)) => OwnedValue::Null,
             (OwnedValue::Integer(int_left), OwnedValue::Integer(int_right)) => {
-                OwnedValue::Integer(int_left / int_right)
+                match int_left.checked_div(int_right) {
+                    Some(quotient) => OwnedValue::Integer(quotient),
+                    None => OwnedValue::Float(int_left as f64 / int_right as f64),
+                }
             }
             (OwnedValue::Integer(int_left), OwnedValue::Float(float_right)) => {
-                OwnedValue::Float(int_left as f64 / float_right)
+                if float_right == 0.0 {
+                    OwnedValue::Null
+                } else {
+                    OwnedValue::Float(int_left as f64 / float_right)
+                }
             }
             (OwnedValue::Float(float_left), OwnedValue::Integer(int_right)) => {
-                OwnedValue::Float(float_left / int_right as f64)
+                if int_right == 0 {
+                    OwnedValue::Null
+                } else {
+                    OwnedValue::Float(float_left / int_right as f64)
+                }
             }
             (OwnedValue::Float(float_left), OwnedValue::Float(float_right)) => {
-                OwnedValue::Float(float_left / float_right)
+                if float_right == 0.0 {
+                    OwnedValue::Null
+                } else {
+                    OwnedValue::Float(float_left / float_right)
+                }
             }
-            _ => unreachable!(),
+            _ => OwnedValue::Null,
         }
     }
 }
@@ -158,6 +484,28 @@ impl std::ops::DivAssign<OwnedValue> for OwnedValue {
     }
 }
 
+impl std::ops::Rem<OwnedValue> for OwnedValue {
+    type Output = OwnedValue;
+
+    /// Both operands are truncated to integers. The result is REAL if either
+    /// operand was REAL, and NULL if the divisor is zero.
+    fn rem(self, rhs: OwnedValue) -> Self::Output {
+        let (lhs, rhs) = (self.to_numeric(), rhs.to_numeric());
+        let is_float = matches!(lhs, OwnedValue::Float(_)) || matches!(rhs, OwnedValue::Float(_));
+        match (lhs.to_integer(), rhs.to_integer()) {
+            (Some(_), Some(0)) | (None, _) | (_, None) => OwnedValue::Null,
+            (Some(int_left), Some(int_right)) => {
+                let remainder = int_left.checked_rem(int_right).unwrap_or(0);
+                if is_float {
+                    OwnedValue::Float(remainder as f64)
+                } else {
+                    OwnedValue::Integer(remainder)
+                }
+            }
+        }
+    }
+}
+
 pub fn to_value(value: &OwnedValue) -> Value<'_> {
     match value {
         OwnedValue::Null => Value::Null,
@@ -165,13 +513,7 @@ pub fn to_value(value: &OwnedValue) -> Value<'_> {
         OwnedValue::Float(f) => Value::Float(*f),
         OwnedValue::Text(s) => Value::Text(s),
         OwnedValue::Blob(b) => Value::Blob(b),
-        OwnedValue::Agg(a) => match a.as_ref() {
-            AggContext::Avg(acc, _count) => to_value(acc), // we assume aggfinal was called
-            AggContext::Sum(acc) => to_value(acc),
-            AggContext::Count(count) => to_value(count),
-            AggContext::Max(max) => to_value(max),
-            AggContext::Min(min) => to_value(min),
-        },
+        OwnedValue::Agg(a) => to_value(a.final_value()),
         OwnedValue::Record(_) => todo!(),
     }
 }
@@ -229,6 +571,29 @@ impl OwnedRecord {
     pub fn new(values: Vec<OwnedValue>) -> Self {
         Self { values }
     }
+
+    /// Compare the leading columns of the record with `key`, which may have
+    /// fewer columns, in the order of an ascending index.
+    pub fn compare_prefix(&self, key: &OwnedRecord) -> std::cmp::Ordering {
+        for (value, key_value) in self.values.iter().zip(key.values.iter()) {
+            let ordering = value
+                .partial_cmp(key_value)
+                .unwrap_or(std::cmp::Ordering::Equal);
+            if ordering != std::cmp::Ordering::Equal {
+                return ordering;
+            }
+        }
+        std::cmp::Ordering::Equal
+    }
+}
+
+/// Where `Cursor::seek` positions an index cursor: on the first entry whose
+/// leading columns are greater than or equal to the key, or greater than it.
+/// `Cursor::seek_rowid` does the same for the rowid of a table cursor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SeekOp {
+    GE,
+    GT,
 }
 
 pub enum CursorResult<T> {
@@ -240,8 +605,19 @@ pub trait Cursor {
     fn is_empty(&self) -> bool;
     fn rewind(&mut self) -> Result<CursorResult<()>>;
     fn next(&mut self) -> Result<CursorResult<()>>;
+    fn last(&mut self) -> Result<CursorResult<()>>;
+    fn prev(&mut self) -> Result<CursorResult<()>>;
     fn wait_for_completion(&mut self) -> Result<()>;
     fn rowid(&self) -> Result<Ref<Option<u64>>>;
     fn record(&self) -> Result<Ref<Option<OwnedRecord>>>;
-    fn insert(&mut self, record: &OwnedRecord) -> Result<()>;
+    fn insert(&mut self, key: &OwnedValue, record: &OwnedRecord) -> Result<CursorResult<()>>;
+    fn delete(&mut self) -> Result<CursorResult<()>>;
+    fn index_insert(&mut self, key: &OwnedRecord) -> Result<CursorResult<()>>;
+    fn index_delete(&mut self, key: &OwnedRecord) -> Result<CursorResult<bool>>;
+    fn exists(&mut self, key: &OwnedValue) -> Result<CursorResult<bool>>;
+    fn new_rowid(&mut self) -> Result<CursorResult<i64>>;
+    fn seek(&mut self, key: &OwnedRecord, op: SeekOp) -> Result<CursorResult<bool>>;
+    fn seek_rowid(&mut self, rowid: i64, op: SeekOp) -> Result<CursorResult<bool>>;
+    fn set_null_flag(&mut self, flag: bool);
+    fn get_null_flag(&self) -> bool;
 }
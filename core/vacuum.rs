@@ -0,0 +1,350 @@
+//! VACUUM.
+//!
+//! VACUUM rebuilds the database into a new file, the way SQLite does: the
+//! rows of each table and the keys of each index are copied in order to a new
+//! b-tree, which leaves the pages full and the file without free pages. The
+//! root pages of the new b-trees come right after page 1, in the order that
+//! SQLite's VACUUM creates them in, and the `sqlite_schema` rows are copied
+//! last, in the same order, with their root page numbers changed to the new
+//! ones.
+//!
+//! `VACUUM INTO` leaves the new file as it is. Otherwise, the new database is
+//! built in a file next to the database, named after it with `-vacuum`
+//! appended, whose pages then replace those of the database in a write
+//! transaction, which is journaled or logged like any other. The new file is
+//! deleted afterwards.
+//!
+//! Neither database is held in memory: the new database is committed every
+//! `PAGES_PER_COMMIT` modified pages while it is built, and its pages are then
+//! copied over those of the database a batch at a time.
+
+use crate::btree::{run_io, BTreeCursor};
+use crate::io::OpenFlags;
+use crate::pager::Pager;
+use crate::sqlite3_ondisk::PageType;
+use crate::storage::PageSource;
+use crate::types::{Cursor, OwnedRecord, OwnedValue};
+use anyhow::Result;
+use log::trace;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// How many pages of the new database may be modified before they are
+/// committed to its file.
+const PAGES_PER_COMMIT: usize = 256;
+
+/// Rebuild the database, in place, or into the file at `into`, which must be
+/// empty if it exists.
+pub fn vacuum(pager: &Rc<Pager>, into: Option<&str>) -> Result<()> {
+    let path = match (into, pager.path()) {
+        (Some(into), _) => into.to_string(),
+        (None, Some(path)) => format!("{path}-vacuum"),
+        (None, None) => anyhow::bail!("cannot VACUUM a database that is not a file"),
+    };
+    trace!("vacuum(path = {})", path);
+    let io = pager.io.clone();
+    let file = io.open_file(&path, OpenFlags::Create)?;
+    if file.size()? > 0 {
+        if into.is_some() {
+            anyhow::bail!("output file already exists");
+        }
+        // Left behind by a VACUUM that did not finish.
+        file.truncate(0)?;
+    }
+    let result = build(pager, PageSource::from_file(file)).and_then(|target| {
+        if into.is_none() {
+            pager.replace_pages(&target)?;
+        }
+        Ok(())
+    });
+    if into.is_none() || result.is_err() {
+        io.remove_file(&path)?;
+    }
+    result
+}
+
+/// Copy the database that `pager` reads to a new database in `page_source`.
+/// Returns the pager of the new database.
+fn build(pager: &Rc<Pager>, page_source: PageSource) -> Result<Rc<Pager>> {
+    let io = pager.io.clone();
+    let mut header = pager.header();
+    header.database_size = 0;
+    header.freelist_trunk_page = 0;
+    header.freelist_pages = 0;
+    header.schema_cookie = header.schema_cookie.wrapping_add(1);
+    // The new database is in rollback journal mode, which is what VACUUM INTO
+    // leaves it in. A VACUUM keeps the header of the database.
+    header.set_wal(false);
+    let target = Rc::new(Pager::finish_open(
+        Rc::new(RefCell::new(header)),
+        page_source,
+        None,
+        None,
+        io.clone(),
+        None,
+    )?);
+    let (schema_root, _) = target.allocate_page(PageType::TableLeaf)?;
+    assert_eq!(schema_root, 1);
+
+    let mut schema_rows = Vec::new();
+    let mut cursor = BTreeCursor::new(pager.clone(), 1);
+    run_io(&io, || cursor.rewind())?;
+    loop {
+        let record = cursor.record()?.clone();
+        let Some(record) = record else { break };
+        schema_rows.push(record);
+        run_io(&io, || cursor.next())?;
+    }
+    // Like SQLite, create the tables, each followed by the indexes for its
+    // UNIQUE and PRIMARY KEY constraints, then the other indexes, and add the
+    // views and triggers last.
+    let text = |record: &OwnedRecord, i: usize| match record.values.get(i) {
+        Some(OwnedValue::Text(value)) => Some(value.to_string()),
+        _ => None,
+    };
+    let tables: Vec<String> = schema_rows
+        .iter()
+        .filter(|record| text(record, 0).as_deref() == Some("table"))
+        .filter_map(|record| text(record, 1))
+        .collect();
+    schema_rows.sort_by_key(|record| {
+        let position = |name| tables.iter().position(|table| *table == name);
+        match (text(record, 0).as_deref(), text(record, 4)) {
+            (Some("table"), _) => (0, position(text(record, 1).unwrap_or_default())),
+            (Some("index"), None) => (0, position(text(record, 2).unwrap_or_default())),
+            (Some("index"), Some(_)) => (1, None),
+            _ => (2, None),
+        }
+    });
+
+    // Views and triggers have no b-tree, and their root page is 0.
+    let mut btrees = Vec::new();
+    for record in &mut schema_rows {
+        let Some(OwnedValue::Integer(root_page)) = record.values.get_mut(3) else {
+            continue;
+        };
+        if *root_page <= 0 {
+            continue;
+        }
+        let page = pager.read_page(*root_page as usize)?;
+        while page.is_locked() {
+            io.run_once()?;
+        }
+        let page_type = page
+            .contents
+            .read()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .as_btree()
+            .header
+            .page_type;
+        let is_table = matches!(page_type, PageType::TableLeaf | PageType::TableInterior);
+        let leaf_type = if is_table {
+            PageType::TableLeaf
+        } else {
+            PageType::IndexLeaf
+        };
+        let (new_root_page, _) = target.allocate_page(leaf_type)?;
+        commit_if_full(&target)?;
+        btrees.push((*root_page as usize, new_root_page, is_table));
+        *root_page = new_root_page as i64;
+    }
+
+    for (root_page, new_root_page, is_table) in btrees {
+        let mut cursor = BTreeCursor::new(pager.clone(), root_page);
+        let mut new_cursor = BTreeCursor::new(target.clone(), new_root_page);
+        run_io(&io, || cursor.rewind())?;
+        loop {
+            let record = cursor.record()?.clone();
+            let Some(record) = record else { break };
+            if is_table {
+                let rowid = OwnedValue::Integer(cursor.rowid()?.unwrap() as i64);
+                run_io(&io, || new_cursor.insert(&rowid, &record))?;
+            } else {
+                run_io(&io, || new_cursor.index_insert(&record))?;
+            }
+            commit_if_full(&target)?;
+            run_io(&io, || cursor.next())?;
+        }
+    }
+    let mut new_cursor = BTreeCursor::new(target.clone(), schema_root);
+    for (i, record) in schema_rows.into_iter().enumerate() {
+        let rowid = OwnedValue::Integer(i as i64 + 1);
+        run_io(&io, || new_cursor.insert(&rowid, &record))?;
+        commit_if_full(&target)?;
+    }
+    target.commit()?;
+    Ok(target)
+}
+
+/// Commit the new database once `PAGES_PER_COMMIT` of its pages are modified,
+/// as the pager keeps modified pages in memory until they are committed.
+fn commit_if_full(target: &Pager) -> Result<()> {
+    if target.dirty_page_count() >= PAGES_PER_COMMIT {
+        target.commit()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build, PAGES_PER_COMMIT};
+    use crate::io::OpenFlags;
+    use crate::storage::PageSource;
+    use crate::test_util::{query, TempDatabase};
+    use rstest::rstest;
+    use std::path::Path;
+
+    /// Create a database with free pages, overflow pages, indexes and a view.
+    fn create_db(name: &str, wal: bool) -> TempDatabase {
+        let temp = TempDatabase::new(name);
+        let conn = temp.sqlite();
+        if wal {
+            conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA wal_autocheckpoint = 0;")
+                .unwrap();
+        }
+        conn.execute_batch(
+            "PRAGMA page_size = 1024;
+             CREATE TABLE t (a INTEGER PRIMARY KEY, b);
+             CREATE INDEX t_b ON t (b);
+             CREATE TABLE u (x TEXT UNIQUE, y);
+             CREATE VIEW v AS SELECT a FROM t WHERE a > 1000;
+             WITH RECURSIVE s(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM s WHERE i < 2000)
+             INSERT INTO t SELECT i, printf('%0*d', 5 + i % 60, i) FROM s;
+             INSERT INTO u SELECT b, zeroblob(1500) FROM t WHERE a % 50 = 0;
+             DELETE FROM t WHERE a % 3 <> 0;",
+        )
+        .unwrap();
+        drop(conn);
+        temp
+    }
+
+    /// Check the database with SQLite and return its contents and its number
+    /// of pages.
+    fn check(temp: &TempDatabase) -> (Vec<String>, u32) {
+        temp.check_integrity();
+        let conn = temp.sqlite();
+        let freelist_count: u32 = conn
+            .query_row("PRAGMA freelist_count", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(freelist_count, 0);
+        let contents = conn
+            .query_row(
+                "SELECT (SELECT count(*) || ' ' || sum(a) || ' ' || sum(length(b)) FROM t),
+                        (SELECT count(*) || ' ' || sum(length(y)) FROM u),
+                        (SELECT count(*) || '' FROM v),
+                        (SELECT group_concat(type || ' ' || name, ', ') FROM sqlite_schema)",
+                (),
+                |row| Ok(vec![row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?]),
+            )
+            .unwrap();
+        let page_count = conn
+            .query_row("PRAGMA page_count", (), |row| row.get(0))
+            .unwrap();
+        (contents, page_count)
+    }
+
+    #[rstest]
+    #[case::rollback_journal(false)]
+    #[case::wal(true)]
+    fn test_vacuum(#[case] wal: bool) {
+        let temp = create_db(&format!("vacuum-{}", wal), false);
+        let expected_temp = create_db(&format!("vacuum-{}-sqlite", wal), false);
+        expected_temp.sqlite().execute_batch("VACUUM").unwrap();
+        let (expected, expected_page_count) = check(&expected_temp);
+        if wal {
+            temp.sqlite()
+                .execute_batch("PRAGMA journal_mode = WAL")
+                .unwrap();
+        }
+
+        let (io, db) = temp.open();
+        let conn = db.connect();
+        query(&io, &conn, "VACUUM").unwrap();
+        assert!(!Path::new(&temp.sibling("-vacuum")).exists());
+        // The statements that follow find the tables and indexes at their new
+        // root pages.
+        assert_eq!(
+            query(&io, &conn, "SELECT a FROM t WHERE b = '00600'").unwrap(),
+            [["600"]]
+        );
+        query(&io, &conn, "INSERT INTO t VALUES (3000, 'x')").unwrap();
+        query(&io, &conn, "DELETE FROM t WHERE a = 3000").unwrap();
+        if wal {
+            query(&io, &conn, "PRAGMA wal_checkpoint(TRUNCATE)").unwrap();
+        }
+        drop(conn);
+        drop(db);
+
+        // The tables and indexes take up about as many pages as they do after
+        // SQLite's VACUUM.
+        let (actual, page_count) = check(&temp);
+        assert_eq!(actual, expected);
+        assert!(page_count <= expected_page_count + 2);
+        let file_size = std::fs::metadata(&temp.path).unwrap().len();
+        assert_eq!(file_size, page_count as u64 * 1024);
+    }
+
+    #[test]
+    fn test_vacuum_into() {
+        let temp = create_db("vacuum-from", true);
+        let into = TempDatabase::new("vacuum-into");
+        let (io, db) = temp.open();
+        let conn = db.connect();
+        let sql = format!("VACUUM INTO '{}'", into.path.display());
+        let original = std::fs::read(&temp.path).unwrap();
+        query(&io, &conn, &sql).unwrap();
+        assert_eq!(std::fs::read(&temp.path).unwrap(), original);
+
+        let err = query(&io, &conn, &sql).unwrap_err();
+        assert_eq!(err.to_string(), "output file already exists");
+        query(&io, &conn, "BEGIN").unwrap();
+        let err = query(&io, &conn, "VACUUM").unwrap_err();
+        assert_eq!(err.to_string(), "cannot VACUUM from within a transaction");
+        query(&io, &conn, "ROLLBACK").unwrap();
+        drop(conn);
+        drop(db);
+
+        // The copy is in rollback journal mode, like the copy of SQLite.
+        let (contents, _) = check(&into);
+        assert_eq!(contents[0], "666 666333 22203");
+        let mode: String = into
+            .sqlite()
+            .query_row("PRAGMA journal_mode", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(mode, "delete");
+    }
+
+    #[test]
+    fn test_vacuum_commits_in_batches() {
+        let temp = TempDatabase::with_sql(
+            "vacuum-batches",
+            "PRAGMA page_size = 1024;
+             CREATE TABLE t (a INTEGER PRIMARY KEY, b);
+             CREATE INDEX t_b ON t (b);
+             WITH RECURSIVE s(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM s WHERE i < 30000)
+             INSERT INTO t SELECT i, printf('%050d', i) FROM s;",
+        );
+        let into = TempDatabase::new("vacuum-batches-into");
+        let (io, db) = temp.open();
+        let file = io.open_file(into.path_str(), OpenFlags::Create).unwrap();
+        let target = build(&db.pager, PageSource::from_file(file)).unwrap();
+        let database_size = target.header().database_size as usize;
+        assert!(database_size > 8 * PAGES_PER_COMMIT);
+        // A page's buffer goes back to the pool when the page is dropped, so
+        // the pool holds about as many buffers as were in use at once.
+        let buffers = target.buffer_pool.free_buffers.borrow().len();
+        assert!(buffers < 2 * PAGES_PER_COMMIT, "{} buffers", buffers);
+        drop(target);
+
+        into.check_integrity();
+        let count: u32 = into
+            .sqlite()
+            .query_row("SELECT count(b) FROM t INDEXED BY t_b", (), |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(count, 30000);
+    }
+}
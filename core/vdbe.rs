@@ -1,15 +1,21 @@
 use crate::btree::BTreeCursor;
-use crate::function::AggFunc;
-use crate::pager::Pager;
-use crate::types::{AggContext, Cursor, CursorResult, OwnedRecord, OwnedValue, Record};
+use crate::function::{AggFunc, ScalarFunc};
+use crate::pager::{JournalMode, Pager};
+use crate::schema::Schema;
+use crate::sorter::{PseudoCursor, SortOrder, Sorter};
+use crate::sqlite3_ondisk::PageType;
+use crate::types::{AggContext, Cursor, CursorResult, OwnedRecord, OwnedValue, Record, SeekOp};
+use crate::vacuum;
+use crate::wal::{CheckpointMode, ReadLock};
 
 use anyhow::Result;
-use std::borrow::BorrowMut;
-use std::cell::RefCell;
+use std::borrow::{BorrowMut, Cow};
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
 use std::collections::BTreeMap;
 use std::rc::Rc;
 
-pub type BranchOffset = usize;
+pub type BranchOffset = i64;
 
 pub type CursorID = usize;
 
@@ -30,6 +36,129 @@ pub enum Insn {
     // Await for the competion of open cursor.
     OpenReadAwait,
 
+    // Open a cursor for writing.
+    OpenWriteAsync {
+        cursor_id: CursorID,
+        root_page: PageIdx,
+    },
+
+    // Open a cursor for writing on the b-tree whose root page is in root_reg,
+    // such as one that CreateBtree has just allocated.
+    OpenWriteRegAsync {
+        cursor_id: CursorID,
+        root_reg: usize,
+    },
+
+    // Await for the completion of open cursor for writing.
+    OpenWriteAwait,
+
+    // Store a rowid that is not yet used in the table into rowid_reg.
+    NewRowid {
+        cursor_id: CursorID,
+        rowid_reg: usize,
+    },
+
+    // Jump to the given PC if there is no row with the rowid in rowid_reg.
+    // Otherwise move the cursor to that row.
+    NotExists {
+        cursor_id: CursorID,
+        rowid_reg: usize,
+        target_pc: BranchOffset,
+    },
+
+    // Insert the record in record_reg into the table with the key in key_reg,
+    // replacing any row with the same key.
+    InsertAsync {
+        cursor_id: CursorID,
+        key_reg: usize,    // P3
+        record_reg: usize, // P2
+        flag: usize,       // P5
+    },
+
+    // Await for the completion of the insert.
+    InsertAwait {
+        cursor_id: CursorID,
+    },
+
+    // Delete the row the cursor is on. The next NextAsync moves the cursor to
+    // the row that followed it.
+    DeleteAsync {
+        cursor_id: CursorID,
+    },
+
+    // Await for the completion of the delete.
+    DeleteAwait {
+        cursor_id: CursorID,
+    },
+
+    // Add the index key in record_reg to the index, if it is not there
+    // already.
+    IdxInsertAsync {
+        cursor_id: CursorID,
+        record_reg: usize,
+    },
+
+    // Await for the completion of the index insert.
+    IdxInsertAwait {
+        cursor_id: CursorID,
+    },
+
+    // Remove the index key in start_reg..start_reg+num_regs from the index.
+    IdxDeleteAsync {
+        cursor_id: CursorID,
+        start_reg: usize,
+        num_regs: usize,
+    },
+
+    // Await for the completion of the index delete.
+    IdxDeleteAwait {
+        cursor_id: CursorID,
+    },
+
+    // Jump to the given PC if any value in start_reg..start_reg+num_regs is
+    // NULL or no index entry starts with them. Otherwise move the index cursor
+    // to the first entry that does.
+    NoConflict {
+        cursor_id: CursorID,
+        start_reg: usize,
+        num_regs: usize,
+        target_pc: BranchOffset,
+    },
+
+    // Allocate the root page of a new, empty table or index b-tree and store
+    // its page number in root_reg.
+    CreateBtree {
+        root_reg: usize,
+        is_index: bool,
+    },
+
+    // Free all pages of the b-tree whose root page is in root_reg.
+    Destroy {
+        root_reg: usize,
+    },
+
+    // Set the schema cookie in the database header to value, which marks a
+    // change to the schema.
+    SetCookie {
+        value: u32,
+    },
+
+    // Add the table or index described by the sqlite_schema row in
+    // start_reg..start_reg+5 to the schema.
+    ParseSchema {
+        start_reg: usize,
+    },
+
+    // Remove a table and its indexes from the schema.
+    DropTable {
+        table_name: String,
+    },
+
+    // Remove an index from the schema.
+    DropIndex {
+        index_name: String,
+    },
+
     // Open a cursor for a pseudo-table that contains a single row.
     OpenPseudo {
         cursor_id: CursorID,
@@ -37,6 +166,11 @@ pub enum Insn {
         num_fields: usize,
     },
 
+    // Make the cursor read as a row of NULLs until it is moved.
+    NullRow {
+        cursor_id: CursorID,
+    },
+
     // Rewind the cursor to the beginning of the B-Tree.
     RewindAsync {
         cursor_id: CursorID,
@@ -48,6 +182,71 @@ pub enum Insn {
         pc_if_empty: BranchOffset,
     },
 
+    // Move the cursor to the end of the B-Tree, for visiting it backwards.
+    LastAsync {
+        cursor_id: CursorID,
+    },
+
+    // Await for the completion of moving the cursor to the end.
+    LastAwait {
+        cursor_id: CursorID,
+        pc_if_empty: BranchOffset,
+    },
+
+    // Jump to the given PC if the value in src_reg is not an integer or there
+    // is no row with it as its rowid. Otherwise move the cursor to that row.
+    SeekRowid {
+        cursor_id: CursorID,
+        src_reg: usize,
+        target_pc: BranchOffset,
+    },
+
+    // Move the index cursor to the first entry whose leading columns are
+    // greater than or equal to the key in start_reg..start_reg+num_regs, or
+    // jump to the given PC if there is none. If is_index is not set, move the
+    // table cursor to the first row whose rowid is greater than or equal to
+    // the value in start_reg instead.
+    SeekGE {
+        is_index: bool,
+        cursor_id: CursorID,
+        start_reg: usize,
+        num_regs: usize,
+        target_pc: BranchOffset,
+    },
+
+    // Like SeekGE, but for the first entry or row that is greater than the key.
+    SeekGT {
+        is_index: bool,
+        cursor_id: CursorID,
+        start_reg: usize,
+        num_regs: usize,
+        target_pc: BranchOffset,
+    },
+
+    // Jump to the given PC if the leading columns of the current index entry
+    // are greater than or equal to the key in start_reg..start_reg+num_regs.
+    IdxGE {
+        cursor_id: CursorID,
+        start_reg: usize,
+        num_regs: usize,
+        target_pc: BranchOffset,
+    },
+
+    // Jump to the given PC if the leading columns of the current index entry
+    // are greater than the key in start_reg..start_reg+num_regs.
+    IdxGT {
+        cursor_id: CursorID,
+        start_reg: usize,
+        num_regs: usize,
+        target_pc: BranchOffset,
+    },
+
+    // Read the rowid of the table row that the current index entry points to.
+    IdxRowid {
+        cursor_id: CursorID,
+        dest: usize,
+    },
+
     // Read a column from the current row of the cursor.
     Column {
         cursor_id: CursorID,
@@ -79,17 +278,305 @@ pub enum Insn {
         pc_if_next: BranchOffset,
     },
 
-    // Halt the program.
-    Halt,
+    // Move the cursor back to the previous row.
+    PrevAsync {
+        cursor_id: CursorID,
+    },
+
+    // Await for the completion of moving the cursor back.
+    PrevAwait {
+        cursor_id: CursorID,
+        pc_if_next: BranchOffset,
+    },
+
+    // Halt the program. A nonzero error code makes the statement fail with
+    // the given description.
+    Halt {
+        err_code: usize,
+        description: String,
+    },
+
+    // Halt the program with an error if the register is NULL, as for a NOT
+    // NULL constraint.
+    HaltIfNull {
+        reg: usize,
+        err_code: usize,
+        description: String,
+    },
 
     // Start a transaction.
     Transaction,
 
+    // Set the auto-commit flag of the connection, which BEGIN clears and
+    // COMMIT and ROLLBACK set. The transaction is committed, or rolled back
+    // if rollback is set, when the flag is set.
+    AutoCommit {
+        auto_commit: bool,
+        rollback: bool,
+    },
+
+    // Switch the journal mode, if a mode is given, and store the name of the
+    // journal mode in the destination register.
+    JournalMode {
+        mode: Option<JournalMode>,
+        dest: usize,
+    },
+
+    // Checkpoint the write-ahead log and store whether it was blocked, the
+    // number of frames in the log and the number of frames checkpointed in
+    // the three registers starting at the destination register.
+    Checkpoint {
+        mode: CheckpointMode,
+        dest: usize,
+    },
+
+    // Rebuild the database. If into is set, it is the register that holds the
+    // name of the file to write the new database to, and the database itself
+    // is left as it is.
+    Vacuum {
+        into: Option<usize>,
+    },
+
     // Branch to the given PC.
     Goto {
         target_pc: BranchOffset,
     },
 
+    // Store the address of the next instruction in return_reg and jump to
+    // the subroutine at target_pc.
+    Gosub {
+        target_pc: BranchOffset,
+        return_reg: usize,
+    },
+
+    // Jump back to the address stored in return_reg by Gosub.
+    Return {
+        return_reg: usize,
+    },
+
+    // Compare count registers starting at start_reg_a with those starting at
+    // start_reg_b and remember the result for the next Jump. NULLs compare
+    // equal to each other and less than any other value.
+    Compare {
+        start_reg_a: usize,
+        start_reg_b: usize,
+        count: usize,
+    },
+
+    // Jump to one of three PCs depending on whether the last Compare found
+    // the first set of registers less than, equal to or greater than the second.
+    Jump {
+        target_pc_lt: BranchOffset,
+        target_pc_eq: BranchOffset,
+        target_pc_gt: BranchOffset,
+    },
+
+    // Compare two registers and jump to the given PC if they are equal.
+    // If either register is NULL, jump only if jump_if_null is set.
+    // The comparison opcodes first convert the operands with the given
+    // affinity, if any, as in `OwnedValue::apply_affinity`, leaving the
+    // registers unchanged.
+    Eq {
+        lhs: usize,
+        rhs: usize,
+        target_pc: BranchOffset,
+        jump_if_null: bool,
+        affinity: Option<char>,
+    },
+
+    // Compare two registers and jump to the given PC if they are not equal.
+    // If either register is NULL, jump only if jump_if_null is set.
+    Ne {
+        lhs: usize,
+        rhs: usize,
+        target_pc: BranchOffset,
+        jump_if_null: bool,
+        affinity: Option<char>,
+    },
+
+    // Compare two registers and jump to the given PC if the left-hand side is less than the right-hand side.
+    // If either register is NULL, jump only if jump_if_null is set.
+    Lt {
+        lhs: usize,
+        rhs: usize,
+        target_pc: BranchOffset,
+        jump_if_null: bool,
+        affinity: Option<char>,
+    },
+
+    // Compare two registers and jump to the given PC if the left-hand side is less than or equal to the right-hand side.
+    // If either register is NULL, jump only if jump_if_null is set.
+    Le {
+        lhs: usize,
+        rhs: usize,
+        target_pc: BranchOffset,
+        jump_if_null: bool,
+        affinity: Option<char>,
+    },
+
+    // Compare two registers and jump to the given PC if the left-hand side is greater than the right-hand side.
+    // If either register is NULL, jump only if jump_if_null is set.
+    Gt {
+        lhs: usize,
+        rhs: usize,
+        target_pc: BranchOffset,
+        jump_if_null: bool,
+        affinity: Option<char>,
+    },
+
+    // Compare two registers and jump to the given PC if the left-hand side is greater than or equal to the right-hand side.
+    // If either register is NULL, jump only if jump_if_null is set.
+    Ge {
+        lhs: usize,
+        rhs: usize,
+        target_pc: BranchOffset,
+        jump_if_null: bool,
+        affinity: Option<char>,
+    },
+
+    // Jump to the given PC if the register is true (a non-zero numeric value).
+    // If the register is NULL, jump only if jump_if_null is set.
+    If {
+        reg: usize,
+        target_pc: BranchOffset,
+        jump_if_null: bool,
+    },
+
+    // Jump to the given PC if the register is false (a zero numeric value).
+    // If the register is NULL, jump only if jump_if_null is set.
+    IfNot {
+        reg: usize,
+        target_pc: BranchOffset,
+        jump_if_null: bool,
+    },
+
+    // Jump to the given PC if the register is NULL.
+    IsNull {
+        src: usize,
+        target_pc: BranchOffset,
+    },
+
+    // Jump to the given PC if the register is not NULL.
+    NotNull {
+        src: usize,
+        target_pc: BranchOffset,
+    },
+
+    // Convert the register to an integer, or fail if that cannot be done
+    // without loss.
+    MustBeInt {
+        reg: usize,
+    },
+
+    // Apply column affinities to count registers starting at start_reg, one
+    // letter per register as in Type::affinity().
+    Affinity {
+        start_reg: usize,
+        count: usize,
+        affinities: String,
+    },
+
+    // Add two registers and store the result in a third register.
+    Add {
+        lhs: usize,
+        rhs: usize,
+        dest: usize,
+    },
+
+    // Subtract rhs from lhs and store in dest register.
+    Subtract {
+        lhs: usize,
+        rhs: usize,
+        dest: usize,
+    },
+
+    // Multiply two registers and store the result in a third register.
+    Multiply {
+        lhs: usize,
+        rhs: usize,
+        dest: usize,
+    },
+
+    // Divide lhs by rhs and store the result in a third register.
+    Divide {
+        lhs: usize,
+        rhs: usize,
+        dest: usize,
+    },
+
+    // Compute the remainder of lhs divided by rhs and store the result in a third register.
+    Remainder {
+        lhs: usize,
+        rhs: usize,
+        dest: usize,
+    },
+
+    // Concatenate the text representations of two registers and store the result in a third register.
+    Concat {
+        lhs: usize,
+        rhs: usize,
+        dest: usize,
+    },
+
+    // Bitwise AND of two registers, stored in a third register.
+    BitAnd {
+        lhs: usize,
+        rhs: usize,
+        dest: usize,
+    },
+
+    // Bitwise OR of two registers, stored in a third register.
+    BitOr {
+        lhs: usize,
+        rhs: usize,
+        dest: usize,
+    },
+
+    // Shift lhs left by rhs bits and store the result in a third register.
+    ShiftLeft {
+        lhs: usize,
+        rhs: usize,
+        dest: usize,
+    },
+
+    // Shift lhs right by rhs bits and store the result in a third register.
+    ShiftRight {
+        lhs: usize,
+        rhs: usize,
+        dest: usize,
+    },
+
+    // Logical AND of two registers using three-valued logic, stored in a third register.
+    And {
+        lhs: usize,
+        rhs: usize,
+        dest: usize,
+    },
+
+    // Logical OR of two registers using three-valued logic, stored in a third register.
+    Or {
+        lhs: usize,
+        rhs: usize,
+        dest: usize,
+    },
+
+    // Logical NOT of a register; NULL stays NULL.
+    Not {
+        reg: usize,
+        dest: usize,
+    },
+
+    // Bitwise NOT of a register; NULL stays NULL.
+    BitNot {
+        reg: usize,
+        dest: usize,
+    },
+
+    // Write a NULL into a register.
+    Null {
+        dest: usize,
+    },
+
     // Write an integer value into a register.
     Integer {
         value: i64,
@@ -113,6 +600,12 @@ pub enum Insn {
         dest: usize,
     },
 
+    // Write a blob value into a register.
+    Blob {
+        value: Vec<u8>,
+        dest: usize,
+    },
+
     // Read the rowid of the current row.
     RowId {
         cursor_id: CursorID,
@@ -136,9 +629,27 @@ pub enum Insn {
         func: AggFunc,
     },
 
-    // Open a sorter.
+    // Call a scalar function with the arguments in start_reg..start_reg+arg_count
+    // and store the result in dest.
+    Function {
+        start_reg: usize,
+        arg_count: usize,
+        dest: usize,
+        func: ScalarFunc,
+    },
+
+    // Copy registers src_reg..src_reg+amount into dst_reg..dst_reg+amount.
+    Copy {
+        src_reg: usize,
+        dst_reg: usize,
+        amount: usize, // P3: one less than the number of registers copied
+    },
+
+    // Open a sorter whose records are ordered by their leading key columns.
     SorterOpen {
-        cursor_id: CursorID,
+        cursor_id: CursorID, // P1
+        columns: usize,      // P2
+        order: Vec<SortOrder>,
     },
 
     // Insert a row into the sorter.
@@ -147,15 +658,19 @@ pub enum Insn {
         record_reg: usize,
     },
 
-    // Sort the rows in the sorter.
+    // Sort the rows in the sorter and position it on the first row. Jump to
+    // pc_if_empty if the sorter is empty.
     SorterSort {
         cursor_id: CursorID,
+        pc_if_empty: BranchOffset,
     },
 
-    // Retrieve the next row from the sorter.
+    // Write the current sorter row into dest_reg and make it the row of the
+    // pseudo cursor.
     SorterData {
-        cursor_id: CursorID, // P1
-        dest_reg: usize,     // P2
+        cursor_id: CursorID,     // P1
+        dest_reg: usize,         // P2
+        pseudo_cursor: CursorID, // P3
     },
 
     // Advance to the next row in the sorter.
@@ -167,16 +682,23 @@ pub enum Insn {
 
 pub struct ProgramBuilder {
     next_free_register: usize,
+    next_free_label: BranchOffset,
     next_free_cursor_id: usize,
     insns: Vec<Insn>,
+    // Each label has a list of instruction offsets that jump to it and
+    // must be patched once the label is resolved. The list for label L
+    // is at index -L - 1.
+    unresolved_labels: Vec<Vec<usize>>,
 }
 
 impl ProgramBuilder {
     pub fn new() -> Self {
         Self {
             next_free_register: 0,
+            next_free_label: 0,
             next_free_cursor_id: 0,
             insns: Vec::new(),
+            unresolved_labels: Vec::new(),
         }
     }
 
@@ -204,7 +726,10 @@ impl ProgramBuilder {
 
     pub fn emit_placeholder(&mut self) -> usize {
         let offset = self.insns.len();
-        self.insns.push(Insn::Halt);
+        self.insns.push(Insn::Halt {
+            err_code: 0,
+            description: String::new(),
+        });
         offset
     }
 
@@ -212,15 +737,99 @@ impl ProgramBuilder {
         self.insns.push(insn);
     }
 
+    /// Emit an instruction whose jump target is a label that has not been
+    /// resolved yet. The target is patched when `resolve_label()` is called.
+    pub fn emit_insn_with_label_dependency(&mut self, insn: Insn, label: BranchOffset) {
+        assert!(label < 0, "label must be allocated with alloc_label()");
+        let offset = self.insns.len();
+        self.insns.push(insn);
+        self.unresolved_labels[(-label - 1) as usize].push(offset);
+    }
+
     pub fn fixup_insn(&mut self, offset: usize, insn: Insn) {
         self.insns[offset] = insn;
     }
 
-    pub fn offset(&self) -> usize {
-        self.insns.len()
+    pub fn offset(&self) -> BranchOffset {
+        self.insns.len() as BranchOffset
+    }
+
+    /// Allocate a label, i.e. a placeholder for a jump target that is not
+    /// known yet. Labels are negative so they can never be confused with a
+    /// real instruction offset.
+    pub fn alloc_label(&mut self) -> BranchOffset {
+        self.next_free_label -= 1;
+        self.unresolved_labels.push(Vec::new());
+        self.next_free_label
+    }
+
+    /// Resolve a label to the given offset, patching every instruction that
+    /// was emitted with a dependency on it.
+    pub fn resolve_label(&mut self, label: BranchOffset, to_offset: BranchOffset) {
+        assert!(label < 0, "label must be allocated with alloc_label()");
+        let dependents = std::mem::take(&mut self.unresolved_labels[(-label - 1) as usize]);
+        for offset in dependents {
+            match &mut self.insns[offset] {
+                Insn::Init { target_pc }
+                | Insn::Goto { target_pc }
+                | Insn::Gosub { target_pc, .. }
+                | Insn::DecrJumpZero { target_pc, .. }
+                | Insn::Eq { target_pc, .. }
+                | Insn::Ne { target_pc, .. }
+                | Insn::Lt { target_pc, .. }
+                | Insn::Le { target_pc, .. }
+                | Insn::Gt { target_pc, .. }
+                | Insn::Ge { target_pc, .. }
+                | Insn::If { target_pc, .. }
+                | Insn::IfNot { target_pc, .. }
+                | Insn::IsNull { target_pc, .. }
+                | Insn::NotNull { target_pc, .. }
+                | Insn::NotExists { target_pc, .. }
+                | Insn::NoConflict { target_pc, .. }
+                | Insn::SeekRowid { target_pc, .. }
+                | Insn::SeekGE { target_pc, .. }
+                | Insn::SeekGT { target_pc, .. }
+                | Insn::IdxGE { target_pc, .. }
+                | Insn::IdxGT { target_pc, .. } => {
+                    assert_eq!(*target_pc, label);
+                    *target_pc = to_offset;
+                }
+                Insn::RewindAwait { pc_if_empty, .. }
+                | Insn::LastAwait { pc_if_empty, .. }
+                | Insn::SorterSort { pc_if_empty, .. } => {
+                    assert_eq!(*pc_if_empty, label);
+                    *pc_if_empty = to_offset;
+                }
+                Insn::NextAwait { pc_if_next, .. }
+                | Insn::PrevAwait { pc_if_next, .. }
+                | Insn::SorterNext { pc_if_next, .. } => {
+                    assert_eq!(*pc_if_next, label);
+                    *pc_if_next = to_offset;
+                }
+                Insn::Jump {
+                    target_pc_lt,
+                    target_pc_eq,
+                    target_pc_gt,
+                } => {
+                    let mut resolved = false;
+                    for target_pc in [target_pc_lt, target_pc_eq, target_pc_gt] {
+                        if *target_pc == label {
+                            *target_pc = to_offset;
+                            resolved = true;
+                        }
+                    }
+                    assert!(resolved);
+                }
+                _ => unreachable!("instruction at {} has no jump target", offset),
+            }
+        }
     }
 
     pub fn build(self) -> Program {
+        assert!(
+            self.unresolved_labels.iter().all(|deps| deps.is_empty()),
+            "all labels must be resolved before building the program"
+        );
         Program {
             max_registers: self.next_free_register,
             insns: self.insns,
@@ -239,6 +848,11 @@ pub struct ProgramState {
     pub pc: BranchOffset,
     cursors: RefCell<BTreeMap<CursorID, Box<dyn Cursor>>>,
     registers: Vec<OwnedValue>,
+    // Result of the last Compare instruction, used by Jump.
+    last_compare: Ordering,
+    // The read of the database that the statement holds until it halts or
+    // fails.
+    read: Rc<RefCell<Option<ReadLock>>>,
 }
 
 impl ProgramState {
@@ -250,6 +864,8 @@ impl ProgramState {
             pc: 0,
             cursors,
             registers,
+            last_compare: Ordering::Equal,
+            read: Rc::new(RefCell::new(None)),
         }
     }
 
@@ -276,18 +892,59 @@ impl Program {
         let mut prev_insn: Option<&Insn> = None;
         for (addr, insn) in self.insns.iter().enumerate() {
             indent_count = get_indent_count(indent_count, insn, prev_insn);
-            print_insn(addr, insn, indent.repeat(indent_count));
+            print_insn(addr as BranchOffset, insn, indent.repeat(indent_count));
             prev_insn = Some(insn);
         }
     }
 
+    /// Run the program until it produces a row, waits for I/O or finishes,
+    /// on behalf of connection `connection_id`.
     pub fn step<'a>(
         &self,
         state: &'a mut ProgramState,
         pager: Rc<Pager>,
+        schema: Rc<RefCell<Schema>>,
+        auto_commit: &Cell<bool>,
+        connection_id: usize,
+    ) -> Result<StepResult<'a>> {
+        // The pages of another connection's write transaction are not ours
+        // to read, commit or roll back.
+        pager.check_writer(connection_id)?;
+        let read = state.read.clone();
+        if read.borrow().is_none() {
+            let schema_cookie = pager.schema_cookie();
+            read.replace(pager.begin_read()?);
+            if pager.schema_cookie() != schema_cookie {
+                *RefCell::borrow_mut(&schema) = Schema::read(&pager)?;
+            }
+        }
+        let result = match self.step_inner(state, pager.clone(), schema.clone(), auto_commit) {
+            Err(err) => {
+                read.replace(None);
+                // A statement that fails outside of an explicit transaction
+                // leaves no changes behind.
+                // TODO: Inside one, a statement journal should undo the
+                // changes of the failed statement alone.
+                if auto_commit.get() {
+                    rollback(&pager, &schema)?;
+                }
+                Err(err)
+            }
+            result => result,
+        };
+        pager.update_writer(connection_id);
+        result
+    }
+
+    fn step_inner<'a>(
+        &self,
+        state: &'a mut ProgramState,
+        pager: Rc<Pager>,
+        schema: Rc<RefCell<Schema>>,
+        auto_commit: &Cell<bool>,
     ) -> Result<StepResult<'a>> {
         loop {
-            let insn = &self.insns[state.pc];
+            let insn = &self.insns[state.pc as usize];
             trace_insn(state.pc, insn);
             let mut cursors = state.cursors.borrow_mut();
             match insn {
@@ -305,19 +962,251 @@ impl Program {
                 Insn::OpenReadAwait => {
                     state.pc += 1;
                 }
-                Insn::OpenPseudo {
+                Insn::OpenWriteAsync {
                     cursor_id,
-                    content_reg,
-                    num_fields,
+                    root_page,
                 } => {
-                    let _ = cursor_id;
-                    let _ = content_reg;
-                    let _ = num_fields;
-                    todo!();
+                    let cursor = Box::new(BTreeCursor::new(pager.clone(), *root_page));
+                    cursors.insert(*cursor_id, cursor);
+                    state.pc += 1;
                 }
-                Insn::RewindAsync { cursor_id } => {
-                    let cursor = cursors.get_mut(cursor_id).unwrap();
-                    match cursor.rewind()? {
+                Insn::OpenWriteRegAsync {
+                    cursor_id,
+                    root_reg,
+                } => {
+                    let root_page = match &state.registers[*root_reg] {
+                        OwnedValue::Integer(root_page) => *root_page as usize,
+                        _ => unreachable!("OpenWriteRegAsync on non-integer register"),
+                    };
+                    let cursor = Box::new(BTreeCursor::new(pager.clone(), root_page));
+                    cursors.insert(*cursor_id, cursor);
+                    state.pc += 1;
+                }
+                Insn::OpenWriteAwait => {
+                    state.pc += 1;
+                }
+                Insn::NewRowid {
+                    cursor_id,
+                    rowid_reg,
+                } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    match cursor.new_rowid()? {
+                        CursorResult::Ok(rowid) => {
+                            state.registers[*rowid_reg] = OwnedValue::Integer(rowid);
+                        }
+                        CursorResult::IO => {
+                            // If there is I/O, the instruction is restarted.
+                            return Ok(StepResult::IO);
+                        }
+                    }
+                    state.pc += 1;
+                }
+                Insn::NotExists {
+                    cursor_id,
+                    rowid_reg,
+                    target_pc,
+                } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    match cursor.exists(&state.registers[*rowid_reg])? {
+                        CursorResult::Ok(true) => state.pc += 1,
+                        CursorResult::Ok(false) => state.pc = *target_pc,
+                        CursorResult::IO => {
+                            // If there is I/O, the instruction is restarted.
+                            return Ok(StepResult::IO);
+                        }
+                    }
+                }
+                Insn::InsertAsync {
+                    cursor_id,
+                    key_reg,
+                    record_reg,
+                    flag: _,
+                } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    let record = match &state.registers[*record_reg] {
+                        OwnedValue::Record(record) => record,
+                        _ => unreachable!("InsertAsync on non-record register"),
+                    };
+                    match cursor.insert(&state.registers[*key_reg], record)? {
+                        CursorResult::Ok(()) => {}
+                        CursorResult::IO => {
+                            // If there is I/O, the instruction is restarted.
+                            return Ok(StepResult::IO);
+                        }
+                    }
+                    state.pc += 1;
+                }
+                Insn::InsertAwait { cursor_id } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    cursor.wait_for_completion()?;
+                    state.pc += 1;
+                }
+                Insn::DeleteAsync { cursor_id } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    match cursor.delete()? {
+                        CursorResult::Ok(()) => {}
+                        CursorResult::IO => {
+                            // If there is I/O, the instruction is restarted.
+                            return Ok(StepResult::IO);
+                        }
+                    }
+                    state.pc += 1;
+                }
+                Insn::DeleteAwait { cursor_id } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    cursor.wait_for_completion()?;
+                    state.pc += 1;
+                }
+                Insn::IdxInsertAsync {
+                    cursor_id,
+                    record_reg,
+                } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    let record = match &state.registers[*record_reg] {
+                        OwnedValue::Record(record) => record,
+                        _ => unreachable!("IdxInsertAsync on non-record register"),
+                    };
+                    match cursor.index_insert(record)? {
+                        CursorResult::Ok(()) => {}
+                        CursorResult::IO => {
+                            // If there is I/O, the instruction is restarted.
+                            return Ok(StepResult::IO);
+                        }
+                    }
+                    state.pc += 1;
+                }
+                Insn::IdxInsertAwait { cursor_id } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    cursor.wait_for_completion()?;
+                    state.pc += 1;
+                }
+                Insn::IdxDeleteAsync {
+                    cursor_id,
+                    start_reg,
+                    num_regs,
+                } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    let key = make_owned_record(&state.registers, start_reg, num_regs);
+                    match cursor.index_delete(&key)? {
+                        CursorResult::Ok(true) => {}
+                        CursorResult::Ok(false) => {
+                            anyhow::bail!("database disk image is malformed: index entry not found")
+                        }
+                        CursorResult::IO => {
+                            // If there is I/O, the instruction is restarted.
+                            return Ok(StepResult::IO);
+                        }
+                    }
+                    state.pc += 1;
+                }
+                Insn::IdxDeleteAwait { cursor_id } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    cursor.wait_for_completion()?;
+                    state.pc += 1;
+                }
+                Insn::CreateBtree { root_reg, is_index } => {
+                    let page_type = if *is_index {
+                        PageType::IndexLeaf
+                    } else {
+                        PageType::TableLeaf
+                    };
+                    let (root_page, page) = pager.allocate_page(page_type)?;
+                    pager.add_dirty(root_page, &page);
+                    state.registers[*root_reg] = OwnedValue::Integer(root_page as i64);
+                    state.pc += 1;
+                }
+                Insn::Destroy { root_reg } => {
+                    let root_page = match &state.registers[*root_reg] {
+                        OwnedValue::Integer(root_page) => *root_page as usize,
+                        _ => unreachable!("Destroy on non-integer register"),
+                    };
+                    BTreeCursor::new(pager.clone(), root_page).destroy()?;
+                    state.pc += 1;
+                }
+                Insn::SetCookie { value } => {
+                    pager.set_schema_cookie(*value);
+                    state.pc += 1;
+                }
+                Insn::ParseSchema { start_reg } => {
+                    let (ty, root_page, sql) = match &state.registers[*start_reg..*start_reg + 5] {
+                        [OwnedValue::Text(ty), _, _, OwnedValue::Integer(root_page), sql] => {
+                            let sql = match sql {
+                                OwnedValue::Text(sql) => Some(sql.as_str()),
+                                _ => None,
+                            };
+                            (ty.as_str(), *root_page as usize, sql)
+                        }
+                        _ => unreachable!("ParseSchema on a malformed schema row"),
+                    };
+                    RefCell::borrow_mut(&schema).load_entry(ty, root_page, sql)?;
+                    state.pc += 1;
+                }
+                Insn::DropTable { table_name } => {
+                    RefCell::borrow_mut(&schema).remove_table(table_name);
+                    state.pc += 1;
+                }
+                Insn::DropIndex { index_name } => {
+                    RefCell::borrow_mut(&schema).remove_index(index_name);
+                    state.pc += 1;
+                }
+                Insn::NoConflict {
+                    cursor_id,
+                    start_reg,
+                    num_regs,
+                    target_pc,
+                } => {
+                    let key = make_owned_record(&state.registers, start_reg, num_regs);
+                    // NULLs are distinct from each other, so they never conflict.
+                    let conflict = !key.values.contains(&OwnedValue::Null) && {
+                        let cursor = cursors.get_mut(cursor_id).unwrap();
+                        match cursor.seek(&key, SeekOp::GE)? {
+                            CursorResult::Ok(true) => match *cursor.record()? {
+                                Some(ref record) => record.compare_prefix(&key) == Ordering::Equal,
+                                None => false,
+                            },
+                            CursorResult::Ok(false) => false,
+                            CursorResult::IO => {
+                                // If there is I/O, the instruction is restarted.
+                                return Ok(StepResult::IO);
+                            }
+                        }
+                    };
+                    if conflict {
+                        state.pc += 1;
+                    } else {
+                        state.pc = *target_pc;
+                    }
+                }
+                Insn::OpenPseudo {
+                    cursor_id,
+                    content_reg,
+                    num_fields,
+                } => {
+                    let _ = content_reg;
+                    let _ = num_fields;
+                    let cursor = Box::new(PseudoCursor::new());
+                    cursors.insert(*cursor_id, cursor);
+                    state.pc += 1;
+                }
+                Insn::NullRow { cursor_id } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    cursor.set_null_flag(true);
+                    state.pc += 1;
+                }
+                Insn::RewindAsync { cursor_id } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    match cursor.rewind()? {
+                        CursorResult::Ok(()) => {}
+                        CursorResult::IO => {
+                            // If there is I/O, the instruction is restarted.
+                            return Ok(StepResult::IO);
+                        }
+                    }
+                    state.pc += 1;
+                }
+                Insn::LastAsync { cursor_id } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    match cursor.last()? {
                         CursorResult::Ok(()) => {}
                         CursorResult::IO => {
                             // If there is I/O, the instruction is restarted.
@@ -329,6 +1218,10 @@ impl Program {
                 Insn::RewindAwait {
                     cursor_id,
                     pc_if_empty,
+                }
+                | Insn::LastAwait {
+                    cursor_id,
+                    pc_if_empty,
                 } => {
                     let cursor = cursors.get_mut(cursor_id).unwrap();
                     cursor.wait_for_completion()?;
@@ -338,13 +1231,113 @@ impl Program {
                         state.pc += 1;
                     }
                 }
+                Insn::SeekRowid {
+                    cursor_id,
+                    src_reg,
+                    target_pc,
+                } => {
+                    // Text that looks like a number seeks to that number.
+                    let rowid = match state.registers[*src_reg].apply_affinity('C') {
+                        OwnedValue::Integer(rowid) => Some(rowid),
+                        OwnedValue::Float(f) if f.fract() == 0.0 => float_to_rowid(f),
+                        _ => None,
+                    };
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    let result = match rowid {
+                        Some(rowid) => cursor.exists(&OwnedValue::Integer(rowid))?,
+                        None => CursorResult::Ok(false),
+                    };
+                    match result {
+                        CursorResult::Ok(true) => state.pc += 1,
+                        CursorResult::Ok(false) => state.pc = *target_pc,
+                        CursorResult::IO => {
+                            // If there is I/O, the instruction is restarted.
+                            return Ok(StepResult::IO);
+                        }
+                    }
+                }
+                Insn::SeekGE {
+                    is_index,
+                    cursor_id,
+                    start_reg,
+                    num_regs,
+                    target_pc,
+                }
+                | Insn::SeekGT {
+                    is_index,
+                    cursor_id,
+                    start_reg,
+                    num_regs,
+                    target_pc,
+                } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    let op = match insn {
+                        Insn::SeekGE { .. } => SeekOp::GE,
+                        _ => SeekOp::GT,
+                    };
+                    let result = if *is_index {
+                        let key = make_owned_record(&state.registers, start_reg, num_regs);
+                        cursor.seek(&key, op)?
+                    } else {
+                        match rowid_seek_key(&state.registers[*start_reg], op) {
+                            Some((rowid, op)) => cursor.seek_rowid(rowid, op)?,
+                            None => CursorResult::Ok(false),
+                        }
+                    };
+                    match result {
+                        CursorResult::Ok(true) => state.pc += 1,
+                        CursorResult::Ok(false) => state.pc = *target_pc,
+                        CursorResult::IO => {
+                            // If there is I/O, the instruction is restarted.
+                            return Ok(StepResult::IO);
+                        }
+                    }
+                }
+                Insn::IdxGE {
+                    cursor_id,
+                    start_reg,
+                    num_regs,
+                    target_pc,
+                }
+                | Insn::IdxGT {
+                    cursor_id,
+                    start_reg,
+                    num_regs,
+                    target_pc,
+                } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    let key = make_owned_record(&state.registers, start_reg, num_regs);
+                    let ordering = match *cursor.record()? {
+                        Some(ref record) => record.compare_prefix(&key),
+                        None => unreachable!("index comparison on a cursor without a row"),
+                    };
+                    let jump = match insn {
+                        Insn::IdxGE { .. } => ordering != Ordering::Less,
+                        _ => ordering == Ordering::Greater,
+                    };
+                    if jump {
+                        state.pc = *target_pc;
+                    } else {
+                        state.pc += 1;
+                    }
+                }
+                Insn::IdxRowid { cursor_id, dest } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    state.registers[*dest] = match *cursor.rowid()? {
+                        Some(rowid) => OwnedValue::Integer(rowid as i64),
+                        None => OwnedValue::Null,
+                    };
+                    state.pc += 1;
+                }
                 Insn::Column {
                     cursor_id,
                     column,
                     dest,
                 } => {
                     let cursor = cursors.get_mut(cursor_id).unwrap();
-                    if let Some(ref record) = *cursor.record()? {
+                    if cursor.get_null_flag() {
+                        state.registers[*dest] = OwnedValue::Null;
+                    } else if let Some(ref record) = *cursor.record()? {
                         state.registers[*dest] = record.values[*column].clone();
                     } else {
                         todo!();
@@ -376,9 +1369,24 @@ impl Program {
                     }
                     state.pc += 1;
                 }
+                Insn::PrevAsync { cursor_id } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    match cursor.prev()? {
+                        CursorResult::Ok(_) => {}
+                        CursorResult::IO => {
+                            // If there is I/O, the instruction is restarted.
+                            return Ok(StepResult::IO);
+                        }
+                    }
+                    state.pc += 1;
+                }
                 Insn::NextAwait {
                     cursor_id,
                     pc_if_next,
+                }
+                | Insn::PrevAwait {
+                    cursor_id,
+                    pc_if_next,
                 } => {
                     let cursor = cursors.get_mut(cursor_id).unwrap();
                     cursor.wait_for_completion()?;
@@ -388,51 +1396,462 @@ impl Program {
                         state.pc += 1;
                     }
                 }
-                Insn::Halt => {
+                Insn::Halt {
+                    err_code,
+                    description,
+                } => {
+                    if *err_code > 0 {
+                        anyhow::bail!("{}", description);
+                    }
+                    if auto_commit.get() {
+                        pager.commit()?;
+                    }
+                    state.read.replace(None);
                     return Ok(StepResult::Done);
                 }
-                Insn::Transaction => {
+                Insn::HaltIfNull {
+                    reg,
+                    err_code: _,
+                    description,
+                } => {
+                    if let OwnedValue::Null = state.registers[*reg] {
+                        anyhow::bail!("{}", description);
+                    }
                     state.pc += 1;
                 }
-                Insn::Goto { target_pc } => {
-                    state.pc = *target_pc;
-                }
-                Insn::Integer { value, dest } => {
-                    state.registers[*dest] = OwnedValue::Integer(*value);
+                Insn::Transaction => {
                     state.pc += 1;
                 }
-                Insn::Real { value, dest } => {
-                    state.registers[*dest] = OwnedValue::Float(*value);
+                Insn::AutoCommit {
+                    auto_commit: value,
+                    rollback: is_rollback,
+                } => {
+                    if *value == auto_commit.get() {
+                        match (*value, *is_rollback) {
+                            (false, _) => {
+                                anyhow::bail!("cannot start a transaction within a transaction")
+                            }
+                            (true, false) => {
+                                anyhow::bail!("cannot commit - no transaction is active")
+                            }
+                            (true, true) => {
+                                anyhow::bail!("cannot rollback - no transaction is active")
+                            }
+                        }
+                    }
+                    if *is_rollback {
+                        rollback(&pager, &schema)?;
+                    } else if *value {
+                        pager.commit()?;
+                    }
+                    auto_commit.set(*value);
                     state.pc += 1;
                 }
-                Insn::RealAffinity { register } => {
-                    if let OwnedValue::Integer(i) = &state.registers[*register] {
-                        state.registers[*register] = OwnedValue::Float(*i as f64);
-                    } else {
-                    };
+                Insn::JournalMode { mode, dest } => {
+                    if let Some(mode) = mode {
+                        if *mode != pager.journal_mode() && !auto_commit.get() {
+                            match mode {
+                                JournalMode::Wal => anyhow::bail!(
+                                    "cannot change into wal mode from within a transaction"
+                                ),
+                                JournalMode::Delete => anyhow::bail!(
+                                    "cannot change out of wal mode from within a transaction"
+                                ),
+                            }
+                        }
+                        pager.set_journal_mode(*mode)?;
+                    }
+                    let mode = pager.journal_mode().as_str().to_string();
+                    state.registers[*dest] = OwnedValue::Text(Rc::new(mode));
                     state.pc += 1;
                 }
-                Insn::String8 { value, dest } => {
-                    state.registers[*dest] = OwnedValue::Text(Rc::new(value.into()));
+                Insn::Checkpoint { mode, dest } => {
+                    if !auto_commit.get() {
+                        anyhow::bail!("database table is locked");
+                    }
+                    let (busy, num_frames, num_checkpointed_frames) =
+                        match pager.checkpoint(*mode)? {
+                            Some(result) => (
+                                result.busy as i64,
+                                result.num_frames as i64,
+                                result.num_checkpointed_frames as i64,
+                            ),
+                            None => (0, -1, -1),
+                        };
+                    state.registers[*dest] = OwnedValue::Integer(busy);
+                    state.registers[*dest + 1] = OwnedValue::Integer(num_frames);
+                    state.registers[*dest + 2] = OwnedValue::Integer(num_checkpointed_frames);
                     state.pc += 1;
                 }
-                Insn::RowId { cursor_id, dest } => {
-                    let cursor = cursors.get_mut(cursor_id).unwrap();
-                    if let Some(ref rowid) = *cursor.rowid()? {
-                        state.registers[*dest] = OwnedValue::Integer(*rowid as i64);
-                    } else {
-                        todo!();
+                Insn::Vacuum { into } => {
+                    if !auto_commit.get() {
+                        anyhow::bail!("cannot VACUUM from within a transaction");
+                    }
+                    let into = match into.map(|reg| &state.registers[reg]) {
+                        None => None,
+                        Some(OwnedValue::Text(path)) => Some(path.to_string()),
+                        Some(_) => anyhow::bail!("non-text filename"),
+                    };
+                    vacuum::vacuum(&pager, into.as_deref())?;
+                    if into.is_none() {
+                        // The root pages of the tables and indexes have moved.
+                        *RefCell::borrow_mut(&schema) = Schema::read(&pager)?;
                     }
                     state.pc += 1;
                 }
-                Insn::DecrJumpZero { reg, target_pc } => match state.registers[*reg] {
-                    OwnedValue::Integer(n) => {
-                        let n = n - 1;
-                        if n == 0 {
-                            state.pc = *target_pc;
-                        } else {
-                            state.registers[*reg] = OwnedValue::Integer(n);
-                            state.pc += 1;
+                Insn::Goto { target_pc } => {
+                    state.pc = *target_pc;
+                }
+                Insn::Gosub {
+                    target_pc,
+                    return_reg,
+                } => {
+                    state.registers[*return_reg] = OwnedValue::Integer(state.pc + 1);
+                    state.pc = *target_pc;
+                }
+                Insn::Return { return_reg } => {
+                    let OwnedValue::Integer(pc) = state.registers[*return_reg] else {
+                        unreachable!("Return without a return address in r[{}]", return_reg);
+                    };
+                    state.pc = pc;
+                }
+                Insn::Compare {
+                    start_reg_a,
+                    start_reg_b,
+                    count,
+                } => {
+                    state.last_compare = Ordering::Equal;
+                    for i in 0..*count {
+                        let lhs = &state.registers[*start_reg_a + i];
+                        let rhs = &state.registers[*start_reg_b + i];
+                        let ordering = lhs.partial_cmp(rhs).unwrap_or(Ordering::Equal);
+                        if ordering != Ordering::Equal {
+                            state.last_compare = ordering;
+                            break;
+                        }
+                    }
+                    state.pc += 1;
+                }
+                Insn::Jump {
+                    target_pc_lt,
+                    target_pc_eq,
+                    target_pc_gt,
+                } => {
+                    state.pc = match state.last_compare {
+                        Ordering::Less => *target_pc_lt,
+                        Ordering::Equal => *target_pc_eq,
+                        Ordering::Greater => *target_pc_gt,
+                    };
+                }
+                Insn::Eq {
+                    lhs,
+                    rhs,
+                    target_pc,
+                    jump_if_null,
+                    affinity,
+                } => {
+                    let (lhs, rhs) = comparison_operands(
+                        &state.registers[*lhs],
+                        &state.registers[*rhs],
+                        *affinity,
+                    );
+                    let (lhs, rhs) = (lhs.as_ref(), rhs.as_ref());
+                    let jump = match (lhs, rhs) {
+                        (OwnedValue::Null, _) | (_, OwnedValue::Null) => *jump_if_null,
+                        _ => lhs.partial_cmp(rhs) == Some(std::cmp::Ordering::Equal),
+                    };
+                    if jump {
+                        state.pc = *target_pc;
+                    } else {
+                        state.pc += 1;
+                    }
+                }
+                Insn::Ne {
+                    lhs,
+                    rhs,
+                    target_pc,
+                    jump_if_null,
+                    affinity,
+                } => {
+                    let (lhs, rhs) = comparison_operands(
+                        &state.registers[*lhs],
+                        &state.registers[*rhs],
+                        *affinity,
+                    );
+                    let (lhs, rhs) = (lhs.as_ref(), rhs.as_ref());
+                    let jump = match (lhs, rhs) {
+                        (OwnedValue::Null, _) | (_, OwnedValue::Null) => *jump_if_null,
+                        _ => lhs.partial_cmp(rhs) != Some(std::cmp::Ordering::Equal),
+                    };
+                    if jump {
+                        state.pc = *target_pc;
+                    } else {
+                        state.pc += 1;
+                    }
+                }
+                Insn::Lt {
+                    lhs,
+                    rhs,
+                    target_pc,
+                    jump_if_null,
+                    affinity,
+                } => {
+                    let (lhs, rhs) = comparison_operands(
+                        &state.registers[*lhs],
+                        &state.registers[*rhs],
+                        *affinity,
+                    );
+                    let (lhs, rhs) = (lhs.as_ref(), rhs.as_ref());
+                    let jump = match (lhs, rhs) {
+                        (OwnedValue::Null, _) | (_, OwnedValue::Null) => *jump_if_null,
+                        _ => lhs < rhs,
+                    };
+                    if jump {
+                        state.pc = *target_pc;
+                    } else {
+                        state.pc += 1;
+                    }
+                }
+                Insn::Le {
+                    lhs,
+                    rhs,
+                    target_pc,
+                    jump_if_null,
+                    affinity,
+                } => {
+                    let (lhs, rhs) = comparison_operands(
+                        &state.registers[*lhs],
+                        &state.registers[*rhs],
+                        *affinity,
+                    );
+                    let (lhs, rhs) = (lhs.as_ref(), rhs.as_ref());
+                    let jump = match (lhs, rhs) {
+                        (OwnedValue::Null, _) | (_, OwnedValue::Null) => *jump_if_null,
+                        _ => lhs <= rhs,
+                    };
+                    if jump {
+                        state.pc = *target_pc;
+                    } else {
+                        state.pc += 1;
+                    }
+                }
+                Insn::Gt {
+                    lhs,
+                    rhs,
+                    target_pc,
+                    jump_if_null,
+                    affinity,
+                } => {
+                    let (lhs, rhs) = comparison_operands(
+                        &state.registers[*lhs],
+                        &state.registers[*rhs],
+                        *affinity,
+                    );
+                    let (lhs, rhs) = (lhs.as_ref(), rhs.as_ref());
+                    let jump = match (lhs, rhs) {
+                        (OwnedValue::Null, _) | (_, OwnedValue::Null) => *jump_if_null,
+                        _ => lhs > rhs,
+                    };
+                    if jump {
+                        state.pc = *target_pc;
+                    } else {
+                        state.pc += 1;
+                    }
+                }
+                Insn::Ge {
+                    lhs,
+                    rhs,
+                    target_pc,
+                    jump_if_null,
+                    affinity,
+                } => {
+                    let (lhs, rhs) = comparison_operands(
+                        &state.registers[*lhs],
+                        &state.registers[*rhs],
+                        *affinity,
+                    );
+                    let (lhs, rhs) = (lhs.as_ref(), rhs.as_ref());
+                    let jump = match (lhs, rhs) {
+                        (OwnedValue::Null, _) | (_, OwnedValue::Null) => *jump_if_null,
+                        _ => lhs >= rhs,
+                    };
+                    if jump {
+                        state.pc = *target_pc;
+                    } else {
+                        state.pc += 1;
+                    }
+                }
+                Insn::If {
+                    reg,
+                    target_pc,
+                    jump_if_null,
+                } => {
+                    if exec_if(&state.registers[*reg], *jump_if_null, false) {
+                        state.pc = *target_pc;
+                    } else {
+                        state.pc += 1;
+                    }
+                }
+                Insn::IfNot {
+                    reg,
+                    target_pc,
+                    jump_if_null,
+                } => {
+                    if exec_if(&state.registers[*reg], *jump_if_null, true) {
+                        state.pc = *target_pc;
+                    } else {
+                        state.pc += 1;
+                    }
+                }
+                Insn::IsNull { src, target_pc } => {
+                    if matches!(state.registers[*src], OwnedValue::Null) {
+                        state.pc = *target_pc;
+                    } else {
+                        state.pc += 1;
+                    }
+                }
+                Insn::NotNull { src, target_pc } => {
+                    if matches!(state.registers[*src], OwnedValue::Null) {
+                        state.pc += 1;
+                    } else {
+                        state.pc = *target_pc;
+                    }
+                }
+                Insn::Add { lhs, rhs, dest } => {
+                    state.registers[*dest] =
+                        state.registers[*lhs].clone() + state.registers[*rhs].clone();
+                    state.pc += 1;
+                }
+                Insn::Subtract { lhs, rhs, dest } => {
+                    state.registers[*dest] =
+                        state.registers[*lhs].clone() - state.registers[*rhs].clone();
+                    state.pc += 1;
+                }
+                Insn::Multiply { lhs, rhs, dest } => {
+                    state.registers[*dest] =
+                        state.registers[*lhs].clone() * state.registers[*rhs].clone();
+                    state.pc += 1;
+                }
+                Insn::Divide { lhs, rhs, dest } => {
+                    state.registers[*dest] =
+                        state.registers[*lhs].clone() / state.registers[*rhs].clone();
+                    state.pc += 1;
+                }
+                Insn::Remainder { lhs, rhs, dest } => {
+                    state.registers[*dest] =
+                        state.registers[*lhs].clone() % state.registers[*rhs].clone();
+                    state.pc += 1;
+                }
+                Insn::Concat { lhs, rhs, dest } => {
+                    state.registers[*dest] =
+                        exec_concat(&state.registers[*lhs], &state.registers[*rhs]);
+                    state.pc += 1;
+                }
+                Insn::BitAnd { lhs, rhs, dest } => {
+                    state.registers[*dest] =
+                        exec_bit_and(&state.registers[*lhs], &state.registers[*rhs]);
+                    state.pc += 1;
+                }
+                Insn::BitOr { lhs, rhs, dest } => {
+                    state.registers[*dest] =
+                        exec_bit_or(&state.registers[*lhs], &state.registers[*rhs]);
+                    state.pc += 1;
+                }
+                Insn::ShiftLeft { lhs, rhs, dest } => {
+                    state.registers[*dest] =
+                        exec_shift_left(&state.registers[*lhs], &state.registers[*rhs]);
+                    state.pc += 1;
+                }
+                Insn::ShiftRight { lhs, rhs, dest } => {
+                    state.registers[*dest] =
+                        exec_shift_right(&state.registers[*lhs], &state.registers[*rhs]);
+                    state.pc += 1;
+                }
+                Insn::And { lhs, rhs, dest } => {
+                    state.registers[*dest] =
+                        exec_and(&state.registers[*lhs], &state.registers[*rhs]);
+                    state.pc += 1;
+                }
+                Insn::Or { lhs, rhs, dest } => {
+                    state.registers[*dest] =
+                        exec_or(&state.registers[*lhs], &state.registers[*rhs]);
+                    state.pc += 1;
+                }
+                Insn::Not { reg, dest } => {
+                    state.registers[*dest] = exec_not(&state.registers[*reg]);
+                    state.pc += 1;
+                }
+                Insn::BitNot { reg, dest } => {
+                    state.registers[*dest] = exec_bit_not(&state.registers[*reg]);
+                    state.pc += 1;
+                }
+                Insn::Null { dest } => {
+                    state.registers[*dest] = OwnedValue::Null;
+                    state.pc += 1;
+                }
+                Insn::MustBeInt { reg } => {
+                    state.registers[*reg] = match &state.registers[*reg] {
+                        OwnedValue::Integer(i) => OwnedValue::Integer(*i),
+                        value => match value.apply_affinity('C') {
+                            OwnedValue::Integer(i) => OwnedValue::Integer(i),
+                            _ => anyhow::bail!("datatype mismatch"),
+                        },
+                    };
+                    state.pc += 1;
+                }
+                Insn::Affinity {
+                    start_reg,
+                    count,
+                    affinities,
+                } => {
+                    for (reg, affinity) in (*start_reg..*start_reg + *count).zip(affinities.chars())
+                    {
+                        state.registers[reg] = state.registers[reg].apply_affinity(affinity);
+                    }
+                    state.pc += 1;
+                }
+                Insn::Integer { value, dest } => {
+                    state.registers[*dest] = OwnedValue::Integer(*value);
+                    state.pc += 1;
+                }
+                Insn::Real { value, dest } => {
+                    state.registers[*dest] = OwnedValue::Float(*value);
+                    state.pc += 1;
+                }
+                Insn::RealAffinity { register } => {
+                    if let OwnedValue::Integer(i) = &state.registers[*register] {
+                        state.registers[*register] = OwnedValue::Float(*i as f64);
+                    } else {
+                    };
+                    state.pc += 1;
+                }
+                Insn::String8 { value, dest } => {
+                    state.registers[*dest] = OwnedValue::Text(Rc::new(value.into()));
+                    state.pc += 1;
+                }
+                Insn::Blob { value, dest } => {
+                    state.registers[*dest] = OwnedValue::Blob(Rc::new(value.clone()));
+                    state.pc += 1;
+                }
+                Insn::RowId { cursor_id, dest } => {
+                    let cursor = cursors.get_mut(cursor_id).unwrap();
+                    if cursor.get_null_flag() {
+                        state.registers[*dest] = OwnedValue::Null;
+                    } else if let Some(ref rowid) = *cursor.rowid()? {
+                        state.registers[*dest] = OwnedValue::Integer(*rowid as i64);
+                    } else {
+                        todo!();
+                    }
+                    state.pc += 1;
+                }
+                Insn::DecrJumpZero { reg, target_pc } => match state.registers[*reg] {
+                    OwnedValue::Integer(n) => {
+                        let n = n - 1;
+                        if n == 0 {
+                            state.pc = *target_pc;
+                        } else {
+                            state.registers[*reg] = OwnedValue::Integer(n);
+                            state.pc += 1;
                         }
                     }
                     _ => unreachable!("DecrJumpZero on non-integer register"),
@@ -456,33 +1875,12 @@ impl Program {
                             AggFunc::Count => {
                                 OwnedValue::Agg(Box::new(AggContext::Count(OwnedValue::Integer(0))))
                             }
+                            // min() and max() are NULL until they see a value.
                             AggFunc::Max => {
-                                let col = state.registers[*col].clone();
-                                match col {
-                                    OwnedValue::Integer(_) => OwnedValue::Agg(Box::new(
-                                        AggContext::Max(OwnedValue::Integer(i64::MIN)),
-                                    )),
-                                    OwnedValue::Float(_) => OwnedValue::Agg(Box::new(
-                                        AggContext::Max(OwnedValue::Float(f64::NEG_INFINITY)),
-                                    )),
-                                    _ => {
-                                        unreachable!();
-                                    }
-                                }
+                                OwnedValue::Agg(Box::new(AggContext::Max(OwnedValue::Null)))
                             }
                             AggFunc::Min => {
-                                let col = state.registers[*col].clone();
-                                match col {
-                                    OwnedValue::Integer(_) => OwnedValue::Agg(Box::new(
-                                        AggContext::Min(OwnedValue::Integer(i64::MAX)),
-                                    )),
-                                    OwnedValue::Float(_) => OwnedValue::Agg(Box::new(
-                                        AggContext::Min(OwnedValue::Float(f64::INFINITY)),
-                                    )),
-                                    _ => {
-                                        unreachable!();
-                                    }
-                                }
+                                OwnedValue::Agg(Box::new(AggContext::Min(OwnedValue::Null)))
                             }
                             _ => {
                                 todo!();
@@ -499,11 +1897,21 @@ impl Program {
                             let AggContext::Avg(acc, count) = agg.borrow_mut() else {
                                 unreachable!();
                             };
-                            *acc += col;
-                            *count += 1;
+                            if !matches!(col, OwnedValue::Null) {
+                                *acc += col;
+                                *count += 1;
+                            }
                         }
                         AggFunc::Sum | AggFunc::Total => {
-                            let col = state.registers[*col].clone();
+                            // Any input that is not an integer makes the sum a float.
+                            let col = match &state.registers[*col] {
+                                OwnedValue::Null => OwnedValue::Null,
+                                OwnedValue::Integer(i) => OwnedValue::Integer(*i),
+                                other => match other.to_numeric() {
+                                    OwnedValue::Integer(i) => OwnedValue::Float(i as f64),
+                                    numeric => numeric,
+                                },
+                            };
                             let OwnedValue::Agg(agg) = state.registers[*acc_reg].borrow_mut()
                             else {
                                 unreachable!();
@@ -511,9 +1919,14 @@ impl Program {
                             let AggContext::Sum(acc) = agg.borrow_mut() else {
                                 unreachable!();
                             };
-                            *acc += col;
+                            match (&acc, &col) {
+                                (_, OwnedValue::Null) => {}
+                                (OwnedValue::Null, _) => *acc = col.to_numeric(),
+                                _ => *acc += col,
+                            }
                         }
                         AggFunc::Count => {
+                            let col = state.registers[*col].clone();
                             let OwnedValue::Agg(agg) = state.registers[*acc_reg].borrow_mut()
                             else {
                                 unreachable!();
@@ -521,7 +1934,9 @@ impl Program {
                             let AggContext::Count(count) = agg.borrow_mut() else {
                                 unreachable!();
                             };
-                            *count += 1;
+                            if !matches!(col, OwnedValue::Null) {
+                                *count += 1;
+                            }
                         }
                         AggFunc::Max => {
                             let col = state.registers[*col].clone();
@@ -532,27 +1947,10 @@ impl Program {
                             let AggContext::Max(acc) = agg.borrow_mut() else {
                                 unreachable!();
                             };
-
-                            match (acc, col) {
-                                (
-                                    OwnedValue::Integer(ref mut current_max),
-                                    OwnedValue::Integer(value),
-                                ) => {
-                                    if value > *current_max {
-                                        *current_max = value;
-                                    }
-                                }
-                                (
-                                    OwnedValue::Float(ref mut current_max),
-                                    OwnedValue::Float(value),
-                                ) => {
-                                    if value > *current_max {
-                                        *current_max = value;
-                                    }
-                                }
-                                _ => {
-                                    eprintln!("Unexpected types in max aggregation");
-                                }
+                            // Values of different types compare the way they
+                            // sort, and NULLs are ignored.
+                            if col != OwnedValue::Null && (*acc == OwnedValue::Null || col > *acc) {
+                                *acc = col;
                             }
                         }
                         AggFunc::Min => {
@@ -564,27 +1962,8 @@ impl Program {
                             let AggContext::Min(acc) = agg.borrow_mut() else {
                                 unreachable!();
                             };
-
-                            match (acc, col) {
-                                (
-                                    OwnedValue::Integer(ref mut current_min),
-                                    OwnedValue::Integer(value),
-                                ) => {
-                                    if value < *current_min {
-                                        *current_min = value;
-                                    }
-                                }
-                                (
-                                    OwnedValue::Float(ref mut current_min),
-                                    OwnedValue::Float(value),
-                                ) => {
-                                    if value < *current_min {
-                                        *current_min = value;
-                                    }
-                                }
-                                _ => {
-                                    eprintln!("Unexpected types in min aggregation");
-                                }
+                            if col != OwnedValue::Null && (*acc == OwnedValue::Null || col < *acc) {
+                                *acc = col;
                             }
                         }
                         _ => {
@@ -593,6 +1972,26 @@ impl Program {
                     };
                     state.pc += 1;
                 }
+                Insn::Copy {
+                    src_reg,
+                    dst_reg,
+                    amount,
+                } => {
+                    for i in 0..=*amount {
+                        state.registers[*dst_reg + i] = state.registers[*src_reg + i].clone();
+                    }
+                    state.pc += 1;
+                }
+                Insn::Function {
+                    start_reg,
+                    arg_count,
+                    dest,
+                    func,
+                } => {
+                    let args = &state.registers[*start_reg..*start_reg + *arg_count];
+                    state.registers[*dest] = exec_scalar_func(func, args)?;
+                    state.pc += 1;
+                }
                 Insn::AggFinal { register, func } => {
                     match state.registers[*register].borrow_mut() {
                         OwnedValue::Agg(agg) => {
@@ -611,6 +2010,7 @@ impl Program {
                                     todo!();
                                 }
                             };
+                            state.registers[*register] = agg.final_value().clone();
                         }
                         OwnedValue::Null => {
                             // when the set is empty
@@ -630,21 +2030,28 @@ impl Program {
                     };
                     state.pc += 1;
                 }
-                Insn::SorterOpen { cursor_id } => {
-                    let cursor = Box::new(crate::sorter::Sorter::new());
+                Insn::SorterOpen {
+                    cursor_id,
+                    columns: _,
+                    order,
+                } => {
+                    let cursor = Box::new(Sorter::new(order.clone()));
                     cursors.insert(*cursor_id, cursor);
                     state.pc += 1;
                 }
                 Insn::SorterData {
                     cursor_id,
                     dest_reg,
+                    pseudo_cursor,
                 } => {
                     let cursor = cursors.get_mut(cursor_id).unwrap();
-                    if let Some(ref record) = *cursor.record()? {
-                        state.registers[*dest_reg] = OwnedValue::Record(record.clone());
-                    } else {
-                        todo!();
-                    }
+                    let record = match *cursor.record()? {
+                        Some(ref record) => record.clone(),
+                        None => todo!(),
+                    };
+                    let pseudo = cursors.get_mut(pseudo_cursor).unwrap();
+                    pseudo.insert(&OwnedValue::Null, &record)?;
+                    state.registers[*dest_reg] = OwnedValue::Record(record);
                     state.pc += 1;
                 }
                 Insn::SorterInsert {
@@ -656,13 +2063,20 @@ impl Program {
                         OwnedValue::Record(record) => record,
                         _ => unreachable!("SorterInsert on non-record register"),
                     };
-                    cursor.insert(record)?;
+                    cursor.insert(&OwnedValue::Null, record)?;
                     state.pc += 1;
                 }
-                Insn::SorterSort { cursor_id } => {
+                Insn::SorterSort {
+                    cursor_id,
+                    pc_if_empty,
+                } => {
                     let cursor = cursors.get_mut(cursor_id).unwrap();
                     cursor.rewind()?;
-                    state.pc += 1;
+                    if cursor.is_empty() {
+                        state.pc = *pc_if_empty;
+                    } else {
+                        state.pc += 1;
+                    }
                 }
                 Insn::SorterNext {
                     cursor_id,
@@ -687,6 +2101,400 @@ impl Program {
     }
 }
 
+/// Decide whether an `If` (or, with `not` set, an `IfNot`) instruction jumps.
+/// The value is interpreted as a number; NULL jumps only if `jump_if_null` is set.
+/// Roll back the write transaction and, if it changed the schema, reload the
+/// schema from the database.
+pub fn rollback(pager: &Rc<Pager>, schema: &Rc<RefCell<Schema>>) -> Result<()> {
+    let schema_cookie = pager.schema_cookie();
+    pager.rollback()?;
+    if pager.schema_cookie() != schema_cookie {
+        *RefCell::borrow_mut(schema) = Schema::read(pager)?;
+    }
+    Ok(())
+}
+
+fn exec_if(reg: &OwnedValue, jump_if_null: bool, not: bool) -> bool {
+    let truthy = match reg.to_numeric() {
+        OwnedValue::Null => return jump_if_null,
+        OwnedValue::Integer(i) => i != 0,
+        OwnedValue::Float(f) => f != 0.0,
+        _ => unreachable!("If/IfNot on non-scalar register"),
+    };
+    truthy != not
+}
+
+fn exec_concat(lhs: &OwnedValue, rhs: &OwnedValue) -> OwnedValue {
+    match (lhs.to_text(), rhs.to_text()) {
+        (Some(lhs), Some(rhs)) => OwnedValue::Text(Rc::new(lhs + &rhs)),
+        _ => OwnedValue::Null,
+    }
+}
+
+fn exec_bit_and(lhs: &OwnedValue, rhs: &OwnedValue) -> OwnedValue {
+    match (lhs.to_integer(), rhs.to_integer()) {
+        (Some(lhs), Some(rhs)) => OwnedValue::Integer(lhs & rhs),
+        _ => OwnedValue::Null,
+    }
+}
+
+fn exec_bit_or(lhs: &OwnedValue, rhs: &OwnedValue) -> OwnedValue {
+    match (lhs.to_integer(), rhs.to_integer()) {
+        (Some(lhs), Some(rhs)) => OwnedValue::Integer(lhs | rhs),
+        _ => OwnedValue::Null,
+    }
+}
+
+fn exec_bit_not(reg: &OwnedValue) -> OwnedValue {
+    match reg.to_integer() {
+        Some(i) => OwnedValue::Integer(!i),
+        None => OwnedValue::Null,
+    }
+}
+
+fn exec_shift_left(lhs: &OwnedValue, rhs: &OwnedValue) -> OwnedValue {
+    match (lhs.to_integer(), rhs.to_integer()) {
+        (Some(lhs), Some(rhs)) => OwnedValue::Integer(shift_left(lhs, rhs)),
+        _ => OwnedValue::Null,
+    }
+}
+
+fn exec_shift_right(lhs: &OwnedValue, rhs: &OwnedValue) -> OwnedValue {
+    match (lhs.to_integer(), rhs.to_integer()) {
+        (Some(lhs), Some(rhs)) => OwnedValue::Integer(shift_right(lhs, rhs)),
+        _ => OwnedValue::Null,
+    }
+}
+
+/// A negative shift amount shifts in the opposite direction, and shifting by
+/// 64 or more bits shifts everything out, as in SQLite.
+fn shift_left(value: i64, shift: i64) -> i64 {
+    if shift < 0 {
+        return shift_right(value, shift.saturating_neg());
+    }
+    if shift >= 64 {
+        0
+    } else {
+        value << shift
+    }
+}
+
+fn shift_right(value: i64, shift: i64) -> i64 {
+    if shift < 0 {
+        return shift_left(value, shift.saturating_neg());
+    }
+    if shift >= 64 {
+        if value < 0 {
+            -1
+        } else {
+            0
+        }
+    } else {
+        value >> shift
+    }
+}
+
+/// Interpret a value as a boolean, or `None` for NULL.
+fn truth_value(reg: &OwnedValue) -> Option<bool> {
+    match reg {
+        OwnedValue::Null => None,
+        _ => Some(exec_if(reg, false, false)),
+    }
+}
+
+fn exec_not(reg: &OwnedValue) -> OwnedValue {
+    match truth_value(reg) {
+        Some(value) => OwnedValue::Integer(!value as i64),
+        None => OwnedValue::Null,
+    }
+}
+
+fn exec_and(lhs: &OwnedValue, rhs: &OwnedValue) -> OwnedValue {
+    match (truth_value(lhs), truth_value(rhs)) {
+        (Some(false), _) | (_, Some(false)) => OwnedValue::Integer(0),
+        (Some(true), Some(true)) => OwnedValue::Integer(1),
+        _ => OwnedValue::Null,
+    }
+}
+
+fn exec_or(lhs: &OwnedValue, rhs: &OwnedValue) -> OwnedValue {
+    match (truth_value(lhs), truth_value(rhs)) {
+        (Some(true), _) | (_, Some(true)) => OwnedValue::Integer(1),
+        (Some(false), Some(false)) => OwnedValue::Integer(0),
+        _ => OwnedValue::Null,
+    }
+}
+
+fn exec_scalar_func(func: &ScalarFunc, args: &[OwnedValue]) -> Result<OwnedValue> {
+    let value = match func {
+        ScalarFunc::Abs => return exec_abs(&args[0]),
+        ScalarFunc::Coalesce | ScalarFunc::IfNull => args
+            .iter()
+            .find(|arg| !matches!(arg, OwnedValue::Null))
+            .cloned()
+            .unwrap_or(OwnedValue::Null),
+        ScalarFunc::Hex => exec_hex(&args[0]),
+        ScalarFunc::Iif => {
+            if truth_value(&args[0]) == Some(true) {
+                args[1].clone()
+            } else {
+                args[2].clone()
+            }
+        }
+        ScalarFunc::Instr => exec_instr(&args[0], &args[1]),
+        ScalarFunc::Length => exec_length(&args[0]),
+        ScalarFunc::Lower => exec_map_text(&args[0], |text| text.to_ascii_lowercase()),
+        ScalarFunc::LTrim => exec_trim(args, true, false),
+        ScalarFunc::Max => exec_min_max(args, Ordering::Greater),
+        ScalarFunc::Min => exec_min_max(args, Ordering::Less),
+        ScalarFunc::NullIf => {
+            if args[0].partial_cmp(&args[1]) == Some(Ordering::Equal) {
+                OwnedValue::Null
+            } else {
+                args[0].clone()
+            }
+        }
+        ScalarFunc::Quote => exec_quote(&args[0]),
+        ScalarFunc::Random => OwnedValue::Integer(exec_random()),
+        ScalarFunc::Replace => exec_replace(&args[0], &args[1], &args[2]),
+        ScalarFunc::Round => exec_round(&args[0], args.get(1)),
+        ScalarFunc::RTrim => exec_trim(args, false, true),
+        ScalarFunc::Substr => exec_substr(&args[0], &args[1], args.get(2)),
+        ScalarFunc::Trim => exec_trim(args, true, true),
+        ScalarFunc::Typeof => {
+            let name = match &args[0] {
+                OwnedValue::Null => "null",
+                OwnedValue::Integer(_) => "integer",
+                OwnedValue::Float(_) => "real",
+                OwnedValue::Text(_) => "text",
+                OwnedValue::Blob(_) => "blob",
+                OwnedValue::Agg(_) | OwnedValue::Record(_) => unreachable!(),
+            };
+            OwnedValue::Text(Rc::new(name.to_string()))
+        }
+        ScalarFunc::Upper => exec_map_text(&args[0], |text| text.to_ascii_uppercase()),
+    };
+    Ok(value)
+}
+
+fn exec_abs(reg: &OwnedValue) -> Result<OwnedValue> {
+    match reg {
+        OwnedValue::Null => Ok(OwnedValue::Null),
+        OwnedValue::Integer(i) => match i.checked_abs() {
+            Some(abs) => Ok(OwnedValue::Integer(abs)),
+            None => anyhow::bail!("integer overflow"),
+        },
+        // Text and blobs are converted to a floating point value first.
+        _ => Ok(OwnedValue::Float(to_float(reg).abs())),
+    }
+}
+
+/// Convert a non-NULL value to a floating point number.
+fn to_float(reg: &OwnedValue) -> f64 {
+    match reg.to_numeric() {
+        OwnedValue::Integer(i) => i as f64,
+        OwnedValue::Float(f) => f,
+        _ => 0.0,
+    }
+}
+
+fn exec_hex(reg: &OwnedValue) -> OwnedValue {
+    let bytes = match reg {
+        OwnedValue::Blob(blob) => blob.to_vec(),
+        _ => reg.to_text().unwrap_or_default().into_bytes(),
+    };
+    let hex: String = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+    OwnedValue::Text(Rc::new(hex))
+}
+
+fn exec_instr(haystack: &OwnedValue, needle: &OwnedValue) -> OwnedValue {
+    let position = match (haystack, needle) {
+        (OwnedValue::Null, _) | (_, OwnedValue::Null) => return OwnedValue::Null,
+        (OwnedValue::Blob(haystack), OwnedValue::Blob(needle)) => {
+            if needle.is_empty() {
+                Some(0)
+            } else {
+                haystack
+                    .windows(needle.len())
+                    .position(|window| window == needle.as_slice())
+            }
+        }
+        _ => {
+            let haystack = haystack.to_text().unwrap();
+            let needle = needle.to_text().unwrap();
+            haystack
+                .find(&needle)
+                .map(|byte_idx| haystack[..byte_idx].chars().count())
+        }
+    };
+    OwnedValue::Integer(position.map_or(0, |pos| pos as i64 + 1))
+}
+
+fn exec_length(reg: &OwnedValue) -> OwnedValue {
+    match reg {
+        OwnedValue::Null => OwnedValue::Null,
+        OwnedValue::Blob(blob) => OwnedValue::Integer(blob.len() as i64),
+        _ => OwnedValue::Integer(reg.to_text().unwrap().chars().count() as i64),
+    }
+}
+
+fn exec_map_text(reg: &OwnedValue, f: impl Fn(&str) -> String) -> OwnedValue {
+    match reg.to_text() {
+        Some(text) => OwnedValue::Text(Rc::new(f(&text))),
+        None => OwnedValue::Null,
+    }
+}
+
+/// Remove the characters in the optional second argument (spaces by
+/// default) from the start and/or end of the first.
+fn exec_trim(args: &[OwnedValue], start: bool, end: bool) -> OwnedValue {
+    let Some(text) = args[0].to_text() else {
+        return OwnedValue::Null;
+    };
+    let pattern: Vec<char> = match args.get(1) {
+        Some(pattern) => match pattern.to_text() {
+            Some(pattern) => pattern.chars().collect(),
+            None => return OwnedValue::Null,
+        },
+        None => vec![' '],
+    };
+    let mut trimmed = text.as_str();
+    if start {
+        trimmed = trimmed.trim_start_matches(pattern.as_slice());
+    }
+    if end {
+        trimmed = trimmed.trim_end_matches(pattern.as_slice());
+    }
+    OwnedValue::Text(Rc::new(trimmed.to_string()))
+}
+
+/// Return the smallest or largest argument, or NULL if any argument is NULL.
+/// Ties are resolved the way SQLite does: min() picks the last of the equal
+/// values and max() the first.
+fn exec_min_max(args: &[OwnedValue], wanted: Ordering) -> OwnedValue {
+    if args.iter().any(|arg| matches!(arg, OwnedValue::Null)) {
+        return OwnedValue::Null;
+    }
+    let mut best = &args[0];
+    for arg in &args[1..] {
+        let ordering = arg.partial_cmp(best).unwrap_or(Ordering::Equal);
+        if ordering == wanted || (ordering == Ordering::Equal && wanted == Ordering::Less) {
+            best = arg;
+        }
+    }
+    best.clone()
+}
+
+fn exec_quote(reg: &OwnedValue) -> OwnedValue {
+    let quoted = match reg {
+        OwnedValue::Null => "NULL".to_string(),
+        OwnedValue::Integer(_) | OwnedValue::Float(_) => reg.to_text().unwrap(),
+        OwnedValue::Text(text) => format!("'{}'", text.replace('\'', "''")),
+        OwnedValue::Blob(blob) => {
+            let hex: String = blob.iter().map(|byte| format!("{:02X}", byte)).collect();
+            format!("X'{}'", hex)
+        }
+        OwnedValue::Agg(_) | OwnedValue::Record(_) => unreachable!(),
+    };
+    OwnedValue::Text(Rc::new(quoted))
+}
+
+fn exec_random() -> i64 {
+    use std::hash::{BuildHasher, Hasher};
+    std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish() as i64
+}
+
+fn exec_replace(text: &OwnedValue, pattern: &OwnedValue, replacement: &OwnedValue) -> OwnedValue {
+    match (text.to_text(), pattern.to_text(), replacement.to_text()) {
+        (Some(text), Some(pattern), _) if pattern.is_empty() => OwnedValue::Text(Rc::new(text)),
+        (Some(text), Some(pattern), Some(replacement)) => {
+            OwnedValue::Text(Rc::new(text.replace(&pattern, &replacement)))
+        }
+        _ => OwnedValue::Null,
+    }
+}
+
+fn exec_round(reg: &OwnedValue, digits: Option<&OwnedValue>) -> OwnedValue {
+    if let OwnedValue::Null = reg {
+        return OwnedValue::Null;
+    }
+    let digits = match digits {
+        Some(digits) => match digits.to_integer() {
+            Some(digits) => digits.clamp(0, 30) as i32,
+            None => return OwnedValue::Null,
+        },
+        None => 0,
+    };
+    let value = to_float(reg);
+    // Round as SQLite does: values this large have no fractional part, whole
+    // numbers are rounded half away from zero, and otherwise the value is
+    // formatted with the given number of digits and parsed back, so that
+    // 2.675 (really 2.67499...) rounds down to 2.67.
+    let rounded = if !(-4503599627370496.0..=4503599627370496.0).contains(&value) {
+        value
+    } else if digits == 0 {
+        (value + if value < 0.0 { -0.5 } else { 0.5 }) as i64 as f64
+    } else {
+        format!("{:.*}", digits as usize, value)
+            .parse()
+            .unwrap_or(value)
+    };
+    OwnedValue::Float(rounded)
+}
+
+/// Extract a substring using SQLite's rules: positions are 1-based, a
+/// negative start counts from the end, and a negative length takes the
+/// characters before the start. Blobs are indexed by byte, text by character.
+fn exec_substr(reg: &OwnedValue, start: &OwnedValue, length: Option<&OwnedValue>) -> OwnedValue {
+    if let OwnedValue::Null = reg {
+        return OwnedValue::Null;
+    }
+    let Some(mut p1) = start.to_integer() else {
+        return OwnedValue::Null;
+    };
+    let (mut p2, negative_length) = match length {
+        Some(length) => match length.to_integer() {
+            Some(length) => (length.saturating_abs(), length < 0),
+            None => return OwnedValue::Null,
+        },
+        None => (i64::MAX, false),
+    };
+    let chars: Vec<char> = match reg {
+        OwnedValue::Blob(_) => Vec::new(),
+        _ => reg.to_text().unwrap().chars().collect(),
+    };
+    let len = match reg {
+        OwnedValue::Blob(blob) => blob.len(),
+        _ => chars.len(),
+    } as i64;
+    if p1 < 0 {
+        p1 += len;
+        if p1 < 0 {
+            p2 = (p2 + p1).max(0);
+            p1 = 0;
+        }
+    } else if p1 > 0 {
+        p1 -= 1;
+    } else if p2 > 0 {
+        p2 -= 1;
+    }
+    if negative_length {
+        p1 -= p2;
+        if p1 < 0 {
+            p2 += p1;
+            p1 = 0;
+        }
+    }
+    let start = p1.min(len) as usize;
+    let end = p1.saturating_add(p2).min(len) as usize;
+    match reg {
+        OwnedValue::Blob(blob) => OwnedValue::Blob(Rc::new(blob[start..end].to_vec())),
+        _ => OwnedValue::Text(Rc::new(chars[start..end].iter().collect())),
+    }
+}
+
 fn make_record<'a>(registers: &'a [OwnedValue], start_reg: &usize, count: &usize) -> Record<'a> {
     let mut values = Vec::with_capacity(*count);
     for r in registers.iter().skip(*start_reg).take(*count) {
@@ -703,6 +2511,62 @@ fn make_owned_record(registers: &[OwnedValue], start_reg: &usize, count: &usize)
     OwnedRecord::new(values)
 }
 
+/// The integer that a float without a fractional part is equal to, if it is
+/// in the range of rowids.
+fn float_to_rowid(f: f64) -> Option<i64> {
+    if (-9223372036854775808.0..9223372036854775808.0).contains(&f) {
+        Some(f as i64)
+    } else {
+        None
+    }
+}
+
+/// Turn the key of a seek on a table cursor into a rowid to seek to, keeping
+/// the order that comparisons with rowids follow: NULL is before every rowid,
+/// and text and blobs are after them. Returns `None` if no rowid can be past
+/// the key.
+fn rowid_seek_key(key: &OwnedValue, op: SeekOp) -> Option<(i64, SeekOp)> {
+    // Text that looks like a number seeks to that number.
+    match key.apply_affinity('C') {
+        OwnedValue::Integer(rowid) => Some((rowid, op)),
+        OwnedValue::Float(f) => {
+            // Round towards the rowids that are past the key.
+            let f = match op {
+                SeekOp::GE => f.ceil(),
+                SeekOp::GT => f.floor(),
+            };
+            if f < -9223372036854775808.0 {
+                Some((i64::MIN, SeekOp::GE))
+            } else {
+                float_to_rowid(f).map(|rowid| (rowid, op))
+            }
+        }
+        OwnedValue::Null => Some((i64::MIN, SeekOp::GE)),
+        _ => None,
+    }
+}
+
+/// The operands of a comparison, converted with its affinity if it has one.
+fn comparison_operands<'a>(
+    lhs: &'a OwnedValue,
+    rhs: &'a OwnedValue,
+    affinity: Option<char>,
+) -> (Cow<'a, OwnedValue>, Cow<'a, OwnedValue>) {
+    match affinity {
+        Some(affinity) => (
+            Cow::Owned(lhs.apply_affinity(affinity)),
+            Cow::Owned(rhs.apply_affinity(affinity)),
+        ),
+        None => (Cow::Borrowed(lhs), Cow::Borrowed(rhs)),
+    }
+}
+
+/// The P5 operand of a comparison opcode, which SQLite uses for its affinity
+/// and the SQLITE_JUMPIFNULL flag.
+fn comparison_p5(jump_if_null: bool, affinity: Option<char>) -> u16 {
+    affinity.map_or(0, |affinity| affinity as u16) | if jump_if_null { 0x10 } else { 0 }
+}
+
 fn trace_insn(addr: BranchOffset, insn: &Insn) {
     if !log::log_enabled!(log::Level::Trace) {
         return;
@@ -748,6 +2612,251 @@ fn insn_to_str(addr: BranchOffset, insn: &Insn, indent: String) -> String {
                 0,
                 "".to_string(),
             ),
+            Insn::OpenWriteAsync {
+                cursor_id,
+                root_page,
+            } => (
+                "OpenWriteAsync",
+                *cursor_id as i32,
+                *root_page as i32,
+                0,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("root={}", root_page),
+            ),
+            Insn::OpenWriteRegAsync {
+                cursor_id,
+                root_reg,
+            } => (
+                "OpenWriteRegAsync",
+                *cursor_id as i32,
+                *root_reg as i32,
+                0,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("root=r[{}]", root_reg),
+            ),
+            Insn::OpenWriteAwait => (
+                "OpenWriteAwait",
+                0,
+                0,
+                0,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                "".to_string(),
+            ),
+            Insn::NewRowid {
+                cursor_id,
+                rowid_reg,
+            } => (
+                "NewRowid",
+                *cursor_id as i32,
+                *rowid_reg as i32,
+                0,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("r[{}]=rowid", rowid_reg),
+            ),
+            Insn::NotExists {
+                cursor_id,
+                rowid_reg,
+                target_pc,
+            } => (
+                "NotExists",
+                *cursor_id as i32,
+                *target_pc as i32,
+                *rowid_reg as i32,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("intkey=r[{}]", rowid_reg),
+            ),
+            Insn::SeekRowid {
+                cursor_id,
+                src_reg,
+                target_pc,
+            } => (
+                "SeekRowid",
+                *cursor_id as i32,
+                *target_pc as i32,
+                *src_reg as i32,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("intkey=r[{}]", src_reg),
+            ),
+            Insn::InsertAsync {
+                cursor_id,
+                key_reg,
+                record_reg,
+                flag,
+            } => (
+                "InsertAsync",
+                *cursor_id as i32,
+                *record_reg as i32,
+                *key_reg as i32,
+                OwnedValue::Text(Rc::new("".to_string())),
+                *flag as u16,
+                format!("intkey=r[{}] data=r[{}]", key_reg, record_reg),
+            ),
+            Insn::InsertAwait { cursor_id } => (
+                "InsertAwait",
+                *cursor_id as i32,
+                0,
+                0,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                "".to_string(),
+            ),
+            Insn::DeleteAsync { cursor_id } => (
+                "DeleteAsync",
+                *cursor_id as i32,
+                0,
+                0,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                "".to_string(),
+            ),
+            Insn::DeleteAwait { cursor_id } => (
+                "DeleteAwait",
+                *cursor_id as i32,
+                0,
+                0,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                "".to_string(),
+            ),
+            Insn::IdxInsertAsync {
+                cursor_id,
+                record_reg,
+            } => (
+                "IdxInsertAsync",
+                *cursor_id as i32,
+                *record_reg as i32,
+                0,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("key=r[{}]", record_reg),
+            ),
+            Insn::IdxInsertAwait { cursor_id } => (
+                "IdxInsertAwait",
+                *cursor_id as i32,
+                0,
+                0,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                "".to_string(),
+            ),
+            Insn::IdxDeleteAsync {
+                cursor_id,
+                start_reg,
+                num_regs,
+            } => (
+                "IdxDeleteAsync",
+                *cursor_id as i32,
+                *start_reg as i32,
+                *num_regs as i32,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("key=r[{}..{}]", start_reg, start_reg + num_regs - 1),
+            ),
+            Insn::IdxDeleteAwait { cursor_id } => (
+                "IdxDeleteAwait",
+                *cursor_id as i32,
+                0,
+                0,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                "".to_string(),
+            ),
+            Insn::CreateBtree { root_reg, is_index } => (
+                "CreateBtree",
+                0,
+                *root_reg as i32,
+                if *is_index { 2 } else { 1 },
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("r[{}]=root iDb=0", root_reg),
+            ),
+            Insn::Destroy { root_reg } => (
+                "Destroy",
+                *root_reg as i32,
+                0,
+                0,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("root=r[{}]", root_reg),
+            ),
+            Insn::SetCookie { value } => (
+                "SetCookie",
+                0,
+                1,
+                *value as i32,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                "".to_string(),
+            ),
+            Insn::ParseSchema { start_reg } => (
+                "ParseSchema",
+                *start_reg as i32,
+                0,
+                0,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("r[{}..{}]", start_reg, start_reg + 4),
+            ),
+            Insn::DropTable { table_name } => (
+                "DropTable",
+                0,
+                0,
+                0,
+                OwnedValue::Text(Rc::new(table_name.clone())),
+                0,
+                "".to_string(),
+            ),
+            Insn::DropIndex { index_name } => (
+                "DropIndex",
+                0,
+                0,
+                0,
+                OwnedValue::Text(Rc::new(index_name.clone())),
+                0,
+                "".to_string(),
+            ),
+            Insn::NoConflict {
+                cursor_id,
+                start_reg,
+                num_regs,
+                target_pc,
+            } => (
+                "NoConflict",
+                *cursor_id as i32,
+                *target_pc as i32,
+                *start_reg as i32,
+                OwnedValue::Integer(*num_regs as i64),
+                0,
+                format!("key=r[{}..{}]", start_reg, start_reg + num_regs - 1),
+            ),
+            Insn::MustBeInt { reg } => (
+                "MustBeInt",
+                *reg as i32,
+                0,
+                0,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                "".to_string(),
+            ),
+            Insn::Affinity {
+                start_reg,
+                count,
+                affinities,
+            } => (
+                "Affinity",
+                *start_reg as i32,
+                *count as i32,
+                0,
+                OwnedValue::Text(Rc::new(affinities.clone())),
+                0,
+                format!("r[{}..{}]", start_reg, start_reg + count.saturating_sub(1)),
+            ),
             Insn::OpenPseudo {
                 cursor_id,
                 content_reg,
@@ -761,6 +2870,15 @@ fn insn_to_str(addr: BranchOffset, insn: &Insn, indent: String) -> String {
                 0,
                 format!("{} columns in r[{}]", num_fields, content_reg),
             ),
+            Insn::NullRow { cursor_id } => (
+                "NullRow",
+                *cursor_id as i32,
+                0,
+                0,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                "".to_string(),
+            ),
             Insn::RewindAsync { cursor_id } => (
                 "RewindAsync",
                 *cursor_id as i32,
@@ -770,11 +2888,32 @@ fn insn_to_str(addr: BranchOffset, insn: &Insn, indent: String) -> String {
                 0,
                 "".to_string(),
             ),
-            Insn::RewindAwait {
+            Insn::RewindAwait {
+                cursor_id,
+                pc_if_empty,
+            } => (
+                "RewindAwait",
+                *cursor_id as i32,
+                *pc_if_empty as i32,
+                0,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                "".to_string(),
+            ),
+            Insn::LastAsync { cursor_id } => (
+                "LastAsync",
+                *cursor_id as i32,
+                0,
+                0,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                "".to_string(),
+            ),
+            Insn::LastAwait {
                 cursor_id,
                 pc_if_empty,
             } => (
-                "RewindAwait",
+                "LastAwait",
                 *cursor_id as i32,
                 *pc_if_empty as i32,
                 0,
@@ -838,15 +2977,52 @@ fn insn_to_str(addr: BranchOffset, insn: &Insn, indent: String) -> String {
                 0,
                 "".to_string(),
             ),
-            Insn::Halt => (
-                "Halt",
+            Insn::PrevAsync { cursor_id } => (
+                "PrevAsync",
+                *cursor_id as i32,
+                0,
                 0,
+                OwnedValue::Text(Rc::new("".to_string())),
                 0,
+                "".to_string(),
+            ),
+            Insn::PrevAwait {
+                cursor_id,
+                pc_if_next,
+            } => (
+                "PrevAwait",
+                *cursor_id as i32,
+                *pc_if_next as i32,
                 0,
                 OwnedValue::Text(Rc::new("".to_string())),
                 0,
                 "".to_string(),
             ),
+            Insn::Halt {
+                err_code,
+                description,
+            } => (
+                "Halt",
+                *err_code as i32,
+                0,
+                0,
+                OwnedValue::Text(Rc::new(description.clone())),
+                0,
+                "".to_string(),
+            ),
+            Insn::HaltIfNull {
+                reg,
+                err_code,
+                description,
+            } => (
+                "HaltIfNull",
+                *err_code as i32,
+                0,
+                *reg as i32,
+                OwnedValue::Text(Rc::new(description.clone())),
+                0,
+                "".to_string(),
+            ),
             Insn::Transaction => (
                 "Transaction",
                 0,
@@ -856,6 +3032,45 @@ fn insn_to_str(addr: BranchOffset, insn: &Insn, indent: String) -> String {
                 0,
                 "".to_string(),
             ),
+            Insn::AutoCommit {
+                auto_commit,
+                rollback,
+            } => (
+                "AutoCommit",
+                *auto_commit as i32,
+                *rollback as i32,
+                0,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                "".to_string(),
+            ),
+            Insn::JournalMode { mode, dest } => (
+                "JournalMode",
+                0,
+                *dest as i32,
+                0,
+                OwnedValue::Text(Rc::new(mode.map_or("", |mode| mode.as_str()).to_string())),
+                0,
+                format!("r[{}]=journal_mode", dest),
+            ),
+            Insn::Checkpoint { mode, dest } => (
+                "Checkpoint",
+                0,
+                *mode as i32,
+                *dest as i32,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("r[{}..{}]=wal_checkpoint", dest, dest + 3),
+            ),
+            Insn::Vacuum { into } => (
+                "Vacuum",
+                0,
+                into.map_or(0, |reg| reg as i32),
+                0,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                "".to_string(),
+            ),
             Insn::Goto { target_pc } => (
                 "Goto",
                 0,
@@ -865,6 +3080,328 @@ fn insn_to_str(addr: BranchOffset, insn: &Insn, indent: String) -> String {
                 0,
                 "".to_string(),
             ),
+            Insn::Gosub {
+                target_pc,
+                return_reg,
+            } => (
+                "Gosub",
+                *return_reg as i32,
+                *target_pc as i32,
+                0,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                "".to_string(),
+            ),
+            Insn::Return { return_reg } => (
+                "Return",
+                *return_reg as i32,
+                0,
+                0,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                "".to_string(),
+            ),
+            Insn::Compare {
+                start_reg_a,
+                start_reg_b,
+                count,
+            } => (
+                "Compare",
+                *start_reg_a as i32,
+                *start_reg_b as i32,
+                *count as i32,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!(
+                    "r[{}..{}]==r[{}..{}]",
+                    start_reg_a,
+                    start_reg_a + count,
+                    start_reg_b,
+                    start_reg_b + count
+                ),
+            ),
+            Insn::Jump {
+                target_pc_lt,
+                target_pc_eq,
+                target_pc_gt,
+            } => (
+                "Jump",
+                *target_pc_lt as i32,
+                *target_pc_eq as i32,
+                *target_pc_gt as i32,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                "".to_string(),
+            ),
+            Insn::Eq {
+                lhs,
+                rhs,
+                target_pc,
+                jump_if_null,
+                affinity,
+            } => (
+                "Eq",
+                *lhs as i32,
+                *rhs as i32,
+                *target_pc as i32,
+                OwnedValue::Text(Rc::new("".to_string())),
+                comparison_p5(*jump_if_null, *affinity),
+                format!("if r[{}]==r[{}] goto {}", lhs, rhs, target_pc),
+            ),
+            Insn::Ne {
+                lhs,
+                rhs,
+                target_pc,
+                jump_if_null,
+                affinity,
+            } => (
+                "Ne",
+                *lhs as i32,
+                *rhs as i32,
+                *target_pc as i32,
+                OwnedValue::Text(Rc::new("".to_string())),
+                comparison_p5(*jump_if_null, *affinity),
+                format!("if r[{}]!=r[{}] goto {}", lhs, rhs, target_pc),
+            ),
+            Insn::Lt {
+                lhs,
+                rhs,
+                target_pc,
+                jump_if_null,
+                affinity,
+            } => (
+                "Lt",
+                *lhs as i32,
+                *rhs as i32,
+                *target_pc as i32,
+                OwnedValue::Text(Rc::new("".to_string())),
+                comparison_p5(*jump_if_null, *affinity),
+                format!("if r[{}]<r[{}] goto {}", lhs, rhs, target_pc),
+            ),
+            Insn::Le {
+                lhs,
+                rhs,
+                target_pc,
+                jump_if_null,
+                affinity,
+            } => (
+                "Le",
+                *lhs as i32,
+                *rhs as i32,
+                *target_pc as i32,
+                OwnedValue::Text(Rc::new("".to_string())),
+                comparison_p5(*jump_if_null, *affinity),
+                format!("if r[{}]<=r[{}] goto {}", lhs, rhs, target_pc),
+            ),
+            Insn::Gt {
+                lhs,
+                rhs,
+                target_pc,
+                jump_if_null,
+                affinity,
+            } => (
+                "Gt",
+                *lhs as i32,
+                *rhs as i32,
+                *target_pc as i32,
+                OwnedValue::Text(Rc::new("".to_string())),
+                comparison_p5(*jump_if_null, *affinity),
+                format!("if r[{}]>r[{}] goto {}", lhs, rhs, target_pc),
+            ),
+            Insn::Ge {
+                lhs,
+                rhs,
+                target_pc,
+                jump_if_null,
+                affinity,
+            } => (
+                "Ge",
+                *lhs as i32,
+                *rhs as i32,
+                *target_pc as i32,
+                OwnedValue::Text(Rc::new("".to_string())),
+                comparison_p5(*jump_if_null, *affinity),
+                format!("if r[{}]>=r[{}] goto {}", lhs, rhs, target_pc),
+            ),
+            Insn::If {
+                reg,
+                target_pc,
+                jump_if_null,
+            } => (
+                "If",
+                *reg as i32,
+                *target_pc as i32,
+                *jump_if_null as i32,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("if r[{}] goto {}", reg, target_pc),
+            ),
+            Insn::IfNot {
+                reg,
+                target_pc,
+                jump_if_null,
+            } => (
+                "IfNot",
+                *reg as i32,
+                *target_pc as i32,
+                *jump_if_null as i32,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("if !r[{}] goto {}", reg, target_pc),
+            ),
+            Insn::IsNull { src, target_pc } => (
+                "IsNull",
+                *src as i32,
+                *target_pc as i32,
+                0,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("if (r[{}]==NULL) goto {}", src, target_pc),
+            ),
+            Insn::NotNull { src, target_pc } => (
+                "NotNull",
+                *src as i32,
+                *target_pc as i32,
+                0,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("if (r[{}]!=NULL) goto {}", src, target_pc),
+            ),
+            Insn::Add { lhs, rhs, dest } => (
+                "Add",
+                *lhs as i32,
+                *rhs as i32,
+                *dest as i32,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("r[{}]=r[{}]+r[{}]", dest, lhs, rhs),
+            ),
+            Insn::Subtract { lhs, rhs, dest } => (
+                "Subtract",
+                *lhs as i32,
+                *rhs as i32,
+                *dest as i32,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("r[{}]=r[{}]-r[{}]", dest, lhs, rhs),
+            ),
+            Insn::Multiply { lhs, rhs, dest } => (
+                "Multiply",
+                *lhs as i32,
+                *rhs as i32,
+                *dest as i32,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("r[{}]=r[{}]*r[{}]", dest, lhs, rhs),
+            ),
+            Insn::Divide { lhs, rhs, dest } => (
+                "Divide",
+                *lhs as i32,
+                *rhs as i32,
+                *dest as i32,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("r[{}]=r[{}]/r[{}]", dest, lhs, rhs),
+            ),
+            Insn::Remainder { lhs, rhs, dest } => (
+                "Remainder",
+                *lhs as i32,
+                *rhs as i32,
+                *dest as i32,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("r[{}]=r[{}]%r[{}]", dest, lhs, rhs),
+            ),
+            Insn::Concat { lhs, rhs, dest } => (
+                "Concat",
+                *lhs as i32,
+                *rhs as i32,
+                *dest as i32,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("r[{}]=r[{}]||r[{}]", dest, lhs, rhs),
+            ),
+            Insn::BitAnd { lhs, rhs, dest } => (
+                "BitAnd",
+                *lhs as i32,
+                *rhs as i32,
+                *dest as i32,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("r[{}]=r[{}]&r[{}]", dest, lhs, rhs),
+            ),
+            Insn::BitOr { lhs, rhs, dest } => (
+                "BitOr",
+                *lhs as i32,
+                *rhs as i32,
+                *dest as i32,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("r[{}]=r[{}]|r[{}]", dest, lhs, rhs),
+            ),
+            Insn::ShiftLeft { lhs, rhs, dest } => (
+                "ShiftLeft",
+                *lhs as i32,
+                *rhs as i32,
+                *dest as i32,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("r[{}]=r[{}]<<r[{}]", dest, lhs, rhs),
+            ),
+            Insn::ShiftRight { lhs, rhs, dest } => (
+                "ShiftRight",
+                *lhs as i32,
+                *rhs as i32,
+                *dest as i32,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("r[{}]=r[{}]>>r[{}]", dest, lhs, rhs),
+            ),
+            Insn::And { lhs, rhs, dest } => (
+                "And",
+                *lhs as i32,
+                *rhs as i32,
+                *dest as i32,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("r[{}]=r[{}]&&r[{}]", dest, lhs, rhs),
+            ),
+            Insn::Or { lhs, rhs, dest } => (
+                "Or",
+                *lhs as i32,
+                *rhs as i32,
+                *dest as i32,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("r[{}]=r[{}]||r[{}]", dest, lhs, rhs),
+            ),
+            Insn::Not { reg, dest } => (
+                "Not",
+                *reg as i32,
+                *dest as i32,
+                0,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("r[{}]=!r[{}]", dest, reg),
+            ),
+            Insn::BitNot { reg, dest } => (
+                "BitNot",
+                *reg as i32,
+                *dest as i32,
+                0,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("r[{}]=~r[{}]", dest, reg),
+            ),
+            Insn::Null { dest } => (
+                "Null",
+                0,
+                *dest as i32,
+                0,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("r[{}]=NULL", dest),
+            ),
             Insn::Integer { value, dest } => (
                 "Integer",
                 *dest as i32,
@@ -901,6 +3438,88 @@ fn insn_to_str(addr: BranchOffset, insn: &Insn, indent: String) -> String {
                 0,
                 format!("r[{}]= '{}'", dest, value),
             ),
+            Insn::Blob { value, dest } => (
+                "Blob",
+                value.len() as i32,
+                *dest as i32,
+                0,
+                OwnedValue::Blob(Rc::new(value.clone())),
+                0,
+                format!("r[{}]= (blob)", dest),
+            ),
+            Insn::SeekGE {
+                is_index: false,
+                cursor_id,
+                start_reg,
+                target_pc,
+                ..
+            }
+            | Insn::SeekGT {
+                is_index: false,
+                cursor_id,
+                start_reg,
+                target_pc,
+                ..
+            } => (
+                match insn {
+                    Insn::SeekGE { .. } => "SeekGE",
+                    _ => "SeekGT",
+                },
+                *cursor_id as i32,
+                *target_pc as i32,
+                *start_reg as i32,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("key=r[{}]", start_reg),
+            ),
+            Insn::SeekGE {
+                cursor_id,
+                start_reg,
+                num_regs,
+                target_pc,
+                ..
+            }
+            | Insn::SeekGT {
+                cursor_id,
+                start_reg,
+                num_regs,
+                target_pc,
+                ..
+            }
+            | Insn::IdxGE {
+                cursor_id,
+                start_reg,
+                num_regs,
+                target_pc,
+            }
+            | Insn::IdxGT {
+                cursor_id,
+                start_reg,
+                num_regs,
+                target_pc,
+            } => (
+                match insn {
+                    Insn::SeekGE { .. } => "SeekGE",
+                    Insn::SeekGT { .. } => "SeekGT",
+                    Insn::IdxGE { .. } => "IdxGE",
+                    _ => "IdxGT",
+                },
+                *cursor_id as i32,
+                *target_pc as i32,
+                *start_reg as i32,
+                OwnedValue::Integer(*num_regs as i64),
+                0,
+                format!("key=r[{}..{}]", start_reg, start_reg + num_regs - 1),
+            ),
+            Insn::IdxRowid { cursor_id, dest } => (
+                "IdxRowid",
+                *cursor_id as i32,
+                *dest as i32,
+                0,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("r[{}]=rowid", dest),
+            ),
             Insn::RowId { cursor_id, dest } => (
                 "RowId",
                 *cursor_id as i32,
@@ -928,6 +3547,43 @@ fn insn_to_str(addr: BranchOffset, insn: &Insn, indent: String) -> String {
                 0,
                 format!("accum=r[{}] step({})", *acc_reg, *col),
             ),
+            Insn::Copy {
+                src_reg,
+                dst_reg,
+                amount,
+            } => (
+                "Copy",
+                *src_reg as i32,
+                *dst_reg as i32,
+                *amount as i32,
+                OwnedValue::Text(Rc::new("".to_string())),
+                0,
+                format!("r[{}]=r[{}]", dst_reg, src_reg),
+            ),
+            Insn::Function {
+                start_reg,
+                arg_count,
+                dest,
+                func,
+            } => (
+                "Function",
+                0,
+                *start_reg as i32,
+                *dest as i32,
+                OwnedValue::Text(Rc::new(func.to_string().into())),
+                *arg_count as u16,
+                if *arg_count == 0 {
+                    format!("r[{}]={}()", dest, func.to_string())
+                } else {
+                    format!(
+                        "r[{}]={}(r[{}..{}])",
+                        dest,
+                        func.to_string(),
+                        start_reg,
+                        start_reg + arg_count - 1
+                    )
+                },
+            ),
             Insn::AggFinal { register, func } => (
                 "AggFinal",
                 0,
@@ -937,23 +3593,35 @@ fn insn_to_str(addr: BranchOffset, insn: &Insn, indent: String) -> String {
                 0,
                 format!("accum=r[{}]", *register),
             ),
-            Insn::SorterOpen { cursor_id } => (
-                "SorterOpen",
-                *cursor_id as i32,
-                0,
-                0,
-                OwnedValue::Text(Rc::new("".to_string())),
-                0,
-                format!("cursor={}", cursor_id),
-            ),
+            Insn::SorterOpen {
+                cursor_id,
+                columns,
+                order,
+            } => {
+                let keys = order
+                    .iter()
+                    .map(|key| if key.descending { "-B" } else { "B" })
+                    .collect::<Vec<_>>()
+                    .join(",");
+                (
+                    "SorterOpen",
+                    *cursor_id as i32,
+                    *columns as i32,
+                    0,
+                    OwnedValue::Text(Rc::new(format!("k({},{})", order.len(), keys))),
+                    0,
+                    format!("cursor={}", cursor_id),
+                )
+            }
             Insn::SorterData {
                 cursor_id,
                 dest_reg,
+                pseudo_cursor,
             } => (
                 "SorterData",
                 *cursor_id as i32,
                 *dest_reg as i32,
-                0,
+                *pseudo_cursor as i32,
                 OwnedValue::Text(Rc::new("".to_string())),
                 0,
                 format!("r[{}]=data", dest_reg),
@@ -970,10 +3638,13 @@ fn insn_to_str(addr: BranchOffset, insn: &Insn, indent: String) -> String {
                 0,
                 format!("key=r[{}]", record_reg),
             ),
-            Insn::SorterSort { cursor_id } => (
+            Insn::SorterSort {
+                cursor_id,
+                pc_if_empty,
+            } => (
                 "SorterSort",
                 *cursor_id as i32,
-                0,
+                *pc_if_empty as i32,
                 0,
                 OwnedValue::Text(Rc::new("".to_string())),
                 0,
@@ -1012,7 +3683,12 @@ fn get_indent_count(indent_count: usize, curr_insn: &Insn, prev_insn: Option<&In
                 cursor_id: _,
                 pc_if_empty: _,
             } => indent_count + 1,
-            Insn::SorterSort { cursor_id: _ } => indent_count + 1,
+            Insn::SorterSort {
+                cursor_id: _,
+                pc_if_empty: _,
+            } => indent_count + 1,
+            Insn::LastAwait { .. } => indent_count + 1,
+            Insn::SeekGE { .. } | Insn::SeekGT { .. } => indent_count + 1,
             _ => indent_count,
         }
     } else {
@@ -1021,6 +3697,7 @@ fn get_indent_count(indent_count: usize, curr_insn: &Insn, prev_insn: Option<&In
 
     let indent_count = match curr_insn {
         Insn::NextAsync { cursor_id: _ } => indent_count - 1,
+        Insn::PrevAsync { .. } => indent_count - 1,
         Insn::SorterNext {
             cursor_id: _,
             pc_if_next: _,
@@ -0,0 +1,883 @@
+//! Write-ahead log.
+//!
+//! In WAL mode, a transaction commits by appending the new contents of the
+//! pages it changed to a log file next to the database, named after it with
+//! `-wal` appended, instead of writing them to the database file. Readers look
+//! for the newest committed version of a page in the log before they read the
+//! database file. A checkpoint copies the pages in the log back to the
+//! database file, after which the log can start over.
+//!
+//! The log uses SQLite's format. It starts with a 32-byte header:
+//!
+//! ```text
+//! +--------+------+-----------------------------------------------+
+//! | Offset | Size | Description                                   |
+//! +--------+------+-----------------------------------------------+
+//! |      0 |    4 | Magic number, 0x377f0682 or 0x377f0683        |
+//! |      4 |    4 | File format version, 3007000                  |
+//! |      8 |    4 | Page size                                     |
+//! |     12 |    4 | Checkpoint sequence number                    |
+//! |     16 |    4 | Salt-1                                        |
+//! |     20 |    4 | Salt-2                                        |
+//! |     24 |    4 | Checksum-1 of the first 24 bytes              |
+//! |     28 |    4 | Checksum-2 of the first 24 bytes              |
+//! +--------+------+-----------------------------------------------+
+//! ```
+//!
+//! Frames follow, each a 24-byte header and the contents of a page:
+//!
+//! ```text
+//! +--------+------+-----------------------------------------------+
+//! | Offset | Size | Description                                   |
+//! +--------+------+-----------------------------------------------+
+//! |      0 |    4 | Page number                                   |
+//! |      4 |    4 | Database size in pages for a commit, else 0   |
+//! |      8 |    4 | Salt-1, copied from the header                |
+//! |     12 |    4 | Salt-2, copied from the header                |
+//! |     16 |    4 | Checksum-1                                    |
+//! |     20 |    4 | Checksum-2                                    |
+//! +--------+------+-----------------------------------------------+
+//! ```
+//!
+//! The checksum of a frame covers its first 8 bytes and the page, continuing
+//! from the checksum of the frame before it, or of the header for the first
+//! frame. A frame whose salts or checksum do not match ends the log, and only
+//! the frames up to the last commit are part of the database.
+//!
+//! For more information, see: https://www.sqlite.org/fileformat.html#the_write_ahead_log
+use crate::io::{
+    is_not_found, read_at, write_blocks, Buffer, Completion, File, OpenFlags, WriteCompletion,
+    BLOCK_SIZE, IO,
+};
+use crate::storage::{PageIO, PageSource};
+use anyhow::Result;
+use log::trace;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, Hasher};
+use std::rc::{Rc, Weak};
+
+const WAL_MAGIC: u32 = 0x377f0682;
+const WAL_VERSION: u32 = 3007000;
+const WAL_HEADER_SIZE: usize = 32;
+const WAL_FRAME_HEADER_SIZE: usize = 24;
+/// The frames of a transaction are written out this many at a time, so that
+/// a large transaction is never held in memory.
+const FRAMES_PER_WRITE: usize = 64;
+
+/// How a checkpoint copies the log to the database file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheckpointMode {
+    /// Copy the committed frames that no reader still needs to the database
+    /// file.
+    Passive,
+    /// Like `Passive`, and report the checkpoint as busy unless every frame
+    /// was copied.
+    Full,
+    /// Like `Full`, and start the log over, which needs every reader to see
+    /// the database file as it is after the checkpoint.
+    Restart,
+    /// Like `Restart`, and truncate the log file to zero bytes.
+    Truncate,
+}
+
+/// The result of a checkpoint, as `PRAGMA wal_checkpoint` reports it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CheckpointResult {
+    /// Whether a reader kept the checkpoint from doing all that its mode
+    /// asks for.
+    pub busy: bool,
+    /// The number of frames in the log.
+    pub num_frames: usize,
+    /// The number of frames that have been copied to the database file.
+    pub num_checkpointed_frames: usize,
+}
+
+thread_local! {
+    /// The state of the logs in use, by path, so that every handle on a
+    /// database in the process sees the same log.
+    static LOGS: RefCell<HashMap<String, Weak<RefCell<WalState>>>> = RefCell::new(HashMap::new());
+}
+
+/// The state of a log that is in use, shared by the handles on it.
+struct WalState {
+    file: Option<Rc<dyn File>>,
+    page_size: usize,
+    /// Whether checksums are computed on big-endian words.
+    big_endian_checksums: bool,
+    checkpoint_seq: u32,
+    salts: [u32; 2],
+    /// The checksum of the last committed frame, or of the header.
+    checksum: (u32, u32),
+    /// The number of committed frames.
+    max_frame: usize,
+    /// The number of frames copied to the database file by checkpoints.
+    num_backfilled: usize,
+    /// The committed frames of each page in the log, oldest first.
+    frames: HashMap<usize, Vec<usize>>,
+    /// The size of the database in pages as of each commit, by the last
+    /// frame of the commit.
+    commits: BTreeMap<usize, usize>,
+    /// The number of transactions committed to the log since it was opened,
+    /// which tells handles whether their pages are out of date.
+    num_commits: u64,
+    /// The read mark of each handle that is reading: the last frame of the
+    /// snapshot of the database that the handle sees.
+    read_marks: HashMap<usize, usize>,
+    next_handle: usize,
+}
+
+/// A handle on the log of a database.
+pub struct Wal {
+    io: Rc<dyn IO>,
+    path: String,
+    state: RefCell<Option<Rc<RefCell<WalState>>>>,
+    handle: Cell<usize>,
+    /// The number of reads in progress through this handle, which share a
+    /// read mark.
+    num_reads: Cell<usize>,
+    /// The number of commits in the log when this handle last started to
+    /// read it.
+    num_commits: Cell<u64>,
+}
+
+/// A read of the database through a handle on the log. Checkpoints leave
+/// the pages of its snapshot alone until it is dropped.
+pub struct ReadLock {
+    wal: Rc<Wal>,
+}
+
+impl Drop for ReadLock {
+    fn drop(&mut self) {
+        self.wal.end_read();
+    }
+}
+
+impl Wal {
+    pub fn new(io: Rc<dyn IO>, path: String) -> Self {
+        Self {
+            io,
+            path,
+            state: RefCell::new(None),
+            handle: Cell::new(0),
+            num_reads: Cell::new(0),
+            num_commits: Cell::new(0),
+        }
+    }
+
+    /// Start using the log, reading the frames that earlier transactions
+    /// committed to it, if the file exists. Handles that are open on the log
+    /// already share their view of it.
+    pub fn open(&self, page_size: usize) -> Result<()> {
+        self.close();
+        let shared = LOGS.with(|logs| logs.borrow().get(&self.path).and_then(Weak::upgrade));
+        let shared = match shared {
+            Some(shared) => shared,
+            None => {
+                let state = Rc::new(RefCell::new(self.read_state(page_size)?));
+                LOGS.with(|logs| {
+                    let mut logs = logs.borrow_mut();
+                    logs.retain(|_, state| state.strong_count() > 0);
+                    logs.insert(self.path.clone(), Rc::downgrade(&state));
+                });
+                state
+            }
+        };
+        {
+            let mut state = shared.borrow_mut();
+            self.handle.set(state.next_handle);
+            state.next_handle += 1;
+            self.num_commits.set(state.num_commits);
+        }
+        self.num_reads.set(0);
+        self.state.replace(Some(shared));
+        Ok(())
+    }
+
+    fn read_state(&self, page_size: usize) -> Result<WalState> {
+        let mut state = WalState {
+            file: None,
+            page_size,
+            big_endian_checksums: false,
+            checkpoint_seq: 0,
+            salts: [random_u32(), random_u32()],
+            checksum: (0, 0),
+            max_frame: 0,
+            num_backfilled: 0,
+            frames: HashMap::new(),
+            commits: BTreeMap::new(),
+            num_commits: 0,
+            read_marks: HashMap::new(),
+            next_handle: 0,
+        };
+        let file = match self.io.open_file(&self.path, OpenFlags::None) {
+            Ok(file) => file,
+            Err(err) if is_not_found(&err) => return Ok(state),
+            Err(err) => return Err(err),
+        };
+        let image = read_at(&*self.io, &*file, 0, file.size()?)?;
+        state.file = Some(file);
+        state.recover(&image);
+        trace!(
+            "open(path = {}, frames = {}, pages = {})",
+            self.path,
+            state.max_frame,
+            state.frames.len()
+        );
+        Ok(state)
+    }
+
+    /// Stop using the log.
+    pub fn close(&self) {
+        if let Some(shared) = self.state.replace(None) {
+            shared.borrow_mut().read_marks.remove(&self.handle.get());
+        }
+        self.num_reads.set(0);
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.state.borrow().is_some()
+    }
+
+    /// Delete the log file, if it exists.
+    pub fn delete(&self) -> Result<()> {
+        match self.io.remove_file(&self.path) {
+            Err(err) if is_not_found(&err) => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Start a read. The first of the reads in progress through this handle
+    /// takes a snapshot of the transactions committed so far. Returns
+    /// whether transactions were committed through other handles since the
+    /// last snapshot.
+    pub fn begin_read(self: &Rc<Self>) -> (ReadLock, bool) {
+        let mut changed = false;
+        if self.num_reads.get() == 0 {
+            let shared = self.shared();
+            let mut state = shared.borrow_mut();
+            let max_frame = state.max_frame;
+            state.read_marks.insert(self.handle.get(), max_frame);
+            changed = self.num_commits.replace(state.num_commits) != state.num_commits;
+            trace!(
+                "begin_read(read_mark = {}, changed = {})",
+                max_frame,
+                changed
+            );
+        }
+        self.num_reads.set(self.num_reads.get() + 1);
+        (ReadLock { wal: self.clone() }, changed)
+    }
+
+    fn end_read(&self) {
+        // Closing the log ends the reads in progress.
+        if self.num_reads.get() == 0 {
+            return;
+        }
+        self.num_reads.set(self.num_reads.get() - 1);
+        if self.num_reads.get() == 0 {
+            trace!("end_read()");
+            self.shared()
+                .borrow_mut()
+                .read_marks
+                .remove(&self.handle.get());
+        }
+    }
+
+    fn shared(&self) -> Rc<RefCell<WalState>> {
+        self.state.borrow().as_ref().unwrap().clone()
+    }
+
+    /// The last frame of the snapshot of this handle: its read mark if it is
+    /// reading, otherwise the last committed frame.
+    fn read_mark(&self, state: &WalState) -> usize {
+        match state.read_marks.get(&self.handle.get()) {
+            Some(read_mark) => *read_mark,
+            None => state.max_frame,
+        }
+    }
+
+    /// The size of the database in pages as of the snapshot, if the log
+    /// holds a commit of it.
+    pub fn database_size(&self) -> Option<usize> {
+        let shared = self.state.borrow().clone()?;
+        let state = shared.borrow();
+        let read_mark = self.read_mark(&state);
+        let (_, database_size) = state.commits.range(..=read_mark).next_back()?;
+        Some(*database_size)
+    }
+
+    /// The newest frame of the snapshot that holds a page, if any.
+    pub fn find_frame(&self, page_idx: usize) -> Option<usize> {
+        let shared = self.state.borrow().clone()?;
+        let state = shared.borrow();
+        let read_mark = self.read_mark(&state);
+        let frames = state.frames.get(&page_idx)?;
+        frames
+            .iter()
+            .rev()
+            .find(|frame| **frame <= read_mark)
+            .copied()
+    }
+
+    /// Read the page of a frame into the completion's buffer and complete it.
+    pub fn read_frame(&self, frame: usize, c: Rc<Completion>) -> Result<()> {
+        trace!("read_frame(frame = {})", frame);
+        let data = {
+            let shared = self.shared();
+            let state = shared.borrow();
+            let file = state.file.as_ref().unwrap();
+            let len = c.buf().len().min(state.page_size);
+            read_at(&*self.io, &**file, state.frame_offset(frame), len)?
+        };
+        c.buf_mut().as_mut_slice()[..data.len()].copy_from_slice(&data);
+        c.complete();
+        Ok(())
+    }
+
+    /// Append the pages of a transaction to the log, the last frame marking
+    /// the commit, and wait until they are on stable storage. The pages are
+    /// written out as they come, a batch of frames at a time; readers only
+    /// see them once the commit frame is written. The transaction must have
+    /// been made on the newest snapshot.
+    pub fn append(
+        &self,
+        pages: impl Iterator<Item = Result<(usize, Vec<u8>)>>,
+        database_size: usize,
+    ) -> Result<()> {
+        let shared = self.shared();
+        let mut state = shared.borrow_mut();
+        trace!("append(path = {})", self.path);
+        if self.num_commits.get() != state.num_commits {
+            anyhow::bail!("database is locked");
+        }
+        if state.file.is_none() {
+            state.file = Some(self.io.open_file(&self.path, OpenFlags::Create)?);
+        }
+        // Once every frame has been copied to the database file, the log
+        // starts over.
+        if state.max_frame > 0 && state.num_backfilled == state.max_frame && state.can_restart() {
+            state.restart();
+        }
+        let mut image = Vec::new();
+        if state.max_frame == 0 {
+            image.extend_from_slice(&state.header());
+            state.checksum = state.checksum(&image[..24], (0, 0));
+        }
+        let mut offset = match state.max_frame {
+            0 => 0,
+            max_frame => state.frame_offset(max_frame + 1) - WAL_FRAME_HEADER_SIZE,
+        };
+        let file = state.file.clone().unwrap();
+        let mut checksum = state.checksum;
+        let mut page_idxs = Vec::new();
+        let mut pages = pages.peekable();
+        while let Some(page) = pages.next() {
+            let (page_idx, data) = page?;
+            assert_eq!(data.len(), state.page_size);
+            let is_commit = pages.peek().is_none();
+            let commit_size = if is_commit { database_size as u32 } else { 0 };
+            let mut header = [0; WAL_FRAME_HEADER_SIZE];
+            header[0..4].copy_from_slice(&(page_idx as u32).to_be_bytes());
+            header[4..8].copy_from_slice(&commit_size.to_be_bytes());
+            header[8..12].copy_from_slice(&state.salts[0].to_be_bytes());
+            header[12..16].copy_from_slice(&state.salts[1].to_be_bytes());
+            checksum = state.checksum(&header[..8], checksum);
+            checksum = state.checksum(&data, checksum);
+            header[16..20].copy_from_slice(&checksum.0.to_be_bytes());
+            header[20..24].copy_from_slice(&checksum.1.to_be_bytes());
+            image.extend_from_slice(&header);
+            image.extend_from_slice(&data);
+            page_idxs.push(page_idx);
+            if is_commit || page_idxs.len() % FRAMES_PER_WRITE == 0 {
+                write_at(&*self.io, &*file, offset, &image)?;
+                offset += image.len();
+                image.clear();
+            }
+        }
+        file.sync()?;
+
+        for page_idx in page_idxs {
+            state.max_frame += 1;
+            let frame = state.max_frame;
+            state.frames.entry(page_idx).or_default().push(frame);
+        }
+        let max_frame = state.max_frame;
+        state.commits.insert(max_frame, database_size);
+        state.checksum = checksum;
+        state.num_commits += 1;
+        self.num_commits.set(state.num_commits);
+        // The reads in progress through this handle see its own commit.
+        if let Some(read_mark) = state.read_marks.get_mut(&self.handle.get()) {
+            *read_mark = max_frame;
+        }
+        Ok(())
+    }
+
+    /// Copy the newest committed frame of each page in the log to the
+    /// database file, up to the oldest read mark, so that readers still find
+    /// the pages of their snapshots in the database file.
+    pub fn checkpoint(&self, db: &PageSource, mode: CheckpointMode) -> Result<CheckpointResult> {
+        let shared = self.shared();
+        let mut state = shared.borrow_mut();
+        let safe_frame = state
+            .read_marks
+            .values()
+            .copied()
+            .fold(state.max_frame, usize::min);
+        trace!(
+            "checkpoint(path = {}, mode = {:?}, safe_frame = {})",
+            self.path,
+            mode,
+            safe_frame
+        );
+        if state.num_backfilled < safe_frame {
+            let file = state.file.as_ref().unwrap();
+            let written = Rc::new(Cell::new(0));
+            let mut num_writes = 0;
+            for (page_idx, frames) in &state.frames {
+                let Some(frame) = frames.iter().rev().find(|frame| **frame <= safe_frame) else {
+                    continue;
+                };
+                if *frame <= state.num_backfilled {
+                    continue;
+                }
+                let data = read_at(
+                    &*self.io,
+                    &**file,
+                    state.frame_offset(*frame),
+                    state.page_size,
+                )?;
+                let mut buffer = Buffer::allocate(state.page_size, Rc::new(|_| {}));
+                buffer.as_mut_slice().copy_from_slice(&data);
+                let c = {
+                    let written = written.clone();
+                    Rc::new(WriteCompletion::new(Box::new(move |_| {
+                        written.set(written.get() + 1)
+                    })))
+                };
+                db.write(*page_idx, Rc::new(RefCell::new(buffer)), c)?;
+                num_writes += 1;
+            }
+            while written.get() < num_writes {
+                self.io.run_once()?;
+            }
+            // Pages past the end of the database are not in any snapshot
+            // once the whole log is copied.
+            if safe_frame == state.max_frame {
+                let (_, database_size) = state.commits.range(..=safe_frame).next_back().unwrap();
+                db.truncate(database_size * state.page_size)?;
+            }
+            db.sync()?;
+            state.num_backfilled = safe_frame;
+        }
+        let result = CheckpointResult {
+            busy: mode != CheckpointMode::Passive && state.num_backfilled < state.max_frame,
+            num_frames: state.max_frame,
+            num_checkpointed_frames: state.num_backfilled,
+        };
+        if mode == CheckpointMode::Restart || mode == CheckpointMode::Truncate {
+            if result.busy || !state.can_restart() {
+                return Ok(CheckpointResult {
+                    busy: true,
+                    ..result
+                });
+            }
+            if let Some(file) = state.file.clone() {
+                state.restart();
+                if mode == CheckpointMode::Truncate {
+                    file.truncate(0)?;
+                    file.sync()?;
+                    return Ok(CheckpointResult {
+                        busy: false,
+                        num_frames: 0,
+                        num_checkpointed_frames: 0,
+                    });
+                }
+                // The new salts invalidate the frames in the log.
+                let header = state.header();
+                state.checksum = state.checksum(&header[..24], (0, 0));
+                write_at(&*self.io, &*file, 0, &header)?;
+                file.sync()?;
+            }
+        }
+        Ok(result)
+    }
+}
+
+impl WalState {
+    /// Read the committed frames of a log file.
+    fn recover(&mut self, image: &[u8]) {
+        if image.len() < WAL_HEADER_SIZE {
+            return;
+        }
+        let read_u32 = |offset: usize| {
+            u32::from_be_bytes([
+                image[offset],
+                image[offset + 1],
+                image[offset + 2],
+                image[offset + 3],
+            ])
+        };
+        let magic = read_u32(0);
+        let page_size = read_u32(8) as usize;
+        if magic & !1 != WAL_MAGIC || read_u32(4) != WAL_VERSION || page_size != self.page_size {
+            return;
+        }
+        self.big_endian_checksums = magic & 1 != 0;
+        if self.checksum(&image[..24], (0, 0)) != (read_u32(24), read_u32(28)) {
+            return;
+        }
+        self.checkpoint_seq = read_u32(12);
+        self.salts = [read_u32(16), read_u32(20)];
+        self.checksum = (read_u32(24), read_u32(28));
+
+        let mut checksum = self.checksum;
+        let mut frames = Vec::new();
+        let mut frame = 1;
+        while self.frame_offset(frame) + page_size <= image.len() {
+            let offset = self.frame_offset(frame) - WAL_FRAME_HEADER_SIZE;
+            let page_idx = read_u32(offset) as usize;
+            let commit_size = read_u32(offset + 4) as usize;
+            if page_idx == 0 || [read_u32(offset + 8), read_u32(offset + 12)] != self.salts {
+                break;
+            }
+            checksum = self.checksum(&image[offset..offset + 8], checksum);
+            checksum = self.checksum(
+                &image[offset + WAL_FRAME_HEADER_SIZE..offset + WAL_FRAME_HEADER_SIZE + page_size],
+                checksum,
+            );
+            if checksum != (read_u32(offset + 16), read_u32(offset + 20)) {
+                break;
+            }
+            frames.push(page_idx);
+            if commit_size != 0 {
+                for page_idx in frames.drain(..) {
+                    self.max_frame += 1;
+                    self.frames
+                        .entry(page_idx)
+                        .or_default()
+                        .push(self.max_frame);
+                }
+                self.commits.insert(self.max_frame, commit_size);
+                self.checksum = checksum;
+            }
+            frame += 1;
+        }
+    }
+
+    /// Whether the log can start over: every frame has been copied to the
+    /// database file, and no reader needs a frame that is not there.
+    fn can_restart(&self) -> bool {
+        self.num_backfilled == self.max_frame
+            && self
+                .read_marks
+                .values()
+                .all(|read_mark| *read_mark == self.max_frame)
+    }
+
+    /// Forget the frames, which have been copied to the database file, so
+    /// that the next commit writes a new header and overwrites them. The new
+    /// salts make the old frames invalid. Readers find the pages of their
+    /// snapshots in the database file from now on.
+    fn restart(&mut self) {
+        self.checkpoint_seq = self.checkpoint_seq.wrapping_add(1);
+        self.salts = [self.salts[0].wrapping_add(1), random_u32()];
+        self.max_frame = 0;
+        self.num_backfilled = 0;
+        self.frames.clear();
+        self.commits.clear();
+        for read_mark in self.read_marks.values_mut() {
+            *read_mark = 0;
+        }
+    }
+
+    fn header(&self) -> [u8; WAL_HEADER_SIZE] {
+        let mut header = [0; WAL_HEADER_SIZE];
+        let magic = WAL_MAGIC | self.big_endian_checksums as u32;
+        header[0..4].copy_from_slice(&magic.to_be_bytes());
+        header[4..8].copy_from_slice(&WAL_VERSION.to_be_bytes());
+        header[8..12].copy_from_slice(&(self.page_size as u32).to_be_bytes());
+        header[12..16].copy_from_slice(&self.checkpoint_seq.to_be_bytes());
+        header[16..20].copy_from_slice(&self.salts[0].to_be_bytes());
+        header[20..24].copy_from_slice(&self.salts[1].to_be_bytes());
+        let checksum = self.checksum(&header[..24], (0, 0));
+        header[24..28].copy_from_slice(&checksum.0.to_be_bytes());
+        header[28..32].copy_from_slice(&checksum.1.to_be_bytes());
+        header
+    }
+
+    /// The offset of the page of a frame, counting frames from 1.
+    fn frame_offset(&self, frame: usize) -> usize {
+        WAL_HEADER_SIZE
+            + (frame - 1) * (WAL_FRAME_HEADER_SIZE + self.page_size)
+            + WAL_FRAME_HEADER_SIZE
+    }
+
+    fn checksum(&self, data: &[u8], checksum: (u32, u32)) -> (u32, u32) {
+        let word = |bytes: &[u8]| {
+            let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+            if self.big_endian_checksums {
+                u32::from_be_bytes(bytes)
+            } else {
+                u32::from_le_bytes(bytes)
+            }
+        };
+        let (mut s0, mut s1) = checksum;
+        for words in data.chunks_exact(8) {
+            s0 = s0.wrapping_add(word(&words[0..4])).wrapping_add(s1);
+            s1 = s1.wrapping_add(word(&words[4..8])).wrapping_add(s0);
+        }
+        (s0, s1)
+    }
+}
+
+/// Reads pages from the log if it holds them, and from the database file
+/// otherwise.
+pub struct WalPageIO {
+    db: PageSource,
+    wal: Rc<Wal>,
+}
+
+impl WalPageIO {
+    pub fn new(db: PageSource, wal: Rc<Wal>) -> Self {
+        Self { db, wal }
+    }
+}
+
+impl PageIO for WalPageIO {
+    fn get(&self, page_idx: usize, c: Rc<Completion>) -> Result<()> {
+        match self.wal.find_frame(page_idx) {
+            Some(frame) => self.wal.read_frame(frame, c),
+            None => self.db.get(page_idx, c),
+        }
+    }
+
+    fn write(
+        &self,
+        page_idx: usize,
+        buffer: Rc<RefCell<Buffer>>,
+        c: Rc<WriteCompletion>,
+    ) -> Result<()> {
+        self.db.write(page_idx, buffer, c)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.db.sync()
+    }
+
+    fn truncate(&self, len: usize) -> Result<()> {
+        self.db.truncate(len)
+    }
+}
+
+/// Write `data` at `pos` in whole blocks and cut the file off after it. The
+/// bytes of the first block before `pos` are read and written back.
+fn write_at(io: &dyn IO, file: &dyn File, pos: usize, data: &[u8]) -> Result<()> {
+    let start = pos / BLOCK_SIZE * BLOCK_SIZE;
+    let mut image = read_at(io, file, start, pos - start)?;
+    image.extend_from_slice(data);
+    image.resize(image.len().next_multiple_of(BLOCK_SIZE), 0);
+    write_blocks(io, file, start, &image)?;
+    file.truncate(pos + data.len())
+}
+
+/// A random number for the salts of the log header.
+fn random_u32() -> u32 {
+    // Every `RandomState` has different keys.
+    std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::PlatformIO;
+    use crate::test_util::{next_row, query, TempDatabase};
+    use crate::Database;
+
+    /// Create a database in WAL mode whose rows are all in the log, and copy
+    /// it and its log to a new database while SQLite still has them open, so
+    /// that the log is not checkpointed.
+    fn create_wal_db() -> TempDatabase {
+        let source = TempDatabase::new("wal-source");
+        let conn = source.sqlite();
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL; PRAGMA wal_autocheckpoint = 0;
+             CREATE TABLE t (a INTEGER PRIMARY KEY, b);
+             WITH RECURSIVE s(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM s WHERE i < 1000)
+             INSERT INTO t SELECT i, printf('%050d', i) FROM s;
+             DELETE FROM t WHERE a % 2 = 0;",
+        )
+        .unwrap();
+        let temp = TempDatabase::new("wal");
+        std::fs::copy(&source.path, &temp.path).unwrap();
+        std::fs::copy(source.sibling("-wal"), temp.sibling("-wal")).unwrap();
+        temp
+    }
+
+    #[test]
+    fn test_wal_interop() {
+        let temp = create_wal_db();
+
+        // Limbo reads the pages that SQLite committed to the log, and appends
+        // its own commits to it.
+        let (io, db) = temp.open();
+        let conn = db.connect();
+        assert_eq!(query(&io, &conn, "PRAGMA journal_mode").unwrap(), [["wal"]]);
+        assert_eq!(
+            query(&io, &conn, "SELECT count(*), sum(a) FROM t").unwrap(),
+            [["500", "250000"]]
+        );
+        query(&io, &conn, "DELETE FROM t WHERE a > 900").unwrap();
+        query(&io, &conn, "INSERT INTO t VALUES (2000, 'x')").unwrap();
+        assert_eq!(
+            query(&io, &conn, "SELECT count(*), sum(a) FROM t").unwrap(),
+            [["451", "204500"]]
+        );
+        drop(conn);
+        drop(db);
+
+        // SQLite reads the pages that Limbo committed to the log.
+        let check = |expected: (i64, i64)| {
+            temp.check_integrity();
+            let actual: (i64, i64) = temp
+                .sqlite()
+                .query_row("SELECT count(*), sum(a) FROM t", (), |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })
+                .unwrap();
+            assert_eq!(actual, expected);
+        };
+        let copy = TempDatabase::new("wal-copy");
+        std::fs::copy(&temp.path, &copy.path).unwrap();
+        std::fs::copy(temp.sibling("-wal"), copy.sibling("-wal")).unwrap();
+        check((451, 204500));
+
+        // A checkpoint copies the pages to the database file.
+        std::fs::rename(&copy.path, &temp.path).unwrap();
+        std::fs::rename(copy.sibling("-wal"), temp.sibling("-wal")).unwrap();
+        let (io, db) = temp.open();
+        let conn = db.connect();
+        let frames = query(&io, &conn, "PRAGMA wal_checkpoint(TRUNCATE)").unwrap();
+        assert_eq!(frames, [["0", "0", "0"]]);
+        assert_eq!(std::fs::metadata(temp.sibling("-wal")).unwrap().len(), 0);
+        std::fs::remove_file(temp.sibling("-wal")).unwrap();
+        check((451, 204500));
+    }
+
+    #[test]
+    fn test_wal_torn_commit() {
+        let temp = TempDatabase::new("wal-torn");
+        let path = temp.sibling("-wal");
+        let io: Rc<dyn IO> = Rc::new(PlatformIO::new().unwrap());
+        let wal = Wal::new(io.clone(), path.clone());
+        wal.open(512).unwrap();
+        wal.append(
+            [(1, vec![1; 512]), (2, vec![2; 512])].into_iter().map(Ok),
+            2,
+        )
+        .unwrap();
+        wal.append(
+            [(2, vec![3; 512]), (3, vec![4; 512])].into_iter().map(Ok),
+            3,
+        )
+        .unwrap();
+        assert_eq!(wal.find_frame(2), Some(3));
+
+        // The last frame, which marks the second commit, is cut off.
+        let len = std::fs::metadata(&path).unwrap().len();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 100)
+            .unwrap();
+        wal.open(512).unwrap();
+        assert_eq!(wal.database_size(), Some(2));
+        assert_eq!(wal.find_frame(2), Some(2));
+        assert_eq!(wal.find_frame(3), None);
+
+        // The next commit overwrites the frames of the torn one.
+        wal.append([(3, vec![5; 512])].into_iter().map(Ok), 3)
+            .unwrap();
+        wal.open(512).unwrap();
+        assert_eq!(wal.database_size(), Some(3));
+        assert_eq!(wal.find_frame(3), Some(3));
+    }
+
+    #[test]
+    fn test_checkpoint_with_reader() {
+        let temp = TempDatabase::with_sql(
+            "wal-reader",
+            "PRAGMA journal_mode = WAL;
+             CREATE TABLE t (a INTEGER PRIMARY KEY, b);
+             WITH RECURSIVE s(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM s WHERE i < 1000)
+             INSERT INTO t SELECT i, printf('%0200d', 0) FROM s;",
+        );
+        let io: Rc<dyn IO> = Rc::new(PlatformIO::new().unwrap());
+        let writer = Database::open_file(io.clone(), temp.path_str()).unwrap();
+        let writer = writer.connect();
+        let reader = Database::open_file(io.clone(), temp.path_str()).unwrap();
+        let reader = reader.connect();
+        let sql = "UPDATE t SET b = replace(b, '0', '1') WHERE a <= 500";
+        query(&io, &writer, sql).unwrap();
+
+        // The reader's snapshot includes the first update only. It reads the
+        // pages of the rows that the first update did not change from the
+        // database file.
+        let mut rows = reader.query("SELECT b FROM t").unwrap().unwrap();
+        let mut next_b = || next_row(&io, &mut rows).unwrap().map(|row| row[0].clone());
+        assert_eq!(next_b(), Some("1".repeat(200)));
+        query(&io, &writer, "UPDATE t SET b = '2'").unwrap();
+
+        // Only the frames of the first update are copied, and the log cannot
+        // start over.
+        let passive = writer.checkpoint(CheckpointMode::Passive).unwrap().unwrap();
+        assert!(!passive.busy);
+        assert!(passive.num_checkpointed_frames > 0);
+        assert!(passive.num_checkpointed_frames < passive.num_frames);
+        let truncate = writer
+            .checkpoint(CheckpointMode::Truncate)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            truncate,
+            CheckpointResult {
+                busy: true,
+                ..passive
+            }
+        );
+
+        let mut num_rows = 1;
+        while let Some(b) = next_b() {
+            num_rows += 1;
+            let digit = if num_rows <= 500 { "1" } else { "0" };
+            assert_eq!(b, digit.repeat(200));
+        }
+        assert_eq!(num_rows, 1000);
+        drop(rows);
+
+        // Once the reader is done, the whole log is copied and truncated, and
+        // the reader sees the second update.
+        let truncate = writer
+            .checkpoint(CheckpointMode::Truncate)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            truncate,
+            CheckpointResult {
+                busy: false,
+                num_frames: 0,
+                num_checkpointed_frames: 0,
+            }
+        );
+        assert_eq!(std::fs::metadata(temp.sibling("-wal")).unwrap().len(), 0);
+        assert_eq!(
+            query(&io, &reader, "SELECT count(*) FROM t WHERE b = '2'").unwrap(),
+            [["1000"]]
+        );
+        temp.check_integrity();
+    }
+}
@@ -1,5 +1,5 @@
 use anyhow::Result;
-use limbo_core::{Database, File, PlatformIO, IO};
+use limbo_core::{Database, File, OpenFlags, PlatformIO, IO};
 use rand::prelude::*;
 use rand_chacha::ChaCha8Rng;
 use std::cell::RefCell;
@@ -68,8 +68,8 @@ impl SimulatorIO {
 }
 
 impl IO for SimulatorIO {
-    fn open_file(&self, path: &str) -> Result<Rc<dyn limbo_core::File>> {
-        let inner = self.inner.open_file(path)?;
+    fn open_file(&self, path: &str, flags: OpenFlags) -> Result<Rc<dyn limbo_core::File>> {
+        let inner = self.inner.open_file(path, flags)?;
         let file = Rc::new(SimulatorFile {
             inner,
             fault: RefCell::new(false),
@@ -78,6 +78,13 @@ impl IO for SimulatorIO {
         Ok(file)
     }
 
+    fn remove_file(&self, path: &str) -> Result<()> {
+        if *self.fault.borrow() {
+            return Err(anyhow::anyhow!("Injected fault"));
+        }
+        self.inner.remove_file(path)
+    }
+
     fn run_once(&self) -> Result<()> {
         if *self.fault.borrow() {
             return Err(anyhow::anyhow!("Injected fault"));
@@ -117,4 +124,22 @@ impl limbo_core::File for SimulatorFile {
         }
         self.inner.pwrite(pos, buffer, c)
     }
+
+    fn sync(&self) -> Result<()> {
+        if *self.fault.borrow() {
+            return Err(anyhow::anyhow!("Injected fault"));
+        }
+        self.inner.sync()
+    }
+
+    fn size(&self) -> Result<usize> {
+        self.inner.size()
+    }
+
+    fn truncate(&self, len: usize) -> Result<()> {
+        if *self.fault.borrow() {
+            return Err(anyhow::anyhow!("Injected fault"));
+        }
+        self.inner.truncate(len)
+    }
 }